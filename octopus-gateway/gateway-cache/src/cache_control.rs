@@ -0,0 +1,126 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the `Cache-Control` header
+
+use http::header::CACHE_CONTROL;
+use http::HeaderMap;
+
+/// the directives of the `Cache-Control` headers of a request or a response
+///
+/// the unknown directives are ignored, a malformed delta-seconds counts as 0
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    /// parse all the `Cache-Control` headers
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = CacheControl::default();
+        for value in headers.get_all(CACHE_CONTROL).iter() {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for directive in split_directives(value) {
+                let (name, arg) = match directive.split_once('=') {
+                    Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                let seconds = || Some(arg.and_then(|a| a.parse().ok()).unwrap_or(0));
+                match name.to_ascii_lowercase().as_str() {
+                    "no-store" => cc.no_store = true,
+                    // the field names of `no-cache` and `private` are not honoured, the whole
+                    // response is treated so
+                    "no-cache" => cc.no_cache = true,
+                    "private" => cc.private = true,
+                    "public" => cc.public = true,
+                    "must-revalidate" => cc.must_revalidate = true,
+                    "proxy-revalidate" => cc.proxy_revalidate = true,
+                    "max-age" => cc.max_age = seconds(),
+                    "s-maxage" => cc.s_maxage = seconds(),
+                    "stale-while-revalidate" => cc.stale_while_revalidate = seconds(),
+                    "stale-if-error" => cc.stale_if_error = seconds(),
+                    _ => {}
+                }
+            }
+        }
+        cc
+    }
+}
+
+/// split on the commas which are not quoted
+fn split_directives(value: &str) -> Vec<&str> {
+    let mut directives = vec![];
+    let (mut start, mut quoted) = (0, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                directives.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    directives.push(&value[start..]);
+    directives.retain(|d| !d.trim().is_empty());
+    directives
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(values: &[&'static str]) -> CacheControl {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(CACHE_CONTROL, value.parse().unwrap());
+        }
+        CacheControl::parse(&headers)
+    }
+
+    #[test]
+    fn test_parse() {
+        let cc = parse(&["public, max-age=60", "S-MAXAGE=\"120\", stale-while-revalidate=30"]);
+        assert!(cc.public && !cc.private && !cc.no_store);
+        assert_eq!(cc.max_age, Some(60));
+        assert_eq!(cc.s_maxage, Some(120));
+        assert_eq!(cc.stale_while_revalidate, Some(30));
+        assert_eq!(cc.stale_if_error, None);
+
+        let cc = parse(&["no-cache=\"Set-Cookie, X-Foo\", must-revalidate,, max-age=abc"]);
+        assert!(cc.no_cache && cc.must_revalidate);
+        assert_eq!(cc.max_age, Some(0));
+
+        assert_eq!(parse(&[]), CacheControl::default());
+    }
+}
@@ -0,0 +1,183 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the key a response is cached with
+
+use gateway_httpd::util::normalize_path;
+use gateway_httpd::RequestHeader;
+use http::{header, Method};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// the key of a cached response, built from the request
+///
+/// the method, the host, the normalized path and the query make the primary key. the responses
+/// varying on request headers are stored apart under a [CacheKey::variance()] of the primary key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    method: Method,
+    host: String,
+    path: String,
+    query: Option<String>,
+}
+
+impl CacheKey {
+    /// `path` is normalized, the `host` is case insensitive
+    ///
+    /// the proxy forwards the normalized path as well, so the key is always the one of the path
+    /// the upstream received
+    pub fn new(method: Method, host: &str, path: &str, query: Option<&str>) -> Self {
+        // a HEAD request is answered from the response of GET
+        let method = if method == Method::HEAD { Method::GET } else { method };
+        CacheKey {
+            method,
+            host: host.to_ascii_lowercase(),
+            path: normalize_path(path),
+            query: query.map(|q| q.to_string()),
+        }
+    }
+
+    /// the key of the request, the host is taken from the `Host` header or else the uri
+    pub fn from_request(req: &RequestHeader) -> Self {
+        let host = req
+            .headers
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri.authority().map(|a| a.as_str()))
+            .unwrap_or_default();
+        CacheKey::new(req.method.clone(), host, req.uri.path(), req.uri.query())
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// the primary key as a string, e.g. `GET example.com/index.html?lang=en`
+    pub fn primary(&self) -> String {
+        self.to_string()
+    }
+
+    /// the host, the path and the query, e.g. `example.com/index.html?lang=en`
+    pub fn url(&self) -> String {
+        match self.query.as_ref() {
+            Some(query) => format!("{}{}?{query}", self.host, self.path),
+            None => format!("{}{}", self.host, self.path),
+        }
+    }
+
+    /// the hashed primary key, which the storages use
+    pub fn hash(&self) -> String {
+        hex_hash([self.primary().as_bytes()])
+    }
+
+    /// the hashed key of the variant of the response selected by the `vary` headers of the request
+    pub fn variance(&self, vary: &[String], req: &RequestHeader) -> String {
+        let mut parts = vec![self.primary().into_bytes()];
+        for name in vary.iter() {
+            let mut part = format!("\n{name}:").into_bytes();
+            for (i, value) in req.headers.get_all(name.as_str()).iter().enumerate() {
+                if i > 0 {
+                    part.push(b',');
+                }
+                part.extend_from_slice(value.as_bytes());
+            }
+            parts.push(part);
+        }
+        hex_hash(parts.iter().map(|p| p.as_slice()))
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}{}", self.method, self.host, self.path)?;
+        if let Some(query) = self.query.as_ref() {
+            write!(f, "?{query}")?;
+        }
+        Ok(())
+    }
+}
+
+fn hex_hash<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, headers: &[(&'static str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.append_header(*name, value.to_string()).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_key() {
+        let req = request("GET", "/a//b/../c?x=1", &[("Host", "Example.COM")]);
+        let key = CacheKey::from_request(&req);
+        assert_eq!(key.primary(), "GET example.com/a/c?x=1");
+        assert_eq!(key.url(), "example.com/a/c?x=1");
+        assert_eq!(key.hash().len(), 64);
+
+        // HEAD shares the key of GET
+        let head = CacheKey::from_request(&request("HEAD", "/a/c?x=1", &[("Host", "example.com")]));
+        assert_eq!(head, key);
+        assert_eq!(head.hash(), key.hash());
+
+        let other = CacheKey::from_request(&request("GET", "/a/c?x=2", &[("Host", "example.com")]));
+        assert_ne!(other.hash(), key.hash());
+    }
+
+    #[test]
+    fn test_variance() {
+        let vary = vec!["accept-encoding".to_string()];
+        let gzip = request("GET", "/", &[("Host", "a"), ("Accept-Encoding", "gzip")]);
+        let br = request("GET", "/", &[("Host", "a"), ("Accept-Encoding", "br")]);
+        let gzip2 = request(
+            "GET",
+            "/",
+            &[("Host", "a"), ("Accept-Encoding", "gzip"), ("Cookie", "x")],
+        );
+        let key = CacheKey::from_request(&gzip);
+        assert_ne!(key.variance(&vary, &gzip), key.variance(&vary, &br));
+        assert_eq!(key.variance(&vary, &gzip), key.variance(&vary, &gzip2));
+        assert_ne!(key.variance(&vary, &gzip), key.hash());
+    }
+}
@@ -0,0 +1,761 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the http cache
+//!
+//! [HttpCache] follows a request through the phases of the cache: once enabled it is looked up
+//! with the [CacheKey] of the request, which ends in a [CachePhase::Hit], a [CachePhase::Stale]
+//! or a [CachePhase::Miss]. the response fetched on a miss is stored in the [Storage] if
+//! [resp_cacheable()] allows
+//!
+//! a stale response is revalidated with the upstream, and may still be served while it is
+//! revalidated, or when the upstream fails, as its `stale-while-revalidate` and `stale-if-error`
+//! allow
+//!
+//! the cached responses are purged by key, by url prefix or by `Surrogate-Key` tag with a
+//! [Purger], if they are recorded in a [CacheIndex]
+//!
+//! with a [CacheLock], only one request of a key fetches the missing or stale response from the
+//! upstream, the others wait for it and are served the response while it is being written
+
+use bytes::{Bytes, BytesMut};
+use gateway_error::{Error, ErrorType::*, Result};
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use http::{header, Method};
+use lock::{LockOutcome, Locked, ReadLock, WriteLock};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+pub mod cache_control;
+pub mod httpdate;
+pub mod key;
+pub mod lock;
+pub mod meta;
+pub mod purge;
+pub mod storage;
+
+pub use key::CacheKey;
+pub use lock::CacheLock;
+pub use meta::{has_validators, merge_not_modified, req_cacheable, resp_cacheable, CacheMeta, RespCacheable};
+pub use purge::{CacheIndex, PurgeTarget, Purger};
+pub use storage::{CacheObject, DiskStorage, EvictionListener, MemoryStorage, Storage};
+
+/// the hop-by-hop headers, which are not stored
+const HOP_HEADERS: [header::HeaderName; 6] = [
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::TE,
+    header::TRAILER,
+    header::UPGRADE,
+    header::PROXY_AUTHENTICATE,
+];
+
+/// where a request is in the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePhase {
+    /// the cache is not used
+    Disabled,
+    /// the cache is enabled but not looked up yet
+    Uninit,
+    /// the request or its response is not cacheable, the response is proxied as it is
+    Bypass,
+    /// nothing is cached, the response is fetched and stored
+    Miss,
+    /// a fresh response is cached
+    Hit,
+    /// an expired response is cached, it is revalidated or fetched again
+    Stale,
+    /// the stale response is served while it is revalidated in the background
+    StaleUpdating,
+    /// the stale response is confirmed by the upstream with a 304 and served
+    Revalidated,
+}
+
+impl CachePhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CachePhase::Disabled => "disabled",
+            CachePhase::Uninit => "uninitialized",
+            CachePhase::Bypass => "bypass",
+            CachePhase::Miss => "miss",
+            CachePhase::Hit => "hit",
+            CachePhase::Stale => "stale",
+            CachePhase::StaleUpdating => "stale-updating",
+            CachePhase::Revalidated => "revalidated",
+        }
+    }
+}
+
+/// the cache state of a request
+pub struct HttpCache {
+    phase: CachePhase,
+    inner: Option<Box<HttpCacheInner>>,
+}
+
+struct HttpCacheInner {
+    storage: Arc<dyn Storage>,
+    max_object_size: usize,
+    key: Option<CacheKey>,
+    /// the object found by the lookup
+    cached: Option<CacheObject>,
+    /// the storage key of `cached`
+    cached_key: Option<String>,
+    miss: Option<MissResponse>,
+    lock: Option<Arc<CacheLock>>,
+    /// where the stored responses are recorded to be purged
+    index: Option<Arc<CacheIndex>>,
+    /// held while this request fetches the response for the others
+    write_lock: Option<WriteLock>,
+    /// the response being fetched by another request, which is served as it is written
+    partial: Option<ReadLock>,
+    /// whether the body of `cached` is served
+    body_read: bool,
+}
+
+/// the response being fetched and stored
+struct MissResponse {
+    meta: CacheMeta,
+    body: BytesMut,
+    /// the storage key of the variant, if the response varies on request headers
+    variance: Option<String>,
+}
+
+impl Default for HttpCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpCache {
+    pub fn new() -> Self {
+        HttpCache {
+            phase: CachePhase::Disabled,
+            inner: None,
+        }
+    }
+
+    /// cache the responses of this request in `storage`, the responses with a body larger than
+    /// `max_object_size` are not cached
+    pub fn enable(&mut self, storage: Arc<dyn Storage>, max_object_size: usize) {
+        if self.phase != CachePhase::Disabled {
+            return;
+        }
+        self.phase = CachePhase::Uninit;
+        self.inner = Some(Box::new(HttpCacheInner {
+            storage,
+            max_object_size,
+            key: None,
+            cached: None,
+            cached_key: None,
+            miss: None,
+            lock: None,
+            index: None,
+            write_lock: None,
+            partial: None,
+            body_read: false,
+        }));
+    }
+
+    /// record the stored responses in `index` to purge them, the cache must be enabled
+    pub fn set_index(&mut self, index: Arc<CacheIndex>) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.index = Some(index);
+        }
+    }
+
+    /// let only one request of a key fetch the response at a time, the cache must be enabled
+    pub fn set_lock(&mut self, lock: Arc<CacheLock>) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.lock = Some(lock);
+        }
+    }
+
+    pub fn disable(&mut self) {
+        self.phase = CachePhase::Disabled;
+        self.inner = None;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.phase != CachePhase::Disabled
+    }
+
+    pub fn phase(&self) -> CachePhase {
+        self.phase
+    }
+
+    /// give up caching the response, e.g. because it is not cacheable
+    pub fn bypass(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            self.phase = CachePhase::Bypass;
+            inner.miss = None;
+            // the waiters fetch the response on their own
+            inner.write_lock = None;
+        }
+    }
+
+    /// treat the fresh response found by the lookup as expired
+    pub fn expire(&mut self) {
+        if self.phase != CachePhase::Hit {
+            return;
+        }
+        self.phase = CachePhase::Stale;
+        // the response being written by another request is not stored yet
+        if let Some(inner) = self.inner.as_mut().filter(|inner| inner.partial.is_some()) {
+            self.phase = CachePhase::Miss;
+            inner.partial = None;
+            inner.cached = None;
+        }
+    }
+
+    /// set the key to look up, the cache must be enabled
+    pub fn set_key(&mut self, key: CacheKey) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.key = Some(key);
+        }
+    }
+
+    pub fn key(&self) -> Option<&CacheKey> {
+        self.inner.as_ref()?.key.as_ref()
+    }
+
+    /// the cached object found by the lookup, if any
+    pub fn cached(&self) -> Option<&CacheObject> {
+        self.inner.as_ref()?.cached.as_ref()
+    }
+
+    /// look up the response of `req`, which moves the cache to [CachePhase::Hit],
+    /// [CachePhase::Stale] or [CachePhase::Miss]
+    ///
+    /// with a lock, a miss or a stale response which cannot be served while revalidated waits
+    /// for the request already fetching it, up to the timeout of the lock. the response is then
+    /// a hit, which may be served while it is still written
+    pub async fn lookup(&mut self, req: &RequestHeader) -> Result<()> {
+        if self.inner.is_none() || self.phase != CachePhase::Uninit {
+            return Error::e_explain(InternalError, "the cache is not enabled or already looked up");
+        }
+        self.find(req).await?;
+        let wait = self.phase == CachePhase::Miss
+            || (self.phase == CachePhase::Stale && !self.can_serve_stale_while_revalidate());
+        let Some(inner) = self.inner.as_mut().filter(|_| wait) else {
+            return Ok(());
+        };
+        let (Some(lock), Some(key)) = (inner.lock.clone(), inner.key.as_ref()) else {
+            return Ok(());
+        };
+        let mut reader = match lock.lock(&key.hash()) {
+            Locked::Write(writer) => {
+                inner.write_lock = Some(writer);
+                return Ok(());
+            }
+            Locked::Read(reader) => reader,
+        };
+        match tokio::time::timeout(lock.timeout(), reader.wait()).await {
+            Ok(LockOutcome::Streaming(meta, variance)) => {
+                let own_variance = match meta.vary() {
+                    [] => None,
+                    vary => Some(key.variance(vary, req)),
+                };
+                // another variant is being fetched
+                if own_variance != variance {
+                    return Ok(());
+                }
+                self.phase = CachePhase::Hit;
+                inner.cached = Some(CacheObject::new(*meta, Bytes::new()));
+                inner.cached_key = None;
+                inner.partial = Some(reader);
+                Ok(())
+            }
+            Ok(LockOutcome::Done) => self.find(req).await,
+            // fetch the response on its own
+            Ok(LockOutcome::Abandoned) | Err(_) => Ok(()),
+        }
+    }
+
+    /// look up the storage
+    async fn find(&mut self, req: &RequestHeader) -> Result<()> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Ok(());
+        };
+        let Some(key) = inner.key.as_ref() else {
+            return Error::e_explain(InternalError, "the cache key is not set");
+        };
+        let mut cached_key = key.hash();
+        let mut cached = inner.storage.lookup(&cached_key).await?;
+        // the primary key of a response varying on request headers only records the header names
+        if let Some(vary) = cached.as_ref().map(|c| c.meta.vary()).filter(|v| !v.is_empty()) {
+            cached_key = key.variance(vary, req);
+            cached = inner.storage.lookup(&cached_key).await?;
+        }
+        self.phase = match cached.as_ref() {
+            Some(c) if c.meta.is_fresh(SystemTime::now()) => CachePhase::Hit,
+            Some(_) => CachePhase::Stale,
+            None => CachePhase::Miss,
+        };
+        inner.cached_key = cached.as_ref().map(|_| cached_key);
+        inner.cached = cached;
+        Ok(())
+    }
+
+    /// whether the stale response may be served while it is revalidated
+    pub fn can_serve_stale_while_revalidate(&self) -> bool {
+        self.phase == CachePhase::Stale
+            && self
+                .cached()
+                .is_some_and(|c| c.meta.serve_stale_while_revalidate(SystemTime::now()))
+    }
+
+    /// whether the stale response may be served instead of the error of the upstream
+    pub fn can_serve_stale_if_error(&self) -> bool {
+        self.phase == CachePhase::Stale
+            && self
+                .cached()
+                .is_some_and(|c| c.meta.serve_stale_if_error(SystemTime::now()))
+    }
+
+    /// serve the stale response while it is revalidated in the background, return the cache state
+    /// to revalidate it with, or `None` if another request is revalidating it already
+    pub fn revalidate_in_background(&mut self) -> Option<HttpCache> {
+        if self.phase != CachePhase::Stale {
+            return None;
+        }
+        self.phase = CachePhase::StaleUpdating;
+        let inner = self.inner.as_ref()?;
+        let write_lock = match (inner.lock.as_ref(), inner.key.as_ref()) {
+            (Some(lock), Some(key)) => match lock.lock(&key.hash()) {
+                Locked::Write(writer) => Some(writer),
+                Locked::Read(_) => return None,
+            },
+            _ => None,
+        };
+        Some(HttpCache {
+            phase: CachePhase::Stale,
+            inner: Some(Box::new(HttpCacheInner {
+                storage: inner.storage.clone(),
+                max_object_size: inner.max_object_size,
+                key: inner.key.clone(),
+                cached: inner.cached.clone(),
+                cached_key: inner.cached_key.clone(),
+                miss: None,
+                lock: inner.lock.clone(),
+                index: inner.index.clone(),
+                write_lock,
+                partial: None,
+                body_read: false,
+            })),
+        })
+    }
+
+    /// make the request to the upstream conditional on the validators of the stale response
+    ///
+    /// the conditions of the downstream are replaced, as the 304 answers the cache, not the
+    /// downstream
+    pub fn conditional_request(&self, req: &mut RequestHeader) -> Result<()> {
+        if self.phase != CachePhase::Stale {
+            return Ok(());
+        }
+        let Some(cached) = self.cached() else {
+            return Ok(());
+        };
+        let headers = &cached.meta.header().headers;
+        for (validator, condition) in [
+            (header::ETAG, header::IF_NONE_MATCH),
+            (header::LAST_MODIFIED, header::IF_MODIFIED_SINCE),
+        ] {
+            req.remove_header(&condition);
+            if let Some(value) = headers.get(&validator) {
+                req.insert_header(condition, value.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the stale response is confirmed by a 304, `header` is the stored header merged with the 304
+    /// by [merge_not_modified()] and `cacheable` the decision on it
+    ///
+    /// the cached object is served from then on, and updated in the storage if it is cacheable
+    pub async fn revalidated(&mut self, header: ResponseHeader, cacheable: RespCacheable) -> Result<()> {
+        if self.phase != CachePhase::Stale {
+            return Ok(());
+        }
+        self.phase = CachePhase::Revalidated;
+        let Some(inner) = self.inner.as_mut() else {
+            return Ok(());
+        };
+        let (Some(cached), Some(key)) = (inner.cached.as_mut(), inner.cached_key.as_ref()) else {
+            return Ok(());
+        };
+        match cacheable {
+            RespCacheable::Cacheable(mut meta) => {
+                normalize_header(meta.header_mut(), cached.body.len())?;
+                cached.meta = *meta;
+                inner.storage.update_meta(key, cached.meta.clone()).await?;
+                if let (Some(index), Some(cache_key)) = (inner.index.as_ref(), inner.key.as_ref()) {
+                    index.insert(cache_key, key, purge::surrogate_keys(cached.meta.header()));
+                }
+                // the waiters find the updated response in the storage
+                if let Some(writer) = inner.write_lock.take() {
+                    writer.finish();
+                }
+            }
+            RespCacheable::Uncacheable(_) => {
+                *cached.meta.header_mut() = header;
+                normalize_header(cached.meta.header_mut(), cached.body.len())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// start storing the response fetched on a miss, `meta` comes from [resp_cacheable()]
+    pub fn set_miss_response(&mut self, meta: CacheMeta, req: &RequestHeader) {
+        if !matches!(self.phase, CachePhase::Miss | CachePhase::Stale) {
+            return;
+        }
+        // the response to HEAD has no body to store
+        if req.method == Method::HEAD {
+            self.bypass();
+            return;
+        }
+        let Some(inner) = self.inner.as_mut() else {
+            return;
+        };
+        let too_large = meta
+            .header()
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+            .is_some_and(|len| len > inner.max_object_size);
+        if too_large {
+            self.bypass();
+            return;
+        }
+        let variance = match (meta.vary(), inner.key.as_ref()) {
+            ([], _) | (_, None) => None,
+            (vary, Some(key)) => Some(key.variance(vary, req)),
+        };
+        if let Some(writer) = inner.write_lock.as_ref() {
+            // the body is framed as it is fetched
+            let mut meta = meta.clone();
+            for name in HOP_HEADERS.iter().filter(|name| **name != header::TRANSFER_ENCODING) {
+                meta.header_mut().remove_header(name);
+            }
+            meta.header_mut().remove_header("keep-alive");
+            writer.set_meta(meta, variance.clone());
+        }
+        inner.miss = Some(MissResponse {
+            meta,
+            body: BytesMut::new(),
+            variance,
+        });
+    }
+
+    /// add a piece of the body of the response being stored
+    pub fn write_miss_body(&mut self, data: &Bytes) {
+        let Some(inner) = self.inner.as_mut() else {
+            return;
+        };
+        let Some(miss) = inner.miss.as_mut() else {
+            return;
+        };
+        if miss.body.len() + data.len() > inner.max_object_size {
+            self.bypass();
+            return;
+        }
+        miss.body.extend_from_slice(data);
+        if let Some(writer) = inner.write_lock.as_ref() {
+            writer.write_body(data.clone());
+        }
+    }
+
+    /// store the response once its whole body is written
+    pub async fn finish_miss(&mut self) -> Result<()> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Ok(());
+        };
+        let Some(miss) = inner.miss.take() else {
+            return Ok(());
+        };
+        let Some(key) = inner.key.as_ref() else {
+            return Ok(());
+        };
+        let MissResponse {
+            mut meta,
+            body,
+            variance,
+        } = miss;
+        let body = body.freeze();
+        normalize_header(meta.header_mut(), body.len())?;
+        let tags = purge::surrogate_keys(meta.header());
+        let storage_key = variance.unwrap_or_else(|| key.hash());
+        let stored = match inner.storage.put(&storage_key, CacheObject::new(meta.clone(), body)).await {
+            // the primary key of a variant only records the header names
+            Ok(()) if storage_key != key.hash() => {
+                let marker = CacheObject::new(meta, Bytes::new());
+                inner.storage.put(&key.hash(), marker).await
+            }
+            stored => stored,
+        };
+        if let (Ok(()), Some(index)) = (stored.as_ref(), inner.index.as_ref()) {
+            index.insert(key, &storage_key, tags);
+        }
+        // the waiters have the whole body even if it fails to be stored
+        if let Some(writer) = inner.write_lock.take() {
+            writer.finish();
+        }
+        stored
+    }
+
+    /// the next piece of the body of the cached response to serve, `None` once the whole body is
+    /// read
+    pub async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Ok(None);
+        };
+        if let Some(partial) = inner.partial.as_mut() {
+            return partial.read_body().await;
+        }
+        if inner.body_read {
+            return Ok(None);
+        }
+        inner.body_read = true;
+        Ok(inner
+            .cached
+            .as_ref()
+            .map(|c| c.body.clone())
+            .filter(|body| !body.is_empty()))
+    }
+}
+
+/// the stored header describes the whole body, not how it was transferred
+fn normalize_header(header: &mut ResponseHeader, body_len: usize) -> Result<()> {
+    for name in HOP_HEADERS.iter() {
+        header.remove_header(name);
+    }
+    header.remove_header("keep-alive");
+    header.insert_header(header::CONTENT_LENGTH, body_len.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, headers: &[(&'static str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        req.insert_header("Host", "example.com").unwrap();
+        for (name, value) in headers {
+            req.insert_header(*name, value.to_string()).unwrap();
+        }
+        req
+    }
+
+    fn response(headers: &[(&'static str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in headers {
+            resp.insert_header(*name, value.to_string()).unwrap();
+        }
+        resp
+    }
+
+    async fn fetch(storage: &Arc<MemoryStorage>, req: &RequestHeader, resp: Option<&ResponseHeader>) -> HttpCache {
+        let mut cache = HttpCache::new();
+        cache.enable(storage.clone(), 100);
+        cache.set_key(CacheKey::from_request(req));
+        cache.lookup(req).await.unwrap();
+        if let (CachePhase::Miss | CachePhase::Stale, Some(resp)) = (cache.phase(), resp) {
+            match resp_cacheable(req, resp, SystemTime::now()) {
+                RespCacheable::Cacheable(meta) => cache.set_miss_response(*meta, req),
+                RespCacheable::Uncacheable(_) => cache.bypass(),
+            }
+            cache.write_miss_body(&Bytes::from_static(b"hello "));
+            cache.write_miss_body(&Bytes::from_static(b"world"));
+            cache.finish_miss().await.unwrap();
+        }
+        cache
+    }
+
+    #[tokio::test]
+    async fn test_miss_then_hit() {
+        let storage = Arc::new(MemoryStorage::new(10000));
+        let req = request("/a", &[]);
+        let resp = response(&[("Cache-Control", "max-age=60"), ("Transfer-Encoding", "chunked")]);
+        assert_eq!(fetch(&storage, &req, Some(&resp)).await.phase(), CachePhase::Miss);
+
+        let cache = fetch(&storage, &request("/./a", &[]), None).await;
+        assert_eq!(cache.phase(), CachePhase::Hit);
+        let cached = cache.cached().unwrap();
+        assert_eq!(cached.body, "hello world");
+        assert_eq!(cached.meta.header().headers["content-length"], "11");
+        assert!(cached.meta.header().headers.get("transfer-encoding").is_none());
+
+        // uncacheable
+        let resp = response(&[("Cache-Control", "no-store")]);
+        assert_eq!(
+            fetch(&storage, &request("/b", &[]), Some(&resp)).await.phase(),
+            CachePhase::Bypass
+        );
+        assert_eq!(
+            fetch(&storage, &request("/b", &[]), None).await.phase(),
+            CachePhase::Miss
+        );
+
+        // expired
+        let resp = response(&[("Cache-Control", "max-age=1"), ("Age", "5")]);
+        fetch(&storage, &request("/c", &[]), Some(&resp)).await;
+        assert_eq!(
+            fetch(&storage, &request("/c", &[]), None).await.phase(),
+            CachePhase::Stale
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vary() {
+        let storage = Arc::new(MemoryStorage::new(10000));
+        let resp = response(&[("Cache-Control", "max-age=60"), ("Vary", "Accept-Encoding")]);
+        let gzip = request("/", &[("Accept-Encoding", "gzip")]);
+        let br = request("/", &[("Accept-Encoding", "br")]);
+        fetch(&storage, &gzip, Some(&resp)).await;
+        assert_eq!(fetch(&storage, &gzip, None).await.phase(), CachePhase::Hit);
+        assert_eq!(fetch(&storage, &br, None).await.phase(), CachePhase::Miss);
+        fetch(&storage, &br, Some(&resp)).await;
+        assert_eq!(fetch(&storage, &br, None).await.phase(), CachePhase::Hit);
+        assert_eq!(fetch(&storage, &gzip, None).await.phase(), CachePhase::Hit);
+    }
+
+    #[tokio::test]
+    async fn test_revalidate() {
+        let storage = Arc::new(MemoryStorage::new(10000));
+        let req = request("/", &[("If-None-Match", "\"client\"")]);
+        let resp = response(&[
+            ("Cache-Control", "max-age=0, stale-while-revalidate=60"),
+            ("ETag", "\"1\""),
+        ]);
+        fetch(&storage, &req, Some(&resp)).await;
+
+        let mut cache = fetch(&storage, &req, None).await;
+        assert_eq!(cache.phase(), CachePhase::Stale);
+        assert!(cache.can_serve_stale_while_revalidate());
+        assert!(!cache.can_serve_stale_if_error());
+        let mut upstream_req = req.clone();
+        cache.conditional_request(&mut upstream_req).unwrap();
+        assert_eq!(upstream_req.headers["if-none-match"], "\"1\"");
+        assert!(upstream_req.headers.get("if-modified-since").is_none());
+
+        let mut background = cache.revalidate_in_background().unwrap();
+        assert_eq!(cache.phase(), CachePhase::StaleUpdating);
+        assert_eq!(background.phase(), CachePhase::Stale);
+        let not_modified = response(&[("Cache-Control", "max-age=60"), ("X-Revalidated", "1")]);
+        let header = merge_not_modified(background.cached().unwrap().meta.header(), &not_modified).unwrap();
+        let cacheable = resp_cacheable(&req, &header, SystemTime::now());
+        background.revalidated(header, cacheable).await.unwrap();
+        assert_eq!(background.phase(), CachePhase::Revalidated);
+
+        let cache = fetch(&storage, &req, None).await;
+        assert_eq!(cache.phase(), CachePhase::Hit);
+        let cached = cache.cached().unwrap();
+        assert_eq!(cached.meta.header().headers["x-revalidated"], "1");
+        assert_eq!(cached.meta.header().headers["etag"], "\"1\"");
+        assert_eq!(cached.body, "hello world");
+    }
+
+    #[tokio::test]
+    async fn test_too_large() {
+        let storage = Arc::new(MemoryStorage::new(10000));
+        let req = request("/", &[]);
+        let mut cache = HttpCache::new();
+        cache.enable(storage.clone(), 5);
+        cache.set_key(CacheKey::from_request(&req));
+        cache.lookup(&req).await.unwrap();
+        let resp = response(&[("Cache-Control", "max-age=60")]);
+        let RespCacheable::Cacheable(meta) = resp_cacheable(&req, &resp, SystemTime::now()) else {
+            panic!("cacheable");
+        };
+        cache.set_miss_response(*meta, &req);
+        cache.write_miss_body(&Bytes::from_static(b"123456"));
+        assert_eq!(cache.phase(), CachePhase::Bypass);
+        cache.finish_miss().await.unwrap();
+        assert!(storage.is_empty());
+        assert!(cache.lookup(&req).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let storage = Arc::new(MemoryStorage::new(10000));
+        let lock = Arc::new(CacheLock::new(std::time::Duration::from_secs(1)));
+        let req = request("/", &[]);
+        let new_cache = || {
+            let mut cache = HttpCache::new();
+            cache.enable(storage.clone(), 100);
+            cache.set_lock(lock.clone());
+            cache.set_key(CacheKey::from_request(&req));
+            cache
+        };
+        let mut writer = new_cache();
+        writer.lookup(&req).await.unwrap();
+        assert_eq!(writer.phase(), CachePhase::Miss);
+
+        let mut reader = new_cache();
+        let waiting = {
+            let req = req.clone();
+            tokio::spawn(async move {
+                reader.lookup(&req).await.unwrap();
+                assert_eq!(reader.phase(), CachePhase::Hit);
+                let mut body = vec![];
+                while let Some(data) = reader.read_body().await.unwrap() {
+                    body.extend_from_slice(&data);
+                }
+                body
+            })
+        };
+        tokio::task::yield_now().await;
+        let resp = response(&[("Cache-Control", "max-age=60"), ("Transfer-Encoding", "chunked")]);
+        let RespCacheable::Cacheable(meta) = resp_cacheable(&req, &resp, SystemTime::now()) else {
+            panic!("cacheable");
+        };
+        writer.set_miss_response(*meta, &req);
+        writer.write_miss_body(&Bytes::from_static(b"hello "));
+        tokio::task::yield_now().await;
+        writer.write_miss_body(&Bytes::from_static(b"world"));
+        writer.finish_miss().await.unwrap();
+        assert_eq!(waiting.await.unwrap(), b"hello world");
+
+        let mut cache = new_cache();
+        cache.lookup(&req).await.unwrap();
+        assert_eq!(cache.phase(), CachePhase::Hit);
+        assert_eq!(cache.read_body().await.unwrap().unwrap(), "hello world");
+        assert!(cache.read_body().await.unwrap().is_none());
+
+        // the waiter fetches on its own once the lock is abandoned
+        let req = request("/b", &[]);
+        let mut writer = new_cache();
+        writer.set_key(CacheKey::from_request(&req));
+        writer.lookup(&req).await.unwrap();
+        let mut reader = new_cache();
+        reader.set_key(CacheKey::from_request(&req));
+        let waiting = tokio::spawn(async move {
+            reader.lookup(&req).await.unwrap();
+            reader.phase()
+        });
+        tokio::task::yield_now().await;
+        writer.bypass();
+        assert_eq!(waiting.await.unwrap(), CachePhase::Miss);
+    }
+}
@@ -0,0 +1,309 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the cache lock
+//!
+//! when a response is missing from the cache, only the first request of its key fetches it from
+//! the upstream. the others wait on the lock and read the response while it is being fetched
+
+use crate::meta::CacheMeta;
+use bytes::Bytes;
+use gateway_error::{Error, ErrorType::*, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// the locks of the cache keys being fetched
+pub struct CacheLock {
+    /// how long to wait for the response header before fetching on its own
+    timeout: Duration,
+    locks: Mutex<HashMap<String, Arc<LockCore>>>,
+}
+
+/// the result of [CacheLock::lock()]
+pub enum Locked {
+    /// the lock is acquired, the response is to be fetched
+    Write(WriteLock),
+    /// another request is fetching the response
+    Read(ReadLock),
+}
+
+impl CacheLock {
+    pub fn new(timeout: Duration) -> Self {
+        CacheLock {
+            timeout,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// lock the key, or wait on it if it is already locked
+    pub fn lock(self: &Arc<Self>, key: &str) -> Locked {
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(core) = locks.get(key) {
+            return Locked::Read(ReadLock {
+                core: core.clone(),
+                changed: core.changed.subscribe(),
+                read: 0,
+            });
+        }
+        let core = Arc::new(LockCore {
+            state: Mutex::new(LockState {
+                status: LockStatus::Fetching,
+                meta: None,
+                body: vec![],
+            }),
+            changed: watch::channel(()).0,
+        });
+        locks.insert(key.to_string(), core.clone());
+        Locked::Write(WriteLock {
+            lock: self.clone(),
+            key: key.to_string(),
+            core,
+            released: false,
+        })
+    }
+
+    pub fn is_locked(&self, key: &str) -> bool {
+        self.locks.lock().unwrap().contains_key(key)
+    }
+}
+
+struct LockCore {
+    state: Mutex<LockState>,
+    /// notified on every change of the state
+    changed: watch::Sender<()>,
+}
+
+struct LockState {
+    status: LockStatus,
+    /// the header of the response, with the storage key of its variant
+    meta: Option<(CacheMeta, Option<String>)>,
+    body: Vec<Bytes>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LockStatus {
+    Fetching,
+    /// the header is known, the body is being written
+    Streaming,
+    /// the whole response is written and stored
+    Done,
+    /// the response turns out uncacheable, or the fetch fails
+    Abandoned,
+}
+
+/// the lock held by the request fetching the response, which is abandoned if dropped before it
+/// is finished
+pub struct WriteLock {
+    lock: Arc<CacheLock>,
+    key: String,
+    core: Arc<LockCore>,
+    released: bool,
+}
+
+impl WriteLock {
+    /// let the waiters read the response, `variance` is the storage key of the variant if the
+    /// response varies on request headers
+    pub fn set_meta(&self, meta: CacheMeta, variance: Option<String>) {
+        self.update(|state| {
+            state.meta = Some((meta, variance));
+            state.status = LockStatus::Streaming;
+        });
+    }
+
+    pub fn write_body(&self, data: Bytes) {
+        self.update(|state| state.body.push(data));
+    }
+
+    /// the response is stored, the new requests find it in the storage
+    pub fn finish(mut self) {
+        self.release(LockStatus::Done);
+    }
+
+    fn update(&self, f: impl FnOnce(&mut LockState)) {
+        f(&mut self.core.state.lock().unwrap());
+        self.core.changed.send_replace(());
+    }
+
+    fn release(&mut self, status: LockStatus) {
+        if self.released {
+            return;
+        }
+        self.released = true;
+        {
+            let mut locks = self.lock.locks.lock().unwrap();
+            if locks.get(&self.key).is_some_and(|core| Arc::ptr_eq(core, &self.core)) {
+                locks.remove(&self.key);
+            }
+        }
+        self.update(|state| state.status = status);
+    }
+}
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        self.release(LockStatus::Abandoned);
+    }
+}
+
+/// what a waiter on the lock ends up with
+pub enum LockOutcome {
+    /// the response is being written, its body is read from the [ReadLock]
+    Streaming(Box<CacheMeta>, Option<String>),
+    /// the response is stored before the waiter looked at it
+    Done,
+    Abandoned,
+}
+
+/// a waiter on the lock
+pub struct ReadLock {
+    core: Arc<LockCore>,
+    changed: watch::Receiver<()>,
+    /// the number of the body pieces read
+    read: usize,
+}
+
+impl ReadLock {
+    /// wait until the header of the response is known, or the lock is released
+    pub async fn wait(&mut self) -> LockOutcome {
+        loop {
+            self.changed.borrow_and_update();
+            {
+                let state = self.core.state.lock().unwrap();
+                match (state.status, state.meta.as_ref()) {
+                    (LockStatus::Fetching, _) => {}
+                    (LockStatus::Abandoned, _) => return LockOutcome::Abandoned,
+                    (_, Some((meta, variance))) => {
+                        return LockOutcome::Streaming(Box::new(meta.clone()), variance.clone())
+                    }
+                    (_, None) => return LockOutcome::Done,
+                }
+            }
+            // the sender lives as long as the core
+            let _ = self.changed.changed().await;
+        }
+    }
+
+    /// the next piece of the body, `None` once the whole body is read
+    pub async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        loop {
+            self.changed.borrow_and_update();
+            {
+                let state = self.core.state.lock().unwrap();
+                if let Some(data) = state.body.get(self.read) {
+                    self.read += 1;
+                    return Ok(Some(data.clone()));
+                }
+                match state.status {
+                    LockStatus::Done => return Ok(None),
+                    LockStatus::Abandoned => {
+                        return Error::e_explain(InternalError, "the response being cached is abandoned")
+                    }
+                    LockStatus::Fetching | LockStatus::Streaming => {}
+                }
+            }
+            let _ = self.changed.changed().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gateway_httpd::ResponseHeader;
+    use std::time::SystemTime;
+
+    fn meta() -> CacheMeta {
+        let now = SystemTime::now();
+        CacheMeta::new(ResponseHeader::build(200, None).unwrap(), now, now, vec![])
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let lock = Arc::new(CacheLock::new(Duration::from_secs(1)));
+        let Locked::Write(writer) = lock.lock("a") else {
+            panic!("the first one writes");
+        };
+        let Locked::Read(mut reader) = lock.lock("a") else {
+            panic!("the second one reads");
+        };
+        assert!(matches!(lock.lock("b"), Locked::Write(_)));
+        // the lock of b is dropped
+        assert!(!lock.is_locked("b"));
+
+        let read = tokio::spawn(async move {
+            let LockOutcome::Streaming(meta, None) = reader.wait().await else {
+                panic!("streaming");
+            };
+            assert_eq!(meta.header().status, 200);
+            let mut body = vec![];
+            while let Some(data) = reader.read_body().await.unwrap() {
+                body.extend_from_slice(&data);
+            }
+            body
+        });
+        writer.set_meta(meta(), None);
+        writer.write_body(Bytes::from_static(b"hello "));
+        tokio::task::yield_now().await;
+        writer.write_body(Bytes::from_static(b"world"));
+        writer.finish();
+        assert_eq!(read.await.unwrap(), b"hello world");
+        assert!(!lock.is_locked("a"));
+
+        // released before the waiter looks
+        let Locked::Write(writer) = lock.lock("a") else {
+            panic!("the lock is released");
+        };
+        let Locked::Read(mut reader) = lock.lock("a") else {
+            panic!("locked");
+        };
+        writer.finish();
+        assert!(matches!(reader.wait().await, LockOutcome::Done));
+    }
+
+    #[tokio::test]
+    async fn test_abandon() {
+        let lock = Arc::new(CacheLock::new(Duration::from_secs(1)));
+        let Locked::Write(writer) = lock.lock("a") else {
+            panic!("the first one writes");
+        };
+        let Locked::Read(mut waiting) = lock.lock("a") else {
+            panic!("locked");
+        };
+        let Locked::Read(mut streaming) = lock.lock("a") else {
+            panic!("locked");
+        };
+        writer.set_meta(meta(), None);
+        assert!(matches!(streaming.wait().await, LockOutcome::Streaming(..)));
+        writer.write_body(Bytes::from_static(b"hello"));
+        drop(writer);
+        assert!(matches!(waiting.wait().await, LockOutcome::Abandoned));
+        assert_eq!(streaming.read_body().await.unwrap().unwrap(), "hello");
+        assert!(streaming.read_body().await.is_err());
+        assert!(!lock.is_locked("a"));
+    }
+}
@@ -0,0 +1,493 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the metadata of the cached responses and the rules deciding what is cached and for how long
+
+use crate::cache_control::CacheControl;
+use crate::httpdate::parse_http_date;
+use bytes::BufMut;
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use http::{header, Method, Version};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// the statuses which are cacheable when the response says for how long
+const CACHEABLE_STATUS: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// the max number of headers of a stored response
+const MAX_HEADERS: usize = 256;
+
+/// the header and the freshness of a cached response
+#[derive(Debug, Clone)]
+pub struct CacheMeta {
+    /// when the response was generated by the origin, i.e. the response time minus its initial age
+    created: SystemTime,
+    fresh_until: SystemTime,
+    /// how long after `fresh_until` the response may be served while it is revalidated
+    stale_while_revalidate: Duration,
+    /// how long after `fresh_until` the response may be served when the upstream fails
+    stale_if_error: Duration,
+    /// the lowercase names of the request headers the response varies on
+    vary: Vec<String>,
+    header: ResponseHeader,
+}
+
+impl CacheMeta {
+    pub fn new(header: ResponseHeader, created: SystemTime, fresh_until: SystemTime, vary: Vec<String>) -> Self {
+        CacheMeta {
+            created,
+            fresh_until,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            vary,
+            header,
+        }
+    }
+
+    pub fn set_stale_while_revalidate(&mut self, duration: Duration) {
+        self.stale_while_revalidate = duration;
+    }
+
+    pub fn set_stale_if_error(&mut self, duration: Duration) {
+        self.stale_if_error = duration;
+    }
+
+    pub fn stale_while_revalidate(&self) -> Duration {
+        self.stale_while_revalidate
+    }
+
+    pub fn stale_if_error(&self) -> Duration {
+        self.stale_if_error
+    }
+
+    pub fn header(&self) -> &ResponseHeader {
+        &self.header
+    }
+
+    pub fn header_mut(&mut self) -> &mut ResponseHeader {
+        &mut self.header
+    }
+
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    pub fn fresh_until(&self) -> SystemTime {
+        self.fresh_until
+    }
+
+    pub fn vary(&self) -> &[String] {
+        &self.vary
+    }
+
+    /// make the response stale from `now` on, if it is still fresh
+    pub fn expire(&mut self, now: SystemTime) {
+        self.fresh_until = self.fresh_until.min(now);
+    }
+
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        now < self.fresh_until
+    }
+
+    /// whether the stale response may be served at `now` while it is revalidated
+    pub fn serve_stale_while_revalidate(&self, now: SystemTime) -> bool {
+        now < self.fresh_until + self.stale_while_revalidate
+    }
+
+    /// whether the stale response may be served at `now` instead of an upstream error
+    pub fn serve_stale_if_error(&self, now: SystemTime) -> bool {
+        now < self.fresh_until + self.stale_if_error
+    }
+
+    /// the value of the `Age` header of the response served at `now`
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.created).unwrap_or_default()
+    }
+
+    /// serialize the meta, the header is written as http/1.1
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(512);
+        let times = format!(
+            "{} {} {} {}\n",
+            millis(self.created),
+            millis(self.fresh_until),
+            self.stale_while_revalidate.as_millis(),
+            self.stale_if_error.as_millis()
+        );
+        buf.put_slice(times.as_bytes());
+        buf.put_slice(self.vary.join(",").as_bytes());
+        buf.put_u8(b'\n');
+        buf.put_slice(b"HTTP/1.1 ");
+        buf.put_slice(self.header.status.as_str().as_bytes());
+        buf.put_slice(b" \r\n");
+        self.header.header_to_h1_write(&mut buf);
+        buf.put_slice(b"\r\n");
+        buf
+    }
+
+    /// the reverse of [Self::encode()], return the meta and the length it is decoded from
+    pub(crate) fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let malformed = || Error::explain(InternalError, "malformed cache meta");
+        let mut lines = buf.splitn(3, |b| *b == b'\n');
+        let times = std::str::from_utf8(lines.next().ok_or_else(malformed)?).or_err(InternalError, "cache meta")?;
+        let vary = std::str::from_utf8(lines.next().ok_or_else(malformed)?).or_err(InternalError, "cache meta")?;
+        let head = lines.next().ok_or_else(malformed)?;
+        let offset = buf.len() - head.len();
+
+        let times: Vec<&str> = times.split(' ').collect();
+        let [created, fresh_until, swr, sie] = times[..] else {
+            return Err(malformed());
+        };
+        let duration = |t: &str| t.parse().map(Duration::from_millis).or_err(InternalError, "cache meta");
+        let created = UNIX_EPOCH + duration(created)?;
+        let fresh_until = UNIX_EPOCH + duration(fresh_until)?;
+        let vary = vary
+            .split(',')
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+            .collect();
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut resp = httparse::Response::new(&mut headers);
+        let len = match resp.parse(head).or_err(InternalError, "cache meta")? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => return Err(malformed()),
+        };
+        let mut header = ResponseHeader::build(resp.code.unwrap_or_default(), Some(resp.headers.len()))?;
+        header.set_version(if resp.version == Some(0) {
+            Version::HTTP_10
+        } else {
+            Version::HTTP_11
+        });
+        for h in resp.headers.iter() {
+            header.append_header(h.name.to_string(), h.value)?;
+        }
+        let mut meta = CacheMeta::new(header, created, fresh_until, vary);
+        meta.set_stale_while_revalidate(duration(swr)?);
+        meta.set_stale_if_error(duration(sie)?);
+        Ok((meta, offset + len))
+    }
+}
+
+fn millis(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+/// whether the response of the request may come from the cache
+pub fn req_cacheable(req: &RequestHeader) -> bool {
+    (req.method == Method::GET || req.method == Method::HEAD) && !CacheControl::parse(&req.headers).no_store
+}
+
+/// the decision of [resp_cacheable()]
+#[derive(Debug)]
+pub enum RespCacheable {
+    Cacheable(Box<CacheMeta>),
+    /// the reason the response is not cached
+    Uncacheable(&'static str),
+}
+
+/// decide whether the response to the request can be stored by a shared cache, and how long it is
+/// fresh, received at `now`
+///
+/// only the responses with an explicit lifetime are cached, no heuristic freshness is applied. the
+/// responses which are stale right away are cached only if they can be revalidated
+pub fn resp_cacheable(req: &RequestHeader, resp: &ResponseHeader, now: SystemTime) -> RespCacheable {
+    use RespCacheable::*;
+
+    if !CACHEABLE_STATUS.contains(&resp.status.as_u16()) {
+        return Uncacheable("status");
+    }
+    let cc = CacheControl::parse(&resp.headers);
+    if cc.no_store {
+        return Uncacheable("no-store");
+    }
+    if cc.private {
+        return Uncacheable("private");
+    }
+    if resp.headers.contains_key(header::SET_COOKIE) {
+        return Uncacheable("set-cookie");
+    }
+    let shared = cc.public || cc.s_maxage.is_some() || cc.must_revalidate;
+    if req.headers.contains_key(header::AUTHORIZATION) && !shared {
+        return Uncacheable("authorization");
+    }
+    let Some(vary) = vary_names(resp) else {
+        return Uncacheable("vary");
+    };
+
+    let date = header_date(resp, header::DATE).unwrap_or(now);
+    let lifetime = if cc.no_cache {
+        Duration::ZERO
+    } else if let Some(s) = cc.s_maxage.or(cc.max_age) {
+        Duration::from_secs(s)
+    } else if let Some(expires) = resp.headers.get(header::EXPIRES) {
+        // an invalid date means already expired
+        let expires = expires.to_str().ok().and_then(parse_http_date).unwrap_or(UNIX_EPOCH);
+        expires.duration_since(date).unwrap_or_default()
+    } else {
+        return Uncacheable("no lifetime");
+    };
+    if lifetime.is_zero() && !has_validators(resp) {
+        return Uncacheable("expired");
+    }
+
+    let age = resp
+        .headers
+        .get(header::AGE)
+        .and_then(|a| a.to_str().ok()?.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let apparent_age = now.duration_since(date).unwrap_or_default();
+    let created = now - age.max(apparent_age);
+    let mut meta = CacheMeta::new(resp.clone(), created, created + lifetime, vary);
+    // must-revalidate forbids serving stale responses
+    if !cc.must_revalidate && !cc.proxy_revalidate {
+        meta.set_stale_while_revalidate(Duration::from_secs(cc.stale_while_revalidate.unwrap_or(0)));
+        meta.set_stale_if_error(Duration::from_secs(cc.stale_if_error.unwrap_or(0)));
+    }
+    Cacheable(Box::new(meta))
+}
+
+/// whether the response has an `ETag` or a `Last-Modified` to be revalidated with
+pub fn has_validators(resp: &ResponseHeader) -> bool {
+    resp.headers.contains_key(header::ETAG) || resp.headers.contains_key(header::LAST_MODIFIED)
+}
+
+/// the stored header updated with the headers of a `304 Not Modified` response to its revalidation
+///
+/// the framing and hop-by-hop headers of the 304 are not taken
+pub fn merge_not_modified(stored: &ResponseHeader, not_modified: &ResponseHeader) -> Result<ResponseHeader> {
+    let mut merged = stored.clone();
+    let mut names: Vec<&header::HeaderName> = not_modified.headers.keys().collect();
+    names.retain(|n| !SKIPPED_ON_MERGE.contains(n));
+    for name in names {
+        merged.remove_header(name);
+        for value in not_modified.headers.get_all(name).iter() {
+            merged.append_header(name.clone(), value.clone())?;
+        }
+    }
+    Ok(merged)
+}
+
+const SKIPPED_ON_MERGE: [header::HeaderName; 5] = [
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::CONNECTION,
+    header::TRAILER,
+    header::UPGRADE,
+];
+
+fn header_date(resp: &ResponseHeader, name: header::HeaderName) -> Option<SystemTime> {
+    parse_http_date(resp.headers.get(name)?.to_str().ok()?)
+}
+
+/// the sorted lowercase names in the `Vary` headers, `None` for `Vary: *`
+pub fn vary_names(resp: &ResponseHeader) -> Option<Vec<String>> {
+    let mut names = vec![];
+    for value in resp.headers.get_all(header::VARY).iter() {
+        for name in String::from_utf8_lossy(value.as_bytes()).split(',') {
+            let name = name.trim().to_ascii_lowercase();
+            match name.as_str() {
+                "*" => return None,
+                "" => {}
+                _ => names.push(name),
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    Some(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpdate::fmt_http_date;
+
+    fn response(status: u16, headers: &[(&'static str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(status, None).unwrap();
+        for (name, value) in headers {
+            resp.append_header(*name, value.to_string()).unwrap();
+        }
+        resp
+    }
+
+    fn cacheable(resp: &ResponseHeader, now: SystemTime) -> Option<CacheMeta> {
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        match resp_cacheable(&req, resp, now) {
+            RespCacheable::Cacheable(meta) => Some(*meta),
+            RespCacheable::Uncacheable(_) => None,
+        }
+    }
+
+    #[test]
+    fn test_freshness() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let date = fmt_http_date(now - Duration::from_secs(10));
+
+        // the age is the larger of the Age header and the apparent age
+        let meta = cacheable(&response(200, &[("Cache-Control", "max-age=60"), ("Date", &date)]), now).unwrap();
+        assert_eq!(meta.age(now), Duration::from_secs(10));
+        assert_eq!(meta.fresh_until(), now + Duration::from_secs(50));
+        let meta = cacheable(&response(200, &[("Cache-Control", "max-age=60"), ("Age", "30")]), now).unwrap();
+        assert_eq!(meta.age(now), Duration::from_secs(30));
+        assert!(meta.is_fresh(now + Duration::from_secs(29)));
+        assert!(!meta.is_fresh(now + Duration::from_secs(30)));
+
+        // s-maxage wins over max-age, which wins over Expires
+        let expires = fmt_http_date(now + Duration::from_secs(600));
+        let resp = response(
+            200,
+            &[("Cache-Control", "max-age=60, s-maxage=120"), ("Expires", &expires)],
+        );
+        assert_eq!(
+            cacheable(&resp, now).unwrap().fresh_until(),
+            now + Duration::from_secs(120)
+        );
+        let date = fmt_http_date(now);
+        let resp = response(200, &[("Expires", &expires), ("Date", &date)]);
+        assert_eq!(
+            cacheable(&resp, now).unwrap().fresh_until(),
+            now + Duration::from_secs(600)
+        );
+        assert!(cacheable(&response(200, &[("Expires", "0")]), now).is_none());
+    }
+
+    #[test]
+    fn test_uncacheable() {
+        let now = SystemTime::now();
+        for headers in [
+            vec![],
+            vec![("Cache-Control", "no-store, max-age=60")],
+            vec![("Cache-Control", "private, max-age=60")],
+            vec![("Cache-Control", "no-cache, max-age=60")],
+            vec![("Cache-Control", "max-age=0")],
+            vec![("Cache-Control", "max-age=60"), ("Set-Cookie", "a=b")],
+            vec![("Cache-Control", "max-age=60"), ("Vary", "Accept, *")],
+        ] {
+            assert!(cacheable(&response(200, &headers), now).is_none(), "{headers:?}");
+        }
+        assert!(cacheable(&response(500, &[("Cache-Control", "max-age=60")]), now).is_none());
+        assert!(cacheable(&response(404, &[("Cache-Control", "max-age=60")]), now).is_some());
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("Authorization", "Basic eA==").unwrap();
+        let resp = response(200, &[("Cache-Control", "max-age=60")]);
+        assert!(matches!(
+            resp_cacheable(&req, &resp, now),
+            RespCacheable::Uncacheable("authorization")
+        ));
+        let resp = response(200, &[("Cache-Control", "public, max-age=60")]);
+        assert!(matches!(resp_cacheable(&req, &resp, now), RespCacheable::Cacheable(_)));
+
+        assert!(req_cacheable(&req));
+        req.insert_header("Cache-Control", "no-store").unwrap();
+        assert!(!req_cacheable(&req));
+        assert!(!req_cacheable(&RequestHeader::build("POST", b"/", None).unwrap()));
+    }
+
+    #[test]
+    fn test_stale() {
+        let now = SystemTime::now();
+        let resp = response(
+            200,
+            &[(
+                "Cache-Control",
+                "max-age=60, stale-while-revalidate=30, stale-if-error=600",
+            )],
+        );
+        let meta = cacheable(&resp, now).unwrap();
+        assert!(meta.serve_stale_while_revalidate(now + Duration::from_secs(89)));
+        assert!(!meta.serve_stale_while_revalidate(now + Duration::from_secs(91)));
+        assert!(meta.serve_stale_if_error(now + Duration::from_secs(600)));
+        assert!(!meta.serve_stale_if_error(now + Duration::from_secs(661)));
+
+        let resp = response(
+            200,
+            &[("Cache-Control", "max-age=60, must-revalidate, stale-if-error=600")],
+        );
+        let meta = cacheable(&resp, now).unwrap();
+        assert!(!meta.serve_stale_if_error(now + Duration::from_secs(61)));
+
+        // stale right away, but it can be revalidated
+        let meta = cacheable(&response(200, &[("Cache-Control", "no-cache"), ("ETag", "\"1\"")]), now).unwrap();
+        assert!(!meta.is_fresh(now));
+    }
+
+    #[test]
+    fn test_merge_not_modified() {
+        let stored = response(
+            200,
+            &[
+                ("Content-Length", "5"),
+                ("Cache-Control", "max-age=1"),
+                ("ETag", "\"1\""),
+                ("X-Keep", "a"),
+            ],
+        );
+        let not_modified = response(
+            304,
+            &[
+                ("Cache-Control", "max-age=60"),
+                ("Content-Length", "0"),
+                ("X-New", "b"),
+                ("X-New", "c"),
+            ],
+        );
+        let merged = merge_not_modified(&stored, &not_modified).unwrap();
+        assert_eq!(merged.status, 200);
+        assert_eq!(merged.headers["cache-control"], "max-age=60");
+        assert_eq!(merged.headers["content-length"], "5");
+        assert_eq!(merged.headers["x-keep"], "a");
+        assert_eq!(merged.headers.get_all("x-new").iter().count(), 2);
+    }
+
+    #[test]
+    fn test_encode() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let resp = response(
+            200,
+            &[
+                ("Cache-Control", "max-age=60, stale-if-error=5"),
+                ("Vary", "Accept-Encoding"),
+                ("X-Case", "a"),
+            ],
+        );
+        let meta = cacheable(&resp, now).unwrap();
+        let mut buf = meta.encode();
+        buf.extend_from_slice(b"body");
+        let (decoded, len) = CacheMeta::decode(&buf).unwrap();
+        assert_eq!(&buf[len..], b"body");
+        assert_eq!(decoded.created(), meta.created());
+        assert_eq!(decoded.fresh_until(), meta.fresh_until());
+        assert_eq!(decoded.stale_if_error(), Duration::from_secs(5));
+        assert_eq!(decoded.vary(), ["accept-encoding"]);
+        assert_eq!(decoded.header().status, 200);
+        assert_eq!(decoded.header().headers, meta.header().headers);
+        let mut h1 = vec![];
+        decoded.header().header_to_h1_write(&mut h1);
+        assert!(String::from_utf8(h1).unwrap().contains("X-Case: a\r\n"));
+
+        assert!(CacheMeta::decode(b"1 2 0 0\n\nHTTP/1.1 200").is_err());
+        assert!(CacheMeta::decode(b"1 2\n\nHTTP/1.1 200 \r\n\r\n").is_err());
+    }
+}
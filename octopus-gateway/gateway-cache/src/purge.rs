@@ -0,0 +1,381 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! purging the cached responses
+//!
+//! the storages only know the hashed keys, so the [CacheIndex] records what is stored under each
+//! url and its `Surrogate-Key` tags as the responses are cached. the responses stored before the
+//! index, e.g. in a [crate::DiskStorage] before a restart, can only be purged by their exact key
+
+use crate::key::CacheKey;
+use crate::storage::{EvictionListener, Storage};
+use gateway_error::Result;
+use gateway_httpd::ResponseHeader;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// the header of the upstream response listing the tags to purge it with
+pub const SURROGATE_KEY: &str = "surrogate-key";
+
+/// the space separated tags of the `Surrogate-Key` headers of the response
+pub fn surrogate_keys(resp: &ResponseHeader) -> Vec<String> {
+    let mut tags = vec![];
+    for value in resp.headers.get_all(SURROGATE_KEY).iter() {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for tag in value.split_ascii_whitespace() {
+            if !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_string());
+            }
+        }
+    }
+    tags
+}
+
+/// the storage keys and the tags of the cached urls
+///
+/// the index is kept in step with the storage by being its [EvictionListener], see
+/// [Storage::set_eviction_listener()]
+#[derive(Default)]
+pub struct CacheIndex {
+    inner: Mutex<IndexInner>,
+}
+
+#[derive(Default)]
+struct IndexInner {
+    /// keyed by [CacheKey::url()], ordered for the prefix lookups
+    urls: BTreeMap<String, IndexEntry>,
+    /// the urls of each tag
+    tags: HashMap<String, HashSet<String>>,
+    /// the url of each storage key
+    keys: HashMap<String, String>,
+}
+
+impl IndexInner {
+    fn remove(&mut self, url: &str) {
+        let Some(entry) = self.urls.remove(url) else {
+            return;
+        };
+        for key in entry.storage_keys.iter() {
+            self.keys.remove(key);
+        }
+        for tag in entry.tags.iter() {
+            if let Some(urls) = self.tags.get_mut(tag) {
+                urls.remove(url);
+                if urls.is_empty() {
+                    self.tags.remove(tag);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct IndexEntry {
+    /// the primary key and the variants
+    storage_keys: HashSet<String>,
+    tags: Vec<String>,
+}
+
+impl CacheIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record that the response of `key` is stored under `storage_key`, with the tags replacing
+    /// the previous ones of the url
+    pub fn insert(&self, key: &CacheKey, storage_key: &str, tags: Vec<String>) {
+        let url = key.url();
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.urls.entry(url.clone()).or_default();
+        entry.storage_keys.insert(key.hash());
+        entry.storage_keys.insert(storage_key.to_string());
+        let old_tags = std::mem::replace(&mut entry.tags, tags.clone());
+        for k in [key.hash(), storage_key.to_string()] {
+            inner.keys.insert(k, url.clone());
+        }
+        for tag in old_tags.iter() {
+            if let Some(urls) = inner.tags.get_mut(tag) {
+                urls.remove(&url);
+                if urls.is_empty() {
+                    inner.tags.remove(tag);
+                }
+            }
+        }
+        for tag in tags {
+            inner.tags.entry(tag).or_default().insert(url.clone());
+        }
+    }
+
+    /// the number of urls indexed
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().urls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the urls matching the target, with their storage keys
+    fn find(&self, target: &PurgeTarget) -> Vec<(String, Vec<String>)> {
+        let inner = self.inner.lock().unwrap();
+        let keys = |url: &String| {
+            let storage_keys = inner
+                .urls
+                .get(url)
+                .map(|e| e.storage_keys.iter().cloned().collect::<Vec<_>>());
+            (url.clone(), storage_keys.unwrap_or_default())
+        };
+        match target {
+            PurgeTarget::Key(key) => {
+                let (url, mut storage_keys) = keys(&key.url());
+                // not indexed, e.g. stored before a restart
+                if storage_keys.is_empty() {
+                    storage_keys.push(key.hash());
+                }
+                vec![(url, storage_keys)]
+            }
+            PurgeTarget::Prefix(prefix) => inner
+                .urls
+                .range(prefix.clone()..)
+                .take_while(|(url, _)| url.starts_with(prefix.as_str()))
+                .map(|(url, _)| keys(url))
+                .collect(),
+            PurgeTarget::Tag(tag) => inner.tags.get(tag).into_iter().flatten().map(keys).collect(),
+        }
+    }
+
+    fn remove(&self, url: &str) {
+        self.inner.lock().unwrap().remove(url);
+    }
+}
+
+impl EvictionListener for CacheIndex {
+    /// forget the evicted key, and the url once none of its keys is stored
+    fn evicted(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(url) = inner.keys.remove(key) else {
+            return;
+        };
+        let Some(entry) = inner.urls.get_mut(&url) else {
+            return;
+        };
+        entry.storage_keys.remove(key);
+        if entry.storage_keys.is_empty() {
+            inner.remove(&url);
+        }
+    }
+}
+
+/// what to purge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PurgeTarget {
+    /// the response of the exact key, with all its variants
+    Key(CacheKey),
+    /// the responses whose [CacheKey::url()] starts with the prefix, e.g. `example.com/images/`
+    Prefix(String),
+    /// the responses tagged by the upstream with the `Surrogate-Key` header
+    Tag(String),
+}
+
+/// purge the responses of a storage
+pub struct Purger {
+    storage: Arc<dyn Storage>,
+    index: Arc<CacheIndex>,
+}
+
+impl Purger {
+    /// `index` must be the one the responses of `storage` are recorded in
+    pub fn new(storage: Arc<dyn Storage>, index: Arc<CacheIndex>) -> Self {
+        Purger { storage, index }
+    }
+
+    /// remove the responses, return the number of urls purged
+    pub async fn purge(&self, target: &PurgeTarget) -> Result<usize> {
+        let mut purged = 0;
+        for (url, storage_keys) in self.index.find(target) {
+            let mut found = false;
+            for key in storage_keys.iter() {
+                found |= self.storage.purge(key).await?;
+            }
+            self.index.remove(&url);
+            purged += usize::from(found);
+        }
+        Ok(purged)
+    }
+
+    /// mark the responses stale, so that they are revalidated before being served again, return
+    /// the number of urls purged
+    ///
+    /// unlike [Purger::purge()], they may still be served stale as their `stale-while-revalidate`
+    /// and `stale-if-error` allow
+    pub async fn soft_purge(&self, target: &PurgeTarget) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut purged = 0;
+        for (_, storage_keys) in self.index.find(target) {
+            let mut found = false;
+            for key in storage_keys.iter() {
+                let Some(object) = self.storage.lookup(key).await? else {
+                    continue;
+                };
+                let mut meta = object.meta;
+                meta.expire(now);
+                found |= self.storage.update_meta(key, meta).await?;
+            }
+            purged += usize::from(found);
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resp_cacheable, CachePhase, HttpCache, MemoryStorage, RespCacheable};
+    use bytes::Bytes;
+    use gateway_httpd::RequestHeader;
+
+    struct Cache {
+        storage: Arc<MemoryStorage>,
+        index: Arc<CacheIndex>,
+    }
+
+    impl Cache {
+        fn request(path: &str, encoding: &str) -> RequestHeader {
+            let mut req = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+            req.insert_header("Host", "example.com").unwrap();
+            req.insert_header("Accept-Encoding", encoding.to_string()).unwrap();
+            req
+        }
+
+        /// look up the request, and store the response with the headers on a miss
+        async fn fetch(&self, path: &str, encoding: &str, headers: &[(&'static str, &str)]) -> CachePhase {
+            let req = Self::request(path, encoding);
+            let mut cache = HttpCache::new();
+            cache.enable(self.storage.clone(), 100);
+            cache.set_index(self.index.clone());
+            cache.set_key(CacheKey::from_request(&req));
+            cache.lookup(&req).await.unwrap();
+            let phase = cache.phase();
+            if phase == CachePhase::Miss && !headers.is_empty() {
+                let mut resp = ResponseHeader::build(200, None).unwrap();
+                resp.insert_header("Cache-Control", "max-age=60").unwrap();
+                for (name, value) in headers {
+                    resp.insert_header(*name, value.to_string()).unwrap();
+                }
+                let RespCacheable::Cacheable(meta) = resp_cacheable(&req, &resp, SystemTime::now()) else {
+                    panic!("cacheable");
+                };
+                cache.set_miss_response(*meta, &req);
+                cache.write_miss_body(&Bytes::from_static(b"body"));
+                cache.finish_miss().await.unwrap();
+            }
+            phase
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let cache = Cache {
+            storage: Arc::new(MemoryStorage::new(10000)),
+            index: Arc::new(CacheIndex::new()),
+        };
+        let purger = Purger::new(cache.storage.clone(), cache.index.clone());
+        cache.fetch("/a/1", "gzip", &[("Surrogate-Key", "t1 t2")]).await;
+        cache.fetch("/a/2", "gzip", &[("Surrogate-Key", "t2")]).await;
+        cache.fetch("/b", "gzip", &[("Surrogate-Key", "t1")]).await;
+        cache.fetch("/v", "gzip", &[("Vary", "Accept-Encoding")]).await;
+        cache.fetch("/v", "br", &[("Vary", "Accept-Encoding")]).await;
+        assert_eq!(cache.index.len(), 4);
+        assert_eq!(cache.fetch("/v", "br", &[]).await, CachePhase::Hit);
+
+        let key = |path: &str| PurgeTarget::Key(CacheKey::from_request(&Cache::request(path, "")));
+        assert_eq!(purger.purge(&key("/b")).await.unwrap(), 1);
+        assert_eq!(purger.purge(&key("/b")).await.unwrap(), 0);
+        assert_eq!(cache.fetch("/b", "gzip", &[]).await, CachePhase::Miss);
+
+        // the tags of /b are gone with it
+        assert_eq!(purger.purge(&PurgeTarget::Tag("t1".into())).await.unwrap(), 1);
+        assert_eq!(cache.fetch("/a/1", "gzip", &[]).await, CachePhase::Miss);
+        assert_eq!(cache.fetch("/a/2", "gzip", &[]).await, CachePhase::Hit);
+
+        // all the variants
+        let prefix = PurgeTarget::Prefix("example.com/v".into());
+        assert_eq!(purger.purge(&prefix).await.unwrap(), 1);
+        assert_eq!(cache.storage.len(), 1);
+        assert_eq!(cache.fetch("/v", "br", &[]).await, CachePhase::Miss);
+        assert_eq!(cache.index.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let cache = Cache {
+            storage: Arc::new(MemoryStorage::new(500)),
+            index: Arc::new(CacheIndex::new()),
+        };
+        cache.storage.set_eviction_listener(cache.index.clone());
+        for i in 0..50 {
+            let tag = format!("t{i}");
+            cache.fetch(&format!("/{i}"), "gzip", &[("Surrogate-Key", &tag)]).await;
+        }
+        cache.fetch("/v", "gzip", &[("Vary", "Accept-Encoding")]).await;
+        cache.fetch("/v", "br", &[("Vary", "Accept-Encoding")]).await;
+        // the evicted urls are forgotten with their tags
+        let stored = cache.storage.len();
+        assert!(stored < 20, "{stored}");
+        {
+            let inner = cache.index.inner.lock().unwrap();
+            assert_eq!(inner.keys.len(), stored);
+            assert!(inner.urls.len() < stored && inner.urls.contains_key("example.com/v"));
+            assert!(inner.tags.len() < stored && !inner.tags.contains_key("t0"));
+        }
+
+        // the url of the variants is forgotten once all of them are evicted
+        let purger = Purger::new(cache.storage.clone(), cache.index.clone());
+        for i in 0..50 {
+            cache.fetch(&format!("/x{i}"), "gzip", &[("Cache-Control", "max-age=60")]).await;
+        }
+        assert!(!cache.index.inner.lock().unwrap().urls.contains_key("example.com/v"));
+        assert_eq!(cache.index.len(), cache.storage.len());
+        assert_eq!(purger.purge(&PurgeTarget::Prefix("example.com/v".into())).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_soft_purge() {
+        let cache = Cache {
+            storage: Arc::new(MemoryStorage::new(10000)),
+            index: Arc::new(CacheIndex::new()),
+        };
+        let purger = Purger::new(cache.storage.clone(), cache.index.clone());
+        cache.fetch("/a", "gzip", &[("Surrogate-Key", "t")]).await;
+        assert_eq!(purger.soft_purge(&PurgeTarget::Tag("t".into())).await.unwrap(), 1);
+        assert_eq!(cache.fetch("/a", "gzip", &[]).await, CachePhase::Stale);
+        // still indexed
+        assert_eq!(
+            purger.purge(&PurgeTarget::Prefix("example.com/".into())).await.unwrap(),
+            1
+        );
+        assert_eq!(cache.fetch("/a", "gzip", &[]).await, CachePhase::Miss);
+    }
+}
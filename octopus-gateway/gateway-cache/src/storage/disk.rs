@@ -0,0 +1,249 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the disk storage
+
+use super::lru::Lru;
+use super::{CacheObject, EvictionListener, Evictions, Storage};
+use crate::meta::CacheMeta;
+use async_trait::async_trait;
+use bytes::Bytes;
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use log::warn;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// the suffix of the files being written
+const TMP_SUFFIX: &str = ".tmp";
+
+/// keep each object in a file under a directory, evicting the least recently used ones once the
+/// total size is over the capacity
+///
+/// the files are named after their keys, e.g. `<dir>/ab/abcdef...`, and written to a temp file
+/// first, so that a crash never leaves a partial object behind
+pub struct DiskStorage {
+    dir: PathBuf,
+    /// the sizes of the files
+    index: Mutex<Lru<()>>,
+    tmp_id: AtomicU64,
+    evictions: Evictions,
+}
+
+impl DiskStorage {
+    /// store the objects under `dir`, `capacity` is in bytes
+    ///
+    /// the objects already under `dir` are kept, the least recently modified ones are the first
+    /// to be evicted
+    pub fn new(dir: impl Into<PathBuf>, capacity: usize) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).or_err_with(FileOpenError, || {
+            format!("failed to create the cache dir {}", dir.display())
+        })?;
+
+        let mut files = vec![];
+        for sub in read_dir(&dir)? {
+            if !sub.is_dir() {
+                continue;
+            }
+            for path in read_dir(&sub)? {
+                let Some(name) = path.file_name().and_then(|n| n.to_str()).map(|n| n.to_string()) else {
+                    continue;
+                };
+                if name.ends_with(TMP_SUFFIX) {
+                    // left over by a crash
+                    let _ = std::fs::remove_file(&path);
+                } else if let Ok(m) = path.metadata() {
+                    files.push((m.modified().unwrap_or(SystemTime::UNIX_EPOCH), name, m.len() as usize));
+                }
+            }
+        }
+        files.sort();
+        let mut index = Lru::new(capacity);
+        let mut evicted = vec![];
+        for (_, name, size) in files {
+            evicted.extend(index.insert(name, (), size));
+        }
+        let storage = DiskStorage {
+            dir,
+            index: Mutex::new(index),
+            tmp_id: AtomicU64::new(0),
+            evictions: Evictions::default(),
+        };
+        for (key, _) in evicted {
+            let _ = std::fs::remove_file(storage.path(&key)?);
+        }
+        Ok(storage)
+    }
+
+    /// the total size of the stored objects
+    pub fn size(&self) -> usize {
+        self.index.lock().unwrap().weight()
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        // the keys are hashes, anything else could escape the dir
+        if key.len() < 2 || !key.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Error::e_explain(InternalError, format!("invalid cache key `{key}`"));
+        }
+        Ok(self.dir.join(&key[..2]).join(key))
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = std::fs::read_dir(dir).or_err_with(FileReadError, || {
+        format!("failed to read the cache dir {}", dir.display())
+    })?;
+    Ok(entries.filter_map(|e| e.ok().map(|e| e.path())).collect())
+}
+
+#[async_trait]
+impl Storage for DiskStorage {
+    async fn lookup(&self, key: &str) -> Result<Option<CacheObject>> {
+        let path = self.path(key)?;
+        if self.index.lock().unwrap().get(key).is_none() {
+            return Ok(None);
+        }
+        let buf = match tokio::fs::read(&path).await {
+            Ok(buf) => Bytes::from(buf),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                // removed behind the back of the storage
+                if self.index.lock().unwrap().remove(key).is_some() {
+                    self.evictions.notify(key);
+                }
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(e).or_err_with(FileReadError, || format!("failed to read {}", path.display()));
+            }
+        };
+        match CacheMeta::decode(&buf) {
+            Ok((meta, len)) => Ok(Some(CacheObject::new(meta, buf.slice(len..)))),
+            Err(e) => {
+                // the file is unusable, drop it
+                warn!("removing the corrupted cache file {}: {e}", path.display());
+                self.purge(key).await?;
+                self.evictions.notify(key);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn put(&self, key: &str, object: CacheObject) -> Result<()> {
+        let path = self.path(key)?;
+        let mut buf = object.meta.encode();
+        buf.extend_from_slice(&object.body);
+        let size = buf.len();
+
+        let tmp_id = self.tmp_id.fetch_add(1, Ordering::Relaxed);
+        let tmp = path.with_file_name(format!("{key}.{}.{tmp_id}{TMP_SUFFIX}", std::process::id()));
+        let write = async {
+            tokio::fs::create_dir_all(path.parent().expect("the path has a parent")).await?;
+            tokio::fs::write(&tmp, &buf).await?;
+            tokio::fs::rename(&tmp, &path).await
+        };
+        if let Err(e) = write.await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e).or_err_with(FileWriteError, || format!("failed to write {}", path.display()));
+        }
+
+        let (evicted, stored) = {
+            let mut index = self.index.lock().unwrap();
+            let evicted = index.insert(key.to_string(), (), size);
+            (evicted, index.contains(key))
+        };
+        for (evicted, _) in evicted {
+            // the file of the key itself is already replaced, unless it is too large to keep
+            if evicted != key || !stored {
+                let _ = tokio::fs::remove_file(self.path(&evicted)?).await;
+            }
+            if evicted != key {
+                self.evictions.notify(&evicted);
+            }
+        }
+        Ok(())
+    }
+
+    async fn purge(&self, key: &str) -> Result<bool> {
+        let path = self.path(key)?;
+        let indexed = self.index.lock().unwrap().remove(key).is_some();
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(indexed),
+            Err(e) => Err(e).or_err_with(FileWriteError, || format!("failed to remove {}", path.display())),
+        }
+    }
+
+    fn set_eviction_listener(&self, listener: Arc<dyn EvictionListener>) {
+        self.evictions.set(listener);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::tests::object;
+
+    #[tokio::test]
+    async fn test_disk_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path(), 1000).unwrap();
+        storage.put("abcd", object("hello")).await.unwrap();
+        let cached = storage.lookup("abcd").await.unwrap().unwrap();
+        assert_eq!(cached.body, "hello");
+        assert_eq!(cached.meta.header().status, 200);
+        assert!(dir.path().join("ab/abcd").exists());
+        assert!(storage.lookup("abce").await.unwrap().is_none());
+        assert!(storage.lookup("../etc").await.is_err());
+
+        // the objects survive a restart
+        storage.put("efgh", object("world")).await.unwrap();
+        let size = storage.size();
+        drop(storage);
+        let storage = DiskStorage::new(dir.path(), 1000).unwrap();
+        assert_eq!(storage.size(), size);
+        assert_eq!(storage.lookup("efgh").await.unwrap().unwrap().body, "world");
+
+        assert!(storage.purge("abcd").await.unwrap());
+        assert!(!dir.path().join("ab/abcd").exists());
+        assert!(!storage.purge("abcd").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_disk_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(dir.path(), 1000).unwrap();
+        storage.put("aa01", object("a")).await.unwrap();
+        let one = storage.size();
+        let storage = DiskStorage::new(dir.path(), one * 2).unwrap();
+        storage.put("aa02", object("b")).await.unwrap();
+        storage.lookup("aa01").await.unwrap().unwrap();
+        storage.put("aa03", object("c")).await.unwrap();
+        // aa02 is the least recently used
+        assert!(!dir.path().join("aa/aa02").exists());
+        assert!(storage.lookup("aa02").await.unwrap().is_none());
+        assert!(storage.lookup("aa01").await.unwrap().is_some());
+        assert!(storage.lookup("aa03").await.unwrap().is_some());
+    }
+}
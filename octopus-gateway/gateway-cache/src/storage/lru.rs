@@ -0,0 +1,135 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! a least recently used map bounded by the total weight of its values
+
+use std::collections::{BTreeMap, HashMap};
+
+struct Entry<V> {
+    value: V,
+    weight: usize,
+    tick: u64,
+}
+
+pub(crate) struct Lru<V> {
+    capacity: usize,
+    weight: usize,
+    tick: u64,
+    entries: HashMap<String, Entry<V>>,
+    /// the keys by their last access
+    order: BTreeMap<u64, String>,
+}
+
+impl<V> Lru<V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            weight: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// the value of the key, which becomes the most recently used
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        let entry = self.entries.get_mut(key)?;
+        self.tick += 1;
+        let key = self.order.remove(&entry.tick).expect("the entry is ordered");
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key);
+        Some(&entry.value)
+    }
+
+    /// insert the value, return the values evicted to make room for it
+    ///
+    /// a value heavier than the capacity is evicted right away
+    pub fn insert(&mut self, key: String, value: V, weight: usize) -> Vec<(String, V)> {
+        let mut evicted = vec![];
+        if let Some(old) = self.remove(&key) {
+            evicted.push((key.clone(), old));
+        }
+        self.tick += 1;
+        self.weight += weight;
+        self.order.insert(self.tick, key.clone());
+        let tick = self.tick;
+        self.entries.insert(key, Entry { value, weight, tick });
+        while self.weight > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            let entry = self.entries.remove(&key).expect("the ordered key exists");
+            self.weight -= entry.weight;
+            evicted.push((key, entry.value));
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// the total weight of the values
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(10);
+        assert!(lru.insert("a".into(), 1, 4).is_empty());
+        assert!(lru.insert("b".into(), 2, 4).is_empty());
+        // a becomes the most recently used, b is evicted
+        assert_eq!(lru.get("a"), Some(&1));
+        let evicted = lru.insert("c".into(), 3, 4);
+        assert_eq!(evicted, vec![("b".to_string(), 2)]);
+        assert_eq!(lru.weight(), 8);
+
+        // replacing returns the old value
+        assert_eq!(lru.insert("a".into(), 4, 2), vec![("a".to_string(), 1)]);
+        assert_eq!(lru.weight(), 6);
+        assert_eq!(lru.remove("c"), Some(3));
+        assert_eq!(lru.len(), 1);
+
+        // too heavy to keep
+        let evicted = lru.insert("d".into(), 5, 11);
+        assert_eq!(evicted.len(), 2);
+        assert_eq!(lru.len(), 0);
+        assert_eq!(lru.weight(), 0);
+    }
+}
@@ -0,0 +1,118 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the in-memory storage
+
+use super::lru::Lru;
+use super::{CacheObject, EvictionListener, Evictions, Storage};
+use async_trait::async_trait;
+use gateway_error::Result;
+use std::sync::{Arc, Mutex};
+
+/// keep the objects in memory, evicting the least recently used ones once the total size is over
+/// the capacity
+pub struct MemoryStorage {
+    lru: Mutex<Lru<CacheObject>>,
+    evictions: Evictions,
+}
+
+impl MemoryStorage {
+    /// `capacity` is in bytes
+    pub fn new(capacity: usize) -> Self {
+        MemoryStorage {
+            lru: Mutex::new(Lru::new(capacity)),
+            evictions: Evictions::default(),
+        }
+    }
+
+    /// the total size of the stored objects
+    pub fn size(&self) -> usize {
+        self.lru.lock().unwrap().weight()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn lookup(&self, key: &str) -> Result<Option<CacheObject>> {
+        Ok(self.lru.lock().unwrap().get(key).cloned())
+    }
+
+    async fn put(&self, key: &str, object: CacheObject) -> Result<()> {
+        let size = object.size();
+        // the evicted objects are dropped outside of the lock
+        let evicted = self.lru.lock().unwrap().insert(key.to_string(), object, size);
+        for (evicted, _) in evicted.iter().filter(|(k, _)| k != key) {
+            self.evictions.notify(evicted);
+        }
+        Ok(())
+    }
+
+    async fn purge(&self, key: &str) -> Result<bool> {
+        Ok(self.lru.lock().unwrap().remove(key).is_some())
+    }
+
+    fn set_eviction_listener(&self, listener: Arc<dyn EvictionListener>) {
+        self.evictions.set(listener);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::meta::CacheMeta;
+    use bytes::Bytes;
+    use gateway_httpd::ResponseHeader;
+    use std::time::SystemTime;
+
+    pub(crate) fn object(body: &'static str) -> CacheObject {
+        let now = SystemTime::now();
+        let header = ResponseHeader::build(200, None).unwrap();
+        CacheObject::new(
+            CacheMeta::new(header, now, now, vec![]),
+            Bytes::from_static(body.as_bytes()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        let storage = MemoryStorage::new(10);
+        storage.put("a", object("aaaa")).await.unwrap();
+        storage.put("b", object("bbbb")).await.unwrap();
+        assert_eq!(storage.lookup("a").await.unwrap().unwrap().body, "aaaa");
+        // b is the least recently used
+        storage.put("c", object("cccc")).await.unwrap();
+        assert!(storage.lookup("b").await.unwrap().is_none());
+        assert_eq!(storage.size(), 8);
+
+        assert!(storage.purge("a").await.unwrap());
+        assert!(!storage.purge("a").await.unwrap());
+        assert_eq!(storage.len(), 1);
+    }
+}
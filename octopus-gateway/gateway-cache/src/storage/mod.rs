@@ -0,0 +1,109 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! where the responses are cached
+
+use crate::meta::CacheMeta;
+use async_trait::async_trait;
+use bytes::Bytes;
+use gateway_error::Result;
+use std::sync::{Arc, OnceLock};
+
+pub mod disk;
+mod lru;
+pub mod memory;
+
+pub use disk::DiskStorage;
+pub use memory::MemoryStorage;
+
+/// a cached response
+#[derive(Debug, Clone)]
+pub struct CacheObject {
+    pub meta: CacheMeta,
+    pub body: Bytes,
+}
+
+impl CacheObject {
+    pub fn new(meta: CacheMeta, body: Bytes) -> Self {
+        CacheObject { meta, body }
+    }
+
+    /// the approximate number of bytes the object takes
+    pub fn size(&self) -> usize {
+        let headers: usize = self
+            .meta
+            .header()
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum();
+        headers + self.body.len()
+    }
+}
+
+/// notified of the objects a storage evicts to make room for others
+pub trait EvictionListener: Send + Sync {
+    fn evicted(&self, key: &str);
+}
+
+/// the listener of a storage, set once
+#[derive(Default)]
+pub(crate) struct Evictions(OnceLock<Arc<dyn EvictionListener>>);
+
+impl Evictions {
+    pub fn set(&self, listener: Arc<dyn EvictionListener>) {
+        let _ = self.0.set(listener);
+    }
+
+    pub fn notify(&self, key: &str) {
+        if let Some(listener) = self.0.get() {
+            listener.evicted(key);
+        }
+    }
+}
+
+/// the storage of the cached responses, keyed by the hashed [crate::CacheKey]
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// the object stored under the key
+    async fn lookup(&self, key: &str) -> Result<Option<CacheObject>>;
+
+    /// store the object, replacing the one under the same key
+    async fn put(&self, key: &str, object: CacheObject) -> Result<()>;
+
+    /// replace the meta of the object, return whether it is stored
+    async fn update_meta(&self, key: &str, meta: CacheMeta) -> Result<bool> {
+        let Some(object) = self.lookup(key).await? else {
+            return Ok(false);
+        };
+        self.put(key, CacheObject::new(meta, object.body)).await?;
+        Ok(true)
+    }
+
+    /// remove the object, return whether it was stored
+    async fn purge(&self, key: &str) -> Result<bool>;
+
+    /// notify `listener` of the evicted objects, only the first listener set is kept
+    ///
+    /// the objects which are purged or replaced are not evicted
+    fn set_eviction_listener(&self, _listener: Arc<dyn EvictionListener>) {}
+}
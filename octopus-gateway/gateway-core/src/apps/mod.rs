@@ -0,0 +1,173 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the applications that handle the accepted connections
+
+use crate::protocols::Stream;
+use crate::server::ShutdownWatch;
+use async_trait::async_trait;
+use gateway_httpd::server::{Digest, Session};
+use gateway_httpd::{v1, v2};
+use log::debug;
+use std::sync::Arc;
+
+/// the http/1 or http/2 server session over the accepted stream
+pub type ServerSession = Session<Stream>;
+
+/// the trait of the applications that handle the raw connections of a listening service
+#[async_trait]
+pub trait ServerApp {
+    /// handle a new connection
+    ///
+    /// return the stream if it can be reused for the next request
+    async fn process_new(self: &Arc<Self>, stream: Stream, shutdown: &ShutdownWatch) -> Option<Stream>;
+
+    /// called when the service is shutting down
+    async fn cleanup(&self) {}
+}
+
+/// the trait of the applications that handle http requests
+#[async_trait]
+pub trait HttpServerApp {
+    /// handle a request whose header is already read
+    ///
+    /// return the stream if it can serve the next request
+    async fn process_new_http(self: &Arc<Self>, session: ServerSession, shutdown: &ShutdownWatch) -> Option<Stream>;
+
+    /// called when the service is shutting down
+    async fn http_cleanup(&self) {}
+}
+
+#[async_trait]
+impl<T> ServerApp for T
+where
+    T: HttpServerApp + Send + Sync + 'static,
+{
+    async fn process_new(self: &Arc<Self>, stream: Stream, shutdown: &ShutdownWatch) -> Option<Stream> {
+        let digest = Arc::new(Digest {
+            client_addr: stream.peer_addr(),
+            tls: stream.tls_digest(),
+        });
+        let h2 = digest.tls.as_ref().and_then(|t| t.alpn.as_deref()) == Some("h2");
+        if h2 {
+            process_h2(self, stream, digest, shutdown).await;
+            None
+        } else {
+            process_h1(self, stream, digest, shutdown).await
+        }
+    }
+
+    async fn cleanup(&self) {
+        self.http_cleanup().await
+    }
+}
+
+/// serve the requests of a http/1 connection one after another
+async fn process_h1<T>(
+    app: &Arc<T>,
+    mut stream: Stream,
+    digest: Arc<Digest>,
+    shutdown: &ShutdownWatch,
+) -> Option<Stream>
+where
+    T: HttpServerApp + Send + Sync + 'static,
+{
+    let mut reused = false;
+    loop {
+        let mut session = v1::server::HttpSession::new(stream);
+        session.set_digest(digest.clone());
+        let read = if reused {
+            // an idle keepalive connection is closed on shutdown
+            let mut shutdown = shutdown.clone();
+            tokio::select! {
+                r = session.read_request() => r,
+                _ = shutdown.changed() => return None,
+            }
+        } else {
+            session.read_request().await
+        };
+        match read {
+            Ok(Some(_)) => {}
+            Ok(None) => return None,
+            Err(e) => {
+                debug!("failed to read request: {e}");
+                let _ = session.respond_error(400).await;
+                return None;
+            }
+        }
+        if *shutdown.borrow() {
+            session.set_keepalive(false);
+        }
+
+        stream = app.process_new_http(Session::H1(session), shutdown).await?;
+        reused = true;
+    }
+}
+
+/// serve the streams of a http/2 connection concurrently
+///
+/// on shutdown the client is told to open no new stream, the connection is closed once the
+/// open ones finish
+async fn process_h2<T>(app: &Arc<T>, stream: Stream, digest: Arc<Digest>, shutdown: &ShutdownWatch)
+where
+    T: HttpServerApp + Send + Sync + 'static,
+{
+    let mut conn = match v2::server::handshake(stream).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            debug!("{e}");
+            return;
+        }
+    };
+    let mut watch = shutdown.clone();
+    let mut shutting_down = *shutdown.borrow();
+    if shutting_down {
+        conn.graceful_shutdown();
+    }
+    loop {
+        let accepted = if shutting_down {
+            v2::server::HttpSession::from_h2_conn(&mut conn, digest.clone()).await
+        } else {
+            tokio::select! {
+                r = v2::server::HttpSession::from_h2_conn(&mut conn, digest.clone()) => r,
+                _ = watch.changed() => {
+                    shutting_down = true;
+                    conn.graceful_shutdown();
+                    continue;
+                }
+            }
+        };
+        let session = match accepted {
+            Ok(Some(session)) => session,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("{e}");
+                return;
+            }
+        };
+        let app = app.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            app.process_new_http(Session::H2(Box::new(session)), &shutdown).await;
+        });
+    }
+}
@@ -0,0 +1,72 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! layer 4 connections
+
+use gateway_error::{Error, ErrorType::*, Result};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// connect to `addr` over TCP, the io errors are mapped to the connection [gateway_error::ErrorType]s
+pub async fn connect(addr: SocketAddr, timeout: Option<Duration>) -> Result<TcpStream> {
+    let result = match timeout {
+        Some(t) => match tokio::time::timeout(t, TcpStream::connect(addr)).await {
+            Ok(r) => r,
+            Err(_) => {
+                return Err(Error::explain(ConnectionTimeout, format!("connecting to {addr}")).into_up())
+            }
+        },
+        None => TcpStream::connect(addr).await,
+    };
+    result.map_err(|e| {
+        let etype = match e.kind() {
+            ErrorKind::ConnectionRefused => ConnectionRefused,
+            ErrorKind::TimedOut => ConnectionTimeout,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => ConnectNoRoute,
+            _ => ConnectError,
+        };
+        let mut e = Error::because(etype, format!("connecting to {addr}"), e).into_up();
+        // nothing is sent yet, so it is always safe to retry
+        e.set_retry(true);
+        e
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(connect(addr, None).await.is_ok());
+        drop(listener);
+
+        let e = connect(addr, Some(Duration::from_secs(1))).await.unwrap_err();
+        assert_eq!(e.etype(), &ConnectionRefused);
+        assert!(e.retry());
+    }
+}
@@ -0,0 +1,52 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! connect to the upstream peers
+
+pub mod l4;
+pub mod tls;
+
+use crate::protocols::Stream;
+use crate::upstreams::peer::HttpPeer;
+use gateway_error::Result;
+
+/// establishes the transport connections to the peers
+#[derive(Default)]
+pub struct TransportConnector {
+    tls: tls::Connector,
+}
+
+impl TransportConnector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// connect to the peer, over TLS if the peer asks for it
+    pub async fn new_stream(&self, peer: &HttpPeer) -> Result<Stream> {
+        let stream: Stream = Box::new(l4::connect(peer.address, peer.options.connection_timeout).await?);
+        if peer.tls {
+            self.tls.handshake(peer, stream).await
+        } else {
+            Ok(stream)
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! TLS connections to the upstream peers
+
+use crate::protocols::tls::{provider, CertifiedKey, ALPN};
+use crate::protocols::Stream;
+use crate::upstreams::peer::{CaBundle, HttpPeer};
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{ResolvesClientCert, WebPkiServerVerifier};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// verifies the peer as far as its options ask for
+#[derive(Debug)]
+struct Verifier {
+    inner: Arc<WebPkiServerVerifier>,
+    verify_cert: bool,
+    verify_hostname: bool,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if !self.verify_cert {
+            return Ok(ServerCertVerified::assertion());
+        }
+        match self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) if !self.verify_hostname => Ok(ServerCertVerified::assertion()),
+            r => r,
+        }
+    }
+
+    // the handshake signatures are always checked, they prove the peer owns the cert it sends
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// always presents the same client cert
+#[derive(Debug)]
+struct ClientCert(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for ClientCert {
+    fn resolve(&self, _root_hint_subjects: &[&[u8]], _sigschemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// the options of a peer that make a different [ClientConfig]
+///
+/// the CA bundle and the client cert are compared by address, the peers sharing them should
+/// share the same `Arc`s
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ConfigKey {
+    verify_cert: bool,
+    verify_hostname: bool,
+    ca: Option<usize>,
+    client_cert: Option<usize>,
+}
+
+struct CachedConfig {
+    config: Arc<ClientConfig>,
+    // hold the keyed `Arc`s, so that their addresses are not reused by others
+    _ca: Option<CaBundle>,
+    _client_cert: Option<Arc<CertifiedKey>>,
+}
+
+/// the max number of cached [ClientConfig]s, the cache is simply cleared once it is full
+const MAX_CACHED_CONFIGS: usize = 256;
+
+/// makes TLS connections to the peers
+///
+/// the sessions are resumed across connections to the same peer, as the peers of the same
+/// options share the same [ClientConfig] and so its session cache
+pub struct Connector {
+    configs: Mutex<HashMap<ConfigKey, CachedConfig>>,
+    webpki_roots: Arc<RootCertStore>,
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Connector {
+    pub fn new() -> Self {
+        Connector {
+            configs: Mutex::new(HashMap::new()),
+            webpki_roots: Arc::new(RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            }),
+        }
+    }
+
+    fn config(&self, peer: &HttpPeer) -> Result<Arc<ClientConfig>> {
+        let options = &peer.options;
+        let key = ConfigKey {
+            verify_cert: options.verify_cert,
+            verify_hostname: options.verify_hostname,
+            ca: options.ca.as_ref().map(|ca| Arc::as_ptr(ca) as usize),
+            client_cert: options.client_cert.as_ref().map(|c| Arc::as_ptr(c) as usize),
+        };
+        let mut configs = self.configs.lock().unwrap();
+        if let Some(cached) = configs.get(&key) {
+            return Ok(cached.config.clone());
+        }
+
+        let roots = match options.ca.as_ref() {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in ca.iter() {
+                    roots
+                        .add(cert.clone())
+                        .or_err(InvalidCert, "invalid CA certificate")?;
+                }
+                Arc::new(roots)
+            }
+            None => self.webpki_roots.clone(),
+        };
+        let inner = WebPkiServerVerifier::builder_with_provider(roots, provider())
+            .build()
+            .or_err(InvalidCert, "failed to build the certificate verifier")?;
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .or_err(InternalError, "failed to set the TLS versions")?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(Verifier {
+                inner,
+                verify_cert: options.verify_cert,
+                verify_hostname: options.verify_hostname,
+            }));
+        let mut config = match options.client_cert.as_ref() {
+            Some(cert) => builder.with_client_cert_resolver(Arc::new(ClientCert(cert.clone()))),
+            None => builder.with_no_client_auth(),
+        };
+        // the upstream sessions are http/1.1
+        config.alpn_protocols = ALPN::H1.to_wire_protocols();
+        let config = Arc::new(config);
+
+        if configs.len() >= MAX_CACHED_CONFIGS {
+            configs.clear();
+        }
+        configs.insert(
+            key,
+            CachedConfig {
+                config: config.clone(),
+                _ca: options.ca.clone(),
+                _client_cert: options.client_cert.clone(),
+            },
+        );
+        Ok(config)
+    }
+
+    /// perform the client side handshake with `peer` over `stream`
+    ///
+    /// the errors of verifying the peer are [InvalidCert] with the reason, the others are
+    /// [TLSHandshakeFailure], or [TLSHandshakeTimeout] if the `connection_timeout` of the peer
+    /// elapses
+    pub async fn handshake(&self, peer: &HttpPeer, stream: Stream) -> Result<Stream> {
+        let config = self.config(peer)?;
+        let server_name = if peer.sni.is_empty() {
+            ServerName::IpAddress(peer.address.ip().into())
+        } else {
+            ServerName::try_from(peer.sni.clone())
+                .explain_err(InvalidCert, |_| format!("invalid SNI `{}`", peer.sni))?
+        };
+        let connect = tokio_rustls::TlsConnector::from(config).connect(server_name, stream);
+        let result = match peer.options.connection_timeout {
+            Some(t) => match tokio::time::timeout(t, connect).await {
+                Ok(r) => r,
+                Err(_) => {
+                    let e = Error::explain(TLSHandshakeTimeout, format!("TLS handshake with {}", peer.sni));
+                    return Err(e.into_up());
+                }
+            },
+            None => connect.await,
+        };
+        match result {
+            Ok(tls) => Ok(Box::new(tls)),
+            Err(e) => {
+                let invalid_cert = e
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<rustls::Error>())
+                    .and_then(|e| match e {
+                        rustls::Error::InvalidCertificate(reason) => Some(reason.clone()),
+                        _ => None,
+                    });
+                let e = match invalid_cert {
+                    Some(reason) => Error::explain(
+                        InvalidCert,
+                        format!("invalid certificate of {} ({}): {reason}", peer.sni, peer.address),
+                    ),
+                    None => Error::because(TLSHandshakeFailure, format!("TLS handshake with {}", peer.sni), e),
+                };
+                Err(e.into_up())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::tls::tests::self_signed;
+    use crate::protocols::tls::{cert_key_from_pem, certs_from_pem, key_from_pem};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{HandshakeKind, ServerConfig};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsAcceptor;
+
+    struct Upstream {
+        acceptor: TlsAcceptor,
+        ca: CaBundle,
+    }
+
+    /// an upstream of `localhost`, requiring the client certs issued by `client_ca` if set
+    fn upstream(client_ca: Option<&str>) -> Upstream {
+        let (cert, key) = self_signed(&["localhost"]);
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                roots.add_parsable_certificates(certs_from_pem(ca.as_bytes()).unwrap());
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+                    .build()
+                    .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs_from_pem(cert.as_bytes()).unwrap(), key_from_pem(key.as_bytes()).unwrap())
+            .unwrap();
+        config.alpn_protocols = ALPN::H1.to_wire_protocols();
+        Upstream {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            ca: Arc::new(certs_from_pem(cert.as_bytes()).unwrap()),
+        }
+    }
+
+    fn peer(sni: &str, ca: Option<&CaBundle>) -> HttpPeer {
+        let mut peer = HttpPeer::new_tls("127.0.0.1:443".parse().unwrap(), sni.into());
+        peer.options.ca = ca.cloned();
+        peer
+    }
+
+    /// connect to the upstream and exchange a byte, return how the upstream handshake went
+    async fn connect(connector: &Connector, upstream: &Upstream, peer: &HttpPeer) -> Result<HandshakeKind> {
+        let (client, server) = duplex(65536);
+        let acceptor = upstream.acceptor.clone();
+        let server = tokio::spawn(async move {
+            let mut tls = acceptor.accept(server).await.ok()?;
+            tls.write_all(b"1").await.ok()?;
+            // wait for the client to read the session tickets with the byte
+            tls.read_u8().await.ok()?;
+            tls.get_ref().1.handshake_kind()
+        });
+        let mut stream = connector.handshake(peer, Box::new(client)).await?;
+        assert_eq!(stream.tls_digest().unwrap().alpn.as_deref(), Some("http/1.1"));
+        assert_eq!(stream.read_u8().await.unwrap(), b'1');
+        stream.write_all(b"2").await.unwrap();
+        Ok(server.await.unwrap().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let connector = Connector::new();
+        let upstream = upstream(None);
+        let kind = connect(&connector, &upstream, &peer("localhost", Some(&upstream.ca))).await;
+        assert_eq!(kind.unwrap(), HandshakeKind::Full);
+
+        // not trusted by the webpki roots
+        let e = connect(&connector, &upstream, &peer("localhost", None)).await.unwrap_err();
+        assert_eq!(e.etype(), &InvalidCert);
+        assert!(e.to_string().contains("UnknownIssuer"), "{e}");
+        let mut skip_verify = peer("localhost", None);
+        skip_verify.options.verify_cert = false;
+        connect(&connector, &upstream, &skip_verify).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_hostname() {
+        let connector = Connector::new();
+        let upstream = upstream(None);
+        let mut peer = peer("other.test", Some(&upstream.ca));
+        let e = connect(&connector, &upstream, &peer).await.unwrap_err();
+        assert_eq!(e.etype(), &InvalidCert);
+        assert_eq!(e.esource(), &gateway_error::ErrorSource::Upstream);
+        assert!(e.to_string().contains("other.test"), "{e}");
+
+        peer.options.verify_hostname = false;
+        connect(&connector, &upstream, &peer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_cert() {
+        let (client_cert, client_key) = self_signed(&["client.test"]);
+        let connector = Connector::new();
+        let upstream = upstream(Some(&client_cert));
+        let mut peer = peer("localhost", Some(&upstream.ca));
+        let cert = cert_key_from_pem(client_cert.as_bytes(), client_key.as_bytes()).unwrap();
+        peer.options.client_cert = Some(Arc::new(cert));
+        connect(&connector, &upstream, &peer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_resumption() {
+        let connector = Connector::new();
+        let upstream = upstream(None);
+        let peer = peer("localhost", Some(&upstream.ca));
+        assert_eq!(connect(&connector, &upstream, &peer).await.unwrap(), HandshakeKind::Full);
+        assert_eq!(connect(&connector, &upstream, &peer).await.unwrap(), HandshakeKind::Resumed);
+    }
+}
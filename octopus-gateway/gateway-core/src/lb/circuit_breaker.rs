@@ -0,0 +1,473 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! fail fast when an upstream is failing: stop sending it requests for a while once too many of
+//! them fail or are slow, then let a few trial requests decide whether it recovered
+
+use gateway_error::{Error, ErrorType::CircuitOpen, Result};
+use log::{info, warn};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// the state of a [CircuitBreaker]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// the requests are let through and their outcomes counted
+    Closed,
+    /// the requests are rejected
+    Open,
+    /// a few trial requests are let through
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        }
+    }
+}
+
+/// the thresholds of a [CircuitBreaker]
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// open when the ratio of failed calls over the window reaches this, 0.0 to disable
+    pub failure_rate: f64,
+    /// open when the ratio of slow calls over the window reaches this, 0.0 to disable
+    pub slow_call_rate: f64,
+    /// a call slower than this is slow
+    pub slow_call_duration: Duration,
+    /// the rates are only evaluated with at least this many calls in the window
+    pub min_calls: usize,
+    /// the rolling window over which the calls are counted
+    pub window: Duration,
+    /// the number of buckets the window rolls by
+    pub buckets: usize,
+    /// how long the circuit stays open before letting trial calls through
+    pub open_duration: Duration,
+    /// the number of trial calls of the half-open state, which close the circuit if their rates
+    /// are below the thresholds or else open it again
+    pub half_open_calls: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_rate: 0.5,
+            slow_call_rate: 1.0,
+            slow_call_duration: Duration::from_secs(5),
+            min_calls: 20,
+            window: Duration::from_secs(10),
+            buckets: 10,
+            open_duration: Duration::from_secs(30),
+            half_open_calls: 5,
+        }
+    }
+}
+
+/// the counts of calls
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    calls: usize,
+    failures: usize,
+    slow: usize,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.calls += other.calls;
+        self.failures += other.failures;
+        self.slow += other.slow;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// the number of bucket lengths since the epoch of the window
+    index: u64,
+    counts: Counts,
+}
+
+/// the counts of the last buckets
+struct Window {
+    buckets: Vec<Bucket>,
+    bucket_len: Duration,
+    epoch: Instant,
+}
+
+impl Window {
+    fn new(window: Duration, buckets: usize, epoch: Instant) -> Self {
+        let buckets = buckets.max(1);
+        Window {
+            buckets: (0..buckets).map(|_| Bucket::default()).collect(),
+            bucket_len: (window / buckets as u32).max(Duration::from_millis(1)),
+            epoch,
+        }
+    }
+
+    fn index(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.epoch).as_nanos() / self.bucket_len.as_nanos()) as u64
+    }
+
+    fn add(&mut self, now: Instant, counts: &Counts) {
+        let index = self.index(now);
+        let n = self.buckets.len();
+        let bucket = &mut self.buckets[index as usize % n];
+        // a call reported late to a bucket already reused is counted in the newer one
+        if bucket.index < index {
+            *bucket = Bucket {
+                index,
+                counts: Counts::default(),
+            };
+        }
+        bucket.counts.add(counts);
+    }
+
+    fn counts(&self, now: Instant) -> Counts {
+        let index = self.index(now);
+        let mut counts = Counts::default();
+        for bucket in self.buckets.iter() {
+            if index.saturating_sub(bucket.index) < self.buckets.len() as u64 {
+                counts.add(&bucket.counts);
+            }
+        }
+        counts
+    }
+
+    fn clear(&mut self) {
+        self.buckets.iter_mut().for_each(|b| *b = Bucket::default());
+    }
+}
+
+struct Inner {
+    state: CircuitState,
+    /// when the current state was entered
+    since: Instant,
+    /// bumped on every change of state, the calls let through before are not counted after
+    generation: u64,
+    window: Window,
+    trials_started: usize,
+    trials: Counts,
+}
+
+/// a call let through by a [CircuitBreaker], whose outcome is reported back to it
+#[derive(Debug, Clone, Copy)]
+pub struct Call {
+    generation: u64,
+}
+
+/// the circuit breaker of an upstream
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    /// `name` is only used in the logs
+    pub fn new(name: &str, config: CircuitBreakerConfig) -> Self {
+        let now = Instant::now();
+        let window = Window::new(config.window, config.buckets, now);
+        CircuitBreaker {
+            name: name.to_string(),
+            config,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                since: now,
+                generation: 0,
+                window,
+                trials_started: 0,
+                trials: Counts::default(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &CircuitBreakerConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// let a call through, `CircuitOpen` if the circuit is open or all the trial calls of the
+    /// half-open state are taken
+    pub fn allow(&self) -> Result<Call> {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&self, now: Instant) -> Result<Call> {
+        let mut inner = self.inner.lock().unwrap();
+        let open_until = inner.since + self.config.open_duration;
+        match inner.state {
+            CircuitState::Closed => {}
+            // trial calls which never reported back do not keep the circuit half-open forever
+            CircuitState::Open | CircuitState::HalfOpen if now >= open_until => {
+                if inner.state == CircuitState::Open {
+                    info!("circuit of {} half-open", self.name);
+                }
+                self.transition(&mut inner, CircuitState::HalfOpen, now);
+                inner.trials_started = 1;
+            }
+            CircuitState::HalfOpen if inner.trials_started < self.config.half_open_calls => {
+                inner.trials_started += 1;
+            }
+            CircuitState::Open | CircuitState::HalfOpen => {
+                let retry_in = open_until.saturating_duration_since(now);
+                return Error::e_explain(CircuitOpen, format!("circuit open, retry in {retry_in:?}"));
+            }
+        }
+        Ok(Call {
+            generation: inner.generation,
+        })
+    }
+
+    /// report the outcome of `call`, `latency` is how long it took to succeed, `None` if it
+    /// failed
+    pub fn report(&self, call: Call, latency: Option<Duration>) {
+        self.report_at(call, latency, Instant::now())
+    }
+
+    fn report_at(&self, call: Call, latency: Option<Duration>, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        if call.generation != inner.generation {
+            return;
+        }
+        let counts = Counts {
+            calls: 1,
+            failures: latency.is_none() as usize,
+            slow: latency.is_some_and(|l| l > self.config.slow_call_duration) as usize,
+        };
+        match inner.state {
+            CircuitState::Closed => {
+                inner.window.add(now, &counts);
+                let window = inner.window.counts(now);
+                if window.calls >= self.config.min_calls && self.tripped(&window) {
+                    warn!(
+                        "circuit of {} open, {} failed and {} slow out of {} calls",
+                        self.name, window.failures, window.slow, window.calls
+                    );
+                    self.transition(&mut inner, CircuitState::Open, now);
+                }
+            }
+            CircuitState::HalfOpen => {
+                inner.trials.add(&counts);
+                let trials = inner.trials;
+                if trials.calls < self.config.half_open_calls {
+                    return;
+                }
+                if self.tripped(&trials) {
+                    warn!("circuit of {} open again after the trial calls", self.name);
+                    self.transition(&mut inner, CircuitState::Open, now);
+                } else {
+                    info!("circuit of {} closed", self.name);
+                    self.transition(&mut inner, CircuitState::Closed, now);
+                }
+            }
+            // the calls are rejected, the generation is always stale
+            CircuitState::Open => {}
+        }
+    }
+
+    /// give up on `call` without an outcome, e.g. it was never sent, its trial slot of the
+    /// half-open state is free again
+    pub fn cancel(&self, call: Call) {
+        let mut inner = self.inner.lock().unwrap();
+        if call.generation == inner.generation && inner.state == CircuitState::HalfOpen {
+            inner.trials_started = inner.trials_started.saturating_sub(1);
+        }
+    }
+
+    fn tripped(&self, counts: &Counts) -> bool {
+        let rate = |n: usize| n as f64 / counts.calls as f64;
+        (self.config.failure_rate > 0.0 && rate(counts.failures) >= self.config.failure_rate)
+            || (self.config.slow_call_rate > 0.0 && rate(counts.slow) >= self.config.slow_call_rate)
+    }
+
+    fn transition(&self, inner: &mut Inner, state: CircuitState, now: Instant) {
+        inner.state = state;
+        inner.since = now;
+        inner.generation += 1;
+        inner.window.clear();
+        inner.trials_started = 0;
+        inner.trials = Counts::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            CircuitBreakerConfig {
+                failure_rate: 0.5,
+                slow_call_rate: 0.8,
+                slow_call_duration: Duration::from_millis(100),
+                min_calls: 4,
+                window: Duration::from_secs(10),
+                buckets: 10,
+                open_duration: Duration::from_secs(30),
+                half_open_calls: 2,
+            },
+        )
+    }
+
+    const FAST: Option<Duration> = Some(Duration::from_millis(10));
+    const SLOW: Option<Duration> = Some(Duration::from_millis(200));
+
+    fn call(cb: &CircuitBreaker, latency: Option<Duration>, now: Instant) {
+        let call = cb.allow_at(now).unwrap();
+        cb.report_at(call, latency, now);
+    }
+
+    #[test]
+    fn test_failure_rate() {
+        let cb = breaker();
+        let now = Instant::now();
+        // not enough calls
+        for _ in 0..3 {
+            call(&cb, None, now);
+        }
+        assert_eq!(cb.state(), CircuitState::Closed);
+
+        let cb = breaker();
+        for _ in 0..2 {
+            call(&cb, FAST, now);
+        }
+        // a slow call is not a failure
+        call(&cb, SLOW, now);
+        call(&cb, None, now);
+        let in_flight = cb.allow_at(now).unwrap();
+        call(&cb, None, now);
+        assert_eq!(cb.state(), CircuitState::Closed);
+        // 3 failures out of 6 calls
+        call(&cb, None, now);
+        assert_eq!(cb.state(), CircuitState::Open);
+
+        let e = cb.allow_at(now + Duration::from_secs(29)).unwrap_err();
+        assert_eq!(e.etype(), &CircuitOpen);
+        // a call let through before the circuit opened is ignored
+        cb.report_at(in_flight, FAST, now);
+        assert_eq!(cb.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_window() {
+        let cb = breaker();
+        let now = Instant::now();
+        for _ in 0..3 {
+            call(&cb, None, now);
+        }
+        // the first failures rolled out of the window
+        let later = now + Duration::from_secs(10);
+        for _ in 0..3 {
+            call(&cb, FAST, later);
+        }
+        call(&cb, None, later);
+        call(&cb, None, later + Duration::from_secs(9));
+        assert_eq!(cb.state(), CircuitState::Closed);
+
+        // so did the calls of `later`, 2 failures are not enough calls
+        let end = later + Duration::from_secs(10);
+        call(&cb, None, end);
+        call(&cb, None, end);
+        assert_eq!(cb.state(), CircuitState::Closed);
+        call(&cb, None, end);
+        assert_eq!(cb.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_slow_call_rate() {
+        let cb = breaker();
+        let now = Instant::now();
+        call(&cb, FAST, now);
+        for _ in 0..3 {
+            call(&cb, SLOW, now);
+        }
+        assert_eq!(cb.state(), CircuitState::Closed);
+        // 4 slow calls out of 5
+        call(&cb, SLOW, now);
+        assert_eq!(cb.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_half_open() {
+        let cb = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            call(&cb, None, now);
+        }
+        assert_eq!(cb.state(), CircuitState::Open);
+
+        // only 2 trial calls
+        let now = now + Duration::from_secs(30);
+        let first = cb.allow_at(now).unwrap();
+        assert_eq!(cb.state(), CircuitState::HalfOpen);
+        let second = cb.allow_at(now).unwrap();
+        assert!(cb.allow_at(now).is_err());
+        cb.report_at(first, FAST, now);
+        cb.report_at(second, None, now);
+        assert_eq!(cb.state(), CircuitState::Open);
+
+        // the trial calls which never report back are given up on
+        let now = now + Duration::from_secs(30);
+        let _lost = cb.allow_at(now).unwrap();
+        let second = cb.allow_at(now).unwrap();
+        cb.report_at(second, FAST, now);
+        assert!(cb.allow_at(now + Duration::from_secs(1)).is_err());
+        let now = now + Duration::from_secs(30);
+        call(&cb, FAST, now);
+        assert_eq!(cb.state(), CircuitState::HalfOpen);
+        call(&cb, FAST, now);
+        assert_eq!(cb.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cb = breaker();
+        let now = Instant::now();
+        // not counted
+        for _ in 0..4 {
+            cb.cancel(cb.allow_at(now).unwrap());
+        }
+        assert_eq!(cb.state(), CircuitState::Closed);
+        for _ in 0..4 {
+            call(&cb, None, now);
+        }
+
+        // the cancelled trial call frees its slot
+        let now = now + Duration::from_secs(30);
+        let first = cb.allow_at(now).unwrap();
+        let second = cb.allow_at(now).unwrap();
+        cb.cancel(first);
+        let third = cb.allow_at(now).unwrap();
+        assert!(cb.allow_at(now).is_err());
+        cb.report_at(second, FAST, now);
+        assert_eq!(cb.state(), CircuitState::HalfOpen);
+        cb.report_at(third, FAST, now);
+        assert_eq!(cb.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,583 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! discover backends by periodically resolving DNS A/AAAA/SRV records
+
+use super::ServiceDiscovery;
+use crate::lb::Backend;
+use async_trait::async_trait;
+use gateway_error::{BError, Error, ErrorType::*, OrErr, Result};
+use log::warn;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// the max size of a DNS message over UDP
+const MAX_UDP_MESSAGE: usize = 4096;
+
+/// the DNS record types the resolver understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    AAAA,
+    SRV,
+}
+
+impl RecordType {
+    fn code(&self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::AAAA => 28,
+            RecordType::SRV => 33,
+        }
+    }
+}
+
+/// the data of a DNS record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+/// the records in the answer and additional sections of a DNS response
+#[derive(Debug, Default)]
+pub struct Lookup {
+    pub answers: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+/// a minimal stub resolver which sends recursive queries to a nameserver over UDP
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    pub nameserver: SocketAddr,
+    /// the timeout of each attempt
+    pub timeout: Duration,
+    pub attempts: usize,
+}
+
+impl DnsResolver {
+    pub fn new(nameserver: SocketAddr) -> Self {
+        DnsResolver {
+            nameserver,
+            timeout: Duration::from_secs(2),
+            attempts: 2,
+        }
+    }
+
+    /// use the first nameserver in `/etc/resolv.conf`
+    pub fn from_resolv_conf() -> Result<Self> {
+        let conf = std::fs::read_to_string("/etc/resolv.conf")
+            .or_err(FileReadError, "failed to read /etc/resolv.conf")?;
+        let nameserver = conf
+            .lines()
+            .filter_map(|l| l.trim().strip_prefix("nameserver"))
+            .find_map(|ns| ns.trim().parse::<IpAddr>().ok())
+            .ok_or_else(|| Error::explain(DnsError, "no nameserver in /etc/resolv.conf"))?;
+        Ok(Self::new(SocketAddr::new(nameserver, 53)))
+    }
+
+    /// query the records of the given type, an unknown name returns no records
+    pub async fn lookup(&self, name: &str, rtype: RecordType) -> Result<Lookup> {
+        let mut last_error = None;
+        for _ in 0..self.attempts.max(1) {
+            match tokio::time::timeout(self.timeout, self.query(name, rtype)).await {
+                Ok(Ok(lookup)) => return Ok(lookup),
+                Ok(Err(e)) => last_error = Some(e),
+                Err(_) => {
+                    last_error = Some(Error::explain(
+                        ConnectionTimeout,
+                        format!("timeout resolving {name} from {}", self.nameserver),
+                    ))
+                }
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    async fn query(&self, name: &str, rtype: RecordType) -> Result<Lookup> {
+        let local: SocketAddr = if self.nameserver.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await.or_err(ConnectError, "failed to bind udp socket")?;
+        socket
+            .connect(self.nameserver)
+            .await
+            .or_err(ConnectError, "failed to connect to nameserver")?;
+
+        let id: u16 = rand::random();
+        let query = encode_query(id, name, rtype)?;
+        socket.send(&query).await.or_err(WriteError, "failed to send dns query")?;
+
+        let mut buf = vec![0; MAX_UDP_MESSAGE];
+        loop {
+            let n = socket.recv(&mut buf).await.or_err(ReadError, "failed to read dns response")?;
+            match parse_response(&buf[..n], id) {
+                // not the response to our query, keep waiting
+                Ok(None) => continue,
+                Ok(Some(lookup)) => return Ok(lookup),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+pub(crate) fn encode_name(name: &str, out: &mut Vec<u8>) -> Result<()> {
+    let name = name.trim_end_matches('.');
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Error::e_explain(DnsError, format!("invalid dns name {name}"));
+            }
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+    }
+    out.push(0);
+    Ok(())
+}
+
+fn encode_query(id: u16, name: &str, rtype: RecordType) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(64);
+    query.extend_from_slice(&id.to_be_bytes());
+    // standard query, recursion desired
+    query.extend_from_slice(&0x0100u16.to_be_bytes());
+    // 1 question, no other records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(name, &mut query)?;
+    query.extend_from_slice(&rtype.code().to_be_bytes());
+    // class IN
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+fn malformed() -> BError {
+    Error::explain(DnsError, "malformed dns message")
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Result<u16> {
+    buf.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(malformed)
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Result<u32> {
+    buf.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(malformed)
+}
+
+/// read a possibly compressed name at `pos`, return the name and the position after it
+pub(crate) fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // guard against pointer loops
+    for _ in 0..128 {
+        let len = *buf.get(pos).ok_or_else(malformed)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let pointer = (read_u16(buf, pos)? & 0x3FFF) as usize;
+            end.get_or_insert(pos + 2);
+            pos = pointer;
+        } else if len == 0 {
+            return Ok((name, end.unwrap_or(pos + 1)));
+        } else {
+            let label = buf.get(pos + 1..pos + 1 + len).ok_or_else(malformed)?;
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(&String::from_utf8_lossy(label));
+            pos += 1 + len;
+        }
+    }
+    Err(malformed())
+}
+
+/// parse the response to the query `id`, `None` if the message is not the response
+fn parse_response(buf: &[u8], id: u16) -> Result<Option<Lookup>> {
+    if read_u16(buf, 0)? != id {
+        return Ok(None);
+    }
+    let flags = read_u16(buf, 2)?;
+    if flags & 0x8000 == 0 {
+        return Ok(None);
+    }
+    match flags & 0x000F {
+        0 => {}
+        // NXDOMAIN
+        3 => return Ok(Some(Lookup::default())),
+        rcode => return Error::e_explain(DnsError, format!("dns response code {rcode}")),
+    }
+    if flags & 0x0200 != 0 {
+        warn!("dns response is truncated");
+    }
+
+    let questions = read_u16(buf, 4)?;
+    let answers = read_u16(buf, 6)? as usize;
+    let authorities = read_u16(buf, 8)? as usize;
+    let additionals = read_u16(buf, 10)? as usize;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        let (_, next) = read_name(buf, pos)?;
+        pos = next + 4;
+    }
+
+    let mut lookup = Lookup::default();
+    for i in 0..answers + authorities + additionals {
+        let (name, next) = read_name(buf, pos)?;
+        let rtype = read_u16(buf, next)?;
+        let ttl = read_u32(buf, next + 4)?;
+        let rdlen = read_u16(buf, next + 8)? as usize;
+        let rdata_pos = next + 10;
+        let rdata = buf.get(rdata_pos..rdata_pos + rdlen).ok_or_else(malformed)?;
+        pos = rdata_pos + rdlen;
+
+        let data = match rtype {
+            1 if rdlen == 4 => RData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            28 if rdlen == 16 => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                RData::AAAA(Ipv6Addr::from(octets))
+            }
+            33 if rdlen > 6 => RData::SRV {
+                priority: read_u16(buf, rdata_pos)?,
+                weight: read_u16(buf, rdata_pos + 2)?,
+                port: read_u16(buf, rdata_pos + 4)?,
+                target: read_name(buf, rdata_pos + 6)?.0,
+            },
+            // CNAME, SOA and anything else are not interesting
+            _ => continue,
+        };
+        let record = Record { name, ttl, data };
+        if i < answers {
+            lookup.answers.push(record);
+        } else if i >= answers + authorities {
+            lookup.additionals.push(record);
+        }
+    }
+    Ok(Some(lookup))
+}
+
+/// what [Dns] resolves
+#[derive(Debug, Clone)]
+pub enum DnsQuery {
+    /// resolve the A and AAAA records of the name, all the addresses use the given port
+    Host { name: String, port: u16 },
+    /// resolve the SRV records of the name, only the targets of the lowest priority are used
+    Srv(String),
+}
+
+/// discover backends from DNS
+pub struct Dns {
+    resolver: DnsResolver,
+    queries: Vec<DnsQuery>,
+}
+
+impl Dns {
+    pub fn new(resolver: DnsResolver, queries: Vec<DnsQuery>) -> Box<Self> {
+        Box::new(Dns { resolver, queries })
+    }
+
+    async fn resolve_host(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let mut addrs = vec![];
+        for rtype in [RecordType::A, RecordType::AAAA] {
+            let lookup = self.resolver.lookup(name, rtype).await?;
+            addrs.extend(lookup.answers.iter().filter_map(|r| match r.data {
+                RData::A(ip) => Some(IpAddr::V4(ip)),
+                RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            }));
+        }
+        Ok(addrs)
+    }
+
+    async fn resolve_srv(&self, name: &str, backends: &mut BTreeSet<Backend>) -> Result<()> {
+        let lookup = self.resolver.lookup(name, RecordType::SRV).await?;
+        let srvs: Vec<_> = lookup
+            .answers
+            .iter()
+            .filter_map(|r| match &r.data {
+                RData::SRV {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                } => Some((*priority, *weight, *port, target)),
+                _ => None,
+            })
+            .collect();
+        let Some(min_priority) = srvs.iter().map(|s| s.0).min() else {
+            return Ok(());
+        };
+
+        for (_, weight, port, target) in srvs.into_iter().filter(|s| s.0 == min_priority) {
+            // the nameserver may have resolved the target already
+            let mut addrs: Vec<IpAddr> = lookup
+                .additionals
+                .iter()
+                .filter(|r| r.name.eq_ignore_ascii_case(target))
+                .filter_map(|r| match r.data {
+                    RData::A(ip) => Some(IpAddr::V4(ip)),
+                    RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                    _ => None,
+                })
+                .collect();
+            if addrs.is_empty() {
+                addrs = self.resolve_host(target).await?;
+            }
+            for ip in addrs {
+                backends.insert(Backend {
+                    addr: SocketAddr::new(ip, port),
+                    weight: (weight as usize).max(1),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for Dns {
+    async fn discover(&self) -> Result<BTreeSet<Backend>> {
+        let mut backends = BTreeSet::new();
+        for query in self.queries.iter() {
+            let result = match query {
+                DnsQuery::Host { name, port } => self.resolve_host(name).await.map(|addrs| {
+                    backends.extend(addrs.into_iter().map(|ip| Backend::from(SocketAddr::new(ip, *port))))
+                }),
+                DnsQuery::Srv(name) => self.resolve_srv(name, &mut backends).await,
+            };
+            // the set would miss the backends of the query, keep the previous ones instead
+            if let Err(e) = result {
+                warn!("failed to resolve {query:?}: {e}");
+                return Err(e);
+            }
+        }
+
+        // an empty set would take all the backends out of rotation
+        if backends.is_empty() {
+            return Error::e_explain(DnsError, "no backend resolved");
+        }
+        Ok(backends)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    type Zone = HashMap<(String, u16), (Vec<(String, u16, Vec<u8>)>, Vec<(String, u16, Vec<u8>)>)>;
+
+    fn encode_record(name: &str, rtype: u16, rdata: &[u8], out: &mut Vec<u8>) {
+        encode_name(name, out).unwrap();
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&60u32.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(rdata);
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> Vec<u8> {
+        let mut rdata = vec![];
+        rdata.extend_from_slice(&priority.to_be_bytes());
+        rdata.extend_from_slice(&weight.to_be_bytes());
+        rdata.extend_from_slice(&port.to_be_bytes());
+        encode_name(target, &mut rdata).unwrap();
+        rdata
+    }
+
+    /// a stub nameserver answering from the zone, unknown names get NXDOMAIN and the ones under
+    /// `servfail.` SERVFAIL
+    async fn stub_resolver(zone: Zone) -> DnsResolver {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let zone = Arc::new(zone);
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_UDP_MESSAGE];
+            while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+                let query = &buf[..n];
+                let (name, end) = read_name(query, 12).unwrap();
+                let qtype = read_u16(query, end).unwrap();
+                let question = &query[12..end + 4];
+
+                let mut resp = query[..2].to_vec();
+                let (answers, additionals) = match zone.get(&(name.clone(), qtype)) {
+                    _ if name.ends_with(".servfail") => {
+                        resp.extend_from_slice(&0x8182u16.to_be_bytes());
+                        (vec![], vec![])
+                    }
+                    Some((an, ar)) => {
+                        resp.extend_from_slice(&0x8180u16.to_be_bytes());
+                        (an.clone(), ar.clone())
+                    }
+                    None => {
+                        resp.extend_from_slice(&0x8183u16.to_be_bytes());
+                        (vec![], vec![])
+                    }
+                };
+                resp.extend_from_slice(&1u16.to_be_bytes());
+                resp.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+                resp.extend_from_slice(&0u16.to_be_bytes());
+                resp.extend_from_slice(&(additionals.len() as u16).to_be_bytes());
+                resp.extend_from_slice(question);
+                for (name, rtype, rdata) in answers.iter().chain(additionals.iter()) {
+                    encode_record(name, *rtype, rdata, &mut resp);
+                }
+                socket.send_to(&resp, peer).await.unwrap();
+            }
+        });
+        let mut resolver = DnsResolver::new(addr);
+        resolver.timeout = Duration::from_millis(200);
+        resolver
+    }
+
+    fn zone() -> Zone {
+        let mut zone = Zone::new();
+        zone.insert(
+            ("web.example.com".into(), 1),
+            (
+                vec![
+                    ("web.example.com".into(), 1, vec![10, 0, 0, 1]),
+                    ("web.example.com".into(), 1, vec![10, 0, 0, 2]),
+                ],
+                vec![],
+            ),
+        );
+        zone.insert(
+            ("web.example.com".into(), 28),
+            (vec![("web.example.com".into(), 28, Ipv6Addr::LOCALHOST.octets().to_vec())], vec![]),
+        );
+        zone.insert(
+            ("_http._tcp.example.com".into(), 33),
+            (
+                vec![
+                    ("_http._tcp.example.com".into(), 33, srv(10, 5, 8080, "a.example.com")),
+                    ("_http._tcp.example.com".into(), 33, srv(10, 0, 8081, "web.example.com")),
+                    ("_http._tcp.example.com".into(), 33, srv(20, 1, 9090, "backup.example.com")),
+                ],
+                vec![("a.example.com".into(), 1, vec![10, 0, 1, 1])],
+            ),
+        );
+        zone
+    }
+
+    #[test]
+    fn test_name_compression() {
+        // "a.b" followed by "c" + pointer to "a.b"
+        let buf = b"\x01a\x01b\x00\x01c\xc0\x00";
+        assert_eq!(read_name(buf, 0).unwrap(), ("a.b".to_string(), 5));
+        assert_eq!(read_name(buf, 5).unwrap(), ("c.a.b".to_string(), 9));
+        // pointer loop
+        assert!(read_name(b"\xc0\x00", 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let resolver = stub_resolver(zone()).await;
+        let lookup = resolver.lookup("web.example.com", RecordType::A).await.unwrap();
+        assert_eq!(lookup.answers.len(), 2);
+        assert_eq!(lookup.answers[0].data, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+
+        let lookup = resolver.lookup("unknown.example.com", RecordType::A).await.unwrap();
+        assert!(lookup.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dns_host_discovery() {
+        let resolver = stub_resolver(zone()).await;
+        let dns = Dns::new(
+            resolver,
+            vec![DnsQuery::Host {
+                name: "web.example.com".into(),
+                port: 80,
+            }],
+        );
+        let backends = dns.discover().await.unwrap();
+        assert_eq!(backends.len(), 3);
+        assert!(backends.contains(&Backend::new("[::1]:80").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_dns_srv_discovery() {
+        let resolver = stub_resolver(zone()).await;
+        let dns = Dns::new(resolver, vec![DnsQuery::Srv("_http._tcp.example.com".into())]);
+        let backends = dns.discover().await.unwrap();
+        let expected = BTreeSet::from([
+            Backend {
+                addr: "10.0.1.1:8080".parse().unwrap(),
+                weight: 5,
+            },
+            Backend::new("10.0.0.1:8081").unwrap(),
+            Backend::new("10.0.0.2:8081").unwrap(),
+            Backend::new("[::1]:8081").unwrap(),
+        ]);
+        assert_eq!(backends, expected);
+    }
+
+    #[tokio::test]
+    async fn test_dns_nothing_resolved() {
+        let resolver = stub_resolver(zone()).await;
+        let dns = Dns::new(
+            resolver,
+            vec![DnsQuery::Host {
+                name: "unknown.example.com".into(),
+                port: 80,
+            }],
+        );
+        assert!(dns.discover().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dns_query_failed() {
+        let resolver = stub_resolver(zone()).await;
+        let host = |name: &str| DnsQuery::Host {
+            name: name.into(),
+            port: 80,
+        };
+        let dns = Dns::new(resolver.clone(), vec![host("web.example.com"), host("web.servfail")]);
+        // not the backends of `web.example.com` alone
+        let e = dns.discover().await.unwrap_err();
+        assert_eq!(e.etype(), &DnsError);
+        let dns = Dns::new(
+            resolver,
+            vec![host("web.example.com"), DnsQuery::Srv("_http._tcp.servfail".into())],
+        );
+        assert!(dns.discover().await.is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! discovery of the backends behind a load balancer
+
+mod dns;
+pub use dns::{Dns, DnsQuery, DnsResolver, RData, RecordType};
+
+use super::Backend;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use gateway_error::{ErrorType::*, OrErr, Result};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// the trait to discover the current set of backends
+#[async_trait]
+pub trait ServiceDiscovery {
+    /// return the full set of backends, an error keeps the previously discovered backends in use
+    async fn discover(&self) -> Result<BTreeSet<Backend>>;
+}
+
+#[async_trait]
+impl<T: ServiceDiscovery + Send + Sync> ServiceDiscovery for Arc<T> {
+    async fn discover(&self) -> Result<BTreeSet<Backend>> {
+        self.as_ref().discover().await
+    }
+}
+
+/// a fixed list of backends which can be changed programmatically
+#[derive(Default)]
+pub struct Static {
+    backends: ArcSwap<BTreeSet<Backend>>,
+}
+
+impl Static {
+    pub fn new(backends: BTreeSet<Backend>) -> Box<Self> {
+        Box::new(Static {
+            backends: ArcSwap::new(Arc::new(backends)),
+        })
+    }
+
+    /// build from a list of `ip:port` or resolvable `host:port`
+    pub fn try_from_iter<A, T: IntoIterator<Item = A>>(iter: T) -> std::io::Result<Box<Self>>
+    where
+        A: ToSocketAddrs,
+    {
+        let mut backends = BTreeSet::new();
+        for a in iter {
+            for addr in a.to_socket_addrs()? {
+                backends.insert(Backend::from(addr));
+            }
+        }
+        Ok(Self::new(backends))
+    }
+
+    /// replace the whole set of backends
+    pub fn set(&self, backends: BTreeSet<Backend>) {
+        self.backends.store(Arc::new(backends))
+    }
+
+    pub fn add(&self, backend: Backend) {
+        let mut new = (**self.backends.load()).clone();
+        new.insert(backend);
+        self.set(new)
+    }
+
+    pub fn remove(&self, backend: &Backend) {
+        let mut new = (**self.backends.load()).clone();
+        new.remove(backend);
+        self.set(new)
+    }
+
+    pub fn get(&self) -> BTreeSet<Backend> {
+        (**self.backends.load()).clone()
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for Static {
+    async fn discover(&self) -> Result<BTreeSet<Backend>> {
+        Ok(self.get())
+    }
+}
+
+/// the format of the file read by [File]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Json,
+    Yaml,
+}
+
+impl FileFormat {
+    /// guess the format by the file extension, YAML is assumed for unknown extensions
+    pub fn from_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => FileFormat::Json,
+            _ => FileFormat::Yaml,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackendEntry {
+    Addr(String),
+    Weighted { addr: String, weight: Option<usize> },
+}
+
+/// backends listed in a JSON or YAML file
+///
+/// the file is a list of `ip:port` strings or `{addr, weight}` objects, e.g.
+/// ```yaml
+/// - 10.0.0.1:80
+/// - addr: 10.0.0.2:80
+///   weight: 3
+/// ```
+/// the file is re-read whenever its modification time changes
+pub struct File {
+    path: PathBuf,
+    format: FileFormat,
+    // the modification time and the content of the last read
+    cache: Mutex<Option<(SystemTime, BTreeSet<Backend>)>>,
+}
+
+impl File {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Box<Self> {
+        let path = path.into();
+        let format = FileFormat::from_path(&path);
+        Self::with_format(path, format)
+    }
+
+    pub fn with_format<P: Into<PathBuf>>(path: P, format: FileFormat) -> Box<Self> {
+        Box::new(File {
+            path: path.into(),
+            format,
+            cache: Mutex::new(None),
+        })
+    }
+
+    fn parse(&self, content: &[u8]) -> Result<BTreeSet<Backend>> {
+        let entries: Vec<BackendEntry> = match self.format {
+            FileFormat::Json => serde_json::from_slice(content)
+                .or_err_with(FileReadError, || format!("invalid backend file {:?}", self.path))?,
+            FileFormat::Yaml => serde_yaml::from_slice(content)
+                .or_err_with(FileReadError, || format!("invalid backend file {:?}", self.path))?,
+        };
+        let mut backends = BTreeSet::new();
+        for entry in entries {
+            let backend = match entry {
+                BackendEntry::Addr(addr) => Backend::new(&addr)?,
+                BackendEntry::Weighted { addr, weight } => {
+                    let mut b = Backend::new(&addr)?;
+                    b.weight = weight.unwrap_or(1);
+                    b
+                }
+            };
+            backends.insert(backend);
+        }
+        Ok(backends)
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for File {
+    async fn discover(&self) -> Result<BTreeSet<Backend>> {
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .or_err_with(FileReadError, || format!("failed to stat {:?}", self.path))?;
+        if let Some((time, backends)) = self.cache.lock().unwrap().as_ref() {
+            if *time == modified {
+                return Ok(backends.clone());
+            }
+        }
+
+        let content = tokio::fs::read(&self.path)
+            .await
+            .or_err_with(FileReadError, || format!("failed to read {:?}", self.path))?;
+        let backends = self.parse(&content)?;
+        *self.cache.lock().unwrap() = Some((modified, backends.clone()));
+        Ok(backends)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_static() {
+        let discovery = Static::try_from_iter(["127.0.0.1:80", "127.0.0.2:80"]).unwrap();
+        assert_eq!(discovery.discover().await.unwrap().len(), 2);
+        discovery.remove(&Backend::new("127.0.0.1:80").unwrap());
+        discovery.add(Backend::new("127.0.0.3:80").unwrap());
+        let backends = discovery.discover().await.unwrap();
+        assert!(!backends.contains(&Backend::new("127.0.0.1:80").unwrap()));
+        assert!(backends.contains(&Backend::new("127.0.0.3:80").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_file() {
+        let mut file = tempfile::Builder::new().suffix(".yaml").tempfile().unwrap();
+        file.write_all(b"- 10.0.0.1:80\n- addr: 10.0.0.2:80\n  weight: 3\n").unwrap();
+        let discovery = File::new(file.path());
+        let backends = discovery.discover().await.unwrap();
+        assert_eq!(backends.len(), 2);
+        assert!(backends.iter().any(|b| b.weight == 3));
+
+        // rewrite the file with a different modification time
+        let mut file2 = std::fs::File::create(file.path()).unwrap();
+        file2.write_all(b"[\"10.0.0.3:80\"]").unwrap();
+        file2.set_modified(SystemTime::now() + std::time::Duration::from_secs(1)).unwrap();
+        let backends = discovery.discover().await.unwrap();
+        assert_eq!(backends, BTreeSet::from([Backend::new("10.0.0.3:80").unwrap()]));
+
+        std::fs::write(file.path(), b"- not an address").unwrap();
+        file2.set_modified(SystemTime::now() + std::time::Duration::from_secs(2)).unwrap();
+        assert!(discovery.discover().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_json_file() {
+        let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        file.write_all(br#"[{"addr": "[::1]:8080"}, "10.0.0.1:80"]"#).unwrap();
+        let discovery = File::new(file.path());
+        assert_eq!(discovery.discover().await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,257 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! active health checks of the backends
+
+use super::Backend;
+use crate::connectors::l4;
+use async_trait::async_trait;
+use bytes::Bytes;
+use gateway_error::{Error, ErrorType::*, Result};
+use gateway_httpd::v1::client::HttpSession;
+use gateway_httpd::RequestHeader;
+use std::time::Duration;
+
+/// the trait of the active health check
+#[async_trait]
+pub trait HealthCheck {
+    /// check the given backend, `Ok(())` means the backend is healthy
+    async fn check(&self, target: &Backend) -> Result<()>;
+
+    /// the number of consecutive results needed to flip the health of a backend
+    ///
+    /// `success`: the threshold to flip an unhealthy backend to healthy if true, otherwise the
+    /// threshold to flip a healthy backend to unhealthy
+    fn health_threshold(&self, success: bool) -> usize;
+}
+
+/// check the backend by establishing a TCP connection to it
+pub struct TcpHealthCheck {
+    /// number of consecutive successful checks to flip an unhealthy backend to healthy
+    pub consecutive_success: usize,
+    /// number of consecutive failed checks to flip a healthy backend to unhealthy
+    pub consecutive_failure: usize,
+    pub connect_timeout: Duration,
+}
+
+impl Default for TcpHealthCheck {
+    fn default() -> Self {
+        TcpHealthCheck {
+            consecutive_success: 1,
+            consecutive_failure: 1,
+            connect_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl TcpHealthCheck {
+    pub fn new() -> Box<Self> {
+        Box::default()
+    }
+}
+
+#[async_trait]
+impl HealthCheck for TcpHealthCheck {
+    async fn check(&self, target: &Backend) -> Result<()> {
+        l4::connect(target.addr, Some(self.connect_timeout)).await.map(|_| ())
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.consecutive_success
+        } else {
+            self.consecutive_failure
+        }
+    }
+}
+
+/// check the backend by sending a http/1 request and validating its response
+pub struct HttpHealthCheck {
+    /// number of consecutive successful checks to flip an unhealthy backend to healthy
+    pub consecutive_success: usize,
+    /// number of consecutive failed checks to flip a healthy backend to unhealthy
+    pub consecutive_failure: usize,
+    pub connect_timeout: Duration,
+    /// timeout of each read of the response
+    pub read_timeout: Duration,
+    /// the request to send, `GET /` with the `Host` header by default
+    pub req: RequestHeader,
+    /// the accepted response status codes, any 2xx is accepted if empty
+    pub expected_status: Vec<u16>,
+    /// if set, the response body must contain these bytes
+    pub expected_body: Option<Bytes>,
+    /// the max response body to read when `expected_body` is set
+    pub max_body_size: usize,
+    /// send the check to this port instead of the port of the backend
+    pub port_override: Option<u16>,
+}
+
+impl HttpHealthCheck {
+    /// create a check which sends `GET /` with the given `Host` header, an error if `host` is not a
+    /// valid header value
+    pub fn new(host: &str) -> Result<Self> {
+        let mut req = RequestHeader::build("GET", b"/", None)?;
+        req.append_header("Host", host)
+            .map_err(|e| Error::because(ConfigError, format!("invalid host `{host}`"), e))?;
+        Ok(HttpHealthCheck {
+            consecutive_success: 1,
+            consecutive_failure: 1,
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            req,
+            expected_status: vec![],
+            expected_body: None,
+            max_body_size: 1024 * 64,
+            port_override: None,
+        })
+    }
+
+    fn validate_status(&self, status: u16) -> Result<()> {
+        let ok = if self.expected_status.is_empty() {
+            (200..300).contains(&status)
+        } else {
+            self.expected_status.contains(&status)
+        };
+        if ok {
+            Ok(())
+        } else {
+            Error::e_explain(HTTPStatus(status), "unexpected status of health check")
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for HttpHealthCheck {
+    async fn check(&self, target: &Backend) -> Result<()> {
+        let mut addr = target.addr;
+        if let Some(port) = self.port_override {
+            addr.set_port(port);
+        }
+        let stream = l4::connect(addr, Some(self.connect_timeout)).await?;
+        let mut session = HttpSession::new(stream);
+        session.read_timeout = Some(self.read_timeout);
+        session.write_timeout = Some(self.read_timeout);
+
+        session.write_request_header(&self.req).await?;
+        session.finish_body().await?;
+        let status = session.read_response_header().await?.status.as_u16();
+        self.validate_status(status)?;
+
+        if let Some(expected) = self.expected_body.as_ref() {
+            let body = session.read_body_to_end(self.max_body_size).await?;
+            if !contains(&body, expected) {
+                return Error::e_explain(
+                    Custom("UnexpectedBody"),
+                    "health check response does not contain the expected body",
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.consecutive_success
+        } else {
+            self.consecutive_failure
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn mock_server(response: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response).await;
+            }
+        });
+        addr
+    }
+
+    async fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tcp_check() {
+        let check = TcpHealthCheck::new();
+        let addr = mock_server(b"").await;
+        assert!(check.check(&Backend::from(addr)).await.is_ok());
+
+        let e = check.check(&Backend::from(closed_port().await)).await.unwrap_err();
+        assert_eq!(e.etype(), &ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn test_http_check() {
+        let addr = mock_server(b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nhealthy").await;
+        let backend = Backend::from(addr);
+
+        let mut check = HttpHealthCheck::new("example.com").unwrap();
+        assert!(check.check(&backend).await.is_ok());
+
+        check.expected_body = Some(Bytes::from_static(b"healthy"));
+        assert!(check.check(&backend).await.is_ok());
+
+        check.expected_body = Some(Bytes::from_static(b"ready"));
+        let e = check.check(&backend).await.unwrap_err();
+        assert_eq!(e.etype(), &Custom("UnexpectedBody"));
+
+        check.expected_body = None;
+        check.expected_status = vec![204];
+        let e = check.check(&backend).await.unwrap_err();
+        assert_eq!(e.etype(), &HTTPStatus(200));
+    }
+
+    #[tokio::test]
+    async fn test_http_check_port_override() {
+        let addr = mock_server(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+        let mut check = HttpHealthCheck::new("example.com").unwrap();
+        let e = check.check(&Backend::from(addr)).await.unwrap_err();
+        assert_eq!(e.etype(), &HTTPStatus(503));
+
+        let healthy = mock_server(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        check.port_override = Some(healthy.port());
+        assert!(check.check(&Backend::from(addr)).await.is_ok());
+    }
+
+    #[test]
+    fn test_http_check_invalid_host() {
+        let e = HttpHealthCheck::new("example.com\r\nX-Injected: 1").err().unwrap();
+        assert_eq!(e.etype(), &ConfigError);
+    }
+}
@@ -0,0 +1,522 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! load balancing across a set of upstream backends
+
+pub mod circuit_breaker;
+pub mod discovery;
+pub mod health_check;
+pub mod outlier;
+pub mod selection;
+
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+use arc_swap::ArcSwap;
+use discovery::ServiceDiscovery;
+use futures::FutureExt;
+use gateway_error::{ErrorType::*, OrErr, Result};
+use health_check::HealthCheck;
+use log::{info, warn};
+use outlier::{OutlierConfig, OutlierDetector, Outcome};
+use selection::{BackendIter, BackendSelection};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// an upstream server that requests can be load balanced to
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Backend {
+    pub addr: SocketAddr,
+    /// the relative weight of the backend when it is selected by weight
+    pub weight: usize,
+}
+
+impl Backend {
+    /// create a backend from `ip:port` with weight 1
+    pub fn new(addr: &str) -> Result<Self> {
+        let addr = addr
+            .parse()
+            .or_err_with(InternalError, || format!("invalid backend address {addr}"))?;
+        Ok(Backend { addr, weight: 1 })
+    }
+
+    pub(crate) fn hash_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl From<SocketAddr> for Backend {
+    fn from(addr: SocketAddr) -> Self {
+        Backend { addr, weight: 1 }
+    }
+}
+
+#[derive(Clone)]
+struct HealthInner {
+    /// the result of the health checks
+    healthy: bool,
+    /// whether the backend can be selected, regardless of its health
+    enabled: bool,
+    /// the number of consecutive check results against the current `healthy`
+    consecutive_counter: usize,
+}
+
+/// the health of a backend observed by the health checks
+pub(crate) struct Health(ArcSwap<HealthInner>);
+
+impl Default for Health {
+    fn default() -> Self {
+        Health(ArcSwap::new(Arc::new(HealthInner {
+            healthy: true,
+            enabled: true,
+            consecutive_counter: 0,
+        })))
+    }
+}
+
+impl Clone for Health {
+    fn clone(&self) -> Self {
+        Health(ArcSwap::new(self.0.load_full()))
+    }
+}
+
+impl Health {
+    pub fn ready(&self) -> bool {
+        let h = self.0.load();
+        h.healthy && h.enabled
+    }
+
+    pub fn enable(&self, enabled: bool) {
+        let h = self.0.load();
+        if h.enabled != enabled {
+            let mut new_health = (**h).clone();
+            new_health.enabled = enabled;
+            self.0.store(Arc::new(new_health));
+        }
+    }
+
+    /// record a check result, return true when the health is flipped
+    pub fn observe_health(&self, healthy: bool, flip_threshold: usize) -> bool {
+        let h = self.0.load();
+        let mut flipped = false;
+        if h.healthy != healthy {
+            let mut new_health = (**h).clone();
+            new_health.consecutive_counter += 1;
+            if new_health.consecutive_counter >= flip_threshold {
+                new_health.healthy = healthy;
+                new_health.consecutive_counter = 0;
+                flipped = true;
+            }
+            self.0.store(Arc::new(new_health));
+        } else if h.consecutive_counter > 0 {
+            // the streak against the current health is broken
+            let mut new_health = (**h).clone();
+            new_health.consecutive_counter = 0;
+            self.0.store(Arc::new(new_health));
+        }
+        flipped
+    }
+}
+
+/// a set of backends together with their health
+///
+/// the set is empty until [Self::update()] fetches it from the service discovery
+pub struct Backends {
+    discovery: Box<dyn ServiceDiscovery + Send + Sync + 'static>,
+    health_check: Option<Arc<dyn HealthCheck + Send + Sync + 'static>>,
+    backends: ArcSwap<BTreeSet<Backend>>,
+    health: ArcSwap<HashMap<u64, Health>>,
+}
+
+impl Backends {
+    pub fn new(discovery: Box<dyn ServiceDiscovery + Send + Sync + 'static>) -> Self {
+        Backends {
+            discovery,
+            health_check: None,
+            backends: Default::default(),
+            health: Default::default(),
+        }
+    }
+
+    /// fetch the backends from the service discovery and swap them in
+    ///
+    /// the health of the backends that are still there is kept. return whether the set changed
+    pub async fn update(&self) -> Result<bool> {
+        let new_backends = self.discovery.discover().await?;
+        if **self.backends.load() == new_backends {
+            return Ok(false);
+        }
+
+        let old_health = self.health.load();
+        let health = new_backends
+            .iter()
+            .map(|b| {
+                let key = b.hash_key();
+                (key, old_health.get(&key).cloned().unwrap_or_default())
+            })
+            .collect();
+        // backends missing in the health table are considered ready, so store the health first
+        self.health.store(Arc::new(health));
+        self.backends.store(Arc::new(new_backends));
+        Ok(true)
+    }
+
+    /// set the health check used by [Self::run_health_check()]
+    pub fn set_health_check(&mut self, hc: Box<dyn HealthCheck + Send + Sync + 'static>) {
+        self.health_check = Some(hc.into());
+    }
+
+    /// the current set of backends
+    pub fn get_backend(&self) -> Arc<BTreeSet<Backend>> {
+        self.backends.load_full()
+    }
+
+    /// whether the backend is healthy and enabled
+    ///
+    /// backends unknown to this set are considered ready
+    pub fn ready(&self, backend: &Backend) -> bool {
+        self.health
+            .load()
+            .get(&backend.hash_key())
+            .is_none_or(|h| h.ready())
+    }
+
+    /// manually enable or disable a backend, a disabled backend is never selected
+    pub fn set_enable(&self, backend: &Backend, enabled: bool) {
+        if let Some(h) = self.health.load().get(&backend.hash_key()) {
+            h.enable(enabled)
+        }
+    }
+
+    /// check all the backends once, `parallel` runs the checks concurrently
+    pub async fn run_health_check(&self, parallel: bool) {
+        let Some(health_check) = self.health_check.as_ref() else {
+            return;
+        };
+
+        async fn check_and_report(
+            backend: &Backend,
+            check: &Arc<dyn HealthCheck + Send + Sync>,
+            health_table: &HashMap<u64, Health>,
+        ) {
+            let errored = check.check(backend).await.err();
+            if let Some(h) = health_table.get(&backend.hash_key()) {
+                let flipped = h.observe_health(errored.is_none(), check.health_threshold(errored.is_none()));
+                if flipped {
+                    if let Some(e) = errored {
+                        warn!("{backend:?} becomes unhealthy, {e}");
+                    } else {
+                        info!("{backend:?} becomes healthy");
+                    }
+                }
+            }
+        }
+
+        let backends = self.backends.load();
+        let health_table = self.health.load();
+        if parallel {
+            let jobs = backends
+                .iter()
+                .map(|backend| check_and_report(backend, health_check, &health_table));
+            futures::future::join_all(jobs).await;
+        } else {
+            for backend in backends.iter() {
+                check_and_report(backend, health_check, &health_table).await;
+            }
+        }
+    }
+}
+
+/// select backends with the selection algorithm `S`, skipping the unhealthy ones
+pub struct LoadBalancer<S> {
+    backends: Backends,
+    selector: ArcSwap<S>,
+    outlier: Option<OutlierDetector>,
+    /// how often the health check runs, no health check if `None`
+    pub health_check_frequency: Option<Duration>,
+    /// how often the service discovery runs, the backends never change if `None`
+    pub update_frequency: Option<Duration>,
+    /// whether to check all the backends concurrently
+    pub parallel_health_check: bool,
+}
+
+impl<S: BackendSelection> LoadBalancer<S>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    /// build a load balancer from a list of `ip:port` or resolvable `host:port`
+    pub fn try_from_iter<A, T: IntoIterator<Item = A>>(iter: T) -> std::io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let discovery = discovery::Static::try_from_iter(iter)?;
+        let lb = Self::from_backends(Backends::new(discovery));
+        // static discovery never blocks nor fails
+        lb.update()
+            .now_or_never()
+            .expect("static should not block")
+            .expect("static should not error");
+        Ok(lb)
+    }
+
+    /// build a load balancer, the backends are empty until [Self::update()] is called
+    pub fn from_backends(backends: Backends) -> Self {
+        let selector = ArcSwap::new(Arc::new(S::build(&backends.get_backend())));
+        LoadBalancer {
+            backends,
+            selector,
+            outlier: None,
+            health_check_frequency: None,
+            update_frequency: None,
+            parallel_health_check: false,
+        }
+    }
+
+    /// fetch the backends from the service discovery, the selection is rebuilt if they changed
+    pub async fn update(&self) -> Result<()> {
+        if self.backends.update().await? {
+            let backends = self.backends.get_backend();
+            self.selector.store(Arc::new(S::build(&backends)));
+            if let Some(outlier) = self.outlier.as_ref() {
+                let keys: std::collections::HashSet<u64> = backends.iter().map(|b| b.hash_key()).collect();
+                outlier.retain(|k| keys.contains(&k));
+            }
+        }
+        Ok(())
+    }
+
+    /// select a ready backend for the given key
+    ///
+    /// a backend is ready when it is healthy, enabled and not ejected by the outlier detection.
+    /// at most `max_iterations` candidates are tried before giving up
+    pub fn select(&self, key: &[u8], max_iterations: usize) -> Option<Backend> {
+        self.select_with(key, max_iterations, |_, ready| ready)
+    }
+
+    /// similar to [Self::select()], but `accept` decides whether a candidate is selected, given
+    /// the backend and whether it is ready
+    pub fn select_with<F>(&self, key: &[u8], max_iterations: usize, accept: F) -> Option<Backend>
+    where
+        F: Fn(&Backend, bool) -> bool,
+    {
+        let selection = self.selector.load();
+        let mut iter = selection.iter(key);
+        let mut tried = 0;
+        while tried < max_iterations {
+            tried += 1;
+            let b = iter.next()?;
+            let ready = self.backends.ready(b) && self.outlier.as_ref().is_none_or(|o| o.admit(b));
+            if accept(b, ready) {
+                return Some(b.clone());
+            }
+        }
+        None
+    }
+
+    pub fn set_health_check(&mut self, hc: Box<dyn HealthCheck + Send + Sync + 'static>) {
+        self.backends.set_health_check(hc);
+    }
+
+    /// eject backends according to the outcome of the requests reported by [Self::report()]
+    pub fn set_outlier_detection(&mut self, config: OutlierConfig) {
+        self.outlier = Some(OutlierDetector::new(config));
+    }
+
+    pub fn outlier_detection(&self) -> Option<&OutlierDetector> {
+        self.outlier.as_ref()
+    }
+
+    /// report the outcome of a proxied request to `backend`
+    ///
+    /// does nothing if the outlier detection is not set
+    pub fn report(&self, backend: &Backend, outcome: Outcome) {
+        if let Some(outlier) = self.outlier.as_ref() {
+            outlier.report(backend, outcome, self.backends.get_backend().len());
+        }
+    }
+
+    pub fn backends(&self) -> &Backends {
+        &self.backends
+    }
+
+    /// run the service discovery every [Self::update_frequency] and the health check every
+    /// [Self::health_check_frequency], never returns unless neither is set
+    pub async fn update_loop(&self) {
+        if self.update_frequency.is_none() && self.health_check_frequency.is_none() {
+            return;
+        }
+        let mut next_update = Instant::now();
+        let mut next_health_check = Instant::now();
+        loop {
+            if let Some(frequency) = self.update_frequency {
+                if Instant::now() >= next_update {
+                    // a panicking discovery should not stop the loop
+                    match std::panic::AssertUnwindSafe(self.update()).catch_unwind().await {
+                        Ok(Err(e)) => warn!("failed to update backends, keep the current ones: {e}"),
+                        Err(_) => warn!("service discovery panicked"),
+                        Ok(Ok(())) => {}
+                    }
+                    next_update = Instant::now() + frequency;
+                }
+            }
+            if let Some(frequency) = self.health_check_frequency {
+                if Instant::now() >= next_health_check {
+                    let check = self.backends.run_health_check(self.parallel_health_check);
+                    if std::panic::AssertUnwindSafe(check).catch_unwind().await.is_err() {
+                        warn!("health check panicked");
+                    }
+                    next_health_check = Instant::now() + frequency;
+                }
+            }
+
+            let next = match (self.update_frequency, self.health_check_frequency) {
+                (Some(_), Some(_)) => next_update.min(next_health_check),
+                (Some(_), None) => next_update,
+                _ => next_health_check,
+            };
+            tokio::time::sleep_until(next.into()).await;
+        }
+    }
+}
+
+/// run the service discovery and health checks of the load balancer in the background
+#[async_trait::async_trait]
+impl<S> BackgroundService for LoadBalancer<S>
+where
+    S: BackendSelection + Send + Sync + 'static,
+    S::Iter: BackendIter,
+{
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        tokio::select! {
+            _ = self.update_loop() => {}
+            _ = shutdown.changed() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::health_check::TcpHealthCheck;
+    use super::selection::RoundRobin;
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_health_flip() {
+        let h = Health::default();
+        assert!(h.ready());
+        assert!(!h.observe_health(false, 2));
+        assert!(h.ready());
+        // a success breaks the failure streak
+        assert!(!h.observe_health(true, 2));
+        assert!(!h.observe_health(false, 2));
+        assert!(h.observe_health(false, 2));
+        assert!(!h.ready());
+        assert!(h.observe_health(true, 1));
+        assert!(h.ready());
+
+        h.enable(false);
+        assert!(!h.ready());
+    }
+
+    #[test]
+    fn test_select_skip_disabled() {
+        let lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::try_from_iter(["127.0.0.1:80", "127.0.0.2:80"]).unwrap();
+        let b1 = Backend::new("127.0.0.1:80").unwrap();
+        lb.backends().set_enable(&b1, false);
+        for _ in 0..10 {
+            assert_ne!(lb.select(b"", 2).unwrap(), b1);
+        }
+        assert!(lb
+            .select_with(b"", 2, |b, ready| ready && b.addr.port() == 81)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_select() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = listener.local_addr().unwrap();
+        let bad = {
+            let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            l.local_addr().unwrap()
+        };
+
+        let mut lb: LoadBalancer<RoundRobin> = LoadBalancer::try_from_iter([good, bad]).unwrap();
+        lb.set_health_check(TcpHealthCheck::new());
+        lb.backends().run_health_check(true).await;
+
+        assert!(lb.backends().ready(&Backend::from(good)));
+        assert!(!lb.backends().ready(&Backend::from(bad)));
+        for _ in 0..10 {
+            assert_eq!(lb.select(b"", 2).unwrap().addr, good);
+        }
+        drop(listener);
+    }
+
+    #[test]
+    fn test_outlier_select() {
+        let mut lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::try_from_iter(["127.0.0.1:80", "127.0.0.2:80"]).unwrap();
+        lb.set_outlier_detection(OutlierConfig {
+            consecutive_5xx: 2,
+            ..Default::default()
+        });
+        let b1 = Backend::new("127.0.0.1:80").unwrap();
+        lb.report(&b1, Outcome::from_status(502));
+        lb.report(&b1, Outcome::from_status(503));
+        for _ in 0..10 {
+            assert_ne!(lb.select(b"", 2).unwrap(), b1);
+        }
+
+        lb.outlier_detection().unwrap().readmit(&b1);
+        assert!((0..10).any(|_| lb.select(b"", 2).unwrap() == b1));
+    }
+
+    #[tokio::test]
+    async fn test_update_backends() {
+        let discovery: Arc<discovery::Static> =
+            discovery::Static::try_from_iter(["127.0.0.1:80", "127.0.0.2:80"]).unwrap().into();
+        let b1 = Backend::new("127.0.0.1:80").unwrap();
+        let b3 = Backend::new("127.0.0.3:80").unwrap();
+
+        let lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::from_backends(Backends::new(Box::new(discovery.clone())));
+        assert!(lb.select(b"", 3).is_none());
+        lb.update().await.unwrap();
+        assert_eq!(lb.backends().get_backend().len(), 2);
+
+        // the health of the remaining backends is kept
+        lb.backends().set_enable(&b1, false);
+        discovery.add(b3.clone());
+        lb.update().await.unwrap();
+        assert_eq!(lb.backends().get_backend().len(), 3);
+        assert!(!lb.backends().ready(&b1));
+        assert!((0..10).any(|_| lb.select(b"", 3).unwrap() == b3));
+    }
+}
@@ -0,0 +1,459 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! passive health checking: eject backends based on the outcome of proxied requests
+
+use super::Backend;
+use gateway_error::{Error, ErrorType};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// the outcome of a proxied request to a backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// the backend responded with a non-5xx status
+    Success,
+    ConnectionRefused,
+    ConnectionTimeout,
+    /// the backend responded with a 5xx status
+    ServerError(u16),
+}
+
+impl Outcome {
+    /// the outcome of a response with the given status
+    pub fn from_status(status: u16) -> Self {
+        if (500..600).contains(&status) {
+            Outcome::ServerError(status)
+        } else {
+            Outcome::Success
+        }
+    }
+
+    /// the outcome of a failed request, `None` if the error says nothing about the backend
+    pub fn from_error(e: &Error) -> Option<Self> {
+        match e.root_etype() {
+            ErrorType::ConnectionRefused => Some(Outcome::ConnectionRefused),
+            ErrorType::ConnectionTimeout => Some(Outcome::ConnectionTimeout),
+            ErrorType::HTTPStatus(s) if *s >= 500 => Some(Outcome::ServerError(*s)),
+            _ => None,
+        }
+    }
+
+    fn is_connect_failure(&self) -> bool {
+        matches!(self, Outcome::ConnectionRefused | Outcome::ConnectionTimeout)
+    }
+
+    fn is_failure(&self) -> bool {
+        !matches!(self, Outcome::Success)
+    }
+}
+
+/// the thresholds of the outlier detection
+#[derive(Debug, Clone)]
+pub struct OutlierConfig {
+    /// eject after this many consecutive connection refused/timeouts, 0 to disable
+    pub consecutive_connect_failures: usize,
+    /// eject after this many consecutive 5xx responses, 0 to disable
+    pub consecutive_5xx: usize,
+    /// eject when the failure rate over `interval` exceeds this ratio, 0.0 to disable
+    pub failure_rate: f64,
+    /// the failure rate is only evaluated with at least this many requests in the interval
+    pub failure_rate_min_requests: usize,
+    /// the window over which the failure rate is counted
+    pub interval: Duration,
+    /// the ejection time, multiplied by the number of times the backend was ejected in a row
+    pub base_ejection_time: Duration,
+    /// the upper bound of the ejection time
+    pub max_ejection_time: Duration,
+    /// at most this percentage of the backends can be ejected at the same time, rounded down but
+    /// at least one backend unless it is 0
+    pub max_ejection_percent: usize,
+    /// after the ejection, the backend receives linearly increasing traffic over this period
+    pub ramp_up_time: Duration,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        OutlierConfig {
+            consecutive_connect_failures: 5,
+            consecutive_5xx: 5,
+            failure_rate: 0.0,
+            failure_rate_min_requests: 20,
+            interval: Duration::from_secs(10),
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_percent: 50,
+            ramp_up_time: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BackendStats {
+    consecutive_connect_failures: usize,
+    consecutive_5xx: usize,
+    window_start: Option<Instant>,
+    window_requests: usize,
+    window_failures: usize,
+    /// the backend is ejected until this time
+    ejected_until: Option<Instant>,
+    /// the number of ejections in a row, drives the backoff
+    ejection_count: u32,
+}
+
+impl BackendStats {
+    fn reset_counters(&mut self) {
+        self.consecutive_connect_failures = 0;
+        self.consecutive_5xx = 0;
+        self.window_start = None;
+        self.window_requests = 0;
+        self.window_failures = 0;
+    }
+
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|t| now < t)
+    }
+}
+
+/// the admission of a backend decided by the outlier detection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    /// the backend takes full traffic
+    Admitted,
+    /// the backend is ejected
+    Ejected,
+    /// the backend is ramping up after an ejection, it should take this share of the traffic
+    RampUp(f64),
+}
+
+/// track the outcome of proxied requests per backend and eject the outliers
+pub struct OutlierDetector {
+    config: OutlierConfig,
+    stats: Mutex<HashMap<u64, BackendStats>>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: OutlierConfig) -> Self {
+        OutlierDetector {
+            config,
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &OutlierConfig {
+        &self.config
+    }
+
+    /// record the outcome of a request to `backend`
+    ///
+    /// `total_backends` is the size of the backend set, used to cap the ejected percentage
+    pub fn report(&self, backend: &Backend, outcome: Outcome, total_backends: usize) {
+        self.report_at(backend, outcome, total_backends, Instant::now())
+    }
+
+    fn report_at(&self, backend: &Backend, outcome: Outcome, total_backends: usize, now: Instant) {
+        let mut table = self.stats.lock().unwrap();
+        let ejected_now = table.values().filter(|s| s.is_ejected(now)).count();
+        let stats = table.entry(backend.hash_key()).or_default();
+        if stats.is_ejected(now) {
+            // in flight requests sent before the ejection
+            return;
+        }
+
+        if outcome.is_connect_failure() {
+            stats.consecutive_connect_failures += 1;
+        } else {
+            stats.consecutive_connect_failures = 0;
+        }
+        if matches!(outcome, Outcome::ServerError(_)) {
+            stats.consecutive_5xx += 1;
+        } else {
+            stats.consecutive_5xx = 0;
+        }
+
+        if stats
+            .window_start
+            .is_none_or(|start| now.duration_since(start) >= self.config.interval)
+        {
+            stats.window_start = Some(now);
+            stats.window_requests = 0;
+            stats.window_failures = 0;
+        }
+        stats.window_requests += 1;
+        if outcome.is_failure() {
+            stats.window_failures += 1;
+        }
+
+        // a backend that made it through the ramp up without ejection gets its backoff reset
+        if !outcome.is_failure() && stats.ejection_count > 0 {
+            let recovered = stats
+                .ejected_until
+                .is_some_and(|t| now.duration_since(t) >= self.config.ramp_up_time + self.config.interval);
+            if recovered {
+                stats.ejection_count = 0;
+            }
+        }
+
+        let reason = self.eject_reason(stats);
+        let Some(reason) = reason else {
+            return;
+        };
+
+        // or a small group could never eject anything
+        let max_ejected = match total_backends * self.config.max_ejection_percent / 100 {
+            0 => (self.config.max_ejection_percent > 0) as usize,
+            n => n,
+        };
+        if ejected_now + 1 > max_ejected {
+            warn!("{backend:?} is an outlier ({reason}) but {ejected_now} backends are ejected already");
+            stats.reset_counters();
+            return;
+        }
+
+        stats.ejection_count += 1;
+        let ejection_time = self
+            .config
+            .base_ejection_time
+            .saturating_mul(stats.ejection_count)
+            .min(self.config.max_ejection_time);
+        stats.ejected_until = Some(now + ejection_time);
+        stats.reset_counters();
+        warn!("{backend:?} is ejected for {ejection_time:?}, {reason}");
+    }
+
+    fn eject_reason(&self, stats: &BackendStats) -> Option<&'static str> {
+        let config = &self.config;
+        if config.consecutive_connect_failures > 0
+            && stats.consecutive_connect_failures >= config.consecutive_connect_failures
+        {
+            return Some("consecutive connect failures");
+        }
+        if config.consecutive_5xx > 0 && stats.consecutive_5xx >= config.consecutive_5xx {
+            return Some("consecutive 5xx");
+        }
+        if config.failure_rate > 0.0
+            && stats.window_requests >= config.failure_rate_min_requests
+            && stats.window_failures as f64 / stats.window_requests as f64 >= config.failure_rate
+        {
+            return Some("failure rate");
+        }
+        None
+    }
+
+    /// the admission of the backend at this moment
+    pub fn admission(&self, backend: &Backend) -> Admission {
+        self.admission_at(backend, Instant::now())
+    }
+
+    fn admission_at(&self, backend: &Backend, now: Instant) -> Admission {
+        let table = self.stats.lock().unwrap();
+        let Some(stats) = table.get(&backend.hash_key()) else {
+            return Admission::Admitted;
+        };
+        let Some(until) = stats.ejected_until else {
+            return Admission::Admitted;
+        };
+        if now < until {
+            return Admission::Ejected;
+        }
+        let since = now.duration_since(until);
+        if since >= self.config.ramp_up_time {
+            Admission::Admitted
+        } else {
+            // never starve the backend completely, otherwise it never gets traffic to recover
+            let share = since.as_secs_f64() / self.config.ramp_up_time.as_secs_f64();
+            Admission::RampUp(share.max(0.05))
+        }
+    }
+
+    /// whether the backend should take this request
+    pub fn admit(&self, backend: &Backend) -> bool {
+        match self.admission(backend) {
+            Admission::Admitted => true,
+            Admission::Ejected => false,
+            Admission::RampUp(share) => rand::random::<f64>() < share,
+        }
+    }
+
+    /// forget the backends that are not in the current backend set
+    pub fn retain<F: Fn(u64) -> bool>(&self, keep: F) {
+        self.stats.lock().unwrap().retain(|k, _| keep(*k))
+    }
+
+    /// manually readmit an ejected backend
+    pub fn readmit(&self, backend: &Backend) {
+        if let Some(stats) = self.stats.lock().unwrap().get_mut(&backend.hash_key()) {
+            if stats.ejected_until.take().is_some() {
+                info!("{backend:?} is readmitted");
+            }
+            stats.ejection_count = 0;
+            stats.reset_counters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(i: u8) -> Backend {
+        Backend::new(&format!("127.0.0.{i}:80")).unwrap()
+    }
+
+    fn config() -> OutlierConfig {
+        OutlierConfig {
+            consecutive_connect_failures: 3,
+            consecutive_5xx: 3,
+            base_ejection_time: Duration::from_secs(10),
+            max_ejection_time: Duration::from_secs(25),
+            max_ejection_percent: 100,
+            ramp_up_time: Duration::from_secs(10),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_outcome() {
+        assert_eq!(Outcome::from_status(200), Outcome::Success);
+        assert_eq!(Outcome::from_status(404), Outcome::Success);
+        assert_eq!(Outcome::from_status(502), Outcome::ServerError(502));
+
+        let mut e = Error::new(ErrorType::ConnectError);
+        e.set_cause(Error::new_up(ErrorType::ConnectionRefused));
+        assert_eq!(Outcome::from_error(&e), Some(Outcome::ConnectionRefused));
+        assert_eq!(Outcome::from_error(&Error::new(ErrorType::InvalidHTTPHeader)), None);
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let detector = OutlierDetector::new(config());
+        let b = backend(1);
+        let now = Instant::now();
+        detector.report_at(&b, Outcome::ConnectionRefused, 2, now);
+        detector.report_at(&b, Outcome::ConnectionTimeout, 2, now);
+        // a success breaks the streak
+        detector.report_at(&b, Outcome::Success, 2, now);
+        detector.report_at(&b, Outcome::ConnectionRefused, 2, now);
+        detector.report_at(&b, Outcome::ConnectionRefused, 2, now);
+        assert_eq!(detector.admission_at(&b, now), Admission::Admitted);
+        detector.report_at(&b, Outcome::ConnectionRefused, 2, now);
+        assert_eq!(detector.admission_at(&b, now), Admission::Ejected);
+        assert_eq!(detector.admission_at(&b, now + Duration::from_secs(9)), Admission::Ejected);
+
+        // ramp up after the ejection
+        assert_eq!(
+            detector.admission_at(&b, now + Duration::from_secs(15)),
+            Admission::RampUp(0.5)
+        );
+        assert_eq!(detector.admission_at(&b, now + Duration::from_secs(20)), Admission::Admitted);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let detector = OutlierDetector::new(config());
+        let b = backend(1);
+        let mut now = Instant::now();
+        for expected in [10, 20, 25] {
+            for _ in 0..3 {
+                detector.report_at(&b, Outcome::ServerError(503), 1, now);
+            }
+            let until = now + Duration::from_secs(expected);
+            assert_eq!(detector.admission_at(&b, until - Duration::from_millis(1)), Admission::Ejected);
+            assert_ne!(detector.admission_at(&b, until), Admission::Ejected);
+            now = until;
+        }
+
+        // recovered backend gets the backoff reset
+        now += Duration::from_secs(30);
+        detector.report_at(&b, Outcome::Success, 1, now);
+        for _ in 0..3 {
+            detector.report_at(&b, Outcome::ServerError(503), 1, now);
+        }
+        assert_ne!(detector.admission_at(&b, now + Duration::from_secs(10)), Admission::Ejected);
+    }
+
+    #[test]
+    fn test_failure_rate() {
+        let detector = OutlierDetector::new(OutlierConfig {
+            consecutive_connect_failures: 0,
+            consecutive_5xx: 0,
+            failure_rate: 0.5,
+            failure_rate_min_requests: 10,
+            max_ejection_percent: 100,
+            ..Default::default()
+        });
+        let b = backend(1);
+        let now = Instant::now();
+        for i in 0..9 {
+            let outcome = if i % 2 == 0 { Outcome::ServerError(500) } else { Outcome::Success };
+            detector.report_at(&b, outcome, 1, now);
+        }
+        assert_eq!(detector.admission_at(&b, now), Admission::Admitted);
+        detector.report_at(&b, Outcome::ServerError(500), 1, now);
+        assert_eq!(detector.admission_at(&b, now), Admission::Ejected);
+    }
+
+    #[test]
+    fn test_max_ejection_percent() {
+        let detector = OutlierDetector::new(OutlierConfig {
+            max_ejection_percent: 50,
+            ..config()
+        });
+        let now = Instant::now();
+        for i in 1..=3 {
+            for _ in 0..3 {
+                detector.report_at(&backend(i), Outcome::ConnectionRefused, 4, now);
+            }
+        }
+        assert_eq!(detector.admission_at(&backend(1), now), Admission::Ejected);
+        assert_eq!(detector.admission_at(&backend(2), now), Admission::Ejected);
+        assert_eq!(detector.admission_at(&backend(3), now), Admission::Admitted);
+
+        detector.readmit(&backend(1));
+        assert_eq!(detector.admission_at(&backend(1), now), Admission::Admitted);
+
+        // at least one backend of a small group
+        let detector = OutlierDetector::new(OutlierConfig {
+            max_ejection_percent: 10,
+            ..config()
+        });
+        for i in 1..=2 {
+            for _ in 0..3 {
+                detector.report_at(&backend(i), Outcome::ConnectionRefused, 2, now);
+            }
+        }
+        assert_eq!(detector.admission_at(&backend(1), now), Admission::Ejected);
+        assert_eq!(detector.admission_at(&backend(2), now), Admission::Admitted);
+
+        // none at all
+        let detector = OutlierDetector::new(OutlierConfig {
+            max_ejection_percent: 0,
+            ..config()
+        });
+        for _ in 0..3 {
+            detector.report_at(&backend(1), Outcome::ConnectionRefused, 1, now);
+        }
+        assert_eq!(detector.admission_at(&backend(1), now), Admission::Admitted);
+    }
+}
@@ -0,0 +1,243 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! backend selection algorithms
+
+use super::Backend;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// the trait of the backend selection algorithm used by [super::LoadBalancer]
+pub trait BackendSelection {
+    /// the iterator to walk through the candidates for a selection
+    type Iter;
+
+    /// build the selection from the given set of backends
+    fn build(backends: &BTreeSet<Backend>) -> Self;
+
+    /// return an iterator over the candidates for the given key
+    ///
+    /// the first candidate is the preferred one, the following candidates are used when the
+    /// previous ones are not healthy
+    fn iter(self: &Arc<Self>, key: &[u8]) -> Self::Iter
+    where
+        Self::Iter: BackendIter;
+}
+
+/// the iterator returned by [BackendSelection::iter()]
+pub trait BackendIter {
+    fn next(&mut self) -> Option<&Backend>;
+}
+
+/// the algorithm to pick the preferred index
+pub trait SelectionAlgorithm {
+    fn new() -> Self;
+
+    fn next(&self, key: &[u8]) -> u64;
+}
+
+/// select backends according to their weights with the algorithm `A`
+pub struct Weighted<A> {
+    backends: Box<[Backend]>,
+    // each item is an index of `backends`, repeated by the weight of the backend
+    weighted: Box<[u16]>,
+    algorithm: A,
+}
+
+/// weighted round robin selection
+pub type RoundRobin = Weighted<algorithms::RoundRobin>;
+
+/// weighted random selection
+pub type Random = Weighted<algorithms::Random>;
+
+/// weighted selection by the FNV hash of the key
+pub type FnvHash = Weighted<algorithms::FnvHash>;
+
+impl<A: SelectionAlgorithm> BackendSelection for Weighted<A> {
+    type Iter = WeightedIterator<A>;
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        assert!(
+            backends.len() <= u16::MAX as usize,
+            "too many backends, max {}",
+            u16::MAX
+        );
+        let backends: Box<[Backend]> = backends.iter().cloned().collect();
+        let mut weighted = Vec::with_capacity(backends.len());
+        for (index, b) in backends.iter().enumerate() {
+            for _ in 0..b.weight {
+                weighted.push(index as u16);
+            }
+        }
+        Weighted {
+            backends,
+            weighted: weighted.into_boxed_slice(),
+            algorithm: A::new(),
+        }
+    }
+
+    fn iter(self: &Arc<Self>, key: &[u8]) -> Self::Iter {
+        WeightedIterator::new(key, self.clone())
+    }
+}
+
+/// an iterator over the backends of [Weighted]
+///
+/// the first candidate is picked by weight, then the rest of the backends are walked through one
+/// by one, so that every backend is tried once the preferred one is not usable
+pub struct WeightedIterator<A> {
+    index: u64,
+    first: bool,
+    tried: usize,
+    backend: Arc<Weighted<A>>,
+}
+
+impl<A: SelectionAlgorithm> WeightedIterator<A> {
+    fn new(key: &[u8], backend: Arc<Weighted<A>>) -> Self {
+        WeightedIterator {
+            index: backend.algorithm.next(key),
+            first: true,
+            tried: 0,
+            backend,
+        }
+    }
+}
+
+impl<A: SelectionAlgorithm> BackendIter for WeightedIterator<A> {
+    fn next(&mut self) -> Option<&Backend> {
+        let len = self.backend.backends.len();
+        if len == 0 || self.backend.weighted.is_empty() || self.tried >= len {
+            return None;
+        }
+
+        if self.first {
+            self.first = false;
+            let weighted_len = self.backend.weighted.len() as u64;
+            self.index = self.backend.weighted[(self.index % weighted_len) as usize] as u64;
+        } else {
+            self.index = (self.index + 1) % len as u64;
+        }
+        self.tried += 1;
+        self.backend.backends.get(self.index as usize)
+    }
+}
+
+pub mod algorithms {
+    use super::SelectionAlgorithm;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// round robin over the weighted backends, the key is ignored
+    pub struct RoundRobin(AtomicUsize);
+
+    impl SelectionAlgorithm for RoundRobin {
+        fn new() -> Self {
+            RoundRobin(AtomicUsize::new(0))
+        }
+
+        fn next(&self, _key: &[u8]) -> u64 {
+            self.0.fetch_add(1, Ordering::Relaxed) as u64
+        }
+    }
+
+    /// pick a random weighted backend, the key is ignored
+    pub struct Random;
+
+    impl SelectionAlgorithm for Random {
+        fn new() -> Self {
+            Random
+        }
+
+        fn next(&self, _key: &[u8]) -> u64 {
+            rand::random()
+        }
+    }
+
+    /// the same key always prefers the same backend as long as the backends don't change
+    pub struct FnvHash;
+
+    impl SelectionAlgorithm for FnvHash {
+        fn new() -> Self {
+            FnvHash
+        }
+
+        fn next(&self, key: &[u8]) -> u64 {
+            const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+            const FNV_PRIME: u64 = 0x100000001b3;
+            key.iter().fold(FNV_OFFSET, |hash, b| {
+                (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn backends() -> BTreeSet<Backend> {
+        let mut b1 = Backend::new("127.0.0.1:80").unwrap();
+        b1.weight = 3;
+        let b2 = Backend::new("127.0.0.2:80").unwrap();
+        let b3 = Backend::new("127.0.0.3:80").unwrap();
+        BTreeSet::from([b1, b2, b3])
+    }
+
+    #[test]
+    fn test_round_robin_weight() {
+        let selection = Arc::new(RoundRobin::build(&backends()));
+        let mut count = HashMap::new();
+        for _ in 0..50 {
+            let b = selection.iter(b"").next().unwrap().clone();
+            *count.entry(b.addr.to_string()).or_insert(0) += 1;
+        }
+        assert_eq!(count["127.0.0.1:80"], 30);
+        assert_eq!(count["127.0.0.2:80"], 10);
+        assert_eq!(count["127.0.0.3:80"], 10);
+    }
+
+    #[test]
+    fn test_iter_all_backends() {
+        let selection = Arc::new(Random::build(&backends()));
+        let mut iter = selection.iter(b"");
+        let mut seen = BTreeSet::new();
+        while let Some(b) = iter.next() {
+            seen.insert(b.clone());
+        }
+        assert_eq!(seen, backends());
+    }
+
+    #[test]
+    fn test_hash_stable() {
+        let selection = Arc::new(FnvHash::build(&backends()));
+        let first = selection.iter(b"client-1").next().unwrap().clone();
+        for _ in 0..10 {
+            assert_eq!(selection.iter(b"client-1").next().unwrap(), &first);
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        let selection = Arc::new(RoundRobin::build(&BTreeSet::new()));
+        assert!(selection.iter(b"").next().is_none());
+    }
+}
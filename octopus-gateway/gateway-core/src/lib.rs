@@ -1,14 +1,17 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
+pub mod apps;
+pub mod connectors;
+pub mod lb;
+pub mod listeners;
+pub mod protocols;
+pub mod ratelimit;
+pub mod server;
+pub mod services;
+pub mod upstreams;
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
+pub mod prelude {
+    pub use crate::server::configuration::ServerConf;
+    pub use crate::server::Server;
+    pub use crate::services::background::background_service;
+    pub use crate::services::listening::ListeningService;
+    pub use crate::upstreams::peer::HttpPeer;
 }
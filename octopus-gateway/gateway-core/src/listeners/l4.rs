@@ -0,0 +1,227 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! binding the TCP and unix domain sockets with their options
+
+use gateway_error::{ErrorType::*, OrErr, Result};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::fs::Permissions;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+
+/// the default backlog of the listening sockets, capped by `net.core.somaxconn`
+pub const LISTENER_BACKLOG: i32 = 65535;
+
+/// the address a service listens to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerAddress {
+    /// `ip:port` or `host:port` to listen with TCP
+    Tcp(String, Option<TcpSocketOptions>),
+    /// the path of a unix domain socket, and the permissions of the socket file
+    Uds(String, Option<u32>),
+}
+
+impl std::fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerAddress::Tcp(addr, _) => write!(f, "tcp://{addr}"),
+            ServerAddress::Uds(path, _) => write!(f, "uds://{path}"),
+        }
+    }
+}
+
+/// TCP keepalive of the accepted connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// how long a connection is idle before the first probe
+    pub idle: Duration,
+    /// the interval between the probes
+    pub interval: Duration,
+    /// how many unanswered probes close the connection
+    pub count: u32,
+}
+
+/// the options of a TCP listener
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpSocketOptions {
+    /// accept only IPv6 on an IPv6 address, the system default if `None`
+    pub ipv6_only: Option<bool>,
+    /// set `SO_REUSEPORT`, so that several processes can listen to the same address
+    pub reuseport: bool,
+    /// the size of the accept queue, [LISTENER_BACKLOG] if `None`
+    pub backlog: Option<i32>,
+    /// set `TCP_NODELAY` on the accepted connections
+    pub nodelay: bool,
+    /// the max number of pending TCP fast open requests, fast open is off if `None`
+    pub tcp_fastopen: Option<u32>,
+    /// enable keepalive on the accepted connections
+    pub keepalive: Option<TcpKeepalive>,
+}
+
+impl TcpSocketOptions {
+    /// apply the options of the accepted connections
+    pub(crate) fn apply_to_accepted(&self, stream: &tokio::net::TcpStream) -> Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true).or_err(AcceptError, "failed to set TCP_NODELAY")?;
+        }
+        if let Some(ka) = self.keepalive.as_ref() {
+            let params = socket2::TcpKeepalive::new()
+                .with_time(ka.idle)
+                .with_interval(ka.interval)
+                .with_retries(ka.count);
+            SockRef::from(stream)
+                .set_tcp_keepalive(&params)
+                .or_err(AcceptError, "failed to set the TCP keepalive")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_fastopen(socket: &Socket, backlog: u32) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let backlog = backlog as libc::c_int;
+    // SAFETY: a valid socket and a c_int option
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &backlog as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_tcp_fastopen(_socket: &Socket, _backlog: u32) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// bind and listen to a TCP address, all the resolved addresses are tried in order
+pub(crate) fn bind_tcp(addr: &str, options: Option<&TcpSocketOptions>) -> Result<std::net::TcpListener> {
+    let default = TcpSocketOptions::default();
+    let options = options.unwrap_or(&default);
+    let addrs: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .or_err_with(BindError, || format!("invalid listening address {addr}"))?
+        .collect();
+    let mut last_error = None;
+    for sock_addr in addrs {
+        match bind_tcp_addr(sock_addr, options) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| std::io::ErrorKind::AddrNotAvailable.into()))
+        .or_err_with(BindError, || format!("failed to bind tcp://{addr}"))
+}
+
+fn bind_tcp_addr(addr: SocketAddr, options: &TcpSocketOptions) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // rebinding while the connections of the last process are in TIME_WAIT
+    socket.set_reuse_address(true)?;
+    if options.reuseport {
+        socket.set_reuse_port(true)?;
+    }
+    if let (Some(only_v6), SocketAddr::V6(_)) = (options.ipv6_only, addr) {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.bind(&addr.into())?;
+    if let Some(backlog) = options.tcp_fastopen {
+        set_tcp_fastopen(&socket, backlog)?;
+    }
+    socket.listen(options.backlog.unwrap_or(LISTENER_BACKLOG))?;
+    Ok(socket.into())
+}
+
+/// bind and listen to a unix domain socket, a stale socket file is replaced
+pub(crate) fn bind_uds(path: &str, mode: Option<u32>) -> Result<std::os::unix::net::UnixListener> {
+    let error = || format!("failed to bind uds://{path}");
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).or_err_with(BindError, error),
+    }
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None).or_err_with(BindError, error)?;
+    let addr = socket2::SockAddr::unix(path).or_err_with(BindError, error)?;
+    socket.bind(&addr).or_err_with(BindError, error)?;
+    socket.listen(LISTENER_BACKLOG).or_err_with(BindError, error)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, Permissions::from_mode(mode))
+            .or_err_with(BindError, || format!("failed to set the permissions of {path}"))?;
+    }
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_tcp_options() {
+        let options = TcpSocketOptions {
+            reuseport: true,
+            backlog: Some(16),
+            ..Default::default()
+        };
+        let first = bind_tcp("127.0.0.1:0", Some(&options)).unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+        // SO_REUSEPORT lets another socket bind the same port
+        let second = bind_tcp(&addr, Some(&options)).unwrap();
+        assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+        assert!(bind_tcp(&addr, None).is_err());
+    }
+
+    #[test]
+    fn test_bind_ipv6_only() {
+        let options = TcpSocketOptions {
+            ipv6_only: Some(true),
+            ..Default::default()
+        };
+        // skip if the host has no IPv6
+        let Ok(v6) = bind_tcp("[::]:0", Some(&options)) else {
+            return;
+        };
+        let port = v6.local_addr().unwrap().port();
+        // the IPv4 side of the port is still free
+        bind_tcp(&format!("0.0.0.0:{port}"), None).unwrap();
+    }
+
+    #[test]
+    fn test_bind_uds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.sock");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "stale").unwrap();
+        let _listener = bind_uds(path, Some(0o600)).unwrap();
+        let meta = std::fs::metadata(path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        std::os::unix::net::UnixStream::connect(path).unwrap();
+    }
+}
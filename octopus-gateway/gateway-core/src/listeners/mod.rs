@@ -0,0 +1,343 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the listening endpoints of the services
+
+mod l4;
+
+pub use l4::{ServerAddress, TcpKeepalive, TcpSocketOptions, LISTENER_BACKLOG};
+
+use crate::protocols::tls::server::{Acceptor, TlsSettings};
+use crate::protocols::Stream;
+use crate::server::transfer_fd::Fds;
+use gateway_error::{ErrorType::*, OrErr, Result};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+
+/// an address to listen to, and how to terminate TLS on it if enabled
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub address: ServerAddress,
+    pub tls: Option<Arc<Acceptor>>,
+}
+
+/// the set of the addresses a service listens to
+#[derive(Debug, Default)]
+pub struct Listeners {
+    endpoints: Vec<Endpoint>,
+}
+
+impl Listeners {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// listen to a single TCP address
+    pub fn tcp(addr: &str) -> Self {
+        let mut listeners = Self::new();
+        listeners.add_tcp(addr);
+        listeners
+    }
+
+    /// listen to a single unix domain socket
+    pub fn uds(path: &str, mode: Option<u32>) -> Self {
+        let mut listeners = Self::new();
+        listeners.add_uds(path, mode);
+        listeners
+    }
+
+    pub fn add_tcp(&mut self, addr: &str) {
+        self.add_address(ServerAddress::Tcp(addr.into(), None));
+    }
+
+    pub fn add_tcp_with_settings(&mut self, addr: &str, options: TcpSocketOptions) {
+        self.add_address(ServerAddress::Tcp(addr.into(), Some(options)));
+    }
+
+    /// listen to the unix domain socket at `path`, the socket file is created with `mode` if set
+    pub fn add_uds(&mut self, path: &str, mode: Option<u32>) {
+        self.add_address(ServerAddress::Uds(path.into(), mode));
+    }
+
+    /// listen to a TCP address with TLS, using the cert chain and key in the given PEM files
+    pub fn add_tls(&mut self, addr: &str, cert_path: &str, key_path: &str) -> Result<()> {
+        self.add_tls_with_settings(addr, None, TlsSettings::intermediate(cert_path, key_path)?)
+    }
+
+    pub fn add_tls_with_settings(
+        &mut self,
+        addr: &str,
+        options: Option<TcpSocketOptions>,
+        settings: TlsSettings,
+    ) -> Result<()> {
+        self.endpoints.push(Endpoint {
+            address: ServerAddress::Tcp(addr.into(), options),
+            tls: Some(Arc::new(settings.build()?)),
+        });
+        Ok(())
+    }
+
+    pub fn add_address(&mut self, addr: ServerAddress) {
+        self.endpoints.push(Endpoint {
+            address: addr,
+            tls: None,
+        });
+    }
+
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints
+    }
+
+    /// bind all the addresses
+    ///
+    /// the sockets are bound outside of any runtime, so that bind errors are found before any
+    /// service starts. a socket already in `fds`, i.e. handed over by the old process on
+    /// upgrade, is reused instead of bound again. the newly bound ones are added to `fds`
+    pub fn bind(&self, fds: &mut Fds) -> Result<Vec<BoundListener>> {
+        self.endpoints.iter().map(|e| BoundListener::bind(e, fds)).collect()
+    }
+}
+
+#[derive(Debug)]
+enum BoundSocket {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl BoundSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            BoundSocket::Tcp(l) => l.as_raw_fd(),
+            BoundSocket::Unix(l) => l.as_raw_fd(),
+        }
+    }
+}
+
+/// a bound but not yet accepting socket
+#[derive(Debug)]
+pub struct BoundListener {
+    address: ServerAddress,
+    tls: Option<Arc<Acceptor>>,
+    inner: BoundSocket,
+}
+
+impl BoundListener {
+    fn bind(endpoint: &Endpoint, fds: &mut Fds) -> Result<Self> {
+        let address = &endpoint.address;
+        let key = address.to_string();
+        let inner = match (fds.take(&key), address) {
+            // SAFETY: the socket is received from the old process and owned by nobody else
+            (Some(fd), ServerAddress::Tcp(..)) => BoundSocket::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) }),
+            (Some(fd), ServerAddress::Uds(..)) => {
+                BoundSocket::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
+            }
+            (None, ServerAddress::Tcp(addr, options)) => BoundSocket::Tcp(l4::bind_tcp(addr, options.as_ref())?),
+            (None, ServerAddress::Uds(path, mode)) => BoundSocket::Unix(l4::bind_uds(path, *mode)?),
+        };
+        fds.add(key, inner.as_raw_fd());
+        match &inner {
+            BoundSocket::Tcp(l) => l.set_nonblocking(true),
+            BoundSocket::Unix(l) => l.set_nonblocking(true),
+        }
+        .or_err(BindError, "failed to set the listener non-blocking")?;
+        Ok(BoundListener {
+            address: address.clone(),
+            tls: endpoint.tls.clone(),
+            inner,
+        })
+    }
+
+    pub fn address(&self) -> &ServerAddress {
+        &self.address
+    }
+
+    /// the bound TCP address, `None` for unix domain sockets
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        match &self.inner {
+            BoundSocket::Tcp(l) => l.local_addr().ok(),
+            BoundSocket::Unix(_) => None,
+        }
+    }
+
+    /// start accepting, must be called within a tokio runtime
+    pub fn listen(self) -> Result<Listener> {
+        let inner = match self.inner {
+            BoundSocket::Tcp(l) => AcceptSocket::Tcp(
+                tokio::net::TcpListener::from_std(l).or_err(BindError, "failed to register the listener")?,
+            ),
+            BoundSocket::Unix(l) => AcceptSocket::Unix(
+                tokio::net::UnixListener::from_std(l).or_err(BindError, "failed to register the listener")?,
+            ),
+        };
+        Ok(Listener {
+            address: self.address,
+            tls: self.tls,
+            inner,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum AcceptSocket {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
+}
+
+/// an accepting socket
+#[derive(Debug)]
+pub struct Listener {
+    address: ServerAddress,
+    tls: Option<Arc<Acceptor>>,
+    inner: AcceptSocket,
+}
+
+impl Listener {
+    pub fn address(&self) -> &ServerAddress {
+        &self.address
+    }
+
+    /// accept a connection, the socket options of the address are applied to it
+    ///
+    /// the TLS handshake, if any, is left to [UninitializedStream::handshake()] so that a slow
+    /// client does not hold up the accept loop
+    pub async fn accept(&self) -> Result<UninitializedStream> {
+        let l4: Stream = match &self.inner {
+            AcceptSocket::Tcp(l) => {
+                let (stream, _) = l.accept().await.or_err(AcceptError, "failed to accept")?;
+                if let ServerAddress::Tcp(_, Some(options)) = &self.address {
+                    options.apply_to_accepted(&stream)?;
+                }
+                Box::new(stream)
+            }
+            AcceptSocket::Unix(l) => {
+                let (stream, _) = l.accept().await.or_err(AcceptError, "failed to accept")?;
+                Box::new(stream)
+            }
+        };
+        Ok(UninitializedStream {
+            l4,
+            tls: self.tls.clone(),
+        })
+    }
+}
+
+/// an accepted connection whose TLS handshake is not done yet
+pub struct UninitializedStream {
+    l4: Stream,
+    tls: Option<Arc<Acceptor>>,
+}
+
+impl UninitializedStream {
+    /// finish the TLS handshake if the endpoint has TLS enabled
+    pub async fn handshake(self) -> Result<Stream> {
+        match self.tls {
+            Some(tls) => tls.handshake(self.l4).await,
+            None => Ok(self.l4),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_listen_accept() {
+        let listeners = Listeners::tcp("127.0.0.1:0");
+        let mut fds = Fds::new();
+        let mut bound = listeners.bind(&mut fds).unwrap();
+        assert!(fds.get("tcp://127.0.0.1:0").is_some());
+        let bound = bound.pop().unwrap();
+        let addr = bound.local_addr().unwrap();
+        let listener = bound.listen().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut s = tokio::net::TcpStream::connect(addr).await.unwrap();
+            s.write_all(b"hi").await.unwrap();
+        });
+        let stream = listener.accept().await.unwrap().handshake().await.unwrap();
+        assert!(stream.peer_addr().is_some());
+        client.await.unwrap();
+    }
+
+    #[test]
+    fn test_bind_error() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listeners = Listeners::tcp(&taken.local_addr().unwrap().to_string());
+        assert_eq!(listeners.bind(&mut Fds::new()).unwrap_err().etype(), &BindError);
+    }
+
+    #[test]
+    fn test_bind_inherited() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        let listeners = Listeners::tcp(&addr);
+        let mut fds = Fds::new();
+        // as if received from the old process
+        fds.add(format!("tcp://{addr}"), taken.try_clone().unwrap().into_raw_fd());
+        let bound = listeners.bind(&mut fds).unwrap();
+        assert_eq!(bound[0].local_addr(), taken.local_addr().ok());
+    }
+
+    #[tokio::test]
+    async fn test_accept_options() {
+        let mut listeners = Listeners::new();
+        listeners.add_tcp_with_settings(
+            "127.0.0.1:0",
+            TcpSocketOptions {
+                nodelay: true,
+                keepalive: Some(TcpKeepalive {
+                    idle: std::time::Duration::from_secs(60),
+                    interval: std::time::Duration::from_secs(5),
+                    count: 3,
+                }),
+                ..Default::default()
+            },
+        );
+        let bound = listeners.bind(&mut Fds::new()).unwrap().pop().unwrap();
+        let addr = bound.local_addr().unwrap();
+        let listener = bound.listen().unwrap();
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        listener.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_listen_uds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.sock");
+        let path = path.to_str().unwrap();
+        let listeners = Listeners::uds(path, Some(0o660));
+        let mut fds = Fds::new();
+        let bound = listeners.bind(&mut fds).unwrap().pop().unwrap();
+        assert!(fds.get(&format!("uds://{path}")).is_some());
+        assert_eq!(bound.local_addr(), None);
+        let listener = bound.listen().unwrap();
+
+        let client = tokio::net::UnixStream::connect(path);
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        client.unwrap();
+        let accepted = accepted.unwrap().handshake().await.unwrap();
+        assert!(accepted.peer_addr().is_none());
+    }
+}
@@ -0,0 +1,58 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the transport streams between the gateway and its downstreams and upstreams
+
+pub mod tls;
+
+use gateway_httpd::server::TlsDigest;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::{TcpStream, UnixStream};
+
+/// the trait of the streams the gateway reads and writes
+pub trait IO: AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug {
+    /// the address of the remote side, if it is an inet socket
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// the TLS details, `None` if the stream is plaintext
+    fn tls_digest(&self) -> Option<TlsDigest> {
+        None
+    }
+}
+
+/// the type erased stream used by sessions
+pub type Stream = Box<dyn IO>;
+
+impl IO for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl IO for UnixStream {}
+
+/// in memory stream, mostly for tests
+impl IO for DuplexStream {}
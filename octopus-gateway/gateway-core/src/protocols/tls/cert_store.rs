@@ -0,0 +1,432 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the certificates of a TLS listener, selected by SNI
+//!
+//! the names of a cert are taken from its subject alternative names, or its common name if it
+//! has none. `*.example.com` matches `a.example.com` but neither `example.com` nor
+//! `a.b.example.com`. a handshake without SNI, or whose SNI matches nothing, gets the default
+//! cert, or fails if there is none
+
+use super::{cert_key_from_pem, load_cert_key};
+use crate::server::ShutdownWatch;
+use crate::services::background::BackgroundService;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use log::{error, info};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use x509_parser::extensions::GeneralName;
+
+pub use super::CertifiedKey;
+
+/// picks the cert of a handshake at runtime, e.g. from a database or a cert issuing service
+pub trait CertSelector: Send + Sync {
+    /// return `None` to fall back to the certs in the store
+    fn select(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>>;
+}
+
+/// the PEM files of a cert chain and its key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertFiles {
+    pub cert: String,
+    pub key: String,
+}
+
+impl CertFiles {
+    fn load(&self) -> Result<CertifiedKey> {
+        load_cert_key(&self.cert, &self.key)
+    }
+
+    /// the last modification of either file
+    fn modified(&self) -> Option<SystemTime> {
+        let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(&self.cert).max(modified(&self.key))
+    }
+}
+
+/// the DNS names the leaf cert is valid for, lowercased
+fn cert_names(cert: &CertifiedKey) -> Result<Vec<String>> {
+    let leaf = cert.end_entity_cert().or_err(InvalidCert, "empty certificate chain")?;
+    let (_, x509) = x509_parser::parse_x509_certificate(leaf).or_err(InvalidCert, "failed to parse the certificate")?;
+    let mut names = vec![];
+    if let Ok(Some(san)) = x509.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            if let GeneralName::DNSName(dns) = name {
+                names.push(dns.to_ascii_lowercase());
+            }
+        }
+    }
+    if names.is_empty() {
+        if let Some(cn) = x509.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()) {
+            names.push(cn.to_ascii_lowercase());
+        }
+    }
+    Ok(names)
+}
+
+struct Entry {
+    cert: Arc<CertifiedKey>,
+    names: Vec<String>,
+    default: bool,
+    /// where the cert is reloaded from, `None` if it is added from memory
+    files: Option<CertFiles>,
+    modified: Option<SystemTime>,
+}
+
+/// the lookup tables, rebuilt and swapped as a whole on every change
+#[derive(Default)]
+struct Certs {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    /// keyed by the part after `*.`
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl Certs {
+    fn build(entries: &[Entry]) -> Self {
+        let mut certs = Certs::default();
+        for entry in entries.iter() {
+            for name in entry.names.iter() {
+                let table = match name.strip_prefix("*.") {
+                    Some(domain) => certs.wildcard.entry(domain.to_string()),
+                    None => certs.exact.entry(name.clone()),
+                };
+                // the first added cert of a name wins
+                table.or_insert_with(|| entry.cert.clone());
+            }
+            if entry.default {
+                certs.default = Some(entry.cert.clone());
+            }
+        }
+        certs
+    }
+
+    fn find(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let found = sni.and_then(|sni| {
+            let sni = sni.trim_end_matches('.').to_ascii_lowercase();
+            self.exact.get(&sni).or_else(|| {
+                let (_, parent) = sni.split_once('.')?;
+                self.wildcard.get(parent)
+            })
+        });
+        found.or(self.default.as_ref()).cloned()
+    }
+}
+
+/// the certs of a listener, looked up by SNI
+///
+/// the lookups are lock free, the certs can be added or reloaded while the listener serves.
+/// the connections already established keep the cert of their handshake
+pub struct CertStore {
+    certs: ArcSwap<Certs>,
+    entries: Mutex<Vec<Entry>>,
+    selector: Option<Box<dyn CertSelector>>,
+}
+
+impl Default for CertStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let certs = self.certs.load();
+        f.debug_struct("CertStore")
+            .field("names", &(certs.exact.len() + certs.wildcard.len()))
+            .field("default", &certs.default.is_some())
+            .field("selector", &self.selector.is_some())
+            .finish()
+    }
+}
+
+impl CertStore {
+    pub fn new() -> Self {
+        CertStore {
+            certs: ArcSwap::from_pointee(Certs::default()),
+            entries: Mutex::new(vec![]),
+            selector: None,
+        }
+    }
+
+    /// a store serving the given cert to every handshake
+    pub fn with_default_files(cert_path: &str, key_path: &str) -> Result<Self> {
+        let store = Self::new();
+        store.set_default_files(cert_path, key_path)?;
+        Ok(store)
+    }
+
+    /// ask `selector` first on every handshake
+    pub fn set_selector(&mut self, selector: Box<dyn CertSelector>) {
+        self.selector = Some(selector);
+    }
+
+    /// add a cert for the names it is valid for, the files are read again on [Self::reload()]
+    pub fn add_files(&self, cert_path: &str, key_path: &str) -> Result<()> {
+        self.add_from_files(cert_path, key_path, false)
+    }
+
+    /// use the cert for the handshakes that match no other cert, it is also used for the
+    /// names it is valid for
+    pub fn set_default_files(&self, cert_path: &str, key_path: &str) -> Result<()> {
+        self.add_from_files(cert_path, key_path, true)
+    }
+
+    fn add_from_files(&self, cert_path: &str, key_path: &str, default: bool) -> Result<()> {
+        let files = CertFiles {
+            cert: cert_path.into(),
+            key: key_path.into(),
+        };
+        let modified = files.modified();
+        let cert = files.load()?;
+        self.add_entry(cert, default, Some(files), modified)
+    }
+
+    /// add a PEM encoded cert for the names it is valid for
+    pub fn add_pem(&self, cert_pem: &[u8], key_pem: &[u8], default: bool) -> Result<()> {
+        let cert = cert_key_from_pem(cert_pem, key_pem)?;
+        self.add_entry(cert, default, None, None)
+    }
+
+    fn add_entry(
+        &self,
+        cert: CertifiedKey,
+        default: bool,
+        files: Option<CertFiles>,
+        modified: Option<SystemTime>,
+    ) -> Result<()> {
+        let names = cert_names(&cert)?;
+        if names.is_empty() && !default {
+            return Error::e_explain(InvalidCert, "the certificate has no DNS name");
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if default {
+            entries.iter_mut().for_each(|e| e.default = false);
+        }
+        entries.push(Entry {
+            cert: Arc::new(cert),
+            names,
+            default,
+            files,
+            modified,
+        });
+        self.certs.store(Arc::new(Certs::build(&entries)));
+        Ok(())
+    }
+
+    /// read the cert files that changed since they were loaded
+    ///
+    /// a cert that fails to load keeps being served as it was, the error of the last one that
+    /// fails is returned after all the others are reloaded
+    pub fn reload(&self) -> Result<usize> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut reloaded = 0;
+        let mut failed = None;
+        for entry in entries.iter_mut() {
+            let Some(files) = &entry.files else {
+                continue;
+            };
+            let modified = files.modified();
+            if modified == entry.modified {
+                continue;
+            }
+            match files.load().and_then(|cert| Ok((cert_names(&cert)?, cert))) {
+                Ok((names, cert)) => {
+                    info!("reloaded the certificate {}", files.cert);
+                    entry.cert = Arc::new(cert);
+                    entry.names = names;
+                    entry.modified = modified;
+                    reloaded += 1;
+                }
+                Err(e) => {
+                    error!("failed to reload the certificate {}: {e}", files.cert);
+                    failed = Some(e);
+                }
+            }
+        }
+        if reloaded > 0 {
+            self.certs.store(Arc::new(Certs::build(&entries)));
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(reloaded),
+        }
+    }
+
+    /// the cert of a handshake whose SNI is `sni`
+    pub fn find(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(cert) = self.selector.as_ref().and_then(|s| s.select(sni)) {
+            return Some(cert);
+        }
+        self.certs.load().find(sni)
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name())
+    }
+}
+
+/// reloads the changed cert files of a [CertStore] periodically
+pub struct CertReloader {
+    store: Arc<CertStore>,
+    interval: Duration,
+}
+
+impl CertReloader {
+    pub fn new(store: Arc<CertStore>, interval: Duration) -> Self {
+        CertReloader { store, interval }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CertReloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(self.interval);
+        // the certs are just loaded
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => {
+                    // the errors are already logged
+                    let _ = self.store.reload();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::tls::server::TlsSettings;
+    use crate::protocols::tls::tests::{connector, self_signed, server_name};
+
+    fn add(store: &CertStore, names: &[&str], default: bool) -> Arc<CertifiedKey> {
+        let (cert, key) = self_signed(names);
+        store.add_pem(cert.as_bytes(), key.as_bytes(), default).unwrap();
+        store.find(Some(&names[0].replace('*', "any"))).unwrap()
+    }
+
+    fn same(a: Option<Arc<CertifiedKey>>, b: &Arc<CertifiedKey>) -> bool {
+        a.is_some_and(|a| Arc::ptr_eq(&a, b))
+    }
+
+    #[test]
+    fn test_find() {
+        let store = CertStore::new();
+        assert!(store.find(Some("example.com")).is_none());
+
+        let exact = add(&store, &["example.com", "www.example.com"], false);
+        let wildcard = add(&store, &["*.example.com"], false);
+        let default = add(&store, &["default.test"], true);
+        assert!(same(store.find(Some("example.com")), &exact));
+        assert!(same(store.find(Some("WWW.Example.com.")), &exact));
+        assert!(same(store.find(Some("api.example.com")), &wildcard));
+        // a wildcard covers a single label
+        assert!(same(store.find(Some("a.api.example.com")), &default));
+        assert!(same(store.find(Some("other.org")), &default));
+        assert!(same(store.find(None), &default));
+    }
+
+    #[test]
+    fn test_selector() {
+        struct Selector(Arc<CertifiedKey>);
+        impl CertSelector for Selector {
+            fn select(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+                (sni == Some("dynamic.test")).then(|| self.0.clone())
+            }
+        }
+
+        let other = CertStore::new();
+        let dynamic = add(&other, &["dynamic.test"], false);
+        let mut store = CertStore::new();
+        store.set_selector(Box::new(Selector(dynamic.clone())));
+        let default = add(&store, &["default.test"], true);
+        assert!(same(store.find(Some("dynamic.test")), &dynamic));
+        assert!(same(store.find(Some("example.com")), &default));
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        let (cert_path, key_path) = (cert_path.to_str().unwrap(), key_path.to_str().unwrap());
+        let write = |names: &[&str], age: u64| {
+            let (cert, key) = self_signed(names);
+            std::fs::write(cert_path, cert).unwrap();
+            std::fs::write(key_path, key).unwrap();
+            // the files may be rewritten within the resolution of the mtime
+            let mtime = SystemTime::now() - Duration::from_secs(age);
+            let file = std::fs::File::options().write(true).open(cert_path).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+
+        write(&["old.test"], 60);
+        let store = CertStore::new();
+        store.add_files(cert_path, key_path).unwrap();
+        assert!(store.find(Some("old.test")).is_some());
+        assert_eq!(store.reload().unwrap(), 0);
+
+        write(&["new.test"], 30);
+        assert_eq!(store.reload().unwrap(), 1);
+        assert!(store.find(Some("old.test")).is_none());
+        let new = store.find(Some("new.test")).unwrap();
+
+        // a broken file keeps the cert loaded before
+        std::fs::write(cert_path, "broken").unwrap();
+        assert_eq!(store.reload().unwrap_err().etype(), &InvalidCert);
+        assert!(same(store.find(Some("new.test")), &new));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_by_sni() {
+        let store = Arc::new(CertStore::new());
+        let (a_cert, a_key) = self_signed(&["a.test"]);
+        let (b_cert, b_key) = self_signed(&["*.b.test"]);
+        store.add_pem(a_cert.as_bytes(), a_key.as_bytes(), false).unwrap();
+        store.add_pem(b_cert.as_bytes(), b_key.as_bytes(), false).unwrap();
+        let acceptor = Arc::new(TlsSettings::with_cert_store(store).build().unwrap());
+
+        for (name, cert, ok) in [("a.test", &a_cert, true), ("www.b.test", &b_cert, true), ("c.test", &a_cert, false)] {
+            let (client, server) = tokio::io::duplex(65536);
+            let acceptor = acceptor.clone();
+            let server = tokio::spawn(async move { acceptor.handshake(Box::new(server)).await });
+            let client = connector(cert, &[]).connect(server_name(name), client).await;
+            assert_eq!(client.is_ok(), ok, "{name}");
+            let server = server.await.unwrap();
+            if ok {
+                let digest = server.unwrap().tls_digest().unwrap();
+                assert_eq!(digest.sni.as_deref(), Some(name));
+            } else {
+                assert_eq!(server.unwrap_err().etype(), &TLSHandshakeFailure);
+            }
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! TLS over the transport streams, based on rustls
+
+pub mod cert_store;
+pub mod server;
+
+pub use rustls::sign::CertifiedKey;
+
+use super::{Stream, IO};
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use gateway_httpd::server::{ClientCertDigest, TlsDigest};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::CommonState;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use x509_parser::extensions::GeneralName;
+
+/// the application protocols to negotiate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ALPN {
+    /// http/1.1 only
+    H1,
+    /// http/2 only
+    H2,
+    /// http/2 preferred, http/1.1 as fallback
+    H2H1,
+}
+
+impl ALPN {
+    /// the protocol ids on the wire, in order of preference
+    pub fn to_wire_protocols(&self) -> Vec<Vec<u8>> {
+        match self {
+            ALPN::H1 => vec![b"http/1.1".to_vec()],
+            ALPN::H2 => vec![b"h2".to_vec()],
+            ALPN::H2H1 => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        }
+    }
+}
+
+/// the crypto provider of all the TLS configs
+pub(crate) fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// parse a PEM encoded certificate chain
+pub fn certs_from_pem(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .or_err(InvalidCert, "failed to parse the PEM certificates")?;
+    if certs.is_empty() {
+        return Error::e_explain(InvalidCert, "no certificate found in the PEM");
+    }
+    Ok(certs)
+}
+
+/// parse a PEM encoded private key, PKCS#1, PKCS#8 and SEC1 keys are supported
+pub fn key_from_pem(pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut &pem[..])
+        .or_err(InvalidCert, "failed to parse the PEM private key")?
+        .ok_or_else(|| Error::explain(InvalidCert, "no private key found in the PEM"))
+}
+
+pub(crate) fn read_pem(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).or_err_with(FileReadError, || format!("failed to read {path}"))
+}
+
+/// load a PEM encoded certificate chain from a file
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    certs_from_pem(&read_pem(path)?)
+        .map_err(|e| Error::because(InvalidCert, format!("invalid certificate file {path}"), e))
+}
+
+/// load a PEM encoded private key from a file
+pub fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    key_from_pem(&read_pem(path)?).map_err(|e| Error::because(InvalidCert, format!("invalid key file {path}"), e))
+}
+
+fn certified_key(chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<CertifiedKey> {
+    CertifiedKey::from_der(chain, key, &provider()).or_err(InvalidCert, "the key does not match the certificate")
+}
+
+/// load a cert chain and its private key from PEM files, checking that they match
+pub fn load_cert_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey> {
+    certified_key(load_certs(cert_path)?, load_private_key(key_path)?)
+}
+
+/// parse a PEM encoded cert chain and its private key, checking that they match
+pub fn cert_key_from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey> {
+    certified_key(certs_from_pem(cert_pem)?, key_from_pem(key_pem)?)
+}
+
+fn tls_digest(conn: &CommonState, sni: Option<&str>) -> TlsDigest {
+    TlsDigest {
+        sni: sni.map(|s| s.to_string()),
+        alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+        version: conn
+            .protocol_version()
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string(),
+        cipher: conn
+            .negotiated_cipher_suite()
+            .and_then(|c| c.suite().as_str())
+            .unwrap_or_default()
+            .to_string(),
+        client_cert: None,
+    }
+}
+
+/// the identity in the cert of a client, `None` if the cert fails to parse
+pub fn client_cert_digest(cert: &CertificateDer<'_>) -> Option<ClientCertDigest> {
+    let (_, x509) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let mut sans = vec![];
+    if let Ok(Some(san)) = x509.subject_alternative_name() {
+        for name in san.value.general_names.iter() {
+            let name = match name {
+                GeneralName::DNSName(dns) => format!("DNS:{dns}"),
+                GeneralName::URI(uri) => format!("URI:{uri}"),
+                GeneralName::RFC822Name(email) => format!("email:{email}"),
+                GeneralName::IPAddress(ip) => match ip.len() {
+                    4 => format!("IP:{}", IpAddr::from(<[u8; 4]>::try_from(*ip).ok()?)),
+                    16 => format!("IP:{}", IpAddr::from(<[u8; 16]>::try_from(*ip).ok()?)),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            sans.push(name);
+        }
+    }
+    let fingerprint = Sha256::digest(cert.as_ref()).iter().map(|b| format!("{b:02x}")).collect();
+    Some(ClientCertDigest {
+        subject: x509.subject().to_string(),
+        sans,
+        fingerprint,
+    })
+}
+
+/// the downstream TLS stream
+pub type TlsServerStream = tokio_rustls::server::TlsStream<Stream>;
+
+impl IO for TlsServerStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn tls_digest(&self) -> Option<TlsDigest> {
+        let conn = self.get_ref().1;
+        let mut digest = tls_digest(conn, conn.server_name());
+        // only verified certs get here, the handshake fails otherwise
+        digest.client_cert = conn.peer_certificates().and_then(|c| c.first()).and_then(client_cert_digest);
+        Some(digest)
+    }
+}
+
+/// the upstream TLS stream
+pub type TlsClientStream = tokio_rustls::client::TlsStream<Stream>;
+
+impl IO for TlsClientStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn tls_digest(&self) -> Option<TlsDigest> {
+        Some(tls_digest(self.get_ref().1, None))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    /// a self signed cert of `names`, in PEM
+    pub(crate) fn self_signed(names: &[&str]) -> (String, String) {
+        let names = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let cert = rcgen::generate_simple_self_signed(names).unwrap();
+        (cert.cert.pem(), cert.key_pair.serialize_pem())
+    }
+
+    /// a client trusting `cert_pem` and offering `alpn`
+    pub(crate) fn connector(cert_pem: &str, alpn: &[&[u8]]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in certs_from_pem(cert_pem.as_bytes()).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let mut config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    pub(crate) fn server_name(name: &str) -> ServerName<'static> {
+        ServerName::try_from(name.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_load_pem() {
+        let (cert, key) = self_signed(&["localhost"]);
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert).unwrap();
+        std::fs::write(&key_path, key).unwrap();
+        assert_eq!(load_certs(cert_path.to_str().unwrap()).unwrap().len(), 1);
+        load_private_key(key_path.to_str().unwrap()).unwrap();
+
+        // a key is not a cert
+        let e = load_certs(key_path.to_str().unwrap()).unwrap_err();
+        assert_eq!(e.etype(), &InvalidCert);
+        let e = load_certs(dir.path().join("missing.pem").to_str().unwrap()).unwrap_err();
+        assert_eq!(e.etype(), &FileReadError);
+    }
+}
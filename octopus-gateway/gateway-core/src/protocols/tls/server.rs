@@ -0,0 +1,297 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! TLS termination of the downstream connections
+
+use super::cert_store::CertStore;
+use super::{provider, ALPN};
+use crate::protocols::Stream;
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ResolvesServerCert, WebPkiClientVerifier};
+use rustls::{RootCertStore, ServerConfig};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::TlsAcceptor;
+
+/// the default time limit of a TLS handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the TLS settings of a listening endpoint
+#[derive(Debug)]
+pub struct TlsSettings {
+    certs: Arc<dyn ResolvesServerCert>,
+    alpn: ALPN,
+    handshake_timeout: Duration,
+    client_auth: Option<ClientAuth>,
+}
+
+/// how the clients authenticate with their certs
+#[derive(Debug)]
+struct ClientAuth {
+    ca: Vec<CertificateDer<'static>>,
+    required: bool,
+}
+
+impl TlsSettings {
+    /// the settings with the cert chain and the private key in the given PEM files
+    pub fn intermediate(cert_path: &str, key_path: &str) -> Result<Self> {
+        Ok(Self::with_cert_store(Arc::new(CertStore::with_default_files(cert_path, key_path)?)))
+    }
+
+    /// the settings with the PEM encoded cert chain and private key
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self> {
+        let store = CertStore::new();
+        store.add_pem(cert_pem, key_pem, true)?;
+        Ok(Self::with_cert_store(Arc::new(store)))
+    }
+
+    /// the settings selecting the cert of each handshake from `store`
+    pub fn with_cert_store(store: Arc<CertStore>) -> Self {
+        TlsSettings {
+            certs: store,
+            alpn: ALPN::H1,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            client_auth: None,
+        }
+    }
+
+    /// offer http/2 to the clients, http/1.1 is still used by the ones without ALPN
+    pub fn enable_h2(&mut self) {
+        self.alpn = ALPN::H2H1;
+    }
+
+    pub fn set_alpn(&mut self, alpn: ALPN) {
+        self.alpn = alpn;
+    }
+
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// ask the clients for certs issued by one of `ca`
+    ///
+    /// the handshake fails if a client sends a cert that does not verify, or sends none while
+    /// `required`. the verified cert is in the TLS digest of the connection
+    pub fn set_client_auth(&mut self, ca: Vec<CertificateDer<'static>>, required: bool) {
+        self.client_auth = Some(ClientAuth { ca, required });
+    }
+
+    pub fn build(self) -> Result<Acceptor> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .or_err(InternalError, "failed to set the TLS versions")?;
+        let builder = match self.client_auth {
+            Some(auth) => {
+                let mut roots = RootCertStore::empty();
+                for cert in auth.ca {
+                    roots.add(cert).or_err(InvalidCert, "invalid client CA certificate")?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+                let verifier = if auth.required {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+                let verifier = verifier
+                    .build()
+                    .or_err(InvalidCert, "failed to build the client certificate verifier")?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(self.certs);
+        config.alpn_protocols = self.alpn.to_wire_protocols();
+        Ok(Acceptor {
+            inner: TlsAcceptor::from(Arc::new(config)),
+            handshake_timeout: self.handshake_timeout,
+        })
+    }
+}
+
+/// performs the TLS handshakes of a listening endpoint
+pub struct Acceptor {
+    inner: TlsAcceptor,
+    handshake_timeout: Duration,
+}
+
+impl std::fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Acceptor")
+            .field("handshake_timeout", &self.handshake_timeout)
+            .finish()
+    }
+}
+
+impl Acceptor {
+    /// perform the server side handshake over an accepted stream
+    pub async fn handshake(&self, stream: Stream) -> Result<Stream> {
+        match tokio::time::timeout(self.handshake_timeout, self.inner.accept(stream)).await {
+            Ok(Ok(tls)) => Ok(Box::new(tls)),
+            Ok(Err(e)) => Err(Error::because(TLSHandshakeFailure, "TLS handshake failed", e).into_down()),
+            Err(_) => Err(Error::explain(TLSHandshakeTimeout, "TLS handshake timed out").into_down()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::tls::tests::{connector, self_signed, server_name};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    fn acceptor(alpn: ALPN, timeout: Duration) -> (Acceptor, String) {
+        let (cert, key) = self_signed(&["localhost"]);
+        let mut settings = TlsSettings::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+        settings.set_alpn(alpn);
+        settings.set_handshake_timeout(timeout);
+        (settings.build().unwrap(), cert)
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (acceptor, cert) = acceptor(ALPN::H2H1, HANDSHAKE_TIMEOUT);
+        let (client, server) = duplex(65536);
+        let client = tokio::spawn(async move {
+            let mut tls = connector(&cert, &[b"h2"])
+                .connect(server_name("localhost"), client)
+                .await
+                .unwrap();
+            tls.write_all(b"hello").await.unwrap();
+            tls.shutdown().await.unwrap();
+            // the server may still be sending its session tickets
+            tls
+        });
+
+        let mut stream = acceptor.handshake(Box::new(server)).await.unwrap();
+        let digest = stream.tls_digest().unwrap();
+        assert_eq!(digest.sni.as_deref(), Some("localhost"));
+        assert_eq!(digest.alpn.as_deref(), Some("h2"));
+        assert_eq!(digest.version, "TLSv1_3");
+        assert!(!digest.cipher.is_empty());
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_alpn_h1_only() {
+        let (acceptor, cert) = acceptor(ALPN::H1, HANDSHAKE_TIMEOUT);
+        let (client, server) = duplex(65536);
+        let client = tokio::spawn(async move {
+            connector(&cert, &[b"h2", b"http/1.1"])
+                .connect(server_name("localhost"), client)
+                .await
+                .unwrap()
+        });
+        let stream = acceptor.handshake(Box::new(server)).await.unwrap();
+        assert_eq!(stream.tls_digest().unwrap().alpn.as_deref(), Some("http/1.1"));
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_failure() {
+        let (acceptor, _) = acceptor(ALPN::H1, HANDSHAKE_TIMEOUT);
+        let (mut client, server) = duplex(65536);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let e = acceptor.handshake(Box::new(server)).await.unwrap_err();
+        assert_eq!(e.etype(), &TLSHandshakeFailure);
+        assert_eq!(e.esource(), &gateway_error::ErrorSource::Downstream);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let (acceptor, _) = acceptor(ALPN::H1, Duration::from_millis(50));
+        let (_client, server) = duplex(65536);
+        let e = acceptor.handshake(Box::new(server)).await.unwrap_err();
+        assert_eq!(e.etype(), &TLSHandshakeTimeout);
+    }
+
+    #[tokio::test]
+    async fn test_client_auth() {
+        use crate::protocols::tls::{certs_from_pem, key_from_pem, provider};
+        use rustls::ClientConfig;
+
+        let (client_cert, client_key) = self_signed(&["client.test", "127.0.0.1"]);
+        let (cert, key) = self_signed(&["localhost"]);
+        let handshake = |required: bool, with_cert: bool| {
+            let mut settings = TlsSettings::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+            settings.set_client_auth(certs_from_pem(client_cert.as_bytes()).unwrap(), required);
+            let acceptor = settings.build().unwrap();
+
+            let mut roots = RootCertStore::empty();
+            roots.add_parsable_certificates(certs_from_pem(cert.as_bytes()).unwrap());
+            let builder = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = if with_cert {
+                let chain = certs_from_pem(client_cert.as_bytes()).unwrap();
+                builder
+                    .with_client_auth_cert(chain, key_from_pem(client_key.as_bytes()).unwrap())
+                    .unwrap()
+            } else {
+                builder.with_no_client_auth()
+            };
+            async move {
+                let (client, server) = duplex(65536);
+                let client = tokio::spawn(async move {
+                    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+                    let mut tls = connector.connect(server_name("localhost"), client).await?;
+                    // the server verifies the cert after the client finishes its handshake
+                    tls.write_all(b"hi").await?;
+                    Ok::<_, std::io::Error>(tls)
+                });
+                let result = acceptor.handshake(Box::new(server)).await;
+                let _ = client.await;
+                result
+            }
+        };
+
+        let stream = handshake(true, true).await.unwrap();
+        let client = stream.tls_digest().unwrap().client_cert.unwrap();
+        assert_eq!(client.subject, "CN=rcgen self signed cert");
+        assert_eq!(client.sans, vec!["DNS:client.test", "IP:127.0.0.1"]);
+        let der = &certs_from_pem(client_cert.as_bytes()).unwrap()[0];
+        let expected: String = {
+            use sha2::Digest;
+            sha2::Sha256::digest(der.as_ref()).iter().map(|b| format!("{b:02x}")).collect()
+        };
+        assert_eq!(client.fingerprint, expected);
+
+        let e = handshake(true, false).await.unwrap_err();
+        assert_eq!(e.etype(), &TLSHandshakeFailure);
+        let stream = handshake(false, false).await.unwrap();
+        assert!(stream.tls_digest().unwrap().client_cert.is_none());
+        let stream = handshake(false, true).await.unwrap();
+        assert!(stream.tls_digest().unwrap().client_cert.is_some());
+    }
+
+    #[test]
+    fn test_key_mismatch() {
+        let (cert, _) = self_signed(&["localhost"]);
+        let (_, key) = self_signed(&["localhost"]);
+        let e = TlsSettings::from_pem(cert.as_bytes(), key.as_bytes()).unwrap_err();
+        assert_eq!(e.etype(), &InvalidCert);
+    }
+}
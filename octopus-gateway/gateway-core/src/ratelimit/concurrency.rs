@@ -0,0 +1,345 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! limit the number of requests in flight, queueing the excess for a while before shedding it
+
+use gateway_error::{Error, ErrorType::HTTPStatus, Result};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// the AIMD adaptation of the limit: it grows by one every `limit` requests answered in time,
+/// and is cut by `backoff` when a request fails or is slower than `latency_threshold`
+#[derive(Debug, Clone)]
+pub struct AimdConfig {
+    pub min_limit: usize,
+    pub max_limit: usize,
+    /// a response slower than this is a sign of overload
+    pub latency_threshold: Duration,
+    /// the ratio the limit is multiplied by on overload, in (0, 1)
+    pub backoff: f64,
+}
+
+impl Default for AimdConfig {
+    fn default() -> Self {
+        AimdConfig {
+            min_limit: 1,
+            max_limit: 1000,
+            latency_threshold: Duration::from_millis(500),
+            backoff: 0.9,
+        }
+    }
+}
+
+/// the settings of a [ConcurrencyLimiter]
+#[derive(Debug, Clone)]
+pub struct ConcurrencyConfig {
+    /// the max number of requests in flight, the initial limit if `adaptive`
+    pub max_in_flight: usize,
+    /// the max number of requests waiting for a permit, 0 to shed them right away
+    pub max_queue: usize,
+    /// how long a request waits for a permit before being shed
+    pub queue_timeout: Duration,
+    /// adapt the limit to the observed latency
+    pub adaptive: Option<AimdConfig>,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig {
+            max_in_flight: 100,
+            max_queue: 100,
+            queue_timeout: Duration::from_secs(1),
+            adaptive: None,
+        }
+    }
+}
+
+struct State {
+    /// a float so that the additive increase can be spread over many requests
+    limit: f64,
+    in_flight: usize,
+    queued: usize,
+    /// when the limit was last cut, the overloads of the requests started before are ignored
+    last_decrease: Option<Instant>,
+}
+
+impl State {
+    fn limit(&self) -> usize {
+        self.limit as usize
+    }
+}
+
+/// the limiter of the requests in flight to an upstream group
+///
+/// a request takes a [Permit] before being sent, and waits in a bounded queue when the limit
+/// is reached; the requests which do not fit in the queue or wait for too long are rejected
+/// with 503 so that a slow upstream does not pile them up
+pub struct ConcurrencyLimiter {
+    config: ConcurrencyConfig,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        let limit = match config.adaptive.as_ref() {
+            Some(aimd) => config.max_in_flight.clamp(aimd.min_limit, aimd.max_limit),
+            None => config.max_in_flight,
+        };
+        ConcurrencyLimiter {
+            config,
+            state: Mutex::new(State {
+                limit: limit as f64,
+                in_flight: 0,
+                queued: 0,
+                last_decrease: None,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// the current max number of requests in flight
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit()
+    }
+
+    /// the number of requests in flight
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    /// the number of requests waiting for a permit
+    pub fn queued(&self) -> usize {
+        self.state.lock().unwrap().queued
+    }
+
+    /// wait for a permit, `HTTPStatus(503)` if the queue is full or the wait times out
+    pub async fn acquire(self: &Arc<Self>) -> Result<Permit> {
+        let deadline = Instant::now() + self.config.queue_timeout;
+        let mut queued = None;
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                // the queued requests go first
+                if state.in_flight < state.limit() && (state.queued == 0 || queued.is_some()) {
+                    state.in_flight += 1;
+                    drop(state);
+                    drop(queued);
+                    return Ok(Permit {
+                        limiter: self.clone(),
+                        start: Instant::now(),
+                        released: false,
+                    });
+                }
+                if queued.is_none() {
+                    if state.queued >= self.config.max_queue {
+                        return Error::e_explain(HTTPStatus(503), "too many requests in flight");
+                    }
+                    state.queued += 1;
+                    queued = Some(Queued(self));
+                }
+            }
+            if tokio::time::timeout_at(deadline.into(), self.notify.notified())
+                .await
+                .is_err()
+            {
+                return Error::e_explain(HTTPStatus(503), "timed out waiting for a request in flight");
+            }
+        }
+    }
+
+    fn release(&self, start: Instant, now: Instant, latency: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        if let Some(aimd) = self.config.adaptive.as_ref() {
+            let overloaded = latency.is_none_or(|l| l > aimd.latency_threshold);
+            if overloaded {
+                // one cut per round of requests, not one per request of the round
+                if state.last_decrease.is_none_or(|t| start >= t) {
+                    state.limit = (state.limit * aimd.backoff).max(aimd.min_limit as f64);
+                    state.last_decrease = Some(now);
+                }
+            } else if state.in_flight * 2 >= state.limit() {
+                // only grow a limit which is actually used
+                state.limit = (state.limit + 1.0 / state.limit).min(aimd.max_limit as f64);
+            }
+        }
+        if state.queued > 0 && state.in_flight < state.limit() {
+            self.notify.notify_one();
+        }
+    }
+}
+
+/// a slot in the queue, freed when the wait is over, times out or is cancelled
+struct Queued<'a>(&'a ConcurrencyLimiter);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.queued -= 1;
+        // pass on the notification this waiter may have consumed
+        if state.queued > 0 && state.in_flight < state.limit() {
+            self.0.notify.notify_one();
+        }
+    }
+}
+
+/// the right to send a request, dropping it releases it without any feedback to the limit
+pub struct Permit {
+    limiter: Arc<ConcurrencyLimiter>,
+    start: Instant,
+    released: bool,
+}
+
+impl Permit {
+    /// the time since the permit was acquired
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// release the permit, `latency` is how long the upstream took to respond, `None` if it
+    /// failed
+    pub fn release(mut self, latency: Option<Duration>) {
+        self.released = true;
+        self.limiter.release(self.start, Instant::now(), latency);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.released {
+            let mut state = self.limiter.state.lock().unwrap();
+            state.in_flight -= 1;
+            if state.queued > 0 {
+                self.limiter.notify.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_in_flight: usize, max_queue: usize, adaptive: Option<AimdConfig>) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(ConcurrencyConfig {
+            max_in_flight,
+            max_queue,
+            queue_timeout: Duration::from_millis(100),
+            adaptive,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let limiter = limiter(2, 1, None);
+        let first = limiter.acquire().await.unwrap();
+        let _second = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.in_flight(), 2);
+
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.map(|_| ()) }
+        });
+        while limiter.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+        // the queue is full
+        let e = limiter.acquire().await.err().unwrap();
+        assert_eq!(e.etype(), &HTTPStatus(503));
+
+        drop(first);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(limiter.queued(), 0);
+        assert_eq!(limiter.in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_queue_timeout() {
+        let limiter = limiter(1, 1, None);
+        let _permit = limiter.acquire().await.unwrap();
+        let start = Instant::now();
+        let e = limiter.acquire().await.err().unwrap();
+        assert_eq!(e.etype(), &HTTPStatus(503));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(limiter.queued(), 0);
+
+        // no queue at all
+        let limiter = self::limiter(1, 0, None);
+        let _permit = limiter.acquire().await.unwrap();
+        assert!(limiter.acquire().await.is_err());
+    }
+
+    #[test]
+    fn test_aimd() {
+        let aimd = AimdConfig {
+            min_limit: 1,
+            max_limit: 5,
+            latency_threshold: Duration::from_millis(100),
+            backoff: 0.5,
+        };
+        let limiter = limiter(4, 0, Some(aimd));
+        let limit = || limiter.state.lock().unwrap().limit;
+        // release a request while the limit is used
+        let release = |start: Instant, now: Instant, latency: Option<Duration>| {
+            let mut state = limiter.state.lock().unwrap();
+            state.in_flight = state.limit() + 1;
+            drop(state);
+            limiter.release(start, now, latency);
+        };
+        let fast = Some(Duration::from_millis(10));
+        let slow = Some(Duration::from_millis(200));
+        let start = Instant::now();
+
+        // about `limit` requests answered in time grow the limit by one
+        for _ in 0..4 {
+            release(start, start, fast);
+        }
+        assert_eq!(limiter.limit(), 4);
+        release(start, start, fast);
+        assert_eq!(limiter.limit(), 5);
+        // up to the max
+        for _ in 0..10 {
+            release(start, start, fast);
+        }
+        assert_eq!(limit(), 5.0);
+
+        // an unused limit does not grow
+        limiter.state.lock().unwrap().limit = 4.0;
+        limiter.state.lock().unwrap().in_flight = 1;
+        limiter.release(start, start, fast);
+        assert_eq!(limit(), 4.0);
+
+        // the overloads of the same round cut the limit once
+        let now = start + Duration::from_secs(1);
+        release(start, now, slow);
+        assert_eq!(limit(), 2.0);
+        release(start, now, None);
+        assert_eq!(limit(), 2.0);
+        // a request started after the cut cuts it again, down to the min
+        release(now, now, slow);
+        assert_eq!(limit(), 1.0);
+        release(now + Duration::from_secs(1), now + Duration::from_secs(1), None);
+        assert_eq!(limit(), 1.0);
+    }
+}
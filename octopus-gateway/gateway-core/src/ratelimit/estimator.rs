@@ -0,0 +1,223 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! estimating the counts of many keys in a fixed amount of memory
+
+use super::sliding_window::{window_position, Estimate};
+use super::{Decision, RateLimiter};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// a count-min sketch: `depth` rows of `width` counters, a key counts in one counter of each row
+/// and its count is the min of them
+///
+/// the estimate is never below the real count, and above it only when all the counters of the
+/// key are shared with other keys
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    counters: Box<[AtomicU32]>,
+    hasher: RandomState,
+}
+
+impl CountMinSketch {
+    /// `width` and `depth` must be positive
+    pub fn new(width: usize, depth: usize) -> Self {
+        assert!(width > 0 && depth > 0, "the width and the depth must be positive");
+        CountMinSketch {
+            width,
+            depth,
+            counters: (0..width * depth).map(|_| AtomicU32::new(0)).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// the indexes of the counters of `key`, one per row
+    fn slots(&self, key: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        let hash = self.hasher.hash_one(key);
+        // the hashes of the rows are derived from the two halves of one hash
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..self.depth).map(move |row| {
+            row * self.width + (h1.wrapping_add(h2.wrapping_mul(row as u64)) % self.width as u64) as usize
+        })
+    }
+
+    /// add `n` to the count of `key`, return the new estimate
+    pub fn incr(&self, key: &[u8], n: u32) -> u32 {
+        self.slots(key)
+            .map(|i| self.counters[i].fetch_add(n, Ordering::Relaxed).saturating_add(n))
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// the estimated count of `key`
+    pub fn get(&self, key: &[u8]) -> u32 {
+        self.slots(key)
+            .map(|i| self.counters[i].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or_default()
+    }
+
+    pub fn clear(&self) {
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// a [SlidingWindow](super::SlidingWindow) over the estimated counts of two [CountMinSketch]es,
+/// one for the current fixed window and one for the previous
+///
+/// the memory is fixed whatever the number of keys, the keys may be limited a little early when
+/// the sketches are crowded
+pub struct EstimatedWindow {
+    limit: u64,
+    window: Duration,
+    origin: Instant,
+    /// the sketch of window `i` is `sketches[i % 2]`
+    sketches: [CountMinSketch; 2],
+    /// the index of the current window
+    index: AtomicU64,
+}
+
+impl EstimatedWindow {
+    /// `limit` and `window` must be positive, each sketch has `width * depth` counters
+    pub fn new(limit: u64, window: Duration, width: usize, depth: usize) -> Self {
+        assert!(
+            limit > 0 && !window.is_zero(),
+            "the limit and the window must be positive"
+        );
+        EstimatedWindow {
+            limit,
+            window,
+            origin: Instant::now(),
+            sketches: [CountMinSketch::new(width, depth), CountMinSketch::new(width, depth)],
+            index: AtomicU64::new(0),
+        }
+    }
+
+    /// move to the window `index`, clearing the sketches of the windows which are over
+    fn advance(&self, index: u64) {
+        let mut current = self.index.load(Ordering::Acquire);
+        while current < index {
+            match self
+                .index
+                .compare_exchange(current, index, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    // the requests counted meanwhile in the sketch being cleared may be lost
+                    self.sketches[(index % 2) as usize].clear();
+                    if index > current + 1 {
+                        self.sketches[((index + 1) % 2) as usize].clear();
+                    }
+                    return;
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn check_at(&self, key: &[u8], now: Instant) -> Decision {
+        let (index, elapsed) = window_position(self.origin, self.window, now);
+        self.advance(index);
+        let current = &self.sketches[(index % 2) as usize];
+        let previous = if index > 0 {
+            self.sketches[((index + 1) % 2) as usize].get(key)
+        } else {
+            0
+        };
+        let estimate = Estimate {
+            limit: self.limit,
+            window: self.window,
+            elapsed,
+            current: current.get(key) as u64,
+            previous: previous as u64,
+        };
+        let decision = estimate.decide();
+        if decision.allowed {
+            current.incr(key, 1);
+        }
+        decision
+    }
+}
+
+impl RateLimiter for EstimatedWindow {
+    fn check(&self, key: &[u8]) -> Decision {
+        self.check_at(key, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_count_min_sketch() {
+        let sketch = CountMinSketch::new(1024, 4);
+        assert_eq!(sketch.incr(b"a", 3), 3);
+        assert_eq!(sketch.incr(b"a", 1), 4);
+        assert_eq!(sketch.get(b"a"), 4);
+        assert_eq!(sketch.get(b"b"), 0);
+
+        // never under counted, even when crowded
+        let sketch = Arc::new(CountMinSketch::new(64, 4));
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let sketch = sketch.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000u32 {
+                        sketch.incr(format!("{t}-{}", i % 100).as_bytes(), 1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!((0..100).all(|i| sketch.get(format!("1-{i}").as_bytes()) >= 10));
+        sketch.clear();
+        assert_eq!(sketch.get(b"1-1"), 0);
+    }
+
+    #[test]
+    fn test_estimated_window() {
+        let limiter = EstimatedWindow::new(2, Duration::from_secs(10), 1024, 4);
+        let start = limiter.origin;
+        assert!(limiter.check_at(b"a", start).allowed);
+        assert!(limiter.check_at(b"a", start).allowed);
+        let decision = limiter.check_at(b"a", start + Duration::from_secs(1));
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_secs(14));
+        assert!(limiter.check_at(b"b", start).allowed);
+
+        // half of the previous window left
+        let decision = limiter.check_at(b"a", start + Duration::from_secs(15));
+        assert!(decision.allowed);
+        assert!(!limiter.check_at(b"a", start + Duration::from_secs(15)).allowed);
+        // the windows are over
+        let decision = limiter.check_at(b"a", start + Duration::from_secs(40));
+        assert_eq!(decision.remaining, 1);
+    }
+}
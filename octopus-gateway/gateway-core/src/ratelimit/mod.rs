@@ -0,0 +1,152 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! rate limiting
+//!
+//! a [RateLimiter] counts the requests per key, e.g. the client ip, an api key or a route, and
+//! decides whether each one is let through:
+//! - [TokenBucket] lets through bursts up to its capacity, refilled at a steady rate
+//! - [SlidingWindow] approximates the count over the last window from the counts of the current
+//!   and the previous fixed windows
+//! - [EstimatedWindow] is a sliding window over [CountMinSketch] counts, in a fixed amount of
+//!   memory however many keys there are, at the cost of over-counting on collisions
+//!
+//! a [ConcurrencyLimiter] bounds the requests in flight instead, whatever their rate, and can
+//! adapt its limit to the latency
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+mod concurrency;
+mod estimator;
+mod sliding_window;
+mod token_bucket;
+
+pub use concurrency::{AimdConfig, ConcurrencyConfig, ConcurrencyLimiter, Permit};
+pub use estimator::{CountMinSketch, EstimatedWindow};
+pub use sliding_window::SlidingWindow;
+pub use token_bucket::TokenBucket;
+
+/// the decision on a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    /// whether the request is let through
+    pub allowed: bool,
+    /// the number of requests the limit allows
+    pub limit: u64,
+    /// the number of requests left
+    pub remaining: u64,
+    /// how long until the quota is fully restored
+    pub reset: Duration,
+    /// how long until a request is let through again, zero if allowed
+    pub retry_after: Duration,
+}
+
+/// a limiter of the requests per key
+pub trait RateLimiter: Send + Sync {
+    /// count a request of `key` and decide whether it is let through
+    fn check(&self, key: &[u8]) -> Decision;
+}
+
+/// the number of shards the states of the keys are split into, to reduce the lock contention
+const SHARDS: usize = 16;
+
+/// the min number of keys of a shard before its idle states are swept
+const MIN_SWEEP: usize = 1024;
+
+/// the states of the keys, whose idle ones are dropped as the number of keys grows
+struct KeyStates<S> {
+    shards: Box<[Mutex<Shard<S>>]>,
+    hasher: RandomState,
+}
+
+struct Shard<S> {
+    states: HashMap<Vec<u8>, S>,
+    /// the number of keys at which the idle states are swept next
+    sweep_at: usize,
+}
+
+impl<S> KeyStates<S> {
+    fn new() -> Self {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    states: HashMap::new(),
+                    sweep_at: MIN_SWEEP,
+                })
+            })
+            .collect();
+        KeyStates {
+            shards,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// run `f` on the state of `key`, created by `new` if missing
+    ///
+    /// the states `idle` at `now` are dropped when the shard grows too large
+    fn with<R>(
+        &self,
+        key: &[u8],
+        now: Instant,
+        new: impl FnOnce() -> S,
+        idle: impl Fn(&S, Instant) -> bool,
+        f: impl FnOnce(&mut S) -> R,
+    ) -> R {
+        let mut shard = self.shards[self.hasher.hash_one(key) as usize % SHARDS].lock().unwrap();
+        if !shard.states.contains_key(key) && shard.states.len() >= shard.sweep_at {
+            shard.states.retain(|_, s| !idle(s, now));
+            shard.sweep_at = (shard.states.len() * 2).max(MIN_SWEEP);
+        }
+        let state = shard.states.entry(key.to_vec()).or_insert_with(new);
+        f(state)
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().states.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep() {
+        let states = KeyStates::<Instant>::new();
+        let start = Instant::now();
+        // a state is idle once a later key comes
+        let idle = |s: &Instant, now: Instant| now > *s;
+        let keys = SHARDS * MIN_SWEEP * 4;
+        for i in 0..keys {
+            let now = start + Duration::from_millis(i as u64);
+            states.with(i.to_string().as_bytes(), now, || now, idle, |_| {});
+        }
+        assert!(states.len() <= SHARDS * MIN_SWEEP, "{}", states.len());
+        // the latest state is kept
+        let last = start + Duration::from_millis(keys as u64 - 1);
+        assert!(states.with((keys - 1).to_string().as_bytes(), last, || start, idle, |s| *s == last));
+    }
+}
@@ -0,0 +1,200 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the sliding window counter algorithm
+
+use super::{Decision, KeyStates, RateLimiter};
+use std::time::{Duration, Instant};
+
+/// at most `limit` requests per key over any `window`, approximately
+///
+/// the time is split into fixed windows, the count over the last `window` is estimated as the
+/// count of the current window plus the count of the previous one weighted by how much of it
+/// overlaps the last `window`
+pub struct SlidingWindow {
+    limit: u64,
+    window: Duration,
+    /// where the fixed windows start
+    origin: Instant,
+    counters: KeyStates<Counter>,
+}
+
+struct Counter {
+    /// the index of the current window
+    index: u64,
+    current: u64,
+    previous: u64,
+}
+
+impl SlidingWindow {
+    /// `limit` and `window` must be positive
+    pub fn new(limit: u64, window: Duration) -> Self {
+        assert!(
+            limit > 0 && !window.is_zero(),
+            "the limit and the window must be positive"
+        );
+        SlidingWindow {
+            limit,
+            window,
+            origin: Instant::now(),
+            counters: KeyStates::new(),
+        }
+    }
+
+    /// the number of keys tracked
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn check_at(&self, key: &[u8], now: Instant) -> Decision {
+        let (index, elapsed) = window_position(self.origin, self.window, now);
+        let new = || Counter {
+            index,
+            current: 0,
+            previous: 0,
+        };
+        // nothing counted over the last two windows
+        let idle = |c: &Counter, now: Instant| window_position(self.origin, self.window, now).0 > c.index + 1;
+        self.counters.with(key, now, new, idle, |counter| {
+            if counter.index < index {
+                counter.previous = if counter.index + 1 == index { counter.current } else { 0 };
+                counter.current = 0;
+                counter.index = index;
+            }
+            let estimate = Estimate {
+                limit: self.limit,
+                window: self.window,
+                elapsed,
+                current: counter.current,
+                previous: counter.previous,
+            };
+            let decision = estimate.decide();
+            if decision.allowed {
+                counter.current += 1;
+            }
+            decision
+        })
+    }
+}
+
+impl RateLimiter for SlidingWindow {
+    fn check(&self, key: &[u8]) -> Decision {
+        self.check_at(key, Instant::now())
+    }
+}
+
+/// the index of the fixed window `now` is in, and how far into it
+pub(super) fn window_position(origin: Instant, window: Duration, now: Instant) -> (u64, Duration) {
+    let since = now.saturating_duration_since(origin).as_nanos();
+    let window_nanos = window.as_nanos();
+    let elapsed = Duration::from_nanos((since % window_nanos) as u64);
+    ((since / window_nanos) as u64, elapsed)
+}
+
+/// the estimated count over the last window
+pub(super) struct Estimate {
+    pub limit: u64,
+    pub window: Duration,
+    /// how far into the current fixed window
+    pub elapsed: Duration,
+    pub current: u64,
+    pub previous: u64,
+}
+
+impl Estimate {
+    /// whether one more request is let through
+    pub fn decide(&self) -> Decision {
+        let overlap = 1.0 - self.elapsed.as_secs_f64() / self.window.as_secs_f64();
+        let count = self.previous as f64 * overlap + self.current as f64;
+        let allowed = count + 1.0 <= self.limit as f64;
+        let remaining = (self.limit as f64 - count - allowed as u8 as f64).max(0.0) as u64;
+        let until_next = self.window - self.elapsed;
+        let retry_after = if allowed {
+            Duration::ZERO
+        } else if self.current + 1 > self.limit {
+            // the current window is full, its weight has to shrink as well
+            let left = self.limit.saturating_sub(1) as f64 / self.current as f64;
+            until_next + self.window.mul_f64(1.0 - left)
+        } else {
+            // the previous window has to slide out enough
+            let left = (self.limit - 1 - self.current) as f64 / self.previous as f64;
+            self.window.mul_f64(1.0 - left).saturating_sub(self.elapsed)
+        };
+        // the current window counts fully until it ends, then slides out over the next one
+        let reset = if self.current > 0 {
+            until_next + self.window
+        } else if self.previous > 0 {
+            until_next
+        } else {
+            Duration::ZERO
+        };
+        Decision {
+            allowed,
+            limit: self.limit,
+            remaining,
+            reset,
+            retry_after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sliding_window() {
+        let limiter = SlidingWindow::new(4, Duration::from_secs(10));
+        let start = limiter.origin;
+        for remaining in [3, 2, 1, 0] {
+            let decision = limiter.check_at(b"a", start + Duration::from_secs(5));
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let decision = limiter.check_at(b"a", start + Duration::from_secs(6));
+        assert!(!decision.allowed);
+        // the window ends in 4s, then 1/4 of it has to slide out
+        assert_eq!(decision.retry_after, Duration::from_millis(6500));
+        assert!(limiter.check_at(b"b", start + Duration::from_secs(6)).allowed);
+
+        // 4 counted in the previous window, weighted 0.76 at 2.4s into the next one
+        let decision = limiter.check_at(b"a", start + Duration::from_millis(12_400));
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_millis(100));
+        assert_eq!(decision.reset, Duration::from_millis(7600));
+        let decision = limiter.check_at(b"a", start + Duration::from_millis(12_600));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        let decision = limiter.check_at(b"a", start + Duration::from_secs(13));
+        assert!(!decision.allowed);
+        // 0.4 * 4 + 1 = 2.6, 1 more as 2 of the previous slide out at 15s
+        assert_eq!(decision.retry_after, Duration::from_secs(2));
+
+        // long idle
+        let decision = limiter.check_at(b"a", start + Duration::from_secs(60));
+        assert_eq!(decision.remaining, 3);
+    }
+}
@@ -0,0 +1,134 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the token bucket algorithm
+
+use super::{Decision, KeyStates, RateLimiter};
+use std::time::{Duration, Instant};
+
+/// a bucket of `capacity` tokens per key, refilled with `rate` tokens per `period`, each request
+/// takes a token
+pub struct TokenBucket {
+    capacity: u64,
+    /// tokens per second
+    rate: f64,
+    buckets: KeyStates<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// `rate` and `capacity` must be positive
+    pub fn new(rate: u64, period: Duration, capacity: u64) -> Self {
+        assert!(
+            rate > 0 && !period.is_zero() && capacity > 0,
+            "the rate and the capacity must be positive"
+        );
+        TokenBucket {
+            capacity,
+            rate: rate as f64 / period.as_secs_f64(),
+            buckets: KeyStates::new(),
+        }
+    }
+
+    /// the number of keys tracked
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn check_at(&self, key: &[u8], now: Instant) -> Decision {
+        let capacity = self.capacity as f64;
+        let new = || Bucket {
+            tokens: capacity,
+            updated: now,
+        };
+        // a full bucket is the same as a missing one
+        let idle =
+            |b: &Bucket, now: Instant| b.tokens + now.duration_since(b.updated).as_secs_f64() * self.rate >= capacity;
+        self.buckets.with(key, now, new, idle, |bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(capacity);
+            bucket.updated = now;
+            let allowed = bucket.tokens >= 1.0;
+            if allowed {
+                bucket.tokens -= 1.0;
+            }
+            let retry_after = if allowed {
+                0.0
+            } else {
+                (1.0 - bucket.tokens) / self.rate
+            };
+            Decision {
+                allowed,
+                limit: self.capacity,
+                remaining: bucket.tokens as u64,
+                reset: Duration::from_secs_f64((capacity - bucket.tokens) / self.rate),
+                retry_after: Duration::from_secs_f64(retry_after),
+            }
+        })
+    }
+}
+
+impl RateLimiter for TokenBucket {
+    fn check(&self, key: &[u8]) -> Decision {
+        self.check_at(key, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        // 8 per second, bursts of 3
+        let limiter = TokenBucket::new(8, Duration::from_secs(1), 3);
+        let now = Instant::now();
+        for remaining in [2, 1, 0] {
+            let decision = limiter.check_at(b"a", now);
+            assert!(decision.allowed);
+            assert_eq!((decision.limit, decision.remaining), (3, remaining));
+        }
+        let decision = limiter.check_at(b"a", now);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_millis(125));
+        assert_eq!(decision.reset, Duration::from_millis(375));
+        // the other keys have their own buckets
+        assert!(limiter.check_at(b"b", now).allowed);
+        assert_eq!(limiter.len(), 2);
+
+        // refilled
+        let decision = limiter.check_at(b"a", now + Duration::from_millis(150));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(!limiter.check_at(b"a", now + Duration::from_millis(160)).allowed);
+        let decision = limiter.check_at(b"a", now + Duration::from_secs(10));
+        assert_eq!(decision.remaining, 2);
+    }
+}
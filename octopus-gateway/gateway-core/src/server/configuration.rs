@@ -0,0 +1,64 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the configuration of the server
+
+use std::path::PathBuf;
+
+/// the settings of the [super::Server]
+#[derive(Debug, Clone)]
+pub struct ServerConf {
+    /// the number of worker threads of each service, unless the service sets its own
+    pub threads: usize,
+    /// run in the background
+    pub daemon: bool,
+    /// where the pid of the server is written
+    pub pid_file: PathBuf,
+    /// where stdout and stderr go when the server runs in the background, `/dev/null` if not set
+    pub error_log: Option<PathBuf>,
+    /// the user to switch to once the listening sockets are bound
+    pub user: Option<String>,
+    /// the group to switch to, the primary group of [Self::user] if not set
+    pub group: Option<String>,
+    /// how long a service may take to drain its in flight work after it is told to shut down
+    pub grace_period_seconds: Option<u64>,
+    /// how long to wait for the remaining tasks of a service once the grace period is over
+    pub graceful_shutdown_timeout_seconds: Option<u64>,
+    /// the unix domain socket the listening sockets are handed over on upgrade
+    pub upgrade_sock: String,
+}
+
+impl Default for ServerConf {
+    fn default() -> Self {
+        ServerConf {
+            threads: 1,
+            daemon: false,
+            pid_file: PathBuf::from("/tmp/octopus.pid"),
+            error_log: None,
+            user: None,
+            group: None,
+            grace_period_seconds: Some(60),
+            graceful_shutdown_timeout_seconds: Some(5),
+            upgrade_sock: "/tmp/octopus_upgrade.sock".to_string(),
+        }
+    }
+}
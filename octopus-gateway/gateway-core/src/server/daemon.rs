@@ -0,0 +1,220 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! run the server in the background as an unprivileged user
+
+use super::configuration::ServerConf;
+use gateway_error::{Error, ErrorType::*, OkOrErr, OrErr, Result};
+use log::{info, warn};
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::{Gid, Group, Uid, User};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// the user and group the server switches to once the listening sockets are bound
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    user: Option<CString>,
+    pub uid: Option<Uid>,
+    pub gid: Gid,
+}
+
+impl Credentials {
+    /// look up the configured user and group, `None` if neither is set
+    ///
+    /// the group defaults to the primary group of the user
+    pub fn from_conf(conf: &ServerConf) -> Result<Option<Self>> {
+        let user = match conf.user.as_deref() {
+            Some(name) => Some(
+                User::from_name(name)
+                    .or_err_with(InternalError, || format!("failed to look up user {name}"))?
+                    .or_err_with(InternalError, || format!("unknown user {name}"))?,
+            ),
+            None => None,
+        };
+        let gid = match (conf.group.as_deref(), user.as_ref()) {
+            (Some(name), _) => {
+                Group::from_name(name)
+                    .or_err_with(InternalError, || format!("failed to look up group {name}"))?
+                    .or_err_with(InternalError, || format!("unknown group {name}"))?
+                    .gid
+            }
+            (None, Some(user)) => user.gid,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Credentials {
+            uid: user.as_ref().map(|u| u.uid),
+            user: user.map(|u| CString::new(u.name).expect("user name has no nul")),
+            gid,
+        }))
+    }
+
+    /// switch the process to the user and group, must be called before any thread is started
+    pub fn switch(&self) -> Result<()> {
+        // the supplementary groups go first, they can't be changed without root
+        match self.user.as_ref() {
+            Some(user) => nix::unistd::initgroups(user, self.gid),
+            None => nix::unistd::setgroups(&[self.gid]),
+        }
+        .or_err(InternalError, "failed to set the supplementary groups")?;
+        nix::unistd::setgid(self.gid).or_err_with(InternalError, || format!("failed to set gid {}", self.gid))?;
+        if let Some(uid) = self.uid {
+            nix::unistd::setuid(uid).or_err_with(InternalError, || format!("failed to set uid {uid}"))?;
+        }
+        info!("switched to uid {:?} gid {}", self.uid, self.gid);
+        Ok(())
+    }
+}
+
+/// detach the process from the terminal and keep running in the background
+///
+/// the parent exits right away. stdin is redirected to `/dev/null`, stdout and stderr to
+/// [ServerConf::error_log] or `/dev/null`. must be called before any thread is started
+pub fn daemonize(conf: &ServerConf) -> Result<()> {
+    use nix::unistd::{fork, setsid, ForkResult};
+
+    // the log file is opened before forking, so an error still reaches the terminal
+    let dev_null = File::open("/dev/null").or_err(FileOpenError, "failed to open /dev/null")?;
+    let output = match conf.error_log.as_ref() {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .or_err_with(FileOpenError, || format!("failed to open {}", path.display()))?,
+        None => OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .or_err(FileOpenError, "failed to open /dev/null")?,
+    };
+
+    // SAFETY: no other thread is running yet
+    match unsafe { fork() }.or_err(InternalError, "failed to fork")? {
+        ForkResult::Parent { .. } => std::process::exit(0),
+        ForkResult::Child => {}
+    }
+    setsid().or_err(InternalError, "failed to create a new session")?;
+    // fork again so the daemon, no longer a session leader, never gets a terminal back
+    match unsafe { fork() }.or_err(InternalError, "failed to fork")? {
+        ForkResult::Parent { .. } => std::process::exit(0),
+        ForkResult::Child => {}
+    }
+    std::env::set_current_dir("/").or_err(InternalError, "failed to change the directory to /")?;
+
+    for (from, to) in [(&dev_null, 0), (&output, 1), (&output, 2)] {
+        nix::unistd::dup2(from.as_raw_fd(), to).or_err(InternalError, "failed to redirect the standard io")?;
+    }
+    Ok(())
+}
+
+fn lock_and_write(file: File, arg: FlockArg) -> std::result::Result<Flock<File>, (File, nix::Error)> {
+    let mut lock = Flock::lock(file, arg)?;
+    let pid = std::process::id();
+    // the content is only replaced once the lock is held
+    let written = lock
+        .set_len(0)
+        .and_then(|_| writeln!(lock, "{pid}"))
+        .and_then(|_| lock.sync_all());
+    if let Err(e) = written {
+        warn!("failed to write the pid file: {e}");
+    }
+    Ok(lock)
+}
+
+/// write the pid to `path` and hold an exclusive lock on it for the lifetime of the process
+///
+/// fails if another process holds the lock, unless `upgrade` is set: the old process keeps the
+/// lock while it drains, so the pid is written in the background once it exits
+pub fn write_pid_file(path: &Path, upgrade: bool) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .or_err_with(FileOpenError, || format!("failed to open the pid file {}", path.display()))?;
+
+    match lock_and_write(file, FlockArg::LockExclusiveNonblock) {
+        // the lock is released when the process exits
+        Ok(lock) => std::mem::forget(lock),
+        Err((file, nix::Error::EWOULDBLOCK)) if upgrade => {
+            info!("waiting for the old process to release {}", path.display());
+            std::thread::spawn(move || match lock_and_write(file, FlockArg::LockExclusive) {
+                Ok(lock) => std::mem::forget(lock),
+                Err((_, e)) => warn!("failed to lock the pid file: {e}"),
+            });
+        }
+        Err((_, nix::Error::EWOULDBLOCK)) => {
+            return Error::e_explain(
+                InternalError,
+                format!("{} is locked, is another instance running?", path.display()),
+            );
+        }
+        Err((_, e)) => {
+            return Err(e).or_err_with(InternalError, || format!("failed to lock the pid file {}", path.display()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.pid");
+        std::fs::write(&path, "12345678\n").unwrap();
+        write_pid_file(&path, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        // another open file description conflicts with the lock
+        assert!(write_pid_file(&path, false).is_err());
+        // but an upgrade waits instead
+        write_pid_file(&path, true).unwrap();
+    }
+
+    #[test]
+    fn test_credentials() {
+        let conf = ServerConf::default();
+        assert!(Credentials::from_conf(&conf).unwrap().is_none());
+
+        let conf = ServerConf {
+            user: Some("root".into()),
+            ..Default::default()
+        };
+        let creds = Credentials::from_conf(&conf).unwrap().unwrap();
+        assert_eq!(creds.uid, Some(Uid::from_raw(0)));
+        assert_eq!(creds.gid, Gid::from_raw(0));
+
+        let conf = ServerConf {
+            group: Some("no-such-group-octopus".into()),
+            ..Default::default()
+        };
+        assert!(Credentials::from_conf(&conf).is_err());
+    }
+}
@@ -0,0 +1,308 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the server which runs the services
+
+pub mod configuration;
+pub mod daemon;
+pub mod transfer_fd;
+
+use crate::services::Service;
+use configuration::ServerConf;
+use daemon::Credentials;
+use gateway_error::Result;
+use log::{error, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use transfer_fd::Fds;
+
+/// the receiver side of the shutdown notification, the value turns true when the service should
+/// shut down
+pub type ShutdownWatch = watch::Receiver<bool>;
+
+/// a service started by the server, together with its own runtime
+struct RunningService {
+    name: String,
+    runtime: Runtime,
+    shutdown: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+/// how the services are shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShutdownType {
+    /// stop accepting and wait for the in flight requests up to the grace period
+    Graceful,
+    /// stop right away
+    Fast,
+}
+
+/// the server owns the services and manages their lifecycle
+///
+/// each service runs in its own runtime with its own worker threads. on shutdown the services
+/// are stopped one by one in the reverse order they are added, so a service can depend on the
+/// ones added before it, e.g. a proxy service on the background health checks of its upstreams
+///
+/// # signals
+/// - `SIGTERM`: graceful shutdown, stop accepting and drain the in flight requests
+/// - `SIGINT`: fast shutdown
+/// - `SIGQUIT`: graceful upgrade, hand the listening sockets over to the new process waiting on
+///   [ServerConf::upgrade_sock], then shut down gracefully
+///
+/// to upgrade without dropping connections, start the new process with [Server::upgrade] set,
+/// then send `SIGQUIT` to the old one
+///
+/// # privileges
+/// the listening sockets are bound first, so that ports below 1024 can be used, then the server
+/// optionally daemonizes, writes the pid file and switches to [ServerConf::user] before any
+/// service starts
+pub struct Server {
+    services: Vec<Box<dyn Service>>,
+    listen_fds: Fds,
+    credentials: Option<Credentials>,
+    pub configuration: Arc<ServerConf>,
+    /// receive the listening sockets from the old process instead of binding them
+    pub upgrade: bool,
+}
+
+impl Server {
+    pub fn new(conf: ServerConf) -> Server {
+        Server {
+            services: vec![],
+            listen_fds: Fds::new(),
+            credentials: None,
+            configuration: Arc::new(conf),
+            upgrade: false,
+        }
+    }
+
+    pub fn add_service(&mut self, service: impl Service + 'static) {
+        self.services.push(Box::new(service));
+    }
+
+    pub fn add_services(&mut self, services: Vec<Box<dyn Service>>) {
+        self.services.extend(services);
+    }
+
+    /// prepare the services before starting them, e.g. bind the listening sockets
+    pub fn bootstrap(&mut self) -> Result<()> {
+        self.credentials = Credentials::from_conf(&self.configuration)?;
+        if self.upgrade {
+            self.listen_fds = Fds::get_from_sock(&self.configuration.upgrade_sock, self.credentials.as_ref())?;
+        }
+        for service in self.services.iter_mut() {
+            service.bind(&mut self.listen_fds)?;
+        }
+        Ok(())
+    }
+
+    /// bootstrap and start all the services, then block until a shutdown signal is received
+    ///
+    /// the process exits when all the services stop
+    pub fn run_forever(mut self) -> ! {
+        info!("server starting");
+        if let Err(e) = self.bootstrap().and_then(|_| self.daemonize()) {
+            error!("failed to bootstrap the server: {e}");
+            std::process::exit(1);
+        }
+        let running = self.start_services();
+
+        let signal_runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to create the signal runtime");
+        let shutdown_type = signal_runtime.block_on(self.main_loop());
+
+        self.shutdown_services(running, shutdown_type);
+        info!("all services exited, server stopped");
+        std::process::exit(0)
+    }
+
+    /// run in the background, write the pid file and drop the privileges as configured
+    ///
+    /// must be called after the services are bound and before they start
+    fn daemonize(&self) -> Result<()> {
+        if self.configuration.daemon {
+            daemon::daemonize(&self.configuration)?;
+        }
+        daemon::write_pid_file(&self.configuration.pid_file, self.upgrade)?;
+        if let Some(credentials) = self.credentials.as_ref() {
+            credentials.switch()?;
+        }
+        Ok(())
+    }
+
+    /// wait for the signals until the server should shut down
+    async fn main_loop(&self) -> ShutdownType {
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to register SIGTERM");
+        let mut interrupt = signal(SignalKind::interrupt()).expect("failed to register SIGINT");
+        let mut quit = signal(SignalKind::quit()).expect("failed to register SIGQUIT");
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => {
+                    info!("SIGTERM received, shutting down gracefully");
+                    return ShutdownType::Graceful;
+                }
+                _ = interrupt.recv() => {
+                    info!("SIGINT received, shutting down");
+                    return ShutdownType::Fast;
+                }
+                _ = quit.recv() => {
+                    info!("SIGQUIT received, handing the listening sockets over");
+                    let fds = self.listen_fds.clone();
+                    let path = self.configuration.upgrade_sock.clone();
+                    match tokio::task::spawn_blocking(move || fds.send_to_sock(path)).await {
+                        Ok(Ok(())) => return ShutdownType::Graceful,
+                        // keep serving, otherwise nobody would accept on the sockets
+                        Ok(Err(e)) => error!("upgrade failed, keep running: {e}"),
+                        Err(e) => error!("upgrade failed, keep running: {e}"),
+                    }
+                }
+            }
+        }
+    }
+
+    fn create_runtime(name: &str, threads: usize) -> Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .worker_threads(threads.max(1))
+            .thread_name(name)
+            .build()
+            .expect("failed to create the runtime")
+    }
+
+    fn start_services(&mut self) -> Vec<RunningService> {
+        self.services
+            .drain(..)
+            .map(|mut service| {
+                let name = service.name().to_string();
+                let threads = service.threads().unwrap_or(self.configuration.threads);
+                let runtime = Self::create_runtime(&name, threads);
+                let (shutdown, watch) = watch::channel(false);
+                info!("starting service {name} with {threads} threads");
+                let handle = runtime.spawn(async move {
+                    service.start_service(watch).await;
+                });
+                RunningService {
+                    name,
+                    runtime,
+                    shutdown,
+                    handle,
+                }
+            })
+            .collect()
+    }
+
+    fn shutdown_services(&self, running: Vec<RunningService>, shutdown_type: ShutdownType) {
+        let (grace_period, timeout) = match shutdown_type {
+            ShutdownType::Graceful => (
+                self.configuration.grace_period_seconds.map(Duration::from_secs),
+                self.configuration
+                    .graceful_shutdown_timeout_seconds
+                    .map_or(Duration::ZERO, Duration::from_secs),
+            ),
+            ShutdownType::Fast => (Some(Duration::ZERO), Duration::ZERO),
+        };
+        for service in running.into_iter().rev() {
+            info!("shutting down service {}", service.name);
+            // the service may have exited already, nothing to notify
+            let _ = service.shutdown.send(true);
+            let finished = match grace_period {
+                Some(t) => service
+                    .runtime
+                    .block_on(async { tokio::time::timeout(t, service.handle).await.is_ok() }),
+                None => service.runtime.block_on(service.handle).is_ok(),
+            };
+            if !finished && shutdown_type == ShutdownType::Graceful {
+                warn!("service {} did not drain in time", service.name);
+            }
+            service.runtime.shutdown_timeout(timeout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::background::{background_service, BackgroundService};
+    use crate::server::ShutdownWatch;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    struct Recorder {
+        name: &'static str,
+        stopped: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl BackgroundService for Recorder {
+        async fn start(&self, mut shutdown: ShutdownWatch) {
+            let _ = shutdown.changed().await;
+            self.stopped.lock().unwrap().push(self.name);
+        }
+    }
+
+    #[test]
+    fn test_shutdown_in_order() {
+        let stopped = Arc::new(Mutex::new(vec![]));
+        let mut server = Server::new(ServerConf::default());
+        for name in ["first", "second", "third"] {
+            server.add_service(background_service(
+                name,
+                Recorder {
+                    name,
+                    stopped: stopped.clone(),
+                },
+            ));
+        }
+        server.bootstrap().unwrap();
+        let running = server.start_services();
+        assert_eq!(running.len(), 3);
+        server.shutdown_services(running, ShutdownType::Graceful);
+        assert_eq!(*stopped.lock().unwrap(), vec!["third", "second", "first"]);
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        struct Stuck;
+        #[async_trait]
+        impl BackgroundService for Stuck {
+            async fn start(&self, _shutdown: ShutdownWatch) {
+                std::future::pending::<()>().await;
+            }
+        }
+
+        let mut server = Server::new(ServerConf {
+            grace_period_seconds: Some(0),
+            graceful_shutdown_timeout_seconds: Some(0),
+            ..Default::default()
+        });
+        server.add_service(background_service("stuck", Stuck));
+        let running = server.start_services();
+        // returns even though the service never exits
+        server.shutdown_services(running, ShutdownType::Graceful);
+    }
+}
@@ -0,0 +1,243 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! hand the listening sockets over to a new process
+//!
+//! on upgrade the new process listens to a unix domain socket, the old process connects to it
+//! and sends the file descriptors of all its listening sockets with `SCM_RIGHTS`, together with
+//! the addresses they are bound to. the new process then accepts on the very same sockets, so
+//! no connection is refused while the old process drains
+
+use super::daemon::Credentials;
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use log::{debug, info};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use std::collections::HashMap;
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// the max number of listening sockets that can be handed over
+const MAX_FDS: usize = 32;
+/// the max size of the addresses of the sockets
+const MAX_PAYLOAD: usize = 4096;
+/// how long the new process waits for the old one to send the sockets
+const RECV_TIMEOUT: Duration = Duration::from_secs(60);
+/// how long the old process tries to reach the new one
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the table of the listening sockets, keyed by the address they are bound to
+///
+/// the table does not own the file descriptors, the listeners do
+#[derive(Debug, Default, Clone)]
+pub struct Fds {
+    map: HashMap<String, RawFd>,
+}
+
+impl Fds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, bind: String, fd: RawFd) {
+        self.map.insert(bind, fd);
+    }
+
+    /// take the socket bound to `bind`, the caller becomes the owner of the file descriptor
+    pub fn take(&mut self, bind: &str) -> Option<RawFd> {
+        self.map.remove(bind)
+    }
+
+    pub fn get(&self, bind: &str) -> Option<&RawFd> {
+        self.map.get(bind)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    fn serialize(&self) -> (String, Vec<RawFd>) {
+        let mut binds = Vec::with_capacity(self.map.len());
+        let mut fds = Vec::with_capacity(self.map.len());
+        for (bind, fd) in self.map.iter() {
+            binds.push(bind.as_str());
+            fds.push(*fd);
+        }
+        (binds.join(" "), fds)
+    }
+
+    fn deserialize(binds: &str, fds: Vec<RawFd>) -> Result<Self> {
+        let binds: Vec<&str> = binds.split_whitespace().collect();
+        if binds.len() != fds.len() {
+            return Error::e_explain(
+                ReadError,
+                format!("received {} addresses but {} sockets", binds.len(), fds.len()),
+            );
+        }
+        Ok(Fds {
+            map: binds.into_iter().map(String::from).zip(fds).collect(),
+        })
+    }
+
+    /// send the sockets to the process listening on `path`
+    pub fn send_to_sock<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let (binds, fds) = self.serialize();
+        if fds.len() > MAX_FDS || binds.len() > MAX_PAYLOAD {
+            return Error::e_explain(InternalError, format!("too many listening sockets: {}", fds.len()));
+        }
+
+        // the new process may not be listening yet
+        let deadline = Instant::now() + SEND_TIMEOUT;
+        let stream = loop {
+            match UnixStream::connect(path) {
+                Ok(s) => break s,
+                Err(e) if Instant::now() < deadline => {
+                    debug!("failed to connect to {}: {e}, retrying", path.display());
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    return Err(e)
+                        .or_err_with(ConnectError, || format!("failed to connect to {}", path.display()));
+                }
+            }
+        };
+
+        let iov = [IoSlice::new(binds.as_bytes())];
+        let cmsg = [ControlMessage::ScmRights(&fds)];
+        sendmsg::<()>(stream.as_raw_fd(), &iov, &cmsg, MsgFlags::empty(), None)
+            .or_err(WriteError, "failed to send the listening sockets")?;
+        info!("sent {} listening sockets to {}", fds.len(), path.display());
+        Ok(())
+    }
+
+    /// wait for the old process to send its sockets to `path`
+    ///
+    /// the socket is handed to `owner` if set, which the old process runs as after dropping its
+    /// privileges
+    pub fn get_from_sock<P: AsRef<Path>>(path: P, owner: Option<&Credentials>) -> Result<Self> {
+        let path = path.as_ref();
+        // a leftover from a previous upgrade
+        if path.exists() {
+            std::fs::remove_file(path).or_err_with(BindError, || format!("failed to remove {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path).or_err_with(BindError, || format!("failed to bind {}", path.display()))?;
+        if let Some(owner) = owner {
+            nix::unistd::chown(path, owner.uid, Some(owner.gid))
+                .or_err_with(BindError, || format!("failed to change the owner of {}", path.display()))?;
+        }
+        listener
+            .set_nonblocking(true)
+            .or_err(BindError, "failed to set the upgrade socket non-blocking")?;
+
+        info!("waiting for the listening sockets on {}", path.display());
+        let deadline = Instant::now() + RECV_TIMEOUT;
+        let stream = loop {
+            match listener.accept() {
+                Ok((s, _)) => break s,
+                Err(e) if e.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    let _ = std::fs::remove_file(path);
+                    return Err(e).or_err(AcceptError, "failed to receive the listening sockets");
+                }
+            }
+        };
+        let _ = std::fs::remove_file(path);
+        stream
+            .set_nonblocking(false)
+            .or_err(ReadError, "failed to set the upgrade connection blocking")?;
+        stream
+            .set_read_timeout(Some(SEND_TIMEOUT))
+            .or_err(ReadError, "failed to set the read timeout")?;
+
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS]);
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let msg = recvmsg::<()>(stream.as_raw_fd(), &mut iov, Some(&mut cmsg_buf), MsgFlags::empty())
+            .or_err(ReadError, "failed to receive the listening sockets")?;
+        let mut fds = vec![];
+        for cmsg in msg.cmsgs().or_err(ReadError, "invalid control message")? {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                fds.extend(received);
+            }
+        }
+        let len = msg.bytes;
+        let binds = std::str::from_utf8(&buf[..len]).or_err(ReadError, "invalid socket addresses")?;
+        let fds = Self::deserialize(binds, fds)?;
+        info!("received {} listening sockets", fds.len());
+        Ok(fds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::FromRawFd;
+
+    #[test]
+    fn test_serialize() {
+        let mut fds = Fds::new();
+        fds.add("tcp://127.0.0.1:80".into(), 3);
+        fds.add("tcp://[::1]:443".into(), 4);
+        let (binds, raw) = fds.serialize();
+        let fds = Fds::deserialize(&binds, raw).unwrap();
+        assert_eq!(fds.get("tcp://127.0.0.1:80"), Some(&3));
+        assert_eq!(fds.get("tcp://[::1]:443"), Some(&4));
+
+        assert!(Fds::deserialize("tcp://127.0.0.1:80", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upgrade.sock");
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut fds = Fds::new();
+        fds.add("tcp://a".into(), listener.as_raw_fd());
+
+        let receiver = {
+            let path = path.clone();
+            std::thread::spawn(move || Fds::get_from_sock(path, None).unwrap())
+        };
+        fds.send_to_sock(&path).unwrap();
+        let mut received = receiver.join().unwrap();
+
+        // the received socket is the same listening socket
+        let fd = received.take("tcp://a").unwrap();
+        assert_ne!(fd, listener.as_raw_fd());
+        let received = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        assert_eq!(received.local_addr().unwrap(), addr);
+        drop(listener);
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        received.accept().unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the services that run a task in the background
+
+use super::Service;
+use crate::server::ShutdownWatch;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// the trait of the background tasks
+#[async_trait]
+pub trait BackgroundService {
+    /// run the task until `shutdown` turns true
+    async fn start(&self, shutdown: ShutdownWatch);
+}
+
+/// a [Service] that runs a [BackgroundService]
+pub struct GenBackgroundService<A> {
+    name: String,
+    task: Arc<A>,
+    pub threads: Option<usize>,
+}
+
+impl<A> GenBackgroundService<A> {
+    pub fn new(name: String, task: Arc<A>) -> Self {
+        GenBackgroundService {
+            name,
+            task,
+            // the background tasks are usually light
+            threads: Some(1),
+        }
+    }
+
+    /// the task, which can be shared with the other services, e.g. the load balancer whose
+    /// health is checked in the background
+    pub fn task(&self) -> Arc<A> {
+        self.task.clone()
+    }
+}
+
+#[async_trait]
+impl<A> Service for GenBackgroundService<A>
+where
+    A: BackgroundService + Send + Sync + 'static,
+{
+    async fn start_service(&mut self, shutdown: ShutdownWatch) {
+        self.task.start(shutdown).await;
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.threads
+    }
+}
+
+/// create a [GenBackgroundService] for the task
+pub fn background_service<A>(name: &str, task: A) -> GenBackgroundService<A> {
+    GenBackgroundService::new(format!("BG {name}"), Arc::new(task))
+}
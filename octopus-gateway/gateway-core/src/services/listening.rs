@@ -0,0 +1,192 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the services that accept connections on their listeners
+
+use super::Service;
+use crate::apps::ServerApp;
+use crate::listeners::{BoundListener, Listener, Listeners, ServerAddress, TcpSocketOptions};
+use crate::protocols::tls::server::TlsSettings;
+use crate::server::transfer_fd::Fds;
+use crate::server::ShutdownWatch;
+use async_trait::async_trait;
+use gateway_error::Result;
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// held by every connection in flight, the service is drained when all of them are dropped
+type InFlight = mpsc::Sender<()>;
+
+/// a [Service] that accepts connections and hands them to the app `A`
+pub struct ListeningService<A> {
+    name: String,
+    listeners: Listeners,
+    bound: Vec<BoundListener>,
+    app_logic: Option<A>,
+    pub threads: Option<usize>,
+}
+
+impl<A> ListeningService<A> {
+    pub fn new(name: String, app_logic: A) -> Self {
+        ListeningService {
+            name,
+            listeners: Listeners::new(),
+            bound: vec![],
+            app_logic: Some(app_logic),
+            threads: None,
+        }
+    }
+
+    pub fn with_listeners(name: String, listeners: Listeners, app_logic: A) -> Self {
+        let mut service = Self::new(name, app_logic);
+        service.listeners = listeners;
+        service
+    }
+
+    pub fn add_tcp(&mut self, addr: &str) {
+        self.listeners.add_tcp(addr);
+    }
+
+    pub fn add_tcp_with_settings(&mut self, addr: &str, options: TcpSocketOptions) {
+        self.listeners.add_tcp_with_settings(addr, options);
+    }
+
+    pub fn add_uds(&mut self, path: &str, mode: Option<u32>) {
+        self.listeners.add_uds(path, mode);
+    }
+
+    pub fn add_tls(&mut self, addr: &str, cert_path: &str, key_path: &str) -> Result<()> {
+        self.listeners.add_tls(addr, cert_path, key_path)
+    }
+
+    pub fn add_tls_with_settings(
+        &mut self,
+        addr: &str,
+        options: Option<TcpSocketOptions>,
+        settings: TlsSettings,
+    ) -> Result<()> {
+        self.listeners.add_tls_with_settings(addr, options, settings)
+    }
+
+    pub fn add_address(&mut self, addr: ServerAddress) {
+        self.listeners.add_address(addr);
+    }
+
+    pub fn listeners(&self) -> &Listeners {
+        &self.listeners
+    }
+
+    /// the addresses that are actually bound, only available after [Service::bind()]
+    pub fn bound_addrs(&self) -> Vec<std::net::SocketAddr> {
+        self.bound.iter().filter_map(|b| b.local_addr()).collect()
+    }
+
+    /// the app of the service, `None` once the service is started
+    pub fn app_logic(&self) -> Option<&A> {
+        self.app_logic.as_ref()
+    }
+
+    pub fn app_logic_mut(&mut self) -> Option<&mut A> {
+        self.app_logic.as_mut()
+    }
+}
+
+impl<A: ServerApp + Send + Sync + 'static> ListeningService<A> {
+    async fn run_endpoint(app: Arc<A>, listener: Listener, mut shutdown: ShutdownWatch, in_flight: InFlight) {
+        info!("listening on {}", listener.address());
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    info!("stop accepting on {}", listener.address());
+                    return;
+                }
+                accepted = listener.accept() => match accepted {
+                    Ok(stream) => {
+                        let app = app.clone();
+                        let shutdown = shutdown.clone();
+                        let in_flight = in_flight.clone();
+                        tokio::spawn(async move {
+                            match stream.handshake().await {
+                                Ok(stream) => {
+                                    app.process_new(stream, &shutdown).await;
+                                }
+                                Err(e) => debug!("{e}"),
+                            }
+                            drop(in_flight);
+                        });
+                    }
+                    Err(e) => {
+                        // e.g. out of file descriptors, back off a little
+                        error!("{e}");
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> Service for ListeningService<A> {
+    fn bind(&mut self, fds: &mut Fds) -> Result<()> {
+        if self.bound.is_empty() {
+            self.bound = self.listeners.bind(fds)?;
+        }
+        Ok(())
+    }
+
+    async fn start_service(&mut self, shutdown: ShutdownWatch) {
+        let app = Arc::new(self.app_logic.take().expect("the service is started twice"));
+        let (in_flight, mut drained) = mpsc::channel(1);
+        let mut endpoints = vec![];
+        for bound in self.bound.drain(..) {
+            match bound.listen() {
+                Ok(listener) => endpoints.push(tokio::spawn(Self::run_endpoint(
+                    app.clone(),
+                    listener,
+                    shutdown.clone(),
+                    in_flight.clone(),
+                ))),
+                Err(e) => error!("{e}"),
+            }
+        }
+        futures::future::join_all(endpoints).await;
+
+        // no more new connections, wait for the ones in flight to finish. idle keepalive
+        // connections are closed right away, the others after their current request
+        drop(in_flight);
+        info!("draining the connections of {}", self.name);
+        let _ = drained.recv().await;
+        info!("{} drained", self.name);
+        app.cleanup().await;
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.threads
+    }
+}
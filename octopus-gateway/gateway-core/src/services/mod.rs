@@ -0,0 +1,63 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the services run by the [crate::server::Server]
+//!
+//! a service is either a listening service which accepts connections and hands them to a
+//! [crate::apps::ServerApp], or a background service which runs a task for the whole lifetime of
+//! the server, e.g. health checks and service discovery
+
+pub mod background;
+pub mod listening;
+
+use crate::server::transfer_fd::Fds;
+use crate::server::ShutdownWatch;
+use async_trait::async_trait;
+use gateway_error::Result;
+
+/// the trait of everything the [crate::server::Server] runs
+#[async_trait]
+pub trait Service: Sync + Send {
+    /// bind the listening sockets of the service
+    ///
+    /// called for all the services before any of them starts, so that a failure aborts the
+    /// server before it serves anything. the sockets handed over by the old process on upgrade
+    /// are in `fds`, the service should reuse them and add the ones it binds itself
+    fn bind(&mut self, _fds: &mut Fds) -> Result<()> {
+        Ok(())
+    }
+
+    /// run the service until `shutdown` turns true
+    ///
+    /// the service should stop taking new work then, finish what is in flight and return
+    ///
+    /// this function runs in the dedicated runtime of the service
+    async fn start_service(&mut self, shutdown: ShutdownWatch);
+
+    /// the name of the service, used for logging and naming the threads
+    fn name(&self) -> &str;
+
+    /// the number of worker threads of the service, the server default is used if `None`
+    fn threads(&self) -> Option<usize> {
+        None
+    }
+}
@@ -0,0 +1,101 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the definition of the upstream peers
+
+use crate::lb::Backend;
+use crate::protocols::tls::CertifiedKey;
+use rustls::pki_types::CertificateDer;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// the trusted root certs to verify a peer with
+pub type CaBundle = Arc<Vec<CertificateDer<'static>>>;
+
+/// the options to connect to and talk with a peer
+#[derive(Debug, Clone)]
+pub struct PeerOptions {
+    /// the timeout of establishing the connection, and then of the TLS handshake if any
+    pub connection_timeout: Option<Duration>,
+    /// the timeout of each read from the peer
+    pub read_timeout: Option<Duration>,
+    /// the timeout of each write to the peer
+    pub write_timeout: Option<Duration>,
+    /// verify the cert chain of the peer, only turn it off in test environments
+    pub verify_cert: bool,
+    /// verify that the cert of the peer is valid for its SNI
+    pub verify_hostname: bool,
+    /// the roots to verify the peer with, the webpki roots if not set
+    pub ca: Option<CaBundle>,
+    /// the cert to authenticate to the peer with
+    pub client_cert: Option<Arc<CertifiedKey>>,
+}
+
+impl Default for PeerOptions {
+    fn default() -> Self {
+        PeerOptions {
+            connection_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            verify_cert: true,
+            verify_hostname: true,
+            ca: None,
+            client_cert: None,
+        }
+    }
+}
+
+/// a http upstream server
+#[derive(Debug, Clone)]
+pub struct HttpPeer {
+    pub address: SocketAddr,
+    /// connect over TLS
+    pub tls: bool,
+    /// the server name of the peer, sent in the SNI and the cert of the peer is verified against
+    pub sni: String,
+    pub options: PeerOptions,
+}
+
+impl HttpPeer {
+    pub fn new(address: SocketAddr, sni: String) -> Self {
+        HttpPeer {
+            address,
+            tls: false,
+            sni,
+            options: PeerOptions::default(),
+        }
+    }
+
+    /// a peer connected over TLS
+    pub fn new_tls(address: SocketAddr, sni: String) -> Self {
+        HttpPeer {
+            tls: true,
+            ..Self::new(address, sni)
+        }
+    }
+
+    /// create a peer to the backend selected by a load balancer
+    pub fn from_backend(backend: &Backend, sni: String) -> Self {
+        Self::new(backend.addr, sni)
+    }
+}
@@ -48,9 +48,20 @@ impl ImmutStr {
     }
 }
 
-///
 impl fmt::Display for ImmutStr {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.as_str())
     }
-}
\ No newline at end of file
+}
+
+impl From<&'static str> for ImmutStr {
+    fn from(s: &'static str) -> Self {
+        ImmutStr::Static(s)
+    }
+}
+
+impl From<String> for ImmutStr {
+    fn from(s: String) -> Self {
+        ImmutStr::Owned(s.into_boxed_str())
+    }
+}
@@ -24,10 +24,11 @@
 mod immut_str;
 pub use immut_str::ImmutStr;
 
+use std::fmt;
 use std::result::Result as StdResult;
 use std::error::Error as ErrorTrait;
 
-pub type BError = Box(Error);
+pub type BError = Box<Error>;
 
 /// code fix: wrong type definition fix
 pub type Result<T, E = BError> = StdResult<T, E>;
@@ -46,6 +47,193 @@ pub struct Error {
     pub context: Option<ImmutStr>,
 }
 
+impl Error {
+
+    /// create a new error with the given type, source and retry-ability
+    pub fn create(
+        etype: ErrorType,
+        esource: ErrorSource,
+        context: Option<ImmutStr>,
+        cause: Option<Box<dyn ErrorTrait + Send + Sync>>,
+    ) -> BError {
+        let retry = if let Some(c) = cause.as_ref() {
+            if let Some(e) = c.downcast_ref::<BError>() {
+                RetryType::Decide(e.retry())
+            } else {
+                RetryType::Decide(false)
+            }
+        } else {
+            RetryType::Decide(false)
+        };
+
+        Box::new(Error {
+            etype,
+            esource,
+            retry,
+            cause,
+            context,
+        })
+    }
+
+    #[inline]
+    fn do_new(etype: ErrorType, esource: ErrorSource) -> BError {
+        Self::create(etype, esource, None, None)
+    }
+
+    /// create a new error with unset source
+    #[inline]
+    pub fn new(etype: ErrorType) -> BError {
+        Self::do_new(etype, ErrorSource::Unset)
+    }
+
+    /// create a new error caused by upstream
+    #[inline]
+    pub fn new_up(etype: ErrorType) -> BError {
+        Self::do_new(etype, ErrorSource::Upstream)
+    }
+
+    /// create a new error caused by downstream
+    #[inline]
+    pub fn new_down(etype: ErrorType) -> BError {
+        Self::do_new(etype, ErrorSource::Downstream)
+    }
+
+    /// create a new error caused by the gateway itself
+    #[inline]
+    pub fn new_in(etype: ErrorType) -> BError {
+        Self::do_new(etype, ErrorSource::Internal)
+    }
+
+    /// create a [ErrorType::Custom] error with the given reason
+    #[inline]
+    pub fn new_str(reason: &'static str) -> BError {
+        Self::do_new(ErrorType::Custom(reason), ErrorSource::Unset)
+    }
+
+    /// create a new error chained to `cause`, with the context explained
+    pub fn because<S: Into<ImmutStr>, E: Into<Box<dyn ErrorTrait + Send + Sync>>>(
+        etype: ErrorType,
+        context: S,
+        cause: E,
+    ) -> BError {
+        Self::create(etype, ErrorSource::Unset, Some(context.into()), Some(cause.into()))
+    }
+
+    /// short for `Err(Self::because(...))`
+    #[inline]
+    pub fn e_because<T, S: Into<ImmutStr>, E: Into<Box<dyn ErrorTrait + Send + Sync>>>(
+        etype: ErrorType,
+        context: S,
+        cause: E,
+    ) -> Result<T> {
+        Err(Self::because(etype, context, cause))
+    }
+
+    /// create a new error with the context explained but without any cause
+    pub fn explain<S: Into<ImmutStr>>(etype: ErrorType, context: S) -> BError {
+        Self::create(etype, ErrorSource::Unset, Some(context.into()), None)
+    }
+
+    /// short for `Err(Self::explain(...))`
+    #[inline]
+    pub fn e_explain<T, S: Into<ImmutStr>>(etype: ErrorType, context: S) -> Result<T> {
+        Err(Self::explain(etype, context))
+    }
+
+    /// mark the error as caused by upstream
+    pub fn into_up(mut self: BError) -> BError {
+        self.esource = ErrorSource::Upstream;
+        self
+    }
+
+    /// mark the error as caused by downstream
+    pub fn into_down(mut self: BError) -> BError {
+        self.esource = ErrorSource::Downstream;
+        self
+    }
+
+    /// mark the error as caused by the gateway itself
+    pub fn into_in(mut self: BError) -> BError {
+        self.esource = ErrorSource::Internal;
+        self
+    }
+
+    pub fn etype(&self) -> &ErrorType {
+        &self.etype
+    }
+
+    pub fn esource(&self) -> &ErrorSource {
+        &self.esource
+    }
+
+    /// whether the request which hit this error can be retried
+    pub fn retry(&self) -> bool {
+        self.retry.retry()
+    }
+
+    pub fn set_retry(&mut self, retry: bool) {
+        self.retry = RetryType::Decide(retry);
+    }
+
+    pub fn set_context<T: Into<ImmutStr>>(&mut self, context: T) {
+        self.context = Some(context.into());
+    }
+
+    pub fn set_cause<C: Into<Box<dyn ErrorTrait + Send + Sync>>>(&mut self, cause: C) {
+        self.cause = Some(cause.into());
+    }
+
+    /// the [ErrorType] of the innermost [Error] in the cause chain
+    pub fn root_etype(&self) -> &ErrorType {
+        self.cause.as_ref().map_or(&self.etype, |c| {
+            c.downcast_ref::<BError>()
+                .map_or(&self.etype, |e| e.root_etype())
+        })
+    }
+
+    /// the innermost cause of the error chain
+    pub fn root_cause(&self) -> &(dyn ErrorTrait + Send + Sync + 'static) {
+        self.cause.as_deref().map_or(self, |c| {
+            c.downcast_ref::<BError>().map_or(c, |e| e.root_cause())
+        })
+    }
+
+    /// `sep` is written before the first part, so that an unset source adds no separator
+    fn chain_display(&self, previous: Option<&Error>, mut sep: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let esource = self.esource.as_str();
+        if previous.map(|p| p.esource != self.esource).unwrap_or(true) && !esource.is_empty() {
+            write!(f, "{sep}{}", esource)?;
+            sep = " ";
+        }
+        if previous.map(|p| p.etype != self.etype).unwrap_or(true) {
+            write!(f, "{sep}{}", self.etype.as_str())?;
+            sep = " ";
+        }
+        if let Some(c) = self.context.as_ref() {
+            write!(f, "{sep}context: {}", c)?;
+            sep = " ";
+        }
+        if let Some(c) = self.cause.as_ref() {
+            write!(f, "{sep}cause:")?;
+            if let Some(e) = c.downcast_ref::<BError>() {
+                e.chain_display(Some(self), " ", f)
+            } else {
+                write!(f, " {}", c)
+            }
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chain_display(None, "", f)
+    }
+}
+
+impl ErrorTrait for Error {}
+
 impl RetryType {
     pub fn decide_reuse(&mut self, reused: bool) {
         if matches!(self, RetryType::ReuseOnly) {
@@ -74,12 +262,83 @@ pub enum ErrorType {
     TLSHandshakeTimeout,
     InvalidCert,
 
+    ConnectError,
+    ConnectionClosed,
+
+    // io errors
+    ReadError,
+    WriteError,
+    ReadTimedout,
+    WriteTimedout,
+
+    // listener errors
+    BindError,
+    AcceptError,
+
+    // file errors
+    FileOpenError,
+    FileReadError,
+    FileWriteError,
+
+    // configuration errors
+    /// the configuration is malformed or fails the validation
+    ConfigError,
+
     // protocol errors
     InvalidHTTPHeader,
+    H1Error,
+    H2Error,
+    DnsError,
+    /// the body fails to be compressed or decompressed
+    CompressionError,
 
+    // application errors
+    /// the response has an unexpected http status code
+    HTTPStatus(u16),
+    InternalError,
+    /// the circuit breaker of the upstream is open, the request is rejected without being sent
+    CircuitOpen,
+
+    /// an error type that does not fit into the other types
+    Custom(&'static str),
 }
 
-#[derive(Debug)]
+impl ErrorType {
+    /// the name of the error type
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrorType::ConnectionTimeout => "ConnectionTimeout",
+            ErrorType::ConnectionRefused => "ConnectionRefused",
+            ErrorType::ConnectNoRoute => "ConnectNoRoute",
+            ErrorType::TLSHandshakeFailure => "TLSHandshakeFailure",
+            ErrorType::TLSHandshakeTimeout => "TLSHandshakeTimeout",
+            ErrorType::InvalidCert => "InvalidCert",
+            ErrorType::ConnectError => "ConnectError",
+            ErrorType::ConnectionClosed => "ConnectionClosed",
+            ErrorType::ReadError => "ReadError",
+            ErrorType::WriteError => "WriteError",
+            ErrorType::ReadTimedout => "ReadTimedout",
+            ErrorType::WriteTimedout => "WriteTimedout",
+            ErrorType::BindError => "BindError",
+            ErrorType::AcceptError => "AcceptError",
+            ErrorType::FileOpenError => "FileOpenError",
+            ErrorType::FileReadError => "FileReadError",
+            ErrorType::FileWriteError => "FileWriteError",
+            ErrorType::ConfigError => "ConfigError",
+            ErrorType::InvalidHTTPHeader => "InvalidHTTPHeader",
+            ErrorType::H1Error => "H1Error",
+            ErrorType::H2Error => "H2Error",
+            ErrorType::DnsError => "DnsError",
+            ErrorType::CompressionError => "CompressionError",
+            ErrorType::HTTPStatus(_) => "HTTPStatus",
+            ErrorType::InternalError => "InternalError",
+            ErrorType::CircuitOpen => "CircuitOpen",
+            ErrorType::Custom(s) => s,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ErrorSource {
     /// The error is caused by the remote server side
     Upstream,
@@ -91,6 +350,17 @@ pub enum ErrorSource {
     Unset,
 }
 
+impl ErrorSource {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ErrorSource::Upstream => "Upstream",
+            ErrorSource::Downstream => "Downstream",
+            ErrorSource::Internal => "Internal",
+            ErrorSource::Unset => "",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RetryType {
     Decide(bool),
@@ -98,6 +368,95 @@ pub enum RetryType {
     ReuseOnly,
 }
 
+/// helper trait to chain errors with context
+pub trait OrErr<T, E> {
+    /// wrap the `E` in [Result] with new [ErrorType] and context, the existing `E` will be the cause
+    fn or_err(self, et: ErrorType, context: &'static str) -> Result<T, BError>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>;
+
+    /// similar to [Self::or_err()], but the context is lazily built
+    fn or_err_with<C: Into<ImmutStr>, F: FnOnce() -> C>(
+        self,
+        et: ErrorType,
+        context: F,
+    ) -> Result<T, BError>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>;
+
+    /// replace the `E` in [Result] with a new [Error] generated from the old one
+    fn explain_err<C: Into<ImmutStr>, F: FnOnce(E) -> C>(
+        self,
+        et: ErrorType,
+        context: F,
+    ) -> Result<T, BError>;
+
+    /// similar to [Self::or_err()] but without attaching any context
+    fn or_fail(self) -> Result<T>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>;
+}
+
+impl<T, E> OrErr<T, E> for StdResult<T, E> {
+    fn or_err(self, et: ErrorType, context: &'static str) -> Result<T, BError>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>,
+    {
+        self.map_err(|e| Error::because(et, context, e))
+    }
+
+    fn or_err_with<C: Into<ImmutStr>, F: FnOnce() -> C>(
+        self,
+        et: ErrorType,
+        context: F,
+    ) -> Result<T, BError>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>,
+    {
+        self.map_err(|e| Error::because(et, context(), e))
+    }
+
+    fn explain_err<C: Into<ImmutStr>, F: FnOnce(E) -> C>(
+        self,
+        et: ErrorType,
+        context: F,
+    ) -> Result<T, BError> {
+        self.map_err(|e| Error::explain(et, context(e)))
+    }
+
+    fn or_fail(self) -> Result<T, BError>
+    where
+        E: Into<Box<dyn ErrorTrait + Send + Sync>>,
+    {
+        self.map_err(|e| Error::because(ErrorType::InternalError, "", e))
+    }
+}
+
+/// helper trait to convert an [Option] to an [Error] with context
+pub trait OkOrErr<T> {
+    fn or_err(self, et: ErrorType, context: &'static str) -> Result<T, BError>;
+
+    fn or_err_with<C: Into<ImmutStr>, F: FnOnce() -> C>(
+        self,
+        et: ErrorType,
+        context: F,
+    ) -> Result<T, BError>;
+}
+
+impl<T> OkOrErr<T> for Option<T> {
+    fn or_err(self, et: ErrorType, context: &'static str) -> Result<T, BError> {
+        self.ok_or(Error::explain(et, context))
+    }
+
+    fn or_err_with<C: Into<ImmutStr>, F: FnOnce() -> C>(
+        self,
+        et: ErrorType,
+        context: F,
+    ) -> Result<T, BError> {
+        self.ok_or_else(|| Error::explain(et, context()))
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -107,4 +466,39 @@ mod tests {
     fn it_works() {
 
     }
+
+    #[test]
+    fn test_chain_of_error() {
+        let e1 = Error::new(ErrorType::InternalError);
+        let mut e2 = Error::new(ErrorType::HTTPStatus(400));
+        e2.set_cause(e1);
+        assert_eq!(format!("{}", e2), "HTTPStatus cause: InternalError");
+        assert_eq!(e2.root_etype().as_str(), "InternalError");
+
+        let e3 = Error::explain(ErrorType::InternalError, "the context");
+        assert_eq!(format!("{}", e3), "InternalError context: the context");
+
+        let mut e4 = Error::explain(ErrorType::ConnectError, "failed to connect");
+        e4.set_cause(Error::new_up(ErrorType::ConnectionRefused));
+        assert_eq!(format!("{}", e4), "ConnectError context: failed to connect cause: Upstream ConnectionRefused");
+    }
+
+    #[test]
+    fn test_error_context() {
+        let mut e1 = Error::new(ErrorType::InternalError);
+        e1.set_context(format!("{} {}", "my", "context"));
+        assert_eq!(format!("{}", e1), "InternalError context: my context");
+    }
+
+    #[test]
+    fn test_or_err() {
+        let e: StdResult<(), BError> = Err(Error::new_up(ErrorType::ConnectionRefused));
+        let e = e.or_err(ErrorType::ConnectError, "connect to backend").unwrap_err();
+        assert_eq!(e.etype(), &ErrorType::ConnectError);
+        assert_eq!(e.root_etype(), &ErrorType::ConnectionRefused);
+
+        let o: Option<()> = None;
+        let e = o.or_err(ErrorType::InternalError, "empty").unwrap_err();
+        assert_eq!(format!("{}", e), "InternalError context: empty");
+    }
 }
@@ -86,7 +86,7 @@ impl IntoCaseHeader for Bytes {
 
 pub(crate) fn title_header_name_str(header_name: &HeaderName) -> Option<&'static str> {
 
-    /// using * to de-referencing
+    // using * to de-referencing
     Some(match *header_name {
         header::AGE => "Age",
         header::CACHE_CONTROL => "Cache-Control",
@@ -94,10 +94,13 @@ pub(crate) fn title_header_name_str(header_name: &HeaderName) -> Option<&'static
         header::CONTENT_TYPE => "Content-Type",
         header::CONTENT_ENCODING => "Content-Encoding",
         header::CONTENT_LENGTH => "Content-Length",
+        header::CONTENT_RANGE => "Content-Range",
         header::DATE => "Date",
         header::TRANSFER_ENCODING => "Transfer-Encoding",
         header::HOST => "Host",
         header::SERVER => "Server",
+        header::VARY => "Vary",
+        header::ETAG => "ETag",
         // TODO: add more const header here to map to their titled case
         // TODO: automatically upper case the first letter?
         _ => {
@@ -20,7 +20,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-#[allow(clippy::new_without_default)]
+#![allow(clippy::new_without_default)]
 use http::request::{Parts as ReqParts};
 use http::request::Builder as ReqBuilder;
 use http::response::{Parts as RespParts};
@@ -29,15 +29,21 @@ pub use http::HeaderMap as HMap;
 use std::ops::Deref;
 use bytes::BufMut;
 use http::{HeaderName, HeaderValue, Method, StatusCode, Uri, Version};
-use http::header::{AsHeaderName, IntoHeaderName};
-use gateway_error::{ErrorType::*, Result};
+use http::header::AsHeaderName;
+use gateway_error::{ErrorType::*, OrErr, Result};
 
 mod http_header_support;
+pub mod range;
+pub mod server;
+pub mod util;
+pub mod v1;
+pub mod v2;
 use http_header_support::CaseHttpHeaders;
 use crate::http_header_support::IntoCaseHeader;
 
 pub mod prelude {
     pub use crate::RequestHeader;
+    pub use crate::ResponseHeader;
 }
 
 
@@ -98,9 +104,9 @@ impl RequestHeader {
     ) -> Result<Self> {
         let mut req = Self::build_no_case(method, path, size_hint)?;
 
-        /// problems fix, cause by previous step [Self::build_no_case] return wrong type, which return
-        /// [(RequestHeader, Box<Error>)] tuple type while actually expected [RequestHeader] type, cause by
-        /// [gateway_error::Result] wrong type definition
+        // problems fix, cause by previous step [Self::build_no_case] return wrong type, which return
+        // [(RequestHeader, Box<Error>)] tuple type while actually expected [RequestHeader] type, cause by
+        // [gateway_error::Result] wrong type definition
         req.header_name_map = Some(CaseMap::with_capacity(http_header_map_upper_bound(
             size_hint,
         )));
@@ -155,7 +161,6 @@ impl RequestHeader {
             .try_into()
             .explain_err(InvalidHTTPHeader, |_| "invalid value to append to request header")?;
 
-        ///
         append_header_value(
             self.header_name_map.as_mut(),
             &mut self.base.headers,
@@ -180,7 +185,7 @@ impl RequestHeader {
 
         insert_header_value(
             self.header_name_map.as_mut(),
-            &self.base.headers,
+            &mut self.base.headers,
             name,
             header_value
         )
@@ -197,14 +202,16 @@ impl RequestHeader {
 
     /// set the request of http request, [POST] or [GET], etc
     pub fn set_method(&mut self, method: Method) {
-        self.method = method;
+        self.base.method = method;
     }
 
+    /// set the uri, which replaces the raw path as well
     pub fn set_uri(&mut self, uri: Uri) {
-        self.uri = uri;
+        self.base.uri = uri;
+        self.raw_path_fallback.clear();
     }
 
-    pub fn raw_path(&mut self) -> &[u8] {
+    pub fn raw_path(&self) -> &[u8] {
         if !self.raw_path_fallback.is_empty() {
             &self.raw_path_fallback
         } else {
@@ -273,10 +280,24 @@ pub struct ResponseHeader {
 }
 
 impl AsRef<RespParts> for ResponseHeader {
-    fn as_ref(&self) -> Self {
+    fn as_ref(&self) -> &RespParts {
+        &self.base
+    }
+}
+
+impl Deref for ResponseHeader {
+    type Target = RespParts;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
+}
+
+impl Clone for ResponseHeader {
+    fn clone(&self) -> Self {
         Self {
             base: self.as_own_parts(),
-            header_name_map: None
+            header_name_map: self.header_name_map.clone(),
         }
     }
 }
@@ -384,7 +405,8 @@ impl ResponseHeader {
     pub fn set_status(&mut self, status: impl TryInto<StatusCode>) -> Result<()> {
         self.base.status = status
             .try_into()
-            .explain_err(InvalidHTTPHeader, |_| "invalid status")
+            .explain_err(InvalidHTTPHeader, |_| "invalid status")?;
+        Ok(())
     }
 
     pub fn set_version(&mut self, version: Version) {
@@ -392,6 +414,10 @@ impl ResponseHeader {
             .version = version
     }
 
+    pub fn header_to_h1_write(&self, buf: &mut impl BufMut) {
+        header_to_h1_write(self.header_name_map.as_ref(), &self.base.headers, buf)
+    }
+
     pub fn as_own_parts(&self) -> RespParts {
         clone_resp_parts(&self.base)
     }
@@ -408,7 +434,7 @@ fn clone_req_parts(me: &ReqParts) -> ReqParts {
         .into_parts()
         .0;
 
-    /// assign headers
+    // assign headers
     parts.headers = me.headers.clone();
 
     parts
@@ -464,10 +490,10 @@ fn append_header_value<T>(
     let header_name: HeaderName = case_header_name
         .as_slice()
         .try_into()
-        .or_err(InvalidHTTPHeader, "invalid http header name");
+        .or_err(InvalidHTTPHeader, "invalid http header name")?;
 
     if let Some(name_map) = name_map {
-        name_map.append(header_name.clone(), case_header_name)
+        name_map.append(header_name.clone(), case_header_name);
     }
 
     Ok(value_map.append(header_name, value))
@@ -486,13 +512,13 @@ fn insert_header_value<T>(
     let header_name: HeaderName = case_header_name
         .as_slice()
         .try_into()
-        .or_err(InvalidHTTPHeader, "invalid http header name");
+        .or_err(InvalidHTTPHeader, "invalid http header name")?;
 
     if let Some(name_map) = name_map {
-        name_map.insert(header_name.clone(), case_header_name)
+        name_map.insert(header_name.clone(), case_header_name);
     }
 
-    Ok(value_map.insert(header_name, value))
+    Ok(value_map.insert(header_name, value).is_some())
 }
 
 #[inline]
@@ -516,10 +542,10 @@ fn header_to_h1_write(
     value_map: &HMap,
     buf: &mut impl BufMut
 ) {
-    /// define CLRF format. which determine the format of the end of the line
+    // define CLRF format. which determine the format of the end of the line
     const CLRF: &[u8; 2] = b"\r\n";
 
-    /// define http request header key-value delimiter
+    // define http request header key-value delimiter
     const HEADER_KV_DELIMITER: &[u8; 2] = b": ";
 
 
@@ -561,4 +587,36 @@ mod tests {
     fn it_works() {
 
     }
+
+    #[test]
+    fn test_request_header_case() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("FoO", "Bar").unwrap();
+        req.append_header("x-ID", "1").unwrap();
+        req.append_header("x-id", "2").unwrap();
+
+        let mut buf = vec![];
+        req.header_to_h1_write(&mut buf);
+        assert_eq!(buf, b"FoO: Bar\r\nx-ID: 1\r\nx-id: 2\r\n");
+
+        req.remove_header("foo");
+        assert!(req.headers.get("foo").is_none());
+    }
+
+    #[test]
+    fn test_request_header_invalid_path() {
+        let raw = b"/a\xff";
+        let req = RequestHeader::build("GET", raw, None).unwrap();
+        assert_eq!(req.raw_path(), raw);
+    }
+
+    #[test]
+    fn test_response_header() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Content-Length", "0").unwrap();
+        resp.set_status(404).unwrap();
+        assert_eq!(resp.status, StatusCode::NOT_FOUND);
+        assert!(resp.set_status(1000).is_err());
+        assert_eq!(resp.headers.get("content-length").unwrap(), "0");
+    }
 }
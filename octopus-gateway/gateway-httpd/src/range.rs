@@ -0,0 +1,203 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! byte range requests
+
+use crate::{RequestHeader, ResponseHeader};
+use http::header;
+use std::ops::Range;
+
+/// the max number of ranges of a request, a `Range` with more is ignored
+const MAX_RANGES: usize = 64;
+
+/// the byte ranges of a body requested by the `Range` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeType {
+    /// no `Range`, or one which is ignored, the whole body is served
+    None,
+    Single(Range<usize>),
+    /// sorted and disjoint ranges, served as `multipart/byteranges`
+    Multi(Vec<Range<usize>>),
+    /// none of the ranges is satisfiable, 416 is served
+    Invalid,
+}
+
+impl RequestHeader {
+    /// the ranges of a body of `len` bytes the request asks for
+    ///
+    /// a malformed `Range`, or one of another unit than bytes, is ignored. the overlapping and
+    /// adjacent ranges are merged
+    pub fn range(&self, len: usize) -> RangeType {
+        match self.headers.get(header::RANGE) {
+            Some(value) => parse_range(value.as_bytes(), len),
+            None => RangeType::None,
+        }
+    }
+
+    /// whether the `If-Range` of the request allows serving the ranges of `resp`, true if there
+    /// is no `If-Range`
+    ///
+    /// the condition holds if it is the strong `ETag` or the exact `Last-Modified` of `resp`
+    pub fn if_range_matches(&self, resp: &ResponseHeader) -> bool {
+        let Some(condition) = self.headers.get(header::IF_RANGE) else {
+            return true;
+        };
+        let condition = condition.as_bytes().trim_ascii();
+        let validator = if condition.starts_with(b"\"") || condition.starts_with(b"W/") {
+            // a weak etag never matches
+            resp.headers.get(header::ETAG).filter(|_| condition.starts_with(b"\""))
+        } else {
+            resp.headers.get(header::LAST_MODIFIED)
+        };
+        validator.is_some_and(|v| v.as_bytes().trim_ascii() == condition)
+    }
+}
+
+/// parse the value of `Range` against a body of `len` bytes
+pub fn parse_range(value: &[u8], len: usize) -> RangeType {
+    let Some(ranges) = std::str::from_utf8(value).ok().and_then(|v| parse_specs(v, len)) else {
+        return RangeType::None;
+    };
+    let mut ranges: Vec<Range<usize>> = ranges.into_iter().flatten().collect();
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    match merged.len() {
+        0 => RangeType::Invalid,
+        1 => RangeType::Single(merged.remove(0)),
+        _ => RangeType::Multi(merged),
+    }
+}
+
+/// the ranges of the specs, `None` for an unsatisfiable one, or `None` for all if malformed
+fn parse_specs(value: &str, len: usize) -> Option<Vec<Option<Range<usize>>>> {
+    let (unit, specs) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let number = |s: &str| {
+        let s = s.trim();
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        // a number too large for the body is as good as its length
+        Some(s.parse::<usize>().unwrap_or(usize::MAX))
+    };
+    let mut ranges = vec![];
+    // the empty elements of a list are allowed
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        if ranges.len() == MAX_RANGES {
+            return None;
+        }
+        let (first, last) = spec.split_once('-')?;
+        let range = match (first.trim().is_empty(), last.trim().is_empty()) {
+            // the suffix
+            (true, false) => {
+                let suffix = number(last)?;
+                (suffix > 0 && len > 0).then(|| len.saturating_sub(suffix)..len)
+            }
+            (false, true) => {
+                let first = number(first)?;
+                (first < len).then_some(first..len)
+            }
+            (false, false) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if first > last {
+                    return None;
+                }
+                (first < len).then(|| first..last.saturating_add(1).min(len))
+            }
+            (true, true) => return None,
+        };
+        ranges.push(range);
+    }
+    (!ranges.is_empty()).then_some(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(b"bytes=0-9", 100), RangeType::Single(0..10));
+        assert_eq!(parse_range(b"bytes=90-", 100), RangeType::Single(90..100));
+        assert_eq!(parse_range(b"bytes=-10", 100), RangeType::Single(90..100));
+        assert_eq!(parse_range(b"bytes=-200", 100), RangeType::Single(0..100));
+        assert_eq!(parse_range(b"bytes=90-1000", 100), RangeType::Single(90..100));
+        assert_eq!(parse_range(b"Bytes = 0-0", 100), RangeType::Single(0..1));
+        assert_eq!(
+            parse_range(b"bytes=50-59, 0-9,, 5-12", 100),
+            RangeType::Multi(vec![0..13, 50..60])
+        );
+        // the unsatisfiable ones are dropped
+        assert_eq!(parse_range(b"bytes=0-9,200-300", 100), RangeType::Single(0..10));
+        assert_eq!(parse_range(b"bytes=100-", 100), RangeType::Invalid);
+        assert_eq!(parse_range(b"bytes=-0", 100), RangeType::Invalid);
+        assert_eq!(parse_range(b"bytes=0-", 0), RangeType::Invalid);
+        // ignored
+        for value in [
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=-",
+            "items=0-1",
+            "bytes=",
+            "0-1",
+        ] {
+            assert_eq!(parse_range(value.as_bytes(), 100), RangeType::None, "{value}");
+        }
+        let many = format!("bytes={}", vec!["0-0"; 65].join(","));
+        assert_eq!(parse_range(many.as_bytes(), 100), RangeType::None);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("ETag", "\"v1\"").unwrap();
+        resp.insert_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .unwrap();
+        let req = |if_range: Option<&str>| {
+            let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+            req.insert_header("Range", "bytes=0-1").unwrap();
+            if let Some(v) = if_range {
+                req.insert_header("If-Range", v.to_string()).unwrap();
+            }
+            req
+        };
+        assert!(req(None).if_range_matches(&resp));
+        assert!(req(Some("\"v1\"")).if_range_matches(&resp));
+        assert!(!req(Some("\"v2\"")).if_range_matches(&resp));
+        assert!(!req(Some("W/\"v1\"")).if_range_matches(&resp));
+        assert!(req(Some("Sun, 06 Nov 1994 08:49:37 GMT")).if_range_matches(&resp));
+        assert!(!req(Some("Sun, 06 Nov 1994 08:49:38 GMT")).if_range_matches(&resp));
+        assert_eq!(req(None).range(10), RangeType::Single(0..2));
+
+        resp.insert_header("ETag", "W/\"v1\"").unwrap();
+        assert!(!req(Some("W/\"v1\"")).if_range_matches(&resp));
+    }
+}
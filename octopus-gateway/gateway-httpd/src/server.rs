@@ -0,0 +1,174 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the server session of either http version
+
+use crate::{v1, v2, RequestHeader, ResponseHeader};
+use bytes::Bytes;
+use gateway_error::Result;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// the information of a downstream connection, shared by all its requests
+#[derive(Debug, Clone, Default)]
+pub struct Digest {
+    /// the address of the client, if it connects over an inet socket
+    pub client_addr: Option<SocketAddr>,
+    /// the TLS details, `None` if the connection is plaintext
+    pub tls: Option<TlsDigest>,
+}
+
+/// the TLS details of a connection
+#[derive(Debug, Clone, Default)]
+pub struct TlsDigest {
+    /// the server name the client asked for
+    pub sni: Option<String>,
+    /// the negotiated application protocol
+    pub alpn: Option<String>,
+    pub version: String,
+    pub cipher: String,
+    /// the verified cert of the client, if it authenticated with one
+    pub client_cert: Option<ClientCertDigest>,
+}
+
+/// the identity in the cert of a client
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertDigest {
+    /// the subject DN, e.g. `CN=client, O=example`
+    pub subject: String,
+    /// the subject alternative names, prefixed by their types, e.g. `DNS:a.example.com` or
+    /// `URI:spiffe://example.com/service`
+    pub sans: Vec<String>,
+    /// the SHA-256 of the DER encoded cert, in lowercase hex
+    pub fingerprint: String,
+}
+
+/// a server session of http/1 or http/2
+pub enum Session<S> {
+    H1(v1::server::HttpSession<S>),
+    H2(Box<v2::server::HttpSession>),
+}
+
+impl<S> Session<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn is_http2(&self) -> bool {
+        matches!(self, Session::H2(_))
+    }
+
+    pub fn req_header(&self) -> &RequestHeader {
+        match self {
+            Session::H1(s) => s.req_header(),
+            Session::H2(s) => s.req_header(),
+        }
+    }
+
+    pub fn req_header_mut(&mut self) -> &mut RequestHeader {
+        match self {
+            Session::H1(s) => s.req_header_mut(),
+            Session::H2(s) => s.req_header_mut(),
+        }
+    }
+
+    /// read the next piece of the request body, `None` when the body is finished
+    pub async fn read_body_bytes(&mut self) -> Result<Option<Bytes>> {
+        match self {
+            Session::H1(s) => s.read_body_bytes().await,
+            Session::H2(s) => s.read_body_bytes().await,
+        }
+    }
+
+    pub fn is_body_done(&self) -> bool {
+        match self {
+            Session::H1(s) => s.is_body_done(),
+            Session::H2(s) => s.is_body_done(),
+        }
+    }
+
+    pub async fn write_response_header(&mut self, resp: Box<ResponseHeader>) -> Result<()> {
+        match self {
+            Session::H1(s) => s.write_response_header(resp).await,
+            Session::H2(s) => s.write_response_header(resp, false),
+        }
+    }
+
+    pub async fn write_body(&mut self, data: Bytes) -> Result<()> {
+        match self {
+            Session::H1(s) => s.write_body(&data).await.map(|_| ()),
+            Session::H2(s) => s.write_body(data, false).await,
+        }
+    }
+
+    /// finish the response, must be called after the whole body is written
+    pub async fn finish_body(&mut self) -> Result<()> {
+        match self {
+            Session::H1(s) => s.finish_body().await,
+            Session::H2(s) => s.finish_body(),
+        }
+    }
+
+    /// send a response with an empty body and the given status
+    pub async fn respond_error(&mut self, status: u16) -> Result<()> {
+        match self {
+            Session::H1(s) => s.respond_error(status).await,
+            Session::H2(s) => s.respond_error(status),
+        }
+    }
+
+    pub fn response_written(&self) -> Option<&ResponseHeader> {
+        match self {
+            Session::H1(s) => s.response_written(),
+            Session::H2(s) => s.response_written(),
+        }
+    }
+
+    pub fn body_bytes_sent(&self) -> usize {
+        match self {
+            Session::H1(s) => s.body_bytes_sent(),
+            Session::H2(s) => s.body_bytes_sent(),
+        }
+    }
+
+    /// whether the connection can serve another request, http/2 connections are kept alive by
+    /// their own
+    pub fn set_keepalive(&mut self, keepalive: bool) {
+        if let Session::H1(s) = self {
+            s.set_keepalive(keepalive)
+        }
+    }
+
+    pub fn digest(&self) -> &Digest {
+        match self {
+            Session::H1(s) => s.digest(),
+            Session::H2(s) => s.digest(),
+        }
+    }
+
+    /// finish the session, return the stream if it can serve the next http/1 request
+    pub async fn reuse(self) -> Option<S> {
+        match self {
+            Session::H1(s) => s.reuse().await,
+            Session::H2(_) => None,
+        }
+    }
+}
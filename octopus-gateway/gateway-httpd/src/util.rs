@@ -0,0 +1,126 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! helpers for the parts of the requests
+
+use gateway_error::{Error, ErrorType::*, Result};
+
+/// decode a percent encoded query component, `+` as a space
+///
+/// a malformed escape or invalid utf-8 is an HTTPStatus(400) error
+pub fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+                let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) else {
+                    return Error::e_explain(HTTPStatus(400), format!("invalid escape in `{value}`"));
+                };
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).or_else(|_| Error::e_explain(HTTPStatus(400), format!("invalid utf-8 in `{value}`")))
+}
+
+/// remove the `.` and `..` segments, percent encoded or not, and the empty segments of the path
+pub fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    let mut last = "";
+    for segment in path.split('/') {
+        last = dot_segment(segment);
+        match last {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for segment in segments.iter() {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    // a trailing slash names a different resource
+    if normalized.is_empty() || matches!(last, "" | "." | "..") {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// `.` or `..` if the segment is one of them, e.g. `%2E%2e`, the segment itself if not
+fn dot_segment(segment: &str) -> &str {
+    let mut rest = segment;
+    let mut dots = 0;
+    while !rest.is_empty() && dots < 3 {
+        rest = match rest.strip_prefix('.') {
+            Some(r) => r,
+            None if rest.len() >= 3 && rest.as_bytes()[..3].eq_ignore_ascii_case(b"%2e") => &rest[3..],
+            None => return segment,
+        };
+        dots += 1;
+    }
+    match (rest.is_empty(), dots) {
+        (true, 1) => ".",
+        (true, 2) => "..",
+        _ => segment,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c%2F").unwrap(), "a b c/");
+        assert!(percent_decode("%zz").is_err());
+        assert!(percent_decode("%2").is_err());
+        assert!(percent_decode("%ff").is_err());
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("//a///b"), "/a/b");
+        assert_eq!(normalize_path("/a/./b/../c"), "/a/c");
+        assert_eq!(normalize_path("/a/b/"), "/a/b/");
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path("/../../a"), "/a");
+        assert_eq!(normalize_path("/a/%2e%2E/b/%2e"), "/b/");
+        assert_eq!(normalize_path("/a/.../b/..c/%2e%2e%2e/é"), "/a/.../b/..c/%2e%2e%2e/é");
+    }
+}
@@ -0,0 +1,424 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use bytes::{Bytes, BytesMut};
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// the size of each read from the underlying stream when reading body
+const BODY_BUF_LIMIT: usize = 1024 * 64;
+
+/// upper bound of a chunk-size line, including chunk extensions
+const CHUNK_LINE_LIMIT: usize = 4096;
+
+/// how the length of a http/1 body is determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyMode {
+    /// the body reader is not initialized yet
+    ToStart,
+    /// a body of fixed size, with the remaining bytes to read
+    ContentLength(usize, usize),
+    /// chunked encoding, with the remaining bytes of the current chunk
+    Chunked(usize, ChunkState),
+    /// read until the connection is closed
+    UntilClose(usize),
+    /// the body is completely read, with the total body bytes
+    Complete(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkState {
+    /// expect a chunk-size line
+    Size,
+    /// reading the data of the current chunk
+    Data,
+    /// expect the CRLF after the chunk data
+    DataEnd,
+}
+
+/// read http/1 body from a stream
+///
+/// bytes already read from the stream (e.g. together with the header) should be put in the
+/// `buf` passed to [BodyReader::read_body()]
+#[derive(Debug)]
+pub struct BodyReader {
+    pub mode: BodyMode,
+    total: usize,
+}
+
+impl Default for BodyReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BodyReader {
+    pub fn new() -> Self {
+        BodyReader {
+            mode: BodyMode::ToStart,
+            total: 0,
+        }
+    }
+
+    pub fn init_content_length(&mut self, len: usize) {
+        self.mode = if len == 0 {
+            BodyMode::Complete(0)
+        } else {
+            BodyMode::ContentLength(len, len)
+        }
+    }
+
+    pub fn init_chunked(&mut self) {
+        self.mode = BodyMode::Chunked(0, ChunkState::Size);
+    }
+
+    pub fn init_until_close(&mut self) {
+        self.mode = BodyMode::UntilClose(0);
+    }
+
+    pub fn init_no_body(&mut self) {
+        self.mode = BodyMode::Complete(0);
+    }
+
+    pub fn need_init(&self) -> bool {
+        matches!(self.mode, BodyMode::ToStart)
+    }
+
+    pub fn body_done(&self) -> bool {
+        matches!(self.mode, BodyMode::Complete(_))
+    }
+
+    /// total bytes of body read so far
+    pub fn body_bytes_read(&self) -> usize {
+        self.total
+    }
+
+    /// read the next piece of body, return `None` when the body is finished
+    pub async fn read_body<S>(&mut self, stream: &mut S, buf: &mut BytesMut) -> Result<Option<Bytes>>
+    where
+        S: AsyncRead + Unpin + Send,
+    {
+        loop {
+            match self.mode {
+                BodyMode::ToStart => return Error::e_explain(InternalError, "body reader not initialized"),
+                BodyMode::Complete(_) => return Ok(None),
+                BodyMode::ContentLength(total, remaining) => {
+                    if buf.is_empty() && read_more(stream, buf).await? == 0 {
+                        return Error::e_explain(
+                            ConnectionClosed,
+                            format!("body of {total} bytes ended early, {remaining} bytes remaining"),
+                        );
+                    }
+                    let n = std::cmp::min(remaining, buf.len());
+                    let data = buf.split_to(n).freeze();
+                    self.total += n;
+                    self.mode = if remaining == n {
+                        BodyMode::Complete(self.total)
+                    } else {
+                        BodyMode::ContentLength(total, remaining - n)
+                    };
+                    return Ok(Some(data));
+                }
+                BodyMode::UntilClose(read) => {
+                    if buf.is_empty() && read_more(stream, buf).await? == 0 {
+                        self.mode = BodyMode::Complete(read);
+                        return Ok(None);
+                    }
+                    let data = buf.split().freeze();
+                    self.total += data.len();
+                    self.mode = BodyMode::UntilClose(read + data.len());
+                    return Ok(Some(data));
+                }
+                BodyMode::Chunked(remaining, state) => match state {
+                    ChunkState::Size => {
+                        let line = read_line(stream, buf).await?;
+                        let size = parse_chunk_size(&line)?;
+                        if size == 0 {
+                            // last chunk, skip the trailers
+                            loop {
+                                let trailer = read_line(stream, buf).await?;
+                                if trailer.is_empty() {
+                                    break;
+                                }
+                            }
+                            self.mode = BodyMode::Complete(self.total);
+                            return Ok(None);
+                        }
+                        self.mode = BodyMode::Chunked(size, ChunkState::Data);
+                    }
+                    ChunkState::Data => {
+                        if buf.is_empty() && read_more(stream, buf).await? == 0 {
+                            return Error::e_explain(ConnectionClosed, "chunked body ended early");
+                        }
+                        let n = std::cmp::min(remaining, buf.len());
+                        let data = buf.split_to(n).freeze();
+                        self.total += n;
+                        self.mode = if remaining == n {
+                            BodyMode::Chunked(0, ChunkState::DataEnd)
+                        } else {
+                            BodyMode::Chunked(remaining - n, ChunkState::Data)
+                        };
+                        return Ok(Some(data));
+                    }
+                    ChunkState::DataEnd => {
+                        let line = read_line(stream, buf).await?;
+                        if !line.is_empty() {
+                            return Error::e_explain(H1Error, "missing CRLF after chunk data");
+                        }
+                        self.mode = BodyMode::Chunked(0, ChunkState::Size);
+                    }
+                },
+            }
+        }
+    }
+}
+
+async fn read_more<S>(stream: &mut S, buf: &mut BytesMut) -> Result<usize>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    buf.reserve(BODY_BUF_LIMIT);
+    stream
+        .read_buf(buf)
+        .await
+        .or_err(ReadError, "while reading body")
+}
+
+/// read a CRLF terminated line, the returned line does not contain the CRLF
+async fn read_line<S>(stream: &mut S, buf: &mut BytesMut) -> Result<Bytes>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    loop {
+        if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+            let line = buf.split_to(pos).freeze();
+            let _ = buf.split_to(2);
+            return Ok(line);
+        }
+        if buf.len() > CHUNK_LINE_LIMIT {
+            return Error::e_explain(H1Error, "chunk line too long");
+        }
+        if read_more(stream, buf).await? == 0 {
+            return Error::e_explain(ConnectionClosed, "chunked body ended early");
+        }
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<usize> {
+    // ignore chunk extensions
+    let size = line.split(|b| *b == b';').next().unwrap_or_default();
+    let size = std::str::from_utf8(size)
+        .or_err(H1Error, "invalid chunk size")?
+        .trim();
+    usize::from_str_radix(size, 16).or_err(H1Error, "invalid chunk size")
+}
+
+/// write http/1 body to a stream
+#[derive(Debug)]
+pub struct BodyWriter {
+    pub mode: BodyMode,
+}
+
+impl Default for BodyWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BodyWriter {
+    pub fn new() -> Self {
+        BodyWriter {
+            mode: BodyMode::ToStart,
+        }
+    }
+
+    pub fn init_content_length(&mut self, len: usize) {
+        self.mode = if len == 0 {
+            BodyMode::Complete(0)
+        } else {
+            BodyMode::ContentLength(len, len)
+        }
+    }
+
+    pub fn init_chunked(&mut self) {
+        self.mode = BodyMode::Chunked(0, ChunkState::Data);
+    }
+
+    pub fn init_until_close(&mut self) {
+        self.mode = BodyMode::UntilClose(0);
+    }
+
+    pub fn init_no_body(&mut self) {
+        self.mode = BodyMode::Complete(0);
+    }
+
+    pub fn finished(&self) -> bool {
+        matches!(self.mode, BodyMode::Complete(_))
+    }
+
+    /// write a piece of body, return the number of body bytes written
+    pub async fn write_body<S>(&mut self, stream: &mut S, data: &[u8]) -> Result<usize>
+    where
+        S: AsyncWrite + Unpin + Send,
+    {
+        match self.mode {
+            BodyMode::ToStart => Error::e_explain(InternalError, "body writer not initialized"),
+            BodyMode::Complete(_) => Ok(0),
+            BodyMode::ContentLength(total, remaining) => {
+                let n = std::cmp::min(remaining, data.len());
+                stream
+                    .write_all(&data[..n])
+                    .await
+                    .or_err(WriteError, "while writing body")?;
+                self.mode = if remaining == n {
+                    BodyMode::Complete(total)
+                } else {
+                    BodyMode::ContentLength(total, remaining - n)
+                };
+                Ok(n)
+            }
+            BodyMode::Chunked(written, _) => {
+                if data.is_empty() {
+                    return Ok(0);
+                }
+                let mut chunk = Vec::with_capacity(data.len() + 12);
+                chunk.extend_from_slice(format!("{:X}\r\n", data.len()).as_bytes());
+                chunk.extend_from_slice(data);
+                chunk.extend_from_slice(b"\r\n");
+                stream
+                    .write_all(&chunk)
+                    .await
+                    .or_err(WriteError, "while writing body")?;
+                self.mode = BodyMode::Chunked(written + data.len(), ChunkState::Data);
+                Ok(data.len())
+            }
+            BodyMode::UntilClose(written) => {
+                stream
+                    .write_all(data)
+                    .await
+                    .or_err(WriteError, "while writing body")?;
+                self.mode = BodyMode::UntilClose(written + data.len());
+                Ok(data.len())
+            }
+        }
+    }
+
+    /// finish the body, the last chunk is written for chunked encoding
+    pub async fn finish<S>(&mut self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin + Send,
+    {
+        match self.mode {
+            BodyMode::ContentLength(total, remaining) if remaining > 0 => Error::e_explain(
+                WriteError,
+                format!("body of {total} bytes finished early, {remaining} bytes remaining"),
+            ),
+            BodyMode::Chunked(written, _) => {
+                stream
+                    .write_all(b"0\r\n\r\n")
+                    .await
+                    .or_err(WriteError, "while writing last chunk")?;
+                self.mode = BodyMode::Complete(written);
+                Ok(())
+            }
+            BodyMode::UntilClose(written) => {
+                self.mode = BodyMode::Complete(written);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(reader: &mut BodyReader, input: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = input;
+        let mut buf = BytesMut::new();
+        let mut body = vec![];
+        while let Some(data) = reader.read_body(&mut stream, &mut buf).await? {
+            body.extend_from_slice(&data);
+        }
+        Ok(body)
+    }
+
+    #[tokio::test]
+    async fn test_read_content_length() {
+        let mut reader = BodyReader::new();
+        reader.init_content_length(5);
+        let body = read_all(&mut reader, b"hello world").await.unwrap();
+        assert_eq!(body, b"hello");
+        assert_eq!(reader.mode, BodyMode::Complete(5));
+
+        let mut reader = BodyReader::new();
+        reader.init_content_length(10);
+        assert!(read_all(&mut reader, b"hello").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_chunked() {
+        let mut reader = BodyReader::new();
+        reader.init_chunked();
+        let body = read_all(&mut reader, b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(body, b"hello world");
+        assert!(reader.body_done());
+
+        let mut reader = BodyReader::new();
+        reader.init_chunked();
+        assert!(read_all(&mut reader, b"zz\r\nhello\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_until_close() {
+        let mut reader = BodyReader::new();
+        reader.init_until_close();
+        let body = read_all(&mut reader, b"hello").await.unwrap();
+        assert_eq!(body, b"hello");
+        assert_eq!(reader.mode, BodyMode::Complete(5));
+    }
+
+    #[tokio::test]
+    async fn test_write_chunked() {
+        let mut writer = BodyWriter::new();
+        writer.init_chunked();
+        let mut out = vec![];
+        writer.write_body(&mut out, b"hello").await.unwrap();
+        writer.write_body(&mut out, b"").await.unwrap();
+        writer.finish(&mut out).await.unwrap();
+        assert_eq!(out, b"5\r\nhello\r\n0\r\n\r\n");
+        assert!(writer.finished());
+    }
+
+    #[tokio::test]
+    async fn test_write_content_length() {
+        let mut writer = BodyWriter::new();
+        writer.init_content_length(3);
+        let mut out = vec![];
+        assert_eq!(writer.write_body(&mut out, b"hello").await.unwrap(), 3);
+        assert_eq!(out, b"hel");
+        assert!(writer.finished());
+    }
+}
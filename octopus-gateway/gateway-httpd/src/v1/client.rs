@@ -0,0 +1,299 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! HTTP/1.x client session
+
+use super::body::{BodyReader, BodyWriter};
+use super::common::*;
+use crate::{RequestHeader, ResponseHeader};
+use bytes::{BufMut, Bytes, BytesMut};
+use gateway_error::{Error, ErrorType::*, OkOrErr, OrErr, Result};
+use http::{Method, Version};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// a http/1 client session over the stream `S`
+///
+/// the session sends one request and reads its response. the stream can be taken out by
+/// [HttpSession::into_inner()] to be reused for another session
+pub struct HttpSession<S> {
+    stream: S,
+    buf: BytesMut,
+    req_method: Option<Method>,
+    resp_header: Option<Box<ResponseHeader>>,
+    body_reader: BodyReader,
+    body_writer: BodyWriter,
+    keepalive: bool,
+    /// timeout of each read from the stream
+    pub read_timeout: Option<Duration>,
+    /// timeout of each write to the stream
+    pub write_timeout: Option<Duration>,
+}
+
+impl<S> HttpSession<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        HttpSession {
+            stream,
+            buf: BytesMut::new(),
+            req_method: None,
+            resp_header: None,
+            body_reader: BodyReader::new(),
+            body_writer: BodyWriter::new(),
+            keepalive: true,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    /// write the request line and headers of `req`
+    ///
+    /// the body writer is set up according to the `Content-Length` and `Transfer-Encoding` of `req`
+    pub async fn write_request_header(&mut self, req: &RequestHeader) -> Result<()> {
+        let mut head = BytesMut::with_capacity(INIT_HEADER_BUF_SIZE);
+        head.put_slice(req.method.as_str().as_bytes());
+        head.put_u8(b' ');
+        head.put_slice(req.raw_path());
+        head.put_slice(match req.version {
+            Version::HTTP_10 => b" HTTP/1.0\r\n",
+            _ => b" HTTP/1.1\r\n",
+        });
+        req.header_to_h1_write(&mut head);
+        head.put_slice(CRLF);
+
+        if is_chunked_encoding(&req.headers) {
+            self.body_writer.init_chunked();
+        } else if let Some(len) = content_length(&req.headers)? {
+            self.body_writer.init_content_length(len);
+        } else {
+            self.body_writer.init_no_body();
+        }
+        if is_connection_close(&req.headers) {
+            self.keepalive = false;
+        }
+        self.req_method = Some(req.method.clone());
+
+        let stream = &mut self.stream;
+        with_timeout(
+            self.write_timeout,
+            async {
+                stream
+                    .write_all(&head)
+                    .await
+                    .or_err(WriteError, "while writing request header")?;
+                stream.flush().await.or_err(WriteError, "while flushing request header")
+            },
+            "timeout writing request header",
+        )
+        .await
+    }
+
+    /// write a piece of the request body
+    pub async fn write_body(&mut self, data: &[u8]) -> Result<usize> {
+        let (writer, stream) = (&mut self.body_writer, &mut self.stream);
+        with_timeout(self.write_timeout, writer.write_body(stream, data), "timeout writing body").await
+    }
+
+    /// finish the request body and flush the stream
+    pub async fn finish_body(&mut self) -> Result<()> {
+        self.body_writer.finish(&mut self.stream).await?;
+        self.stream.flush().await.or_err(WriteError, "while flushing body")
+    }
+
+    /// read the response header, informational(1xx) responses are skipped
+    pub async fn read_response_header(&mut self) -> Result<&ResponseHeader> {
+        loop {
+            let resp = self.read_one_header().await?;
+            if resp.status.is_informational() && resp.status != http::StatusCode::SWITCHING_PROTOCOLS {
+                continue;
+            }
+            self.init_body_reader(&resp)?;
+            return Ok(self.resp_header.insert(Box::new(resp)));
+        }
+    }
+
+    async fn read_one_header(&mut self) -> Result<ResponseHeader> {
+        loop {
+            if !self.buf.is_empty() {
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut resp = httparse::Response::new(&mut headers);
+                match resp.parse(&self.buf) {
+                    Ok(httparse::Status::Complete(len)) => {
+                        let header = build_response_header(&resp)?;
+                        let _ = self.buf.split_to(len);
+                        return Ok(header);
+                    }
+                    Ok(httparse::Status::Partial) => {}
+                    Err(e) => return Error::e_because(InvalidHTTPHeader, "invalid response header", e),
+                }
+            }
+            if self.buf.len() > MAX_HEADER_SIZE {
+                return Error::e_explain(InvalidHTTPHeader, "response header too large");
+            }
+
+            self.buf.reserve(INIT_HEADER_BUF_SIZE);
+            let (stream, buf) = (&mut self.stream, &mut self.buf);
+            let n = with_timeout(
+                self.read_timeout,
+                async { stream.read_buf(buf).await.or_err(ReadError, "while reading response header") },
+                "timeout reading response header",
+            )
+            .await?;
+            if n == 0 {
+                return Error::e_explain(ConnectionClosed, "connection closed before response header");
+            }
+        }
+    }
+
+    fn init_body_reader(&mut self, resp: &ResponseHeader) -> Result<()> {
+        let status = resp.status.as_u16();
+        if self.req_method.as_ref() == Some(&Method::HEAD) || status == 204 || status == 304 {
+            self.body_reader.init_no_body();
+        } else if is_chunked_encoding(&resp.headers) {
+            self.body_reader.init_chunked();
+        } else if let Some(len) = content_length(&resp.headers)? {
+            self.body_reader.init_content_length(len);
+        } else {
+            self.keepalive = false;
+            self.body_reader.init_until_close();
+        }
+        if is_connection_close(&resp.headers) || resp.version == Version::HTTP_10 {
+            self.keepalive = false;
+        }
+        Ok(())
+    }
+
+    /// the response header read by [Self::read_response_header()]
+    pub fn response_header(&self) -> Option<&ResponseHeader> {
+        self.resp_header.as_deref()
+    }
+
+    /// read the next piece of the response body, `None` when the body is finished
+    pub async fn read_body_bytes(&mut self) -> Result<Option<Bytes>> {
+        let (reader, stream, buf) = (&mut self.body_reader, &mut self.stream, &mut self.buf);
+        with_timeout(self.read_timeout, reader.read_body(stream, buf), "timeout reading body").await
+    }
+
+    /// read the whole response body, fail when the body is larger than `limit`
+    pub async fn read_body_to_end(&mut self, limit: usize) -> Result<Bytes> {
+        let mut body = BytesMut::new();
+        while let Some(data) = self.read_body_bytes().await? {
+            if body.len() + data.len() > limit {
+                return Error::e_explain(ReadError, format!("body larger than {limit} bytes"));
+            }
+            body.put_slice(&data);
+        }
+        Ok(body.freeze())
+    }
+
+    pub fn is_body_done(&self) -> bool {
+        self.body_reader.body_done()
+    }
+
+    /// whether the connection can be reused after this session
+    pub fn reusable(&self) -> bool {
+        self.keepalive && self.body_reader.body_done() && self.body_writer.finished() && self.buf.is_empty()
+    }
+
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+fn build_response_header(resp: &httparse::Response) -> Result<ResponseHeader> {
+    let code = resp.code.or_err(InvalidHTTPHeader, "missing status code")?;
+    let mut header = ResponseHeader::build(code, Some(resp.headers.len()))?;
+    header.set_version(match resp.version {
+        Some(0) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    });
+    for h in resp.headers.iter() {
+        header.append_header(Bytes::copy_from_slice(h.name.as_bytes()), h.value)?;
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_request_response() {
+        let (client, mut server) = duplex(4096);
+        let mut session = HttpSession::new(client);
+
+        let mut req = RequestHeader::build("POST", b"/status?a=1", None).unwrap();
+        req.insert_header("Host", "example.com").unwrap();
+        req.insert_header("Content-Length", "4").unwrap();
+        session.write_request_header(&req).await.unwrap();
+        session.write_body(b"ping").await.unwrap();
+        session.finish_body().await.unwrap();
+
+        let mut raw = vec![0; 1024];
+        let n = server.read(&mut raw).await.unwrap();
+        assert_eq!(
+            &raw[..n],
+            b"POST /status?a=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\nping"
+        );
+
+        server
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nX-Custom: a\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        let resp = session.read_response_header().await.unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.headers.get("x-custom").unwrap(), "a");
+        assert_eq!(session.read_body_to_end(1024).await.unwrap(), "ok");
+        assert!(session.reusable());
+    }
+
+    #[tokio::test]
+    async fn test_response_until_close() {
+        let (client, mut server) = duplex(4096);
+        let mut session = HttpSession::new(client);
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        session.write_request_header(&req).await.unwrap();
+
+        server.write_all(b"HTTP/1.0 503 Service Unavailable\r\n\r\ndown").await.unwrap();
+        drop(server);
+        let resp = session.read_response_header().await.unwrap();
+        assert_eq!(resp.status, 503);
+        assert_eq!(session.read_body_to_end(1024).await.unwrap(), "down");
+        assert!(!session.reusable());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_response() {
+        let (client, mut server) = duplex(4096);
+        let mut session = HttpSession::new(client);
+        server.write_all(b"HTTP/1.1 abc\r\n\r\n").await.unwrap();
+        let e = session.read_response_header().await.unwrap_err();
+        assert_eq!(e.etype(), &InvalidHTTPHeader);
+    }
+}
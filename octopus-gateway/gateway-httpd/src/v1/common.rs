@@ -0,0 +1,89 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::HMap;
+use gateway_error::{Error, ErrorType::*, OkOrErr, Result};
+use http::header;
+use std::future::Future;
+use std::time::Duration;
+
+/// max number of headers that a http/1 head can carry
+pub(crate) const MAX_HEADERS: usize = 256;
+
+/// max size of a http/1 head
+pub(crate) const MAX_HEADER_SIZE: usize = 1024 * 64;
+
+pub(crate) const INIT_HEADER_BUF_SIZE: usize = 4096;
+
+pub(crate) const CRLF: &[u8; 2] = b"\r\n";
+
+/// whether the `Transfer-Encoding` header says the body is chunked
+pub(crate) fn is_chunked_encoding(headers: &HMap) -> bool {
+    headers
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .next_back()
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// parse the `Content-Length` header, multiple different values are rejected
+pub(crate) fn content_length(headers: &HMap) -> Result<Option<usize>> {
+    let mut length = None;
+    for value in headers.get_all(header::CONTENT_LENGTH) {
+        let len = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .or_err(InvalidHTTPHeader, "invalid content-length")?;
+        if length.is_some_and(|l| l != len) {
+            return Error::e_explain(InvalidHTTPHeader, "conflicting content-length");
+        }
+        length = Some(len);
+    }
+    Ok(length)
+}
+
+/// whether the `Connection` header asks to close the connection
+pub(crate) fn is_connection_close(headers: &HMap) -> bool {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("close"))
+}
+
+/// run the io future under an optional timeout
+pub(crate) async fn with_timeout<T, F>(timeout: Option<Duration>, fut: F, on_timeout: &'static str) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match timeout {
+        Some(t) => match tokio::time::timeout(t, fut).await {
+            Ok(r) => r,
+            Err(_) => Error::e_explain(ReadTimedout, on_timeout),
+        },
+        None => fut.await,
+    }
+}
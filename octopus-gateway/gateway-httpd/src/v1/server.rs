@@ -0,0 +1,419 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! HTTP/1.x server session
+
+use super::body::{BodyReader, BodyWriter};
+use super::common::*;
+use crate::server::Digest;
+use crate::{RequestHeader, ResponseHeader};
+use bytes::{BufMut, Bytes, BytesMut};
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use http::{header, Method, StatusCode, Version};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// the max request body to drain before reusing the connection
+const MAX_DRAIN_SIZE: usize = 1024 * 64;
+
+/// a http/1 server session over the stream `S`
+///
+/// the session reads one request and writes its response. the stream can be reused for the next
+/// request via [HttpSession::reuse()]
+pub struct HttpSession<S> {
+    stream: S,
+    buf: BytesMut,
+    req_header: Option<Box<RequestHeader>>,
+    resp_header: Option<Box<ResponseHeader>>,
+    body_reader: BodyReader,
+    body_writer: BodyWriter,
+    keepalive: bool,
+    body_bytes_sent: usize,
+    digest: Arc<Digest>,
+    /// timeout of each read from the stream
+    pub read_timeout: Option<Duration>,
+    /// timeout of each write to the stream
+    pub write_timeout: Option<Duration>,
+}
+
+impl<S> HttpSession<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        HttpSession {
+            stream,
+            buf: BytesMut::new(),
+            req_header: None,
+            resp_header: None,
+            body_reader: BodyReader::new(),
+            body_writer: BodyWriter::new(),
+            keepalive: false,
+            body_bytes_sent: 0,
+            digest: Arc::default(),
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    /// read the request header
+    ///
+    /// return `Ok(None)` if the connection is closed before any byte of the request is received,
+    /// which is how an idle keepalive connection ends
+    pub async fn read_request(&mut self) -> Result<Option<usize>> {
+        loop {
+            if !self.buf.is_empty() {
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut req = httparse::Request::new(&mut headers);
+                match req.parse(&self.buf) {
+                    Ok(httparse::Status::Complete(len)) => {
+                        let header = build_request_header(&req)?;
+                        let _ = self.buf.split_to(len);
+                        self.init_body_reader(&header)?;
+                        self.req_header = Some(Box::new(header));
+                        return Ok(Some(len));
+                    }
+                    Ok(httparse::Status::Partial) => {}
+                    Err(e) => {
+                        return Err(Error::because(InvalidHTTPHeader, "invalid request header", e).into_down())
+                    }
+                }
+            }
+            if self.buf.len() > MAX_HEADER_SIZE {
+                return Err(Error::explain(InvalidHTTPHeader, "request header too large").into_down());
+            }
+
+            self.buf.reserve(INIT_HEADER_BUF_SIZE);
+            let (stream, buf) = (&mut self.stream, &mut self.buf);
+            let n = with_timeout(
+                self.read_timeout,
+                async { stream.read_buf(buf).await.or_err(ReadError, "while reading request header") },
+                "timeout reading request header",
+            )
+            .await
+            .map_err(|e| e.into_down())?;
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(Error::explain(ConnectionClosed, "connection closed during request header").into_down());
+            }
+        }
+    }
+
+    fn init_body_reader(&mut self, req: &RequestHeader) -> Result<()> {
+        if is_chunked_encoding(&req.headers) {
+            self.body_reader.init_chunked();
+        } else if let Some(len) = content_length(&req.headers).map_err(|e| e.into_down())? {
+            self.body_reader.init_content_length(len);
+        } else {
+            // requests without content-length or chunked encoding have no body
+            self.body_reader.init_no_body();
+        }
+        self.keepalive = req.version == Version::HTTP_11 && !is_connection_close(&req.headers);
+        Ok(())
+    }
+
+    /// the request header read by [Self::read_request()]
+    ///
+    /// # Panics
+    /// if the request header is not read yet
+    pub fn req_header(&self) -> &RequestHeader {
+        self.req_header.as_ref().expect("request header is not read yet")
+    }
+
+    pub fn req_header_mut(&mut self) -> &mut RequestHeader {
+        self.req_header.as_mut().expect("request header is not read yet")
+    }
+
+    /// read the next piece of the request body, `None` when the body is finished
+    pub async fn read_body_bytes(&mut self) -> Result<Option<Bytes>> {
+        let (reader, stream, buf) = (&mut self.body_reader, &mut self.stream, &mut self.buf);
+        with_timeout(self.read_timeout, reader.read_body(stream, buf), "timeout reading body")
+            .await
+            .map_err(|e| e.into_down())
+    }
+
+    pub fn is_body_done(&self) -> bool {
+        self.body_reader.body_done()
+    }
+
+    /// write the response header, the body writer is set up according to the response
+    pub async fn write_response_header(&mut self, resp: Box<ResponseHeader>) -> Result<()> {
+        if self.resp_header.is_some() && !resp.status.is_informational() {
+            return Error::e_explain(InternalError, "response header is already sent");
+        }
+
+        let mut head = BytesMut::with_capacity(INIT_HEADER_BUF_SIZE);
+        head.put_slice(match resp.version {
+            Version::HTTP_10 => b"HTTP/1.0 ",
+            _ => b"HTTP/1.1 ",
+        });
+        head.put_slice(resp.status.as_str().as_bytes());
+        head.put_u8(b' ');
+        head.put_slice(resp.status.canonical_reason().unwrap_or("").as_bytes());
+        head.put_slice(CRLF);
+        resp.header_to_h1_write(&mut head);
+        head.put_slice(CRLF);
+
+        let stream = &mut self.stream;
+        with_timeout(
+            self.write_timeout,
+            async {
+                stream
+                    .write_all(&head)
+                    .await
+                    .or_err(WriteError, "while writing response header")
+            },
+            "timeout writing response header",
+        )
+        .await
+        .map_err(|e| e.into_down())?;
+
+        if resp.status.is_informational() {
+            return Ok(());
+        }
+
+        let status = resp.status.as_u16();
+        let is_head = self.req_header.as_ref().is_some_and(|r| r.method == Method::HEAD);
+        if is_head || status == 204 || status == 304 {
+            self.body_writer.init_no_body();
+        } else if is_chunked_encoding(&resp.headers) {
+            self.body_writer.init_chunked();
+        } else if let Some(len) = content_length(&resp.headers)? {
+            self.body_writer.init_content_length(len);
+        } else {
+            self.body_writer.init_until_close();
+            self.keepalive = false;
+        }
+        if is_connection_close(&resp.headers) {
+            self.keepalive = false;
+        }
+        self.resp_header = Some(resp);
+        Ok(())
+    }
+
+    /// the response header sent by [Self::write_response_header()]
+    pub fn response_written(&self) -> Option<&ResponseHeader> {
+        self.resp_header.as_deref()
+    }
+
+    /// write a piece of the response body
+    pub async fn write_body(&mut self, data: &[u8]) -> Result<usize> {
+        let (writer, stream) = (&mut self.body_writer, &mut self.stream);
+        let n = with_timeout(self.write_timeout, writer.write_body(stream, data), "timeout writing body")
+            .await
+            .map_err(|e| e.into_down())?;
+        self.body_bytes_sent += n;
+        Ok(n)
+    }
+
+    /// finish the response body and flush the stream
+    pub async fn finish_body(&mut self) -> Result<()> {
+        self.body_writer.finish(&mut self.stream).await.map_err(|e| e.into_down())?;
+        self.stream
+            .flush()
+            .await
+            .or_err(WriteError, "while flushing response")
+            .map_err(|e| e.into_down())
+    }
+
+    pub fn body_bytes_sent(&self) -> usize {
+        self.body_bytes_sent
+    }
+
+    /// send a response with an empty body and the given status
+    pub async fn respond_error(&mut self, status: u16) -> Result<()> {
+        let mut resp = ResponseHeader::build(status, Some(2))?;
+        resp.insert_header(header::CONTENT_LENGTH, "0")?;
+        if !self.keepalive {
+            resp.insert_header(header::CONNECTION, "close")?;
+        }
+        self.write_response_header(Box::new(resp)).await?;
+        self.finish_body().await
+    }
+
+    /// whether the connection will be kept alive after this session
+    pub fn will_keepalive(&self) -> bool {
+        self.keepalive
+    }
+
+    /// disable keepalive, the connection will be closed after this session
+    pub fn set_keepalive(&mut self, keepalive: bool) {
+        self.keepalive = keepalive;
+    }
+
+    /// the status of the response sent, if any
+    pub fn response_status(&self) -> Option<StatusCode> {
+        self.resp_header.as_ref().map(|r| r.status)
+    }
+
+    /// the information of the connection, set by the acceptor of the connection
+    pub fn digest(&self) -> &Digest {
+        &self.digest
+    }
+
+    pub fn set_digest(&mut self, digest: Arc<Digest>) {
+        self.digest = digest;
+    }
+
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// consume the session, return the stream if it can serve the next request
+    ///
+    /// the unread request body is drained up to a limit before the stream is returned
+    pub async fn reuse(mut self) -> Option<S> {
+        if !self.keepalive || !self.body_writer.finished() {
+            return None;
+        }
+        let mut drained = 0;
+        while !self.body_reader.body_done() {
+            match self.read_body_bytes().await {
+                Ok(Some(data)) => {
+                    drained += data.len();
+                    if drained > MAX_DRAIN_SIZE {
+                        return None;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => return None,
+            }
+        }
+        // pipelined requests are not supported
+        if !self.buf.is_empty() {
+            return None;
+        }
+        Some(self.stream)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+fn build_request_header(req: &httparse::Request) -> Result<RequestHeader> {
+    let method = req.method.unwrap_or_default();
+    let path = req.path.unwrap_or("/");
+    let mut header = RequestHeader::build(method, path.as_bytes(), Some(req.headers.len()))?;
+    header.set_version(match req.version {
+        Some(0) => Version::HTTP_10,
+        _ => Version::HTTP_11,
+    });
+    for h in req.headers.iter() {
+        header.append_header(Bytes::copy_from_slice(h.name.as_bytes()), h.value)?;
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_read_request_write_response() {
+        let (server, mut client) = duplex(4096);
+        let mut session = HttpSession::new(server);
+        client
+            .write_all(b"POST /a?b=c HTTP/1.1\r\nHost: example.com\r\nX-Case: 1\r\nContent-Length: 4\r\n\r\nping")
+            .await
+            .unwrap();
+        session.read_request().await.unwrap().unwrap();
+        let req = session.req_header();
+        assert_eq!(req.method, Method::POST);
+        assert_eq!(req.uri.path(), "/a");
+        assert_eq!(req.headers.get("x-case").unwrap(), "1");
+        assert_eq!(session.read_body_bytes().await.unwrap().unwrap(), "ping");
+        assert!(session.read_body_bytes().await.unwrap().is_none());
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Content-Length", "2").unwrap();
+        session.write_response_header(Box::new(resp)).await.unwrap();
+        session.write_body(b"ok").await.unwrap();
+        session.finish_body().await.unwrap();
+        assert_eq!(session.body_bytes_sent(), 2);
+
+        let mut buf = vec![0; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        assert!(session.reuse().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_idle_close() {
+        let (server, client) = duplex(4096);
+        let mut session = HttpSession::new(server);
+        drop(client);
+        assert!(session.read_request().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request() {
+        let (server, mut client) = duplex(4096);
+        let mut session = HttpSession::new(server);
+        client.write_all(b"GET / HTTP/1.1\r\nbad header\r\n\r\n").await.unwrap();
+        let e = session.read_request().await.unwrap_err();
+        assert_eq!(e.etype(), &InvalidHTTPHeader);
+        assert_eq!(e.esource(), &gateway_error::ErrorSource::Downstream);
+    }
+
+    #[tokio::test]
+    async fn test_no_keepalive() {
+        let (server, mut client) = duplex(4096);
+        let mut session = HttpSession::new(server);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        session.read_request().await.unwrap();
+        session.respond_error(404).await.unwrap();
+
+        let mut buf = vec![0; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(
+            &buf[..n],
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+        assert!(session.reuse().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_head_response() {
+        let (server, mut client) = duplex(4096);
+        let mut session = HttpSession::new(server);
+        client.write_all(b"HEAD / HTTP/1.1\r\n\r\n").await.unwrap();
+        session.read_request().await.unwrap();
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Content-Length", "10").unwrap();
+        session.write_response_header(Box::new(resp)).await.unwrap();
+        assert_eq!(session.write_body(b"ignored").await.unwrap(), 0);
+        session.finish_body().await.unwrap();
+        assert!(session.reuse().await.is_some());
+    }
+}
@@ -0,0 +1,268 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! HTTP/2 server session
+
+use crate::server::Digest;
+use crate::{RequestHeader, ResponseHeader};
+use bytes::Bytes;
+use gateway_error::{Error, ErrorType::*, OkOrErr, OrErr, Result};
+use h2::server::{Connection, SendResponse};
+use h2::{RecvStream, SendStream};
+use http::{header, Version};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// the max number of concurrent streams of a downstream connection
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// the http/2 connection of a downstream
+pub type H2Connection<S> = Connection<S, Bytes>;
+
+/// perform the http/2 handshake over the stream
+pub async fn handshake<S>(io: S) -> Result<H2Connection<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    h2::server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .handshake(io)
+        .await
+        .or_err(H2Error, "http/2 handshake failed")
+}
+
+/// a http/2 server session, i.e. a single stream of the connection
+pub struct HttpSession {
+    req_header: RequestHeader,
+    body_reader: RecvStream,
+    send_response: SendResponse<Bytes>,
+    body_writer: Option<SendStream<Bytes>>,
+    resp_header: Option<Box<ResponseHeader>>,
+    ended: bool,
+    body_bytes_sent: usize,
+    digest: Arc<Digest>,
+}
+
+impl HttpSession {
+    /// wait for the next request of the connection
+    ///
+    /// return `Ok(None)` once the connection is closed. the connection is only driven while this
+    /// function is polled, so it should be called in a loop as long as the connection lives
+    pub async fn from_h2_conn<S>(conn: &mut H2Connection<S>, digest: Arc<Digest>) -> Result<Option<Self>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(accepted) = conn.accept().await else {
+            return Ok(None);
+        };
+        let (req, send_response) = accepted.or_err(H2Error, "failed to accept the http/2 stream")?;
+        let (parts, body_reader) = req.into_parts();
+        Ok(Some(HttpSession {
+            req_header: parts.into(),
+            body_reader,
+            send_response,
+            body_writer: None,
+            resp_header: None,
+            ended: false,
+            body_bytes_sent: 0,
+            digest,
+        }))
+    }
+
+    pub fn req_header(&self) -> &RequestHeader {
+        &self.req_header
+    }
+
+    pub fn req_header_mut(&mut self) -> &mut RequestHeader {
+        &mut self.req_header
+    }
+
+    /// read the next piece of the request body, `None` when the body is finished
+    pub async fn read_body_bytes(&mut self) -> Result<Option<Bytes>> {
+        let Some(data) = self.body_reader.data().await else {
+            return Ok(None);
+        };
+        let data = data.or_err(ReadError, "failed to read the http/2 request body")?;
+        // let the client send more
+        let _ = self.body_reader.flow_control().release_capacity(data.len());
+        Ok(Some(data))
+    }
+
+    pub fn is_body_done(&self) -> bool {
+        self.body_reader.is_end_stream()
+    }
+
+    /// write the response header, `end` if there is no body
+    ///
+    /// the connection specific headers, which http/2 forbids, are removed
+    pub fn write_response_header(&mut self, mut resp: Box<ResponseHeader>, end: bool) -> Result<()> {
+        if self.resp_header.is_some() {
+            return Error::e_explain(H2Error, "the response header is already sent");
+        }
+        for name in [
+            header::CONNECTION,
+            header::TRANSFER_ENCODING,
+            header::UPGRADE,
+            header::HeaderName::from_static("keep-alive"),
+            header::HeaderName::from_static("proxy-connection"),
+        ] {
+            resp.remove_header(&name);
+        }
+        resp.set_version(Version::HTTP_2);
+        let response = http::Response::from_parts(resp.as_own_parts(), ());
+        let body_writer = self
+            .send_response
+            .send_response(response, end)
+            .or_err(WriteError, "failed to send the http/2 response header")?;
+        self.body_writer = Some(body_writer);
+        self.resp_header = Some(resp);
+        self.ended = end;
+        Ok(())
+    }
+
+    pub fn response_written(&self) -> Option<&ResponseHeader> {
+        self.resp_header.as_deref()
+    }
+
+    /// write a piece of the response body, waiting for the flow control of the client
+    pub async fn write_body(&mut self, mut data: Bytes, end: bool) -> Result<()> {
+        if self.ended {
+            return Error::e_explain(H2Error, "the response is already finished");
+        }
+        let writer = self
+            .body_writer
+            .as_mut()
+            .or_err(H2Error, "the response header is not sent yet")?;
+        self.body_bytes_sent += data.len();
+        if data.is_empty() {
+            if end {
+                writer
+                    .send_data(data, true)
+                    .or_err(WriteError, "failed to finish the http/2 response")?;
+                self.ended = true;
+            }
+            return Ok(());
+        }
+        while !data.is_empty() {
+            writer.reserve_capacity(data.len());
+            let capacity = std::future::poll_fn(|cx| writer.poll_capacity(cx))
+                .await
+                .or_err(WriteError, "the http/2 stream is closed")?
+                .or_err(WriteError, "failed to write the http/2 response body")?;
+            let chunk = data.split_to(capacity.min(data.len()));
+            writer
+                .send_data(chunk, end && data.is_empty())
+                .or_err(WriteError, "failed to write the http/2 response body")?;
+        }
+        self.ended = end;
+        Ok(())
+    }
+
+    /// finish the response, must be called after the whole body is written
+    pub fn finish_body(&mut self) -> Result<()> {
+        if self.ended {
+            return Ok(());
+        }
+        let writer = self
+            .body_writer
+            .as_mut()
+            .or_err(H2Error, "the response header is not sent yet")?;
+        writer
+            .send_data(Bytes::new(), true)
+            .or_err(WriteError, "failed to finish the http/2 response")?;
+        self.ended = true;
+        Ok(())
+    }
+
+    pub fn body_bytes_sent(&self) -> usize {
+        self.body_bytes_sent
+    }
+
+    /// send a response with an empty body and the given status
+    pub fn respond_error(&mut self, status: u16) -> Result<()> {
+        let mut resp = ResponseHeader::build(status, None)?;
+        resp.insert_header(header::CONTENT_LENGTH, "0")?;
+        self.write_response_header(Box::new(resp), true)
+    }
+
+    pub fn digest(&self) -> &Digest {
+        &self.digest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{Method, Request};
+
+    #[tokio::test]
+    async fn test_h2_session() {
+        let (client_io, server_io) = tokio::io::duplex(65536);
+
+        let client = tokio::spawn(async move {
+            let (mut client, conn) = h2::client::handshake(client_io).await.unwrap();
+            tokio::spawn(conn);
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("https://example.com/echo")
+                .body(())
+                .unwrap();
+            let (resp, mut body) = client.send_request(req, false).unwrap();
+            body.send_data(Bytes::from_static(b"hello"), true).unwrap();
+            let resp = resp.await.unwrap();
+            assert_eq!(resp.status(), 200);
+            assert!(resp.headers().get("connection").is_none());
+            let mut body = resp.into_body();
+            let mut received = vec![];
+            while let Some(data) = body.data().await {
+                received.extend_from_slice(&data.unwrap());
+            }
+            received
+        });
+
+        let mut conn = handshake(server_io).await.unwrap();
+        let mut session = HttpSession::from_h2_conn(&mut conn, Arc::default())
+            .await
+            .unwrap()
+            .unwrap();
+        // the connection is driven in the background while the session is served
+        let driver = tokio::spawn(async move { while let Some(Ok(_)) = conn.accept().await {} });
+
+        assert_eq!(session.req_header().uri.path(), "/echo");
+        assert_eq!(session.req_header().uri.host(), Some("example.com"));
+        let mut body = vec![];
+        while let Some(data) = session.read_body_bytes().await.unwrap() {
+            body.extend_from_slice(&data);
+        }
+        assert_eq!(body, b"hello");
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Connection", "keep-alive").unwrap();
+        session.write_response_header(Box::new(resp), false).unwrap();
+        session.write_body(Bytes::from(body), false).await.unwrap();
+        session.finish_body().unwrap();
+        assert_eq!(session.body_bytes_sent(), 5);
+
+        assert_eq!(client.await.unwrap(), b"hello");
+        driver.abort();
+    }
+}
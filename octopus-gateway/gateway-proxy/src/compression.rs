@@ -0,0 +1,669 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! compressing the responses for the downstream
+//!
+//! the responses of a compressible type are compressed with the best encoding the downstream
+//! accepts. the responses the upstream compressed with an encoding the downstream does not accept
+//! may be decompressed instead
+
+use bytes::Bytes;
+use gateway_error::{ErrorType::*, OrErr, Result};
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use http::{header, HeaderValue};
+use std::io::Write;
+
+/// the media types which are compressed, besides the `text/*` and the `+json` and `+xml` ones
+const COMPRESSIBLE_TYPES: [&str; 6] = [
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "application/x-javascript",
+    "image/svg+xml",
+];
+
+/// the encodings, in the order they are preferred when the downstream accepts them equally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+}
+
+/// compress or decompress the response for the downstream
+///
+/// it is off until [ResponseCompression::set_level()] or [ResponseCompression::set_decompress()]
+pub struct ResponseCompression {
+    /// 0 not to compress
+    level: u32,
+    decompress: bool,
+    /// the responses known to be smaller are not compressed
+    min_size: usize,
+    codec: Option<Codec>,
+}
+
+impl Default for ResponseCompression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseCompression {
+    pub fn new() -> Self {
+        ResponseCompression {
+            level: 0,
+            decompress: false,
+            min_size: 0,
+            codec: None,
+        }
+    }
+
+    /// compress the responses at `level`, which is capped at the max level of each encoding, 0
+    /// not to compress
+    pub fn set_level(&mut self, level: u32) {
+        self.level = level;
+    }
+
+    /// decompress the responses compressed with an encoding the downstream does not accept
+    pub fn set_decompress(&mut self, decompress: bool) {
+        self.decompress = decompress;
+    }
+
+    /// not to compress the responses whose `Content-Length` is smaller than `min_size`
+    pub fn set_min_size(&mut self, min_size: usize) {
+        self.min_size = min_size;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.level > 0 || self.decompress
+    }
+
+    /// the encoding the body is being compressed with, or decompressed from
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.codec.as_ref().map(|c| c.encoding)
+    }
+
+    /// decide how to transform the response to `req`, and update its header accordingly
+    pub fn response_header_filter(&mut self, req: &RequestHeader, resp: &mut ResponseHeader) -> Result<()> {
+        if !self.is_enabled() || !has_body(resp) || no_transform(resp) {
+            return Ok(());
+        }
+        let accepted = AcceptEncoding::parse(req);
+        match content_encoding(resp) {
+            None if self.level > 0 && compressible(resp) => {
+                add_vary(resp)?;
+                let too_small = content_length(resp).is_some_and(|len| len < self.min_size);
+                let Some(encoding) = accepted.best().filter(|_| !too_small) else {
+                    return Ok(());
+                };
+                self.codec = Some(Codec::encoder(encoding, self.level)?);
+                resp.insert_header(header::CONTENT_ENCODING, encoding.as_str())?;
+            }
+            Some(encoding) if self.decompress && !accepted.accepts(&encoding) => {
+                // a list of encodings is not decoded
+                let Some(encoding) = Encoding::parse(&encoding) else {
+                    return Ok(());
+                };
+                add_vary(resp)?;
+                self.codec = Some(Codec::decoder(encoding)?);
+                resp.remove_header(&header::CONTENT_ENCODING);
+            }
+            _ => return Ok(()),
+        }
+        resp.remove_header(&header::CONTENT_LENGTH);
+        resp.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+        // the bytes differ from the ones of the upstream
+        if let Some(etag) = resp
+            .headers
+            .get(header::ETAG)
+            .filter(|v| v.as_bytes().starts_with(b"\""))
+        {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            let weak = HeaderValue::from_bytes(&weak).or_err(InternalError, "invalid etag")?;
+            resp.insert_header(header::ETAG, weak)?;
+        }
+        Ok(())
+    }
+
+    /// whether the body of `resp` is to be decompressed for `req`, its bytes are then not the ones
+    /// of the upstream
+    pub fn decompresses(&self, req: &RequestHeader, resp: &ResponseHeader) -> bool {
+        if !self.decompress || !has_body(resp) || no_transform(resp) {
+            return false;
+        }
+        content_encoding(resp).is_some_and(|e| !AcceptEncoding::parse(req).accepts(&e) && Encoding::parse(&e).is_some())
+    }
+
+    /// transform the next piece of the body, `end` when the whole body is read
+    pub fn response_body_filter(&mut self, data: Option<Bytes>, end: bool) -> Result<Option<Bytes>> {
+        let Some(codec) = self.codec.as_mut() else {
+            return Ok(data);
+        };
+        let mut out = match data {
+            Some(data) => codec.write(&data)?,
+            None => vec![],
+        };
+        if end {
+            if let Some(codec) = self.codec.take() {
+                out.extend_from_slice(&codec.finish()?);
+            }
+        }
+        Ok((!out.is_empty()).then(|| Bytes::from(out)))
+    }
+}
+
+/// the `Accept-Encoding` of the request
+struct AcceptEncoding {
+    /// the q values of the encodings, in thousandths
+    encodings: Vec<(String, u16)>,
+}
+
+impl AcceptEncoding {
+    fn parse(req: &RequestHeader) -> Self {
+        let mut encodings = vec![];
+        for value in req.headers.get_all(header::ACCEPT_ENCODING).iter() {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for item in value.split(',') {
+                let mut params = item.split(';');
+                let name = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+                if name.is_empty() {
+                    continue;
+                }
+                let q = params
+                    .filter_map(|p| p.trim().strip_prefix("q=").or_else(|| p.trim().strip_prefix("Q=")))
+                    .map(parse_q)
+                    .next()
+                    .unwrap_or(1000);
+                encodings.push((name, q));
+            }
+        }
+        AcceptEncoding { encodings }
+    }
+
+    /// the q value of the encoding, `*` applies to the ones not listed
+    fn q(&self, encoding: &str) -> u16 {
+        let find = |name: &str| self.encodings.iter().find(|(n, _)| n == name).map(|(_, q)| *q);
+        let encoding = encoding.to_ascii_lowercase();
+        let encoding = if encoding == "x-gzip" {
+            "gzip".to_string()
+        } else {
+            encoding
+        };
+        find(&encoding)
+            .or_else(|| (encoding == "gzip").then(|| find("x-gzip")).flatten())
+            .or_else(|| find("*"))
+            .unwrap_or(0)
+    }
+
+    fn accepts(&self, encoding: &str) -> bool {
+        self.q(encoding) > 0
+    }
+
+    /// the most wanted encoding which is supported
+    fn best(&self) -> Option<Encoding> {
+        let mut best: Option<(Encoding, u16)> = None;
+        for encoding in Encoding::ALL {
+            let q = self.q(encoding.as_str());
+            if q > 0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+/// `0.5` to 500, a malformed value is 0
+fn parse_q(q: &str) -> u16 {
+    let q = q.trim();
+    let (int, frac) = q.split_once('.').unwrap_or((q, ""));
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return 0;
+    }
+    let frac = format!("{frac:0<3}").parse::<u16>().unwrap_or(0);
+    match int {
+        "0" => frac,
+        "1" if frac == 0 => 1000,
+        _ => 0,
+    }
+}
+
+/// the partial and the empty responses are left alone
+fn has_body(resp: &ResponseHeader) -> bool {
+    let status = resp.status.as_u16();
+    let partial = status == 206 || resp.headers.contains_key(header::CONTENT_RANGE);
+    !(resp.status.is_informational() || status == 204 || status == 304 || partial) && content_length(resp) != Some(0)
+}
+
+/// the `Content-Encoding` of the response, unless it is `identity`
+fn content_encoding(resp: &ResponseHeader) -> Option<String> {
+    resp.headers
+        .get(header::CONTENT_ENCODING)
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).trim().to_string())
+        .filter(|v| !v.is_empty() && !v.eq_ignore_ascii_case("identity"))
+}
+
+fn no_transform(resp: &ResponseHeader) -> bool {
+    resp.headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|d| d.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+fn compressible(resp: &ResponseHeader) -> bool {
+    let Some(content_type) = resp.headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || COMPRESSIBLE_TYPES.contains(&media_type.as_str())
+}
+
+fn content_length(resp: &ResponseHeader) -> Option<usize> {
+    resp.headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+}
+
+/// the response differs by the `Accept-Encoding` of the request
+fn add_vary(resp: &mut ResponseHeader) -> Result<()> {
+    let vary: Vec<String> = resp
+        .headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+    if vary
+        .iter()
+        .any(|v| v == "*" || v.eq_ignore_ascii_case("accept-encoding"))
+    {
+        return Ok(());
+    }
+    let mut value = vary.join(", ");
+    if !value.is_empty() {
+        value.push_str(", ");
+    }
+    value.push_str("Accept-Encoding");
+    resp.insert_header(header::VARY, value)?;
+    Ok(())
+}
+
+/// the compressor or the decompressor of the body, writing into a buffer which is taken after
+/// each write
+struct Codec {
+    encoding: Encoding,
+    inner: CodecInner,
+}
+
+enum CodecInner {
+    GzipEncoder(flate2::write::GzEncoder<Vec<u8>>),
+    GzipDecoder(flate2::write::GzDecoder<Vec<u8>>),
+    BrotliEncoder(Box<brotli::CompressorWriter<Vec<u8>>>),
+    BrotliDecoder(Box<brotli::DecompressorWriter<Vec<u8>>>),
+    ZstdEncoder(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    ZstdDecoder(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+/// the size of the internal buffer of brotli
+const BROTLI_BUF_SIZE: usize = 4096;
+
+impl Codec {
+    fn encoder(encoding: Encoding, level: u32) -> Result<Self> {
+        let inner = match encoding {
+            Encoding::Gzip => CodecInner::GzipEncoder(flate2::write::GzEncoder::new(
+                vec![],
+                flate2::Compression::new(level.min(9)),
+            )),
+            Encoding::Brotli => CodecInner::BrotliEncoder(Box::new(brotli::CompressorWriter::new(
+                vec![],
+                BROTLI_BUF_SIZE,
+                level.min(11),
+                22,
+            ))),
+            Encoding::Zstd => CodecInner::ZstdEncoder(
+                zstd::stream::write::Encoder::new(vec![], level.min(22) as i32)
+                    .or_err(CompressionError, "failed to create the zstd encoder")?,
+            ),
+        };
+        Ok(Codec { encoding, inner })
+    }
+
+    fn decoder(encoding: Encoding) -> Result<Self> {
+        let inner = match encoding {
+            Encoding::Gzip => CodecInner::GzipDecoder(flate2::write::GzDecoder::new(vec![])),
+            Encoding::Brotli => {
+                CodecInner::BrotliDecoder(Box::new(brotli::DecompressorWriter::new(vec![], BROTLI_BUF_SIZE)))
+            }
+            Encoding::Zstd => CodecInner::ZstdDecoder(
+                zstd::stream::write::Decoder::new(vec![])
+                    .or_err(CompressionError, "failed to create the zstd decoder")?,
+            ),
+        };
+        Ok(Codec { encoding, inner })
+    }
+
+    /// transform the data, return what is output so far
+    fn write(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let out = match &mut self.inner {
+            CodecInner::GzipEncoder(w) => w.write_all(data).map(|_| w.get_mut()),
+            CodecInner::GzipDecoder(w) => w.write_all(data).map(|_| w.get_mut()),
+            CodecInner::BrotliEncoder(w) => w.write_all(data).map(|_| w.get_mut()),
+            CodecInner::BrotliDecoder(w) => w.write_all(data).map(|_| w.get_mut()),
+            CodecInner::ZstdEncoder(w) => w.write_all(data).map(|_| w.get_mut()),
+            CodecInner::ZstdDecoder(w) => w.write_all(data).map(|_| w.get_mut()),
+        };
+        let out = out.or_err_with(CompressionError, || {
+            format!("failed to transcode {}", self.encoding.as_str())
+        })?;
+        Ok(std::mem::take(out))
+    }
+
+    /// finish the stream, return the rest of the output
+    fn finish(self) -> Result<Vec<u8>> {
+        let encoding = self.encoding.as_str();
+        let out = match self.inner {
+            CodecInner::GzipEncoder(w) => w.finish(),
+            CodecInner::GzipDecoder(w) => w.finish(),
+            CodecInner::BrotliEncoder(w) => Ok(w.into_inner()),
+            CodecInner::BrotliDecoder(w) => w
+                .into_inner()
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "incomplete stream")),
+            CodecInner::ZstdEncoder(w) => w.finish(),
+            CodecInner::ZstdDecoder(mut w) => w.flush().map(|_| w.into_inner()),
+        };
+        out.or_err_with(CompressionError, || format!("failed to finish {encoding}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(accept_encoding: &str) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("Accept-Encoding", accept_encoding.to_string())
+            .unwrap();
+        req
+    }
+
+    fn response(content_type: &str) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Content-Type", content_type.to_string()).unwrap();
+        resp.insert_header("Content-Length", "2048").unwrap();
+        resp.insert_header("ETag", "\"v1\"").unwrap();
+        resp
+    }
+
+    fn filter(compression: &mut ResponseCompression, body: &[&[u8]]) -> Vec<u8> {
+        let mut out = vec![];
+        for (i, piece) in body.iter().enumerate() {
+            let data = Bytes::copy_from_slice(piece);
+            if let Some(data) = compression
+                .response_body_filter(Some(data), i + 1 == body.len())
+                .unwrap()
+            {
+                out.extend_from_slice(&data);
+            }
+        }
+        out
+    }
+
+    fn decode(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        match encoding {
+            Encoding::Gzip => flate2::read::GzDecoder::new(data).read_to_end(&mut out).unwrap(),
+            Encoding::Brotli => brotli::Decompressor::new(data, 4096).read_to_end(&mut out).unwrap(),
+            Encoding::Zstd => zstd::stream::read::Decoder::new(data)
+                .unwrap()
+                .read_to_end(&mut out)
+                .unwrap(),
+        };
+        out
+    }
+
+    #[test]
+    fn test_compress() {
+        let body = "hello world ".repeat(200);
+        for encoding in Encoding::ALL {
+            let mut compression = ResponseCompression::new();
+            compression.set_level(6);
+            let mut resp = response("text/html; charset=utf-8");
+            compression
+                .response_header_filter(&request(encoding.as_str()), &mut resp)
+                .unwrap();
+            assert_eq!(compression.encoding(), Some(encoding));
+            assert_eq!(resp.headers["content-encoding"], encoding.as_str());
+            assert_eq!(resp.headers["transfer-encoding"], "chunked");
+            assert_eq!(resp.headers["vary"], "Accept-Encoding");
+            assert_eq!(resp.headers["etag"], "W/\"v1\"");
+            assert!(resp.headers.get("content-length").is_none());
+
+            let (head, tail) = body.as_bytes().split_at(1000);
+            let out = filter(&mut compression, &[head, tail]);
+            assert!(out.len() < body.len());
+            assert_eq!(decode(encoding, &out), body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        let best = |value: &str| AcceptEncoding::parse(&request(value)).best();
+        assert_eq!(best("gzip, br, zstd"), Some(Encoding::Brotli));
+        assert_eq!(best("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(best("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(best("br;q=0.5, gzip;q=0.8"), Some(Encoding::Gzip));
+        assert_eq!(best("br;q=0, *"), Some(Encoding::Zstd));
+        assert_eq!(best("*;q=0"), None);
+        assert_eq!(best("identity"), None);
+        assert_eq!(best("gzip;q=2"), None);
+
+        let accepted = AcceptEncoding::parse(&request("gzip;q=0.001, br;q=0"));
+        assert!(accepted.accepts("GZIP"));
+        assert!(!accepted.accepts("br"));
+        assert!(!accepted.accepts("zstd"));
+    }
+
+    #[test]
+    fn test_not_compressed() {
+        let mut compression = ResponseCompression::new();
+        compression.set_level(6);
+        compression.set_min_size(1024);
+
+        // not compressible
+        let mut resp = response("image/png");
+        compression.response_header_filter(&request("gzip"), &mut resp).unwrap();
+        assert_eq!(compression.encoding(), None);
+        assert!(resp.headers.get("vary").is_none());
+
+        // too small, but varies
+        let mut resp = response("application/json");
+        resp.insert_header("Content-Length", "100").unwrap();
+        resp.insert_header("Vary", "Origin").unwrap();
+        compression.response_header_filter(&request("gzip"), &mut resp).unwrap();
+        assert_eq!(compression.encoding(), None);
+        assert_eq!(resp.headers["vary"], "Origin, Accept-Encoding");
+        assert_eq!(resp.headers["etag"], "\"v1\"");
+
+        // not to be transformed
+        let mut resp = response("text/plain");
+        resp.insert_header("Cache-Control", "public, no-transform").unwrap();
+        compression.response_header_filter(&request("gzip"), &mut resp).unwrap();
+        assert_eq!(compression.encoding(), None);
+
+        // partial
+        let mut resp = response("text/plain");
+        resp.set_status(206).unwrap();
+        compression.response_header_filter(&request("gzip"), &mut resp).unwrap();
+        assert_eq!(compression.encoding(), None);
+
+        // the body passes through
+        let out = filter(&mut compression, &[b"plain"]);
+        assert_eq!(out, b"plain");
+    }
+
+    #[test]
+    fn test_decompress() {
+        let body = "hello world ".repeat(200);
+        let mut encoder = Codec::encoder(Encoding::Zstd, 3).unwrap();
+        let mut compressed = encoder.write(body.as_bytes()).unwrap();
+        compressed.extend_from_slice(&encoder.finish().unwrap());
+
+        let mut compression = ResponseCompression::new();
+        compression.set_decompress(true);
+        // accepted, passed through
+        let mut resp = response("text/plain");
+        resp.insert_header("Content-Encoding", "zstd").unwrap();
+        compression
+            .response_header_filter(&request("zstd, gzip"), &mut resp)
+            .unwrap();
+        assert_eq!(compression.encoding(), None);
+        assert_eq!(resp.headers["content-encoding"], "zstd");
+
+        let mut resp = response("text/plain");
+        resp.insert_header("Content-Encoding", "zstd").unwrap();
+        compression.response_header_filter(&request("gzip"), &mut resp).unwrap();
+        assert_eq!(compression.encoding(), Some(Encoding::Zstd));
+        assert!(resp.headers.get("content-encoding").is_none());
+        assert_eq!(resp.headers["etag"], "W/\"v1\"");
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+        assert_eq!(filter(&mut compression, &[head, tail]), body.as_bytes());
+
+        // a broken stream
+        let mut resp = response("text/plain");
+        resp.insert_header("Content-Encoding", "gzip").unwrap();
+        compression.response_header_filter(&request("br"), &mut resp).unwrap();
+        let e = compression.response_body_filter(Some(Bytes::from_static(b"not gzip")), true);
+        assert_eq!(e.unwrap_err().etype(), &CompressionError);
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        use crate::testing::*;
+        use tokio::net::TcpStream;
+
+        let mut proxy = TestProxy::new(mock_upstream(proxied).await);
+        proxy.compression = Some(6);
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: gzip\r\n\r\n";
+        let resp = request_bytes(&mut stream, req).await;
+        let split = resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&resp[..split]).to_string();
+        assert!(head.contains("Content-Encoding: gzip\r\n"), "{head}");
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+        assert!(head.contains("Vary: Accept-Encoding\r\n"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+
+        // one chunk or more
+        let mut chunks = &resp[split..];
+        let mut compressed = vec![];
+        loop {
+            let line = chunks.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&chunks[..line]).unwrap(), 16).unwrap();
+            if size == 0 {
+                break;
+            }
+            compressed.extend_from_slice(&chunks[line + 2..line + 2 + size]);
+            chunks = &chunks[line + 4 + size..];
+        }
+        assert_eq!(decode(Encoding::Gzip, &compressed), b"proxied");
+
+        // not accepted
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.contains("Vary: Accept-Encoding\r\n"), "{resp}");
+        assert!(resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        assert!(!resp.contains("Content-Encoding"), "{resp}");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_range_decompressed() {
+        use crate::testing::*;
+        use flate2::write::GzEncoder;
+        use tokio::net::TcpStream;
+
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"hello world").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let upstream = mock_upstream(move |_| {
+            MockResponse::new(200)
+                .header("Content-Type", "text/plain")
+                .header("Content-Encoding", "gzip")
+                .body(gzipped.clone())
+        })
+        .await;
+        let mut proxy = TestProxy::new(upstream);
+        proxy.decompress = true;
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        // the ranges are of the gzipped body, the decompressed one is served whole
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = b"GET / HTTP/1.1\r\nHost: example.com\r\nRange: bytes=0-4\r\n\r\n";
+        let resp = request(&mut stream, req).await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        assert!(!resp.contains("Content-Range") && !resp.contains("Content-Encoding"), "{resp}");
+        assert!(resp.contains("\r\n\r\nB\r\nhello world\r\n"), "{resp}");
+
+        // the gzipped body is accepted as it is
+        let req = b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: gzip\r\nRange: bytes=0-4\r\n\r\n";
+        let resp = request(&mut stream, req).await;
+        assert!(resp.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{resp}");
+        assert!(resp.contains("Content-Encoding: gzip\r\n"), "{resp}");
+    }
+}
@@ -1,14 +1,834 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the http proxy
+//!
+//! [HttpProxy] reads the requests from the downstream, lets the [ProxyHttp] implementation
+//! decide where and how to send them, and streams the responses back
+//!
+//! the `.`, `..` and empty segments of the path of a request are resolved before anything else,
+//! so the route, the cache key and the upstream all see the same path
+//!
+//! the responses are cached when [ProxyHttp::request_cache_filter()] enables [Session::cache]. the
+//! stale ones are revalidated with the upstream, in the background if `stale-while-revalidate`
+//! allows. with a [gateway_cache::CacheLock] the requests of the same key wait for the one
+//! fetching the response, and are served it while it is fetched
+//!
+//! the response bodies are compressed for the downstream when [Session::compression] is enabled
+//!
+//! a [Router] picks the route of a request, for [ProxyHttp] implementations to send it where the
+//! route says, and a [Rewrite] rewrites the request and the response as the route says. the
+//! routes which never reach an upstream are answered with a [StaticResponse]
+//!
+//! the requests over a [RateLimit] are answered with 429
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use gateway_cache::{
+    merge_not_modified, req_cacheable, resp_cacheable, CacheKey, CachePhase, HttpCache, RespCacheable,
+};
+use gateway_core::apps::{HttpServerApp, ServerSession};
+use gateway_core::connectors::TransportConnector;
+use gateway_core::protocols::Stream;
+use gateway_core::server::ShutdownWatch;
+use gateway_core::services::listening::ListeningService;
+use gateway_core::upstreams::peer::HttpPeer;
+use gateway_error::{Error, ErrorSource, ErrorType::*, OrErr, Result};
+use gateway_httpd::server::{ClientCertDigest, Digest};
+use gateway_httpd::v1::client::HttpSession as ClientSession;
+use gateway_httpd::v1::server::HttpSession as H1ServerSession;
+use gateway_httpd::util::normalize_path;
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use http::{header, Method, Uri, Version};
+use log::{debug, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+
+pub mod compression;
+mod proxy_trait;
+mod range;
+pub mod rate_limit;
+pub mod rewrite;
+pub mod router;
+pub mod static_response;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub use compression::ResponseCompression;
+pub use proxy_trait::ProxyHttp;
+pub use rate_limit::RateLimit;
+pub use rewrite::Rewrite;
+pub use router::{RouteMatcher, Router};
+pub use static_response::StaticResponse;
+use range::RangeBodyFilter;
+
+/// the max number of attempts to connect to the upstream
+const MAX_RETRIES: usize = 16;
+
+/// the buffer size of the in-memory stream of a subrequest
+const SUBREQUEST_BUF_SIZE: usize = 64 * 1024;
+
+/// the downstream request being proxied
+pub struct Session {
+    downstream: ServerSession,
+    /// the cache state of the request
+    pub cache: HttpCache,
+    /// how the response body is compressed for the downstream
+    pub compression: ResponseCompression,
+}
+
+impl Session {
+    pub fn new(downstream: ServerSession) -> Self {
+        Session {
+            downstream,
+            cache: HttpCache::new(),
+            compression: ResponseCompression::new(),
+        }
+    }
+
+    /// a session of a request the proxy sends on its own, e.g. to revalidate a cached response in
+    /// the background, with the digest of the downstream it is sent for
+    ///
+    /// the response written to the session is discarded
+    pub async fn subrequest(req: &RequestHeader, digest: Arc<Digest>) -> Result<Self> {
+        let (mut client, server) = tokio::io::duplex(SUBREQUEST_BUF_SIZE);
+        let mut head = Vec::with_capacity(512);
+        head.extend_from_slice(req.method.as_str().as_bytes());
+        head.push(b' ');
+        head.extend_from_slice(req.raw_path());
+        head.extend_from_slice(b" HTTP/1.1\r\n");
+        req.header_to_h1_write(&mut head);
+        head.extend_from_slice(b"\r\n");
+        client
+            .write_all(&head)
+            .await
+            .or_err(WriteError, "while writing subrequest")?;
+        tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut client, &mut tokio::io::sink()).await;
+        });
+
+        let mut session = H1ServerSession::new(Box::new(server) as Stream);
+        session.set_digest(digest);
+        session.read_request().await?;
+        Ok(Session::new(ServerSession::H1(session)))
+    }
+
+    /// the request header from the downstream
+    pub fn req_header(&self) -> &RequestHeader {
+        self.downstream.req_header()
+    }
+
+    pub fn req_header_mut(&mut self) -> &mut RequestHeader {
+        self.downstream.req_header_mut()
+    }
+
+    /// the address of the downstream client
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.downstream.digest().client_addr
+    }
+
+    /// the information of the downstream connection, e.g. its TLS details
+    pub fn digest(&self) -> &Digest {
+        self.downstream.digest()
+    }
+
+    /// the verified cert of the client, for the hooks to authorize the request with
+    pub fn client_cert(&self) -> Option<&ClientCertDigest> {
+        self.digest().tls.as_ref()?.client_cert.as_ref()
+    }
+
+    /// whether the downstream speaks http/2
+    pub fn is_http2(&self) -> bool {
+        self.downstream.is_http2()
+    }
+
+    /// read the next piece of the request body, `None` when the body is finished
+    pub async fn read_request_body(&mut self) -> Result<Option<Bytes>> {
+        self.downstream.read_body_bytes().await
+    }
+
+    pub async fn write_response_header(&mut self, resp: Box<ResponseHeader>) -> Result<()> {
+        self.downstream.write_response_header(resp).await
+    }
+
+    pub async fn write_response_body(&mut self, data: Bytes) -> Result<()> {
+        self.downstream.write_body(data).await
+    }
+
+    /// finish the response, must be called after the whole body is written
+    pub async fn finish_response(&mut self) -> Result<()> {
+        self.downstream.finish_body().await
+    }
+
+    /// send a response with an empty body and the given status
+    pub async fn respond_error(&mut self, status: u16) -> Result<()> {
+        self.downstream.respond_error(status).await
+    }
+
+    /// the response header sent to the downstream, if any
+    pub fn response_written(&self) -> Option<&ResponseHeader> {
+        self.downstream.response_written()
+    }
+
+    /// the underlying http/1 or http/2 session of the downstream
+    pub fn downstream_session(&mut self) -> &mut ServerSession {
+        &mut self.downstream
+    }
+}
+
+/// the [HttpServerApp] that proxies requests according to `SV`
+pub struct HttpProxy<SV> {
+    inner: SV,
+    connector: TransportConnector,
+}
+
+impl<SV> HttpProxy<SV> {
+    pub fn new(inner: SV) -> Self {
+        HttpProxy {
+            inner,
+            connector: TransportConnector::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &SV {
+        &self.inner
+    }
+}
+
+impl<SV> HttpProxy<SV>
+where
+    SV: ProxyHttp + Send + Sync + 'static,
+    SV::CTX: Send + Sync + 'static,
+{
+    async fn connect_upstream(&self, session: &mut Session, ctx: &mut SV::CTX) -> Result<(Box<HttpPeer>, Stream)> {
+        let mut retries = 0;
+        loop {
+            let peer = self.inner.upstream_peer(session, ctx).await?;
+            match self.connector.new_stream(&peer).await {
+                Ok(stream) => return Ok((peer, stream)),
+                Err(e) => {
+                    let e = self.inner.fail_to_connect(session, &peer, ctx, e);
+                    retries += 1;
+                    if !e.retry() || retries >= MAX_RETRIES {
+                        return Err(e);
+                    }
+                    debug!("retry after failing to connect to {:?}: {e}", peer.address);
+                }
+            }
+        }
+    }
+
+    async fn proxy_to_upstream(&self, session: &mut Session, ctx: &mut SV::CTX) -> Result<()> {
+        let (peer, stream) = self.connect_upstream(session, ctx).await?;
+        let mut upstream = ClientSession::new(stream);
+        upstream.read_timeout = peer.options.read_timeout;
+        upstream.write_timeout = peer.options.write_timeout;
+
+        let mut req = session.req_header().clone();
+        if session.is_http2() {
+            Self::h2_to_h1_request(&mut req, session.downstream.is_body_done())?;
+        }
+        session.cache.conditional_request(&mut req)?;
+        if matches!(session.cache.phase(), CachePhase::Miss | CachePhase::Stale) {
+            // the whole response is fetched to be cached, the ranges are cut out of it
+            req.remove_header(&header::RANGE);
+            req.remove_header(&header::IF_RANGE);
+        }
+        self.inner.upstream_request_filter(session, &mut req, ctx).await?;
+
+        upstream
+            .write_request_header(&req)
+            .await
+            .map_err(|e| e.into_up())?;
+        while let Some(data) = session.read_request_body().await? {
+            upstream.write_body(&data).await.map_err(|e| e.into_up())?;
+        }
+        upstream.finish_body().await.map_err(|e| e.into_up())?;
+
+        let mut resp = upstream
+            .read_response_header()
+            .await
+            .map_err(|e| e.into_up())?
+            .clone();
+        self.inner.upstream_response_filter(session, &mut resp, ctx).await?;
+        if session.cache.phase() == CachePhase::Stale {
+            if resp.status == 304 {
+                return self.serve_revalidated(session, &resp, ctx).await;
+            }
+            if resp.status.is_server_error() && session.cache.can_serve_stale_if_error() {
+                debug!("serving the stale response instead of {}", resp.status);
+                return self.serve_from_cache(session, ctx).await;
+            }
+        }
+        if matches!(session.cache.phase(), CachePhase::Miss | CachePhase::Stale) {
+            match self.inner.response_cache_filter(session, &resp, ctx)? {
+                RespCacheable::Cacheable(meta) => {
+                    session.cache.set_miss_response(*meta, session.downstream.req_header())
+                }
+                RespCacheable::Uncacheable(reason) => {
+                    debug!("not caching the response: {reason}");
+                    session.cache.bypass();
+                }
+            }
+        }
+        self.inner.response_filter(session, &mut resp, ctx).await?;
+        let mut range = Self::range_header_filter(session, &mut resp)?;
+        session.compression.response_header_filter(session.downstream.req_header(), &mut resp)?;
+        session.write_response_header(Box::new(resp)).await?;
+
+        loop {
+            let mut body = upstream.read_body_bytes().await.map_err(|e| e.into_up())?;
+            let end = body.is_none();
+            if let Some(data) = body.as_ref() {
+                session.cache.write_miss_body(data);
+            }
+            self.inner.response_body_filter(session, &mut body, end, ctx)?;
+            let body = session.compression.response_body_filter(range.filter_body(body, end), end)?;
+            if let Some(data) = body {
+                session.write_response_body(data).await?;
+            }
+            if end {
+                break;
+            }
+        }
+        session.finish_response().await?;
+        if let Err(e) = session.cache.finish_miss().await {
+            warn!("failed to cache the response: {e}");
+        }
+        Ok(())
+    }
+
+    /// look up the cache, return `Ok(true)` if the response is served from the cache
+    async fn cache_lookup(self: &Arc<Self>, session: &mut Session, ctx: &mut SV::CTX) -> Result<bool> {
+        if !req_cacheable(session.req_header()) {
+            session.cache.bypass();
+            return Ok(false);
+        }
+        let key = self.inner.cache_key_callback(session, ctx)?;
+        session.cache.set_key(key);
+        if let Err(e) = session.cache.lookup(session.downstream.req_header()).await {
+            warn!("failed to look up the cache: {e}");
+            session.cache.bypass();
+            return Ok(false);
+        }
+        if session.cache.phase() == CachePhase::Hit {
+            if !self.inner.cache_hit_filter(session, ctx).await? {
+                self.serve_from_cache(session, ctx).await?;
+                return Ok(true);
+            }
+            session.cache.expire();
+        }
+        if session.cache.can_serve_stale_while_revalidate() {
+            if let Some(cache) = session.cache.revalidate_in_background() {
+                self.spawn_revalidation(session, cache);
+            }
+            self.serve_from_cache(session, ctx).await?;
+            return Ok(true);
+        }
+        if matches!(session.cache.phase(), CachePhase::Miss | CachePhase::Stale) {
+            self.inner.cache_miss(session, ctx);
+        }
+        Ok(false)
+    }
+
+    /// revalidate the stale response of the session in the background with a subrequest
+    fn spawn_revalidation(self: &Arc<Self>, session: &Session, cache: HttpCache) {
+        let mut req = session.req_header().clone();
+        // the body of the response to HEAD is needed to update the cache
+        req.set_method(Method::GET);
+        let http2 = session.is_http2();
+        let digest = Arc::new(session.digest().clone());
+        let proxy = self.clone();
+        tokio::spawn(async move {
+            let revalidate = async {
+                if http2 {
+                    Self::h2_to_h1_request(&mut req, true)?;
+                }
+                let mut subrequest = Session::subrequest(&req, digest).await?;
+                subrequest.cache = cache;
+                let mut ctx = proxy.inner.new_ctx();
+                proxy.proxy_to_upstream(&mut subrequest, &mut ctx).await
+            };
+            if let Err(e) = revalidate.await {
+                debug!("failed to revalidate {} in the background: {e}", req.uri);
+            }
+        });
+    }
+
+    /// the stale response is not modified, update the cache and serve it
+    async fn serve_revalidated(&self, session: &mut Session, resp: &ResponseHeader, ctx: &mut SV::CTX) -> Result<()> {
+        let stored = session.cache.cached().expect("revalidating a cached response");
+        let header = merge_not_modified(stored.meta.header(), resp)?;
+        let cacheable = self.inner.response_cache_filter(session, &header, ctx)?;
+        if let Err(e) = session.cache.revalidated(header, cacheable).await {
+            warn!("failed to update the cached response: {e}");
+        }
+        self.serve_from_cache(session, ctx).await
+    }
+
+    async fn serve_from_cache(&self, session: &mut Session, ctx: &mut SV::CTX) -> Result<()> {
+        let cached = session.cache.cached().expect("served on a hit").clone();
+        let mut resp = cached.meta.header().clone();
+        let age = cached.meta.age(SystemTime::now()).as_secs();
+        resp.insert_header(header::AGE, age.to_string())?;
+        self.inner.response_filter(session, &mut resp, ctx).await?;
+        let mut range = Self::range_header_filter(session, &mut resp)?;
+        session.compression.response_header_filter(session.downstream.req_header(), &mut resp)?;
+        session.write_response_header(Box::new(resp)).await?;
+        if session.req_header().method != Method::HEAD {
+            loop {
+                let mut body = session.cache.read_body().await?;
+                let end = body.is_none();
+                self.inner.response_body_filter(session, &mut body, end, ctx)?;
+                let body = session.compression.response_body_filter(range.filter_body(body, end), end)?;
+                if let Some(data) = body {
+                    session.write_response_body(data).await?;
+                }
+                if end {
+                    break;
+                }
+            }
+        }
+        session.finish_response().await
+    }
+
+    /// resolve the `.` and `..` segments and the empty ones of the path, so the route, the cache
+    /// key and the upstream all see the same path
+    fn normalize_request(req: &mut RequestHeader) -> Result<()> {
+        // `*`, or a path which is not utf-8 and is forwarded as it is
+        let raw = req.uri.path_and_query().filter(|p| p.as_str().as_bytes() == req.raw_path());
+        let Some(path) = raw.map(|p| p.path()).filter(|p| p.starts_with('/')) else {
+            return Ok(());
+        };
+        let path = normalize_path(path);
+        if path == req.uri.path() {
+            return Ok(());
+        }
+        let path_and_query = match req.uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
+        let mut parts = req.uri.clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().or_err(HTTPStatus(400), "invalid path")?);
+        let uri = Uri::from_parts(parts).or_err(HTTPStatus(400), "invalid path")?;
+        req.set_uri(uri);
+        Ok(())
+    }
+
+    /// the filter of the ranges the request asks for, which are ranges of the upstream body, so a
+    /// body decompressed for the downstream is served whole
+    fn range_header_filter(session: &Session, resp: &mut ResponseHeader) -> Result<RangeBodyFilter> {
+        if session.compression.decompresses(session.downstream.req_header(), resp) {
+            return Ok(RangeBodyFilter::default());
+        }
+        range::range_header_filter(session.req_header(), resp)
+    }
+
+    /// the upstreams speak http/1.1, so the host of a http/2 request moves from the
+    /// `:authority` into the `Host` header, and a body of unknown length is chunked
+    fn h2_to_h1_request(req: &mut RequestHeader, body_done: bool) -> Result<()> {
+        if !req.headers.contains_key(header::HOST) {
+            if let Some(authority) = req.uri.authority() {
+                let host = authority.as_str().to_string();
+                req.insert_header(header::HOST, host)?;
+            }
+        }
+        if !body_done && !req.headers.contains_key(header::CONTENT_LENGTH) {
+            req.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+        }
+        req.set_version(Version::HTTP_11);
+        Ok(())
+    }
+
+    async fn process_request(self: &Arc<Self>, session: &mut Session, ctx: &mut SV::CTX) -> Result<()> {
+        Self::normalize_request(session.req_header_mut())?;
+        if self.inner.request_filter(session, ctx).await? {
+            return Ok(());
+        }
+        self.inner.request_cache_filter(session, ctx)?;
+        if session.cache.enabled() && self.cache_lookup(session, ctx).await? {
+            return Ok(());
+        }
+        match self.proxy_to_upstream(session, ctx).await {
+            Err(e) if session.response_written().is_none() && session.cache.can_serve_stale_if_error() => {
+                warn!("serving the stale response instead of the error: {e}");
+                self.serve_from_cache(session, ctx).await
+            }
+            result => result,
+        }
+    }
+}
+
+#[async_trait]
+impl<SV> HttpServerApp for HttpProxy<SV>
+where
+    SV: ProxyHttp + Send + Sync + 'static,
+    SV::CTX: Send + Sync,
+{
+    async fn process_new_http(self: &Arc<Self>, session: ServerSession, _shutdown: &ShutdownWatch) -> Option<Stream> {
+        let mut session = Session::new(session);
+        let mut ctx = self.inner.new_ctx();
+
+        match self.process_request(&mut session, &mut ctx).await {
+            Ok(()) => {
+                self.inner.logging(&mut session, None, &mut ctx).await;
+                session.downstream.reuse().await
+            }
+            Err(e) => {
+                self.inner.fail_to_proxy(&mut session, &e, &mut ctx).await;
+                self.inner.logging(&mut session, Some(&e), &mut ctx).await;
+                None
+            }
+        }
+    }
+}
+
+/// create a [ListeningService] which proxies requests with `inner`
+pub fn http_proxy_service<SV>(name: &str, inner: SV) -> ListeningService<HttpProxy<SV>> {
+    ListeningService::new(name.to_string(), HttpProxy::new(inner))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let proxy = TestProxy::new(mock_upstream(proxied).await);
+        let logged = proxy.logged.clone();
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        assert!(resp.contains("X-Via: octopus\r\n"), "{resp}");
+        assert!(resp.ends_with("\r\n\r\nproxied"), "{resp}");
+
+        // the downstream connection is kept alive
+        let resp = get(&mut stream, "/deny").await;
+        assert!(resp.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{resp}");
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        assert_eq!(logged.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_tls_h1_and_h2() {
+        use gateway_core::protocols::tls::server::TlsSettings;
+        use rustls::pki_types::ServerName;
+        use tokio_rustls::TlsConnector;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let mut settings =
+            TlsSettings::from_pem(cert.cert.pem().as_bytes(), cert.key_pair.serialize_pem().as_bytes()).unwrap();
+        settings.enable_h2();
+        let proxy = TestProxy::new(mock_upstream(proxied).await);
+        let (addr, _shutdown) = start_proxy(proxy, Some(settings));
+
+        let connect = |alpn: &[u8]| {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(cert.cert.der().clone()).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut config = rustls::ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            config.alpn_protocols = vec![alpn.to_vec()];
+            async move {
+                let tcp = TcpStream::connect(addr).await.unwrap();
+                let name = ServerName::try_from("localhost").unwrap();
+                TlsConnector::from(Arc::new(config)).connect(name, tcp).await.unwrap()
+            }
+        };
+
+        // http/1.1 over TLS
+        let mut tls = connect(b"http/1.1").await;
+        tls.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        let mut buf = vec![0; 4096];
+        // the header and the body may come in separate records
+        while !resp.contains("\r\n\r\n") || resp.ends_with("\r\n\r\n") {
+            let n = tls.read(&mut buf).await.unwrap();
+            assert!(n > 0, "{resp}");
+            resp.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        assert!(resp.ends_with("\r\n\r\nproxied"), "{resp}");
+
+        // http/2, the upstream still gets http/1.1 with a Host header
+        let tls = connect(b"h2").await;
+        let (mut client, conn) = h2::client::handshake(tls).await.unwrap();
+        tokio::spawn(conn);
+        for path in ["/", "/deny"] {
+            let req = http::Request::get(format!("https://localhost{path}")).body(()).unwrap();
+            let (resp, _) = client.send_request(req, true).unwrap();
+            let resp = resp.await.unwrap();
+            if path == "/deny" {
+                assert_eq!(resp.status(), 403);
+                continue;
+            }
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers()["x-via"], "octopus");
+            let mut body = resp.into_body();
+            let mut data = vec![];
+            while let Some(chunk) = body.data().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+            assert_eq!(data, b"proxied");
+        }
+    }
+
+    /// [proxied()], cached for a minute under `/cache`, and the number of the requests for them
+    async fn cache_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counted = fetches.clone();
+        let upstream = mock_upstream(move |req| {
+            if !req.uri.path().starts_with("/cache") {
+                return proxied(req);
+            }
+            counted.fetch_add(1, Ordering::Relaxed);
+            proxied(req).header("Cache-Control", "max-age=60")
+        })
+        .await;
+        (upstream, fetches)
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let (upstream, fetches) = cache_upstream().await;
+        let storage = Arc::new(gateway_cache::MemoryStorage::new(1 << 20));
+        let (addr, _shutdown) = start_proxy(TestProxy::new(upstream).with_cache(storage.clone()), None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/cache").await;
+        assert!(resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        assert!(!resp.contains("Age:"), "{resp}");
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        // served from the cache, the response filter still applies
+        let resp = get(&mut stream, "/cache").await;
+        assert!(resp.contains("Age: 0\r\n"), "{resp}");
+        assert!(resp.contains("X-Via: octopus\r\n"), "{resp}");
+        assert!(resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        let resp = request(&mut stream, b"HEAD /cache HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        assert!(
+            resp.contains("Content-Length: 7\r\n") && resp.ends_with("\r\n\r\n"),
+            "{resp}"
+        );
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        // not cached
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        assert_eq!(storage.len(), 1);
+        let resp = request(
+            &mut stream,
+            b"GET /cache HTTP/1.1\r\nHost: example.com\r\nCache-Control: no-store\r\n\r\n",
+        )
+        .await;
+        assert!(!resp.contains("Age:"), "{resp}");
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+
+        // the ranges are cut out of the cached response
+        let range = |path: &str, range: &str, if_range: &str| {
+            format!("GET {path} HTTP/1.1\r\nHost: example.com\r\nRange: {range}\r\n{if_range}\r\n")
+        };
+        let resp = request(&mut stream, range("/cache", "bytes=0-1", "").as_bytes()).await;
+        assert!(resp.starts_with("HTTP/1.1 206 Partial Content\r\n"), "{resp}");
+        assert!(resp.contains("Content-Range: bytes 0-1/7\r\n") && resp.ends_with("\r\n\r\npr"), "{resp}");
+        let resp = request(&mut stream, range("/cache", "bytes=7-", "").as_bytes()).await;
+        assert!(resp.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"), "{resp}");
+        let req = range("/cache", "bytes=0-1", "If-Range: \"v0\"\r\n");
+        let resp = request(&mut stream, req.as_bytes()).await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n") && resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        // the whole response is fetched on a miss
+        let resp = request(&mut stream, range("/cache-range", "bytes=1-2", "").as_bytes()).await;
+        assert!(resp.starts_with("HTTP/1.1 206 Partial Content\r\n") && resp.ends_with("\r\n\r\nro"), "{resp}");
+        let resp = get(&mut stream, "/cache-range").await;
+        assert!(resp.contains("Age: ") && resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        assert_eq!(fetches.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cache_path() {
+        // echoes the path it receives
+        let upstream = mock_upstream(|req| {
+            MockResponse::new(200).header("Cache-Control", "max-age=60").body(req.uri.path())
+        })
+        .await;
+        let storage = Arc::new(gateway_cache::MemoryStorage::new(1 << 20));
+        let (addr, _shutdown) = start_proxy(TestProxy::new(upstream).with_cache(storage.clone()), None);
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // the upstream receives the normalized path, which is the one of the key
+        let resp = get(&mut stream, "/x/../y").await;
+        assert!(!resp.contains("Age:") && resp.ends_with("\r\n\r\n/y"), "{resp}");
+        for path in ["/y", "/x/%2E%2e/y", "//y"] {
+            let resp = get(&mut stream, path).await;
+            assert!(resp.contains("Age: 0\r\n") && resp.ends_with("\r\n\r\n/y"), "{resp}");
+        }
+        let resp = get(&mut stream, "/a//b/.?q=1").await;
+        assert!(resp.ends_with("\r\n\r\n/a/b/"), "{resp}");
+        let resp = get(&mut stream, "/a/b/?q=1").await;
+        assert!(resp.contains("Age: 0\r\n") && resp.ends_with("\r\n\r\n/a/b/"), "{resp}");
+        assert_eq!(storage.len(), 2);
+    }
+
+    /// an upstream of the responses to be revalidated, which counts the requests and the
+    /// conditional ones
+    async fn revalidation_upstream(fetches: Arc<AtomicUsize>, conditional: Arc<AtomicUsize>) -> SocketAddr {
+        mock_upstream(move |req| {
+            fetches.fetch_add(1, Ordering::Relaxed);
+            let matched = req.headers.get("if-none-match").is_some_and(|v| v == "\"v1\"");
+            if matched {
+                conditional.fetch_add(1, Ordering::Relaxed);
+            }
+            let (status, cc) = match (req.uri.path(), matched) {
+                ("/etag", false) => (200, "no-cache"),
+                ("/etag", true) => (304, "max-age=60"),
+                ("/swr", false) => (200, "max-age=0, stale-while-revalidate=60"),
+                ("/swr", true) => (304, "max-age=0, stale-while-revalidate=60"),
+                ("/error", false) => (200, "max-age=0, stale-if-error=60"),
+                ("/lock", _) => (200, "max-age=60"),
+                _ => (500, "no-store"),
+            };
+            let resp = MockResponse::new(status).header("Cache-Control", cc).header("ETag", "\"v1\"");
+            let resp = if status == 200 { resp.body("v1") } else { resp };
+            if req.uri.path() == "/lock" {
+                let slow = Duration::from_millis(100);
+                return resp.delay(slow).body_delay(slow);
+            }
+            resp
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_cache_revalidate() {
+        let (fetches, conditional) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let upstream = revalidation_upstream(fetches.clone(), conditional.clone()).await;
+        let storage = Arc::new(gateway_cache::MemoryStorage::new(1 << 20));
+        let (addr, _shutdown) = start_proxy(TestProxy::new(upstream).with_cache(storage), None);
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // the conditions of the downstream do not match
+        async fn get(stream: &mut TcpStream, path: &str) -> String {
+            let req = format!("GET {path} HTTP/1.1\r\nHost: example.com\r\nIf-None-Match: \"v0\"\r\n\r\n");
+            request(stream, req.as_bytes()).await
+        }
+
+        // the stale response is revalidated, the 304 refreshes it
+        for _ in 0..3 {
+            let resp = get(&mut stream, "/etag").await;
+            assert!(resp.starts_with("HTTP/1.1 200 OK\r\n") && resp.ends_with("\r\n\r\nv1"), "{resp}");
+            assert!(resp.contains("X-Via: octopus\r\n"), "{resp}");
+        }
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+        assert_eq!(conditional.load(Ordering::Relaxed), 1);
+
+        // the stale response is served at once and revalidated in the background
+        get(&mut stream, "/swr").await;
+        let resp = get(&mut stream, "/swr").await;
+        assert!(resp.contains("Age: ") && resp.ends_with("\r\n\r\nv1"), "{resp}");
+        for _ in 0..100 {
+            if conditional.load(Ordering::Relaxed) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(conditional.load(Ordering::Relaxed), 2);
+
+        // the stale response is served instead of the error
+        get(&mut stream, "/error").await;
+        let resp = get(&mut stream, "/error").await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n") && resp.ends_with("\r\n\r\nv1"), "{resp}");
+        assert_eq!(fetches.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn test_cache_lock() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let upstream = revalidation_upstream(fetches.clone(), Arc::new(AtomicUsize::new(0))).await;
+        let storage = Arc::new(gateway_cache::MemoryStorage::new(1 << 20));
+        let (addr, _shutdown) = start_proxy(TestProxy::new(upstream).with_cache(storage), None);
+
+        // the others wait for the first one, and are served while the body is still fetched
+        let mut clients = vec![];
+        for _ in 0..5 {
+            clients.push(tokio::spawn(async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                get(&mut stream, "/lock").await
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for client in clients {
+            let resp = client.await.unwrap();
+            assert!(resp.starts_with("HTTP/1.1 200 OK\r\n") && resp.ends_with("\r\n\r\nv1"), "{resp}");
+            assert!(resp.contains("X-Via: octopus\r\n"), "{resp}");
+        }
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_down() {
+        let upstream = {
+            let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            l.local_addr().unwrap()
+        };
+        let proxy = TestProxy::new(upstream);
+        let logged = proxy.logged.clone();
+        let (addr, _shutdown) = start_proxy(proxy, None);
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{resp}");
+        assert_eq!(logged.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain_on_shutdown() {
+        let upstream = mock_upstream(|req| proxied(req).delay(Duration::from_millis(200))).await;
+        let (addr, shutdown) = start_proxy(TestProxy::new(upstream), None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.send(true).unwrap();
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+        // the request in flight still finishes, but the connection is not kept alive
+        let mut resp = vec![];
+        stream.read_to_end(&mut resp).await.unwrap();
+        let resp = String::from_utf8_lossy(&resp);
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+        assert!(resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        // no longer accepting
+        assert!(TcpStream::connect(addr).await.is_err());
     }
 }
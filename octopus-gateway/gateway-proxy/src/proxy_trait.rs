@@ -0,0 +1,190 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+
+/// the methods to customize the behavior of the proxy
+///
+/// the filters are called in the order they are defined below. all of them except
+/// [ProxyHttp::upstream_peer()] have a default implementation
+#[async_trait]
+pub trait ProxyHttp {
+    /// the per request state shared by the filters
+    type CTX;
+
+    /// create the state for a new request
+    fn new_ctx(&self) -> Self::CTX;
+
+    /// handle the request before anything else
+    ///
+    /// return `Ok(true)` if the response is already sent by this filter, the request is then
+    /// finished without going to any upstream
+    async fn request_filter(&self, _session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(false)
+    }
+
+    /// decide whether the response is cached, by enabling [Session::cache]
+    ///
+    /// the cache is disabled by default
+    fn request_cache_filter(&self, _session: &mut Session, _ctx: &mut Self::CTX) -> Result<()> {
+        Ok(())
+    }
+
+    /// the key the response is cached with
+    fn cache_key_callback(&self, session: &Session, _ctx: &mut Self::CTX) -> Result<CacheKey> {
+        Ok(CacheKey::from_request(session.req_header()))
+    }
+
+    /// called when the response is not cached or the cached one is stale, before the request is
+    /// sent to the upstream
+    fn cache_miss(&self, _session: &mut Session, _ctx: &mut Self::CTX) {}
+
+    /// called when a fresh response is cached, the cached object is in [Session::cache]
+    ///
+    /// return `Ok(true)` to treat the response as expired and fetch it again
+    async fn cache_hit_filter(&self, _session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(false)
+    }
+
+    /// decide whether the response from the upstream is cached and how long it is fresh
+    fn response_cache_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RespCacheable> {
+        Ok(resp_cacheable(session.req_header(), resp, SystemTime::now()))
+    }
+
+    /// decide the upstream peer the request is sent to
+    async fn upstream_peer(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<Box<HttpPeer>>;
+
+    /// called when the connection to the peer fails
+    ///
+    /// the request is retried against [ProxyHttp::upstream_peer()] if the returned error is
+    /// marked as retry-able, which connection errors are by default
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        _ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        e
+    }
+
+    /// modify the request before it is sent to the upstream
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_request: &mut RequestHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(())
+    }
+
+    /// called with every response header from the upstream, before it is cached, even if the
+    /// cached response is served instead, e.g. a 304 of a revalidation or a 5xx when a stale
+    /// response may be served on errors
+    async fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_response: &mut ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(())
+    }
+
+    /// modify the response header before it is sent to the downstream, either from the upstream
+    /// or the cache
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_response: &mut ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(())
+    }
+
+    /// modify the response body before it is sent to the downstream
+    ///
+    /// `end_of_stream` is true with the last piece of the body, the body can be set to `None` to
+    /// drop the piece
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// called when the request fails, the response is sent here unless it is already sent
+    ///
+    /// return the status code sent to the downstream
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, _ctx: &mut Self::CTX) -> u16
+    where
+        Self::CTX: Send + Sync,
+    {
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            CircuitOpen => 503,
+            _ => match e.esource() {
+                ErrorSource::Upstream => match e.root_etype() {
+                    ConnectionTimeout | ReadTimedout | WriteTimedout => 504,
+                    _ => 502,
+                },
+                ErrorSource::Downstream => 400,
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if session.response_written().is_none() {
+            if let Err(e) = session.respond_error(code).await {
+                debug!("failed to send error response: {e}");
+            }
+        }
+        code
+    }
+
+    /// called when the request is finished, `e` is the error if the request failed
+    async fn logging(&self, _session: &mut Session, _e: Option<&Error>, _ctx: &mut Self::CTX)
+    where
+        Self::CTX: Send + Sync,
+    {
+    }
+}
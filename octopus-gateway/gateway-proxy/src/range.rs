@@ -0,0 +1,200 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! serving the ranges of whole responses
+//!
+//! the requests of cacheable responses are sent to the upstream without their `Range`, so that
+//! the whole response is cached. the ranges are then cut out of it as it is served, unless the
+//! body is decompressed for the downstream, which then gets it whole
+
+use bytes::{Bytes, BytesMut};
+use gateway_error::Result;
+use gateway_httpd::range::RangeType;
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use http::{header, Method, StatusCode};
+use std::ops::Range;
+
+/// turn the whole response into the one of the ranges the request asks for, return the filter
+/// cutting its body
+///
+/// only a 200 to GET with a known length is cut, the other responses pass as they are
+pub(crate) fn range_header_filter(req: &RequestHeader, resp: &mut ResponseHeader) -> Result<RangeBodyFilter> {
+    let mut filter = RangeBodyFilter::default();
+    if req.method != Method::GET || resp.status != StatusCode::OK || !req.if_range_matches(resp) {
+        return Ok(filter);
+    }
+    let Some(len) = resp
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+    else {
+        return Ok(filter);
+    };
+    match req.range(len) {
+        RangeType::None => {}
+        RangeType::Single(range) => {
+            resp.set_status(StatusCode::PARTIAL_CONTENT)?;
+            resp.insert_header(header::CONTENT_RANGE, content_range(&range, len))?;
+            resp.insert_header(header::CONTENT_LENGTH, range.len().to_string())?;
+            filter.parts = Some(vec![(range, Bytes::new())]);
+        }
+        RangeType::Multi(ranges) => {
+            let boundary = format!("{:016x}", rand::random::<u64>());
+            let content_type = resp.headers.get(header::CONTENT_TYPE).cloned();
+            let mut parts = Vec::with_capacity(ranges.len());
+            for range in ranges {
+                let mut part = format!("\r\n--{boundary}\r\n").into_bytes();
+                if let Some(content_type) = content_type.as_ref() {
+                    part.extend_from_slice(b"Content-Type: ");
+                    part.extend_from_slice(content_type.as_bytes());
+                    part.extend_from_slice(b"\r\n");
+                }
+                part.extend_from_slice(format!("Content-Range: {}\r\n\r\n", content_range(&range, len)).as_bytes());
+                parts.push((range, Bytes::from(part)));
+            }
+            filter.trailer = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+            let body_len: usize = parts.iter().map(|(range, part)| range.len() + part.len()).sum();
+            resp.set_status(StatusCode::PARTIAL_CONTENT)?;
+            resp.insert_header(
+                header::CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={boundary}"),
+            )?;
+            resp.insert_header(header::CONTENT_LENGTH, (body_len + filter.trailer.len()).to_string())?;
+            filter.parts = Some(parts);
+        }
+        RangeType::Invalid => {
+            resp.set_status(StatusCode::RANGE_NOT_SATISFIABLE)?;
+            resp.insert_header(header::CONTENT_RANGE, format!("bytes */{len}"))?;
+            resp.insert_header(header::CONTENT_LENGTH, "0")?;
+            filter.parts = Some(vec![]);
+        }
+    }
+    Ok(filter)
+}
+
+fn content_range(range: &Range<usize>, len: usize) -> String {
+    format!("bytes {}-{}/{len}", range.start, range.end - 1)
+}
+
+/// cut the ranges out of the whole body
+#[derive(Default)]
+pub(crate) struct RangeBodyFilter {
+    /// the ranges to serve with the part header before each, the whole body if not set
+    parts: Option<Vec<(Range<usize>, Bytes)>>,
+    /// written after the last range
+    trailer: Bytes,
+    /// the offset of the next piece of the whole body
+    offset: usize,
+}
+
+impl RangeBodyFilter {
+    /// the ranges in the next piece of the whole body, `end` when the whole body is read
+    pub(crate) fn filter_body(&mut self, data: Option<Bytes>, end: bool) -> Option<Bytes> {
+        let Some(parts) = self.parts.as_ref() else {
+            return data;
+        };
+        let mut out = BytesMut::new();
+        if let Some(data) = data {
+            let (start, stop) = (self.offset, self.offset + data.len());
+            for (range, part) in parts.iter() {
+                if (start..stop).contains(&range.start) {
+                    out.extend_from_slice(part);
+                }
+                let (from, to) = (range.start.max(start), range.end.min(stop));
+                if from < to {
+                    out.extend_from_slice(&data[from - start..to - start]);
+                }
+            }
+            self.offset = stop;
+        }
+        if end {
+            out.extend_from_slice(&self.trailer);
+        }
+        (!out.is_empty()).then(|| out.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(range: &str, resp: &mut ResponseHeader, body: &[&'static str]) -> String {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("Range", range.to_string()).unwrap();
+        let mut filter = range_header_filter(&req, resp).unwrap();
+        let mut out = vec![];
+        for (i, piece) in body.iter().enumerate() {
+            let end = i + 1 == body.len();
+            if let Some(data) = filter.filter_body(Some(Bytes::from_static(piece.as_bytes())), end) {
+                out.extend_from_slice(&data);
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    fn response() -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Content-Length", "10").unwrap();
+        resp.insert_header("Content-Type", "text/plain").unwrap();
+        resp
+    }
+
+    #[test]
+    fn test_single_range() {
+        let mut resp = response();
+        assert_eq!(filter("bytes=2-6", &mut resp, &["0123", "4567", "89"]), "23456");
+        assert_eq!(resp.status, 206);
+        assert_eq!(resp.headers["content-range"], "bytes 2-6/10");
+        assert_eq!(resp.headers["content-length"], "5");
+
+        let mut resp = response();
+        assert_eq!(filter("bytes=10-", &mut resp, &["0123456789"]), "");
+        assert_eq!(resp.status, 416);
+        assert_eq!(resp.headers["content-range"], "bytes */10");
+
+        // not cut
+        let mut resp = response();
+        assert_eq!(filter("lines=1-2", &mut resp, &["0123456789"]), "0123456789");
+        assert_eq!(resp.status, 200);
+        let mut resp = response();
+        resp.remove_header("Content-Length");
+        assert_eq!(filter("bytes=1-2", &mut resp, &["0123456789"]), "0123456789");
+    }
+
+    #[test]
+    fn test_multi_range() {
+        let mut resp = response();
+        let body = filter("bytes=8-,0-1", &mut resp, &["0123", "4567", "89"]);
+        assert_eq!(resp.status, 206);
+        let content_type = resp.headers["content-type"].to_str().unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+                 \r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+                 \r\n--{boundary}--\r\n"
+            )
+        );
+        assert_eq!(resp.headers["content-length"], body.len().to_string());
+    }
+}
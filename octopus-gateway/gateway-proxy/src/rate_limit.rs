@@ -0,0 +1,157 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! rejecting the requests over a rate limit
+//!
+//! a [RateLimit] is checked in [ProxyHttp::request_filter()](crate::ProxyHttp::request_filter()),
+//! the requests over the limit are answered with 429, `Retry-After` and the `RateLimit-Limit`,
+//! `RateLimit-Remaining` and `RateLimit-Reset` headers
+
+use crate::Session;
+use gateway_core::ratelimit::{Decision, RateLimiter};
+use gateway_error::Result;
+use gateway_httpd::ResponseHeader;
+use http::header::{self, HeaderName};
+use std::time::Duration;
+
+/// what the requests are counted by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitKey {
+    /// the ip of the client
+    ClientIp,
+    /// the value of the header, e.g. an api key, or else the ip of the client
+    Header(HeaderName),
+    /// all the requests together
+    All,
+}
+
+impl LimitKey {
+    /// the key of the request of `session`
+    pub fn key(&self, session: &Session) -> Vec<u8> {
+        let client_ip = || {
+            session
+                .client_addr()
+                .map(|a| a.ip().to_string())
+                .unwrap_or_default()
+                .into_bytes()
+        };
+        match self {
+            LimitKey::ClientIp => client_ip(),
+            LimitKey::Header(name) => match session.req_header().headers.get(name) {
+                // not to be mistaken for an ip
+                Some(value) => [b"h:", value.as_bytes()].concat(),
+                None => client_ip(),
+            },
+            LimitKey::All => vec![],
+        }
+    }
+}
+
+/// a rate limiter and what it counts the requests by
+pub struct RateLimit {
+    limiter: Box<dyn RateLimiter>,
+    key: LimitKey,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Box<dyn RateLimiter>, key: LimitKey) -> Self {
+        RateLimit { limiter, key }
+    }
+
+    /// count the request of `session`, answer it with 429 if it is over the limit
+    ///
+    /// return whether the request is answered
+    pub async fn limit_request(&self, session: &mut Session) -> Result<bool> {
+        let decision = self.limiter.check(&self.key.key(session));
+        if decision.allowed {
+            return Ok(false);
+        }
+        let resp = rejection(&decision)?;
+        session.write_response_header(Box::new(resp)).await?;
+        session.finish_response().await?;
+        Ok(true)
+    }
+}
+
+/// the 429 response of a request over the limit
+pub fn rejection(decision: &Decision) -> Result<ResponseHeader> {
+    let mut resp = ResponseHeader::build(429, Some(5))?;
+    resp.insert_header("Retry-After", ceil_secs(decision.retry_after).max(1).to_string())?;
+    resp.insert_header("RateLimit-Limit", decision.limit.to_string())?;
+    resp.insert_header("RateLimit-Remaining", decision.remaining.to_string())?;
+    resp.insert_header("RateLimit-Reset", ceil_secs(decision.reset).to_string())?;
+    resp.insert_header(header::CONTENT_LENGTH, "0")?;
+    Ok(resp)
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + (duration.subsec_nanos() > 0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejection() {
+        let decision = Decision {
+            allowed: false,
+            limit: 10,
+            remaining: 0,
+            reset: Duration::from_millis(1500),
+            retry_after: Duration::from_millis(100),
+        };
+        let resp = rejection(&decision).unwrap();
+        assert_eq!(resp.status, 429);
+        assert_eq!(resp.headers["retry-after"], "1");
+        assert_eq!(resp.headers["ratelimit-limit"], "10");
+        assert_eq!(resp.headers["ratelimit-remaining"], "0");
+        assert_eq!(resp.headers["ratelimit-reset"], "2");
+        let mut buf = vec![];
+        resp.header_to_h1_write(&mut buf);
+        assert!(String::from_utf8(buf).unwrap().contains("RateLimit-Limit: 10\r\n"));
+    }
+
+
+    #[tokio::test]
+    async fn test_limit_request() {
+        use crate::testing::*;
+        use gateway_core::ratelimit::TokenBucket;
+        use tokio::net::TcpStream;
+
+        let mut proxy = TestProxy::new(mock_upstream(proxied).await);
+        let limiter = TokenBucket::new(1, Duration::from_secs(60), 1);
+        proxy.rate_limit = Some(RateLimit::new(Box::new(limiter), LimitKey::All));
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n") && resp.ends_with("\r\n\r\nproxied"), "{resp}");
+        // the connection is kept alive, and the limit is shared by the others
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{resp}");
+        assert!(resp.contains("\r\nRetry-After: 60\r\nRateLimit-Limit: 1\r\n"), "{resp}");
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{resp}");
+    }
+}
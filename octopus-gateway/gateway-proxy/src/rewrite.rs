@@ -0,0 +1,297 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! rewriting the requests and the responses of a route
+//!
+//! the request is rewritten after it is routed, before it is sent to the upstream, so the route
+//! and the cache key are the ones of the original request, whose path the proxy normalized. the
+//! rewrite only depends on that request, so a cache key still stands for one request to the
+//! upstream. the values may refer to the captures of the route as `{name}`
+
+use gateway_error::{ErrorType::*, OrErr, Result};
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use http::header::{self, HeaderName};
+use http::Uri;
+use regex::Regex;
+
+/// the rewrite actions of a route
+#[derive(Debug, Clone, Default)]
+pub struct Rewrite {
+    /// the new path, replacing the whole path
+    path: Option<String>,
+    /// the regex and its replacement, substituted in the path
+    path_regex: Option<(Regex, String)>,
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    /// the `Host` sent to the upstream
+    host: Option<String>,
+    request_headers: HeaderRewrite,
+    response_headers: HeaderRewrite,
+}
+
+impl Rewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// replace the path with `template`, e.g. `/users/{id}`
+    pub fn set_path(&mut self, template: &str) {
+        self.path = Some(template.to_string());
+    }
+
+    /// replace what `pattern` matches in the path with `replacement`, which may refer to the
+    /// groups of `pattern` as `$1` or `$name`, and to the captures of the route as `{name}`
+    pub fn set_path_regex(&mut self, pattern: &str, replacement: &str) -> Result<()> {
+        let regex = Regex::new(pattern).or_err_with(ConfigError, || format!("invalid regex `{pattern}`"))?;
+        self.path_regex = Some((regex, replacement.to_string()));
+        Ok(())
+    }
+
+    /// remove `prefix` from the path if it starts with it
+    pub fn set_strip_prefix(&mut self, prefix: &str) {
+        self.strip_prefix = Some(prefix.to_string());
+    }
+
+    pub fn set_add_prefix(&mut self, prefix: &str) {
+        self.add_prefix = Some(prefix.to_string());
+    }
+
+    /// send `template` as the `Host` to the upstream
+    pub fn set_host(&mut self, template: &str) {
+        self.host = Some(template.to_string());
+    }
+
+    pub fn request_headers_mut(&mut self) -> &mut HeaderRewrite {
+        &mut self.request_headers
+    }
+
+    pub fn response_headers_mut(&mut self) -> &mut HeaderRewrite {
+        &mut self.response_headers
+    }
+
+    /// whether the path is rewritten
+    fn rewrites_path(&self) -> bool {
+        self.path.is_some() || self.path_regex.is_some() || self.strip_prefix.is_some() || self.add_prefix.is_some()
+    }
+
+    /// rewrite the request to the upstream with the captures of the route
+    ///
+    /// the path is replaced, then substituted, then its prefix stripped and added. the query is
+    /// kept
+    pub fn rewrite_request(&self, req: &mut RequestHeader, params: &[(String, String)]) -> Result<()> {
+        if self.rewrites_path() {
+            let mut path = match self.path.as_ref() {
+                Some(template) => expand(template, params),
+                None => req.uri.path().to_string(),
+            };
+            if let Some((regex, replacement)) = self.path_regex.as_ref() {
+                path = regex.replace_all(&path, expand(replacement, params)).into_owned();
+            }
+            if let Some(rest) = self.strip_prefix.as_ref().and_then(|p| path.strip_prefix(p.as_str())) {
+                path = rest.to_string();
+            }
+            if let Some(prefix) = self.add_prefix.as_ref() {
+                path.insert_str(0, prefix);
+            }
+            if !path.starts_with('/') {
+                path.insert(0, '/');
+            }
+            if let Some(query) = req.uri.query() {
+                path = format!("{path}?{query}");
+            }
+            let uri = Uri::builder()
+                .path_and_query(path.as_str())
+                .build()
+                .or_err_with(InvalidHTTPHeader, || format!("invalid rewritten path `{path}`"))?;
+            req.set_uri(uri);
+        }
+        if let Some(host) = self.host.as_ref() {
+            req.insert_header(header::HOST, expand(host, params))?;
+        }
+        for name in self.request_headers.remove.iter() {
+            req.remove_header(name);
+        }
+        for (name, value) in self.request_headers.set.iter() {
+            req.insert_header(name.clone(), expand(value, params))?;
+        }
+        for (name, value) in self.request_headers.append.iter() {
+            req.append_header(name.clone(), expand(value, params))?;
+        }
+        Ok(())
+    }
+
+    /// rewrite the response to the downstream with the captures of the route
+    pub fn rewrite_response(&self, resp: &mut ResponseHeader, params: &[(String, String)]) -> Result<()> {
+        for name in self.response_headers.remove.iter() {
+            resp.remove_header(name);
+        }
+        for (name, value) in self.response_headers.set.iter() {
+            resp.insert_header(name.clone(), expand(value, params))?;
+        }
+        for (name, value) in self.response_headers.append.iter() {
+            resp.append_header(name.clone(), expand(value, params))?;
+        }
+        Ok(())
+    }
+}
+
+/// the headers to remove, then to set, then to append
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRewrite {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, String)>,
+    append: Vec<(HeaderName, String)>,
+}
+
+impl HeaderRewrite {
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        self.remove.push(header_name(name)?);
+        Ok(())
+    }
+
+    /// replace the values of `name` with `template`
+    pub fn set(&mut self, name: &str, template: &str) -> Result<()> {
+        self.set.push((header_name(name)?, template.to_string()));
+        Ok(())
+    }
+
+    /// add `template` to the values of `name`
+    pub fn append(&mut self, name: &str, template: &str) -> Result<()> {
+        self.append.push((header_name(name)?, template.to_string()));
+        Ok(())
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName> {
+    HeaderName::from_bytes(name.as_bytes()).or_err_with(ConfigError, || format!("invalid header `{name}`"))
+}
+
+/// replace `{name}` in `template` with the capture `name`, empty if there is no such capture.
+/// `{{` and `}}` are the literal braces
+pub fn expand(template: &str, params: &[(String, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        let brace = &rest[i..i + 1];
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            out.push_str(brace);
+            rest = after;
+            continue;
+        }
+        match rest.find('}').filter(|_| brace == "{") {
+            Some(end) => {
+                let name = &rest[..end];
+                if let Some((_, value)) = params.iter().find(|(n, _)| n == name) {
+                    out.push_str(value);
+                }
+                rest = &rest[end + 1..];
+            }
+            None => out.push_str(brace),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Vec<(String, String)> {
+        vec![("id".into(), "42".into()), ("1".into(), "v2".into())]
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand("/users/{id}/{1}", &params()), "/users/42/v2");
+        assert_eq!(expand("{{id}} {missing}", &params()), "{id} ");
+        assert_eq!(expand("a}b{c", &params()), "a}b{c");
+    }
+
+    #[test]
+    fn test_rewrite_path() {
+        let rewrite = |rewrite: &Rewrite, path: &[u8]| {
+            let mut req = RequestHeader::build("GET", path, None).unwrap();
+            rewrite.rewrite_request(&mut req, &params()).unwrap();
+            String::from_utf8(req.raw_path().to_vec()).unwrap()
+        };
+        let mut strip = Rewrite::new();
+        strip.set_strip_prefix("/legacy");
+        strip.set_add_prefix("/app");
+        assert_eq!(rewrite(&strip, b"/legacy/a?b=c"), "/app/a?b=c");
+        assert_eq!(rewrite(&strip, b"/other"), "/app/other");
+
+        let mut strip = Rewrite::new();
+        strip.set_strip_prefix("/legacy");
+        assert_eq!(rewrite(&strip, b"/legacy"), "/");
+
+        let mut template = Rewrite::new();
+        template.set_path("/internal/{1}/users/{id}");
+        assert_eq!(rewrite(&template, b"/api/v2/users/42?x"), "/internal/v2/users/42?x");
+
+        let mut regex = Rewrite::new();
+        regex.set_path_regex(r"^/old/(?<rest>.*)$", "/{1}/$rest").unwrap();
+        assert_eq!(rewrite(&regex, b"/old/a/b"), "/v2/a/b");
+        assert!(regex.set_path_regex("(", "").is_err());
+
+        // a non utf-8 path is replaced as well
+        assert_eq!(rewrite(&template, b"/api/\xff"), "/internal/v2/users/42");
+        // not rewritten
+        assert_eq!(rewrite(&Rewrite::new(), b"/a%20b"), "/a%20b");
+    }
+
+    #[test]
+    fn test_rewrite_headers() {
+        let mut rewrite = Rewrite::new();
+        rewrite.set_host("{1}.backend.internal");
+        let headers = rewrite.request_headers_mut();
+        headers.remove("cookie").unwrap();
+        headers.set("x-user-id", "{id}").unwrap();
+        headers.append("x-tag", "b").unwrap();
+        assert!(headers.set("bad header", "").is_err());
+        let headers = rewrite.response_headers_mut();
+        headers.remove("server").unwrap();
+        headers.set("cache-control", "no-store").unwrap();
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("Host", "example.com").unwrap();
+        req.insert_header("Cookie", "a=b").unwrap();
+        req.insert_header("X-User-Id", "1").unwrap();
+        req.insert_header("X-Tag", "a").unwrap();
+        rewrite.rewrite_request(&mut req, &params()).unwrap();
+        assert_eq!(req.headers["host"], "v2.backend.internal");
+        assert!(req.headers.get("cookie").is_none());
+        assert_eq!(req.headers["x-user-id"], "42");
+        let tags: Vec<_> = req.headers.get_all("x-tag").iter().collect();
+        assert_eq!(tags, ["a", "b"]);
+        assert_eq!(req.uri.path(), "/");
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Server", "backend").unwrap();
+        resp.insert_header("Cache-Control", "max-age=60").unwrap();
+        rewrite.rewrite_response(&mut resp, &params()).unwrap();
+        assert!(resp.headers.get("server").is_none());
+        assert_eq!(resp.headers["cache-control"], "no-store");
+    }
+}
@@ -0,0 +1,404 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! routing the requests
+//!
+//! a [Router] holds the routes of a service, each a [RouteMatcher] with a priority and a target,
+//! e.g. the upstream group and the policies of the route. the routes are tried from the highest
+//! priority down, in the order they are added among the same priority, and the first one matching
+//! the request is taken
+
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use gateway_httpd::util::{normalize_path, percent_decode};
+use gateway_httpd::RequestHeader;
+use http::header::{self, HeaderName};
+use http::Method;
+use regex::Regex;
+
+/// the routes of a service
+pub struct Router<T> {
+    /// in the order they are tried
+    routes: Vec<(RouteMatcher, i32, T)>,
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Router { routes: vec![] }
+    }
+
+    /// add a route, the routes of a higher `priority` are tried first
+    pub fn add(&mut self, matcher: RouteMatcher, priority: i32, target: T) {
+        let at = self.routes.partition_point(|(_, p, _)| *p >= priority);
+        self.routes.insert(at, (matcher, priority, target));
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// the first route matching `req`
+    pub fn route(&self, req: &RequestHeader) -> Option<RouteMatch<'_, T>> {
+        self.routes.iter().find_map(|(matcher, _, target)| {
+            let params = matcher.matches(req)?;
+            Some(RouteMatch { target, params })
+        })
+    }
+}
+
+/// the route a request matches
+#[derive(Debug)]
+pub struct RouteMatch<'a, T> {
+    pub target: &'a T,
+    /// the captures of the path regex, by their names, or their indexes for the unnamed ones
+    pub params: Vec<(String, String)>,
+}
+
+impl<T> RouteMatch<'_, T> {
+    /// the captured value of `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// how the path of a request is matched
+#[derive(Debug, Clone)]
+pub enum PathMatcher {
+    Exact(String),
+    /// the path starts with it, at a segment boundary unless it ends with `/`, so `/api` matches
+    /// `/api` and `/api/users` but not `/apis`
+    Prefix(String),
+    /// the regex matches the whole path
+    Regex(Regex),
+}
+
+impl PathMatcher {
+    pub fn regex(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(&format!("^(?:{pattern})$"))
+            .or_err_with(ConfigError, || format!("invalid path regex `{pattern}`"))?;
+        Ok(PathMatcher::Regex(regex))
+    }
+
+    /// the captures if it matches `path`
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        match self {
+            PathMatcher::Exact(exact) => (path == exact).then(Vec::new),
+            PathMatcher::Prefix(prefix) => {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                (prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')).then(Vec::new)
+            }
+            PathMatcher::Regex(regex) => {
+                let captures = regex.captures(path)?;
+                let params = regex
+                    .capture_names()
+                    .enumerate()
+                    .skip(1)
+                    .filter_map(|(i, name)| {
+                        let value = captures.get(i)?.as_str().to_string();
+                        Some((name.map_or_else(|| i.to_string(), |n| n.to_string()), value))
+                    })
+                    .collect();
+                Some(params)
+            }
+        }
+    }
+}
+
+/// how the value of a header or a query parameter is matched
+#[derive(Debug, Clone)]
+pub enum ValueMatcher {
+    /// whether it is present, whatever its value
+    Present(bool),
+    Exact(String),
+    /// the regex matches the whole value
+    Regex(Regex),
+}
+
+impl ValueMatcher {
+    pub fn regex(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(&format!("^(?:{pattern})$"))
+            .or_err_with(ConfigError, || format!("invalid regex `{pattern}`"))?;
+        Ok(ValueMatcher::Regex(regex))
+    }
+
+    /// whether any of `values` matches
+    fn matches<'a>(&self, mut values: impl Iterator<Item = &'a str>) -> bool {
+        match self {
+            ValueMatcher::Present(present) => values.next().is_some() == *present,
+            ValueMatcher::Exact(exact) => values.any(|v| v == exact),
+            ValueMatcher::Regex(regex) => values.any(|v| regex.is_match(v)),
+        }
+    }
+}
+
+/// the conditions of a route, all of which a request has to meet
+///
+/// a condition not set is met by any request
+#[derive(Debug, Clone, Default)]
+pub struct RouteMatcher {
+    /// any of them
+    hosts: Vec<String>,
+    path: Option<PathMatcher>,
+    /// any of them
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, ValueMatcher)>,
+    query: Vec<(String, ValueMatcher)>,
+}
+
+impl RouteMatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// match the host, case insensitively and without the port, a leading `*.` matches any
+    /// subdomain, e.g. `*.example.com` matches `a.example.com` and `a.b.example.com`
+    pub fn add_host(&mut self, host: &str) -> Result<()> {
+        let host = host.to_ascii_lowercase();
+        let name = host.strip_prefix("*.").unwrap_or(&host);
+        if name.is_empty() || name.contains(['*', ':', '/']) {
+            return Error::e_explain(ConfigError, format!("invalid host `{host}`"));
+        }
+        self.hosts.push(host);
+        Ok(())
+    }
+
+    pub fn set_path(&mut self, path: PathMatcher) {
+        self.path = Some(path);
+    }
+
+    pub fn add_method(&mut self, method: &str) -> Result<()> {
+        let method =
+            Method::from_bytes(method.as_bytes()).or_err_with(ConfigError, || format!("invalid method `{method}`"))?;
+        self.methods.push(method);
+        Ok(())
+    }
+
+    pub fn add_header(&mut self, name: &str, value: ValueMatcher) -> Result<()> {
+        let name =
+            HeaderName::from_bytes(name.as_bytes()).or_err_with(ConfigError, || format!("invalid header `{name}`"))?;
+        self.headers.push((name, value));
+        Ok(())
+    }
+
+    /// match the query parameter `name`, the names and the values are percent decoded
+    pub fn add_query(&mut self, name: &str, value: ValueMatcher) {
+        self.query.push((name.to_string(), value));
+    }
+
+    /// the path captures if `req` matches
+    ///
+    /// the path is matched with its dot-segments and duplicate slashes resolved, the way the proxy
+    /// forwards it, so `/public/../admin` is matched as `/admin`
+    pub fn matches(&self, req: &RequestHeader) -> Option<Vec<(String, String)>> {
+        if !self.methods.is_empty() && !self.methods.contains(&req.method) {
+            return None;
+        }
+        if !self.hosts.is_empty() {
+            let host = request_host(req)?;
+            if !self.hosts.iter().any(|h| host_matches(h, &host)) {
+                return None;
+            }
+        }
+        for (name, value) in self.headers.iter() {
+            let values = req.headers.get_all(name).iter().filter_map(|v| v.to_str().ok());
+            if !value.matches(values) {
+                return None;
+            }
+        }
+        if !self.query.is_empty() {
+            let params = query_params(req.uri.query().unwrap_or_default());
+            for (name, value) in self.query.iter() {
+                let values = params.iter().filter(|(n, _)| n == name).map(|(_, v)| v.as_str());
+                if !value.matches(values) {
+                    return None;
+                }
+            }
+        }
+        match self.path.as_ref() {
+            Some(path) => path.matches(&normalize_path(req.uri.path())),
+            None => Some(vec![]),
+        }
+    }
+}
+
+/// the lowercase host of the request without the port, from the `Host` header or else the uri
+fn request_host(req: &RequestHeader) -> Option<String> {
+    let host = req
+        .headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri.host())?;
+    // an ipv6 address is in brackets
+    let host = match host.find(']') {
+        Some(end) => &host[..=end],
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// the percent decoded query parameters, a malformed one is left as it is
+fn query_params(query: &str) -> Vec<(String, String)> {
+    let decode = |v: &str| percent_decode(v).unwrap_or_else(|_| v.to_string());
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (name, value) = p.split_once('=').unwrap_or((p, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, host: &str, path: &[u8]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, path, None).unwrap();
+        req.insert_header("Host", host.to_string()).unwrap();
+        req
+    }
+
+    #[test]
+    fn test_query_params() {
+        let params = query_params("a=%zz&b=%41+b&&c");
+        assert_eq!(params[0], ("a".into(), "%zz".into()));
+        assert_eq!(params[1], ("b".into(), "A b".into()));
+        assert_eq!(params[2], ("c".into(), "".into()));
+    }
+
+    #[test]
+    fn test_path() {
+        let prefix = PathMatcher::Prefix("/api".into());
+        assert!(prefix.matches("/api").is_some());
+        assert!(prefix.matches("/api/users").is_some());
+        assert!(prefix.matches("/apis").is_none());
+        assert!(PathMatcher::Prefix("/api/".into()).matches("/api").is_none());
+        assert!(PathMatcher::Exact("/".into()).matches("/a").is_none());
+
+        let regex = PathMatcher::regex(r"/users/(?<id>\d+)/(\w+)").unwrap();
+        let params = regex.matches("/users/42/posts").unwrap();
+        assert_eq!(params, vec![("id".into(), "42".into()), ("2".into(), "posts".into())]);
+        // the whole path
+        assert!(regex.matches("/users/42/posts/1").is_none());
+        assert!(PathMatcher::regex("(").is_err());
+    }
+
+    #[test]
+    fn test_matcher() {
+        let mut matcher = RouteMatcher::new();
+        matcher.add_host("*.Example.com").unwrap();
+        matcher.add_host("example.org").unwrap();
+        matcher.add_method("GET").unwrap();
+        matcher
+            .add_header("x-version", ValueMatcher::regex("2|3").unwrap())
+            .unwrap();
+        matcher.add_header("x-debug", ValueMatcher::Present(false)).unwrap();
+        matcher.add_query("lang", ValueMatcher::Exact("en us".into()));
+        matcher.set_path(PathMatcher::Prefix("/api".into()));
+        assert!(matcher.add_host("a.*.com").is_err());
+
+        let mut req = request("GET", "a.example.com:8080", b"/api/x?lang=en%20us&a");
+        req.insert_header("X-Version", "3").unwrap();
+        assert!(matcher.matches(&req).is_some());
+
+        let mut other = req.clone();
+        other.insert_header("Host", "example.org").unwrap();
+        assert!(matcher.matches(&other).is_some());
+        other.insert_header("Host", "example.com").unwrap();
+        assert!(matcher.matches(&other).is_none());
+
+        let mut other = req.clone();
+        other.set_method(Method::POST);
+        assert!(matcher.matches(&other).is_none());
+
+        let mut other = req.clone();
+        other.insert_header("X-Debug", "1").unwrap();
+        assert!(matcher.matches(&other).is_none());
+
+        let mut other = req.clone();
+        other.set_uri("/api/x?lang=fr".parse().unwrap());
+        assert!(matcher.matches(&other).is_none());
+
+        assert!(RouteMatcher::new().matches(&req).is_some());
+    }
+
+    #[test]
+    fn test_normalized_path() {
+        let mut public = RouteMatcher::new();
+        public.set_path(PathMatcher::Prefix("/public".into()));
+        for path in [b"/public/../admin".as_slice(), b"/public/%2e%2E/admin", b"/public/x/../../admin"] {
+            assert!(public.matches(&request("GET", "example.com", path)).is_none());
+        }
+        for path in [b"//public/x".as_slice(), b"/admin/../public/./x", b"/public/x/.."] {
+            assert!(public.matches(&request("GET", "example.com", path)).is_some());
+        }
+        let mut exact = RouteMatcher::new();
+        exact.set_path(PathMatcher::Exact("/a/b".into()));
+        assert!(exact.matches(&request("GET", "example.com", b"/a//b")).is_some());
+    }
+
+    #[test]
+    fn test_router() {
+        let mut router = Router::new();
+        let mut api = RouteMatcher::new();
+        api.set_path(PathMatcher::Prefix("/api".into()));
+        router.add(api, 0, "api");
+        let mut user = RouteMatcher::new();
+        user.set_path(PathMatcher::regex(r"/api/users/(?<id>\d+)").unwrap());
+        router.add(user, 10, "user");
+        router.add(RouteMatcher::new(), -1, "default");
+        let mut api_v2 = RouteMatcher::new();
+        api_v2.set_path(PathMatcher::Prefix("/api".into()));
+        router.add(api_v2, 0, "api-v2");
+        assert_eq!(router.len(), 4);
+
+        let route = router.route(&request("GET", "example.com", b"/api/users/1")).unwrap();
+        assert_eq!((*route.target, route.param("id")), ("user", Some("1")));
+        // the first added among the same priority
+        let route = router.route(&request("GET", "example.com", b"/api/users/x")).unwrap();
+        assert_eq!(*route.target, "api");
+        let route = router.route(&request("GET", "example.com", b"/")).unwrap();
+        assert_eq!(*route.target, "default");
+        assert!(Router::<()>::new()
+            .route(&request("GET", "example.com", b"/"))
+            .is_none());
+    }
+}
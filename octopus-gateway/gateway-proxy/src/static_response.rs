@@ -0,0 +1,208 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the responses the proxy makes on its own, e.g. redirects, maintenance pages and health
+//! endpoints, for the routes which never reach an upstream
+//!
+//! the header values are templates which may refer to the captures of the route and to these
+//! values of the request as `{name}`:
+//! - `host`: the `Host` of the request
+//! - `hostname`: the host without the port
+//! - `path`: the path
+//! - `query`: the query, empty if there is none
+//! - `uri`: the path and the query
+//!
+//! e.g. `https://{hostname}{uri}` redirects to https
+
+use crate::rewrite::expand;
+use crate::Session;
+use bytes::Bytes;
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use http::header::{self, HeaderName};
+use http::{Method, StatusCode};
+
+/// a response made without any upstream
+#[derive(Debug, Clone)]
+pub struct StaticResponse {
+    status: StatusCode,
+    /// the values are templates
+    headers: Vec<(HeaderName, String)>,
+    body: Bytes,
+}
+
+impl StaticResponse {
+    pub fn new(status: u16) -> Result<Self> {
+        let status = StatusCode::from_u16(status).or_err_with(ConfigError, || format!("invalid status {status}"))?;
+        Ok(StaticResponse {
+            status,
+            headers: vec![],
+            body: Bytes::new(),
+        })
+    }
+
+    /// redirect to `location` with a 3xx `status`
+    pub fn redirect(status: u16, location: &str) -> Result<Self> {
+        let mut resp = Self::new(status)?;
+        if !resp.status.is_redirection() {
+            return Error::e_explain(ConfigError, format!("{status} is not a redirect status"));
+        }
+        resp.add_header("Location", location)?;
+        Ok(resp)
+    }
+
+    /// redirect to the same url on https, keeping the method
+    pub fn https_redirect() -> Self {
+        Self::redirect(308, "https://{hostname}{uri}").expect("a valid redirect")
+    }
+
+    /// add the header `name` with the value `template`
+    pub fn add_header(&mut self, name: &str, template: &str) -> Result<()> {
+        let name =
+            HeaderName::from_bytes(name.as_bytes()).or_err_with(ConfigError, || format!("invalid header `{name}`"))?;
+        self.headers.push((name, template.to_string()));
+        Ok(())
+    }
+
+    pub fn set_body(&mut self, body: impl Into<Bytes>) {
+        self.body = body.into();
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// the response header to `req`, with the captures of the route
+    pub fn response_header(&self, req: &RequestHeader, params: &[(String, String)]) -> Result<ResponseHeader> {
+        let mut resp = ResponseHeader::build(self.status, Some(self.headers.len() + 1))?;
+        let params: Vec<_> = params.iter().cloned().chain(request_params(req)).collect();
+        for (name, template) in self.headers.iter() {
+            resp.append_header(name.clone(), expand(template, &params))?;
+        }
+        resp.insert_header(header::CONTENT_LENGTH, self.body.len().to_string())?;
+        Ok(resp)
+    }
+
+    /// send the response to the downstream of `session`
+    pub async fn respond(&self, session: &mut Session, params: &[(String, String)]) -> Result<()> {
+        let resp = self.response_header(session.req_header(), params)?;
+        let head = session.req_header().method == Method::HEAD;
+        session.write_response_header(Box::new(resp)).await?;
+        if !head && !self.body.is_empty() {
+            session.write_response_body(self.body.clone()).await?;
+        }
+        session.finish_response().await
+    }
+}
+
+/// the values of the request the templates may refer to
+fn request_params(req: &RequestHeader) -> [(String, String); 5] {
+    let host = req
+        .headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri.authority().map(|a| a.as_str()))
+        .unwrap_or_default();
+    let hostname = match host.find(']') {
+        Some(end) => &host[..=end],
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    let uri = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+    [
+        ("host".into(), host.into()),
+        ("hostname".into(), hostname.into()),
+        ("path".into(), req.uri.path().into()),
+        ("query".into(), req.uri.query().unwrap_or_default().into()),
+        ("uri".into(), uri.into()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(host: &str, path: &[u8]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", path, None).unwrap();
+        req.insert_header("Host", host.to_string()).unwrap();
+        req
+    }
+
+    #[test]
+    fn test_redirect() {
+        let redirect = StaticResponse::https_redirect();
+        let resp = redirect
+            .response_header(&request("example.com:80", b"/a?b=c"), &[])
+            .unwrap();
+        assert_eq!(resp.status, 308);
+        assert_eq!(resp.headers["location"], "https://example.com/a?b=c");
+        assert_eq!(resp.headers["content-length"], "0");
+
+        let vanity = StaticResponse::redirect(302, "https://docs.example.com/{page}?from={host}").unwrap();
+        let params = [("page".to_string(), "install".to_string())];
+        let resp = vanity
+            .response_header(&request("go.example.com", b"/docs/install"), &params)
+            .unwrap();
+        assert_eq!(resp.status, 302);
+        assert_eq!(
+            resp.headers["location"],
+            "https://docs.example.com/install?from=go.example.com"
+        );
+
+        assert!(StaticResponse::redirect(200, "/").is_err());
+        assert!(StaticResponse::new(1000).is_err());
+    }
+
+    #[test]
+    fn test_static() {
+        let mut maintenance = StaticResponse::new(503).unwrap();
+        maintenance.add_header("Content-Type", "text/html").unwrap();
+        maintenance.add_header("Retry-After", "120").unwrap();
+        maintenance.set_body("<h1>back soon</h1>");
+        let resp = maintenance.response_header(&request("example.com", b"/"), &[]).unwrap();
+        assert_eq!(resp.status, 503);
+        assert_eq!(resp.headers["content-type"], "text/html");
+        assert_eq!(resp.headers["retry-after"], "120");
+        assert_eq!(resp.headers["content-length"], "18");
+        assert!(maintenance.add_header("bad header", "").is_err());
+    }
+
+
+    #[tokio::test]
+    async fn test_respond() {
+        use crate::testing::*;
+        use tokio::net::TcpStream;
+
+        let mut proxy = TestProxy::new(mock_upstream(proxied).await);
+        let mut response = StaticResponse::new(200).unwrap();
+        response.set_body("ok");
+        proxy.response = Some(response);
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        // never reaching the upstream, and the connection is kept alive
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..2 {
+            let resp = get(&mut stream, "/health").await;
+            assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+            assert!(resp.ends_with("\r\nContent-Length: 2\r\n\r\nok"), "{resp}");
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the mock upstream and the proxy fixture of the tests, for the tests of the other crates with
+//! the `testing` feature
+
+use crate::{http_proxy_service, ProxyHttp, RateLimit, Session, StaticResponse};
+use async_trait::async_trait;
+use gateway_cache::{CacheLock, MemoryStorage};
+use gateway_core::protocols::tls::server::TlsSettings;
+use gateway_core::server::transfer_fd::Fds;
+use gateway_core::services::Service;
+use gateway_core::upstreams::peer::HttpPeer;
+use gateway_error::{Error, Result};
+use gateway_httpd::v1::server::HttpSession;
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// the response of a [mock_upstream()], with a `Content-Length` unless it has one
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    delay: Duration,
+    body_delay: Duration,
+}
+
+impl MockResponse {
+    pub fn new(status: u16) -> Self {
+        MockResponse {
+            status,
+            headers: vec![],
+            body: vec![],
+            delay: Duration::ZERO,
+            body_delay: Duration::ZERO,
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// wait before the header
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// wait between the header and the body
+    pub fn body_delay(mut self, delay: Duration) -> Self {
+        self.body_delay = delay;
+        self
+    }
+}
+
+/// 200 with `proxied` in the body if the request came through a [TestProxy], `direct` if not
+pub fn proxied(req: &RequestHeader) -> MockResponse {
+    let body = if req.headers.contains_key("x-proxied") {
+        "proxied"
+    } else {
+        "direct"
+    };
+    MockResponse::new(200).header("Content-Type", "text/plain").body(body)
+}
+
+/// an upstream answering each request with `respond`, the connections are kept alive
+pub async fn mock_upstream<F>(respond: F) -> SocketAddr
+where
+    F: Fn(&RequestHeader) -> MockResponse + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let respond = Arc::new(respond);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let mut session = HttpSession::new(stream);
+                while let Ok(Some(_)) = session.read_request().await {
+                    let resp = respond(session.req_header());
+                    tokio::time::sleep(resp.delay).await;
+                    let mut header = ResponseHeader::build(resp.status, None).unwrap();
+                    for (name, value) in resp.headers {
+                        header.append_header(name, value).unwrap();
+                    }
+                    let framed = ["content-length", "transfer-encoding"]
+                        .iter()
+                        .any(|h| header.headers.contains_key(*h));
+                    if resp.status != 304 && !framed {
+                        header
+                            .insert_header("Content-Length", resp.body.len().to_string())
+                            .unwrap();
+                    }
+                    if session.write_response_header(Box::new(header)).await.is_err() {
+                        break;
+                    }
+                    tokio::time::sleep(resp.body_delay).await;
+                    if session.write_body(&resp.body).await.is_err() || session.finish_body().await.is_err() {
+                        break;
+                    }
+                    match session.reuse().await {
+                        Some(s) => session = HttpSession::new(s),
+                        None => break,
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// a proxy of all the requests to one upstream, with what the tests turn on
+///
+/// the requests to `/deny` are answered with 403 before anything else, the ones to the upstream
+/// get `X-Proxied: 1` and the responses `X-Via: octopus`
+pub struct TestProxy {
+    pub upstream: SocketAddr,
+    /// the number of the requests logged
+    pub logged: Arc<AtomicUsize>,
+    pub cache: Option<(Arc<MemoryStorage>, Arc<CacheLock>)>,
+    pub rate_limit: Option<RateLimit>,
+    /// answers the requests instead of the upstream
+    pub response: Option<StaticResponse>,
+    /// the compression level
+    pub compression: Option<u32>,
+    pub decompress: bool,
+}
+
+impl TestProxy {
+    pub fn new(upstream: SocketAddr) -> Self {
+        TestProxy {
+            upstream,
+            logged: Arc::new(AtomicUsize::new(0)),
+            cache: None,
+            rate_limit: None,
+            response: None,
+            compression: None,
+            decompress: false,
+        }
+    }
+
+    /// cache in `storage` with a lock
+    pub fn with_cache(mut self, storage: Arc<MemoryStorage>) -> Self {
+        let lock = Arc::new(CacheLock::new(Duration::from_secs(1)));
+        self.cache = Some((storage, lock));
+        self
+    }
+}
+
+#[async_trait]
+impl ProxyHttp for TestProxy {
+    type CTX = ();
+
+    fn new_ctx(&self) -> Self::CTX {}
+
+    async fn request_filter(&self, session: &mut Session, _ctx: &mut ()) -> Result<bool> {
+        if session.req_header().uri.path() == "/deny" {
+            session.respond_error(403).await?;
+            return Ok(true);
+        }
+        if let Some(rate_limit) = self.rate_limit.as_ref() {
+            if rate_limit.limit_request(session).await? {
+                return Ok(true);
+            }
+        }
+        if let Some(response) = self.response.as_ref() {
+            response.respond(session, &[]).await?;
+            return Ok(true);
+        }
+        if let Some(level) = self.compression {
+            session.compression.set_level(level);
+        }
+        session.compression.set_decompress(self.decompress);
+        Ok(false)
+    }
+
+    fn request_cache_filter(&self, session: &mut Session, _ctx: &mut ()) -> Result<()> {
+        if let Some((storage, lock)) = self.cache.as_ref() {
+            session.cache.enable(storage.clone(), 1024);
+            session.cache.set_lock(lock.clone());
+        }
+        Ok(())
+    }
+
+    async fn upstream_peer(&self, _session: &mut Session, _ctx: &mut ()) -> Result<Box<HttpPeer>> {
+        Ok(Box::new(HttpPeer::new(self.upstream, "example.com".into())))
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut (),
+    ) -> Result<()> {
+        upstream_request.insert_header("X-Proxied", "1")?;
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        _ctx: &mut (),
+    ) -> Result<()> {
+        upstream_response.insert_header("X-Via", "octopus")?;
+        Ok(())
+    }
+
+    async fn logging(&self, _session: &mut Session, _e: Option<&Error>, _ctx: &mut ()) {
+        self.logged.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// serve `proxy` on a local port, over TLS with `tls`, until `true` is sent to the returned sender
+pub fn start_proxy<SV>(proxy: SV, tls: Option<TlsSettings>) -> (SocketAddr, watch::Sender<bool>)
+where
+    SV: ProxyHttp + Send + Sync + 'static,
+    SV::CTX: Send + Sync + 'static,
+{
+    let mut service = http_proxy_service("test", proxy);
+    match tls {
+        Some(settings) => service.add_tls_with_settings("127.0.0.1:0", None, settings).unwrap(),
+        None => service.add_tcp("127.0.0.1:0"),
+    }
+    service.bind(&mut Fds::new()).unwrap();
+    let addr = service.bound_addrs()[0];
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move { service.start_service(rx).await });
+    (addr, tx)
+}
+
+/// send `req` and read the whole response, by its `Content-Length` or its chunks, or else until
+/// the connection is closed
+pub async fn request_bytes(stream: &mut TcpStream, req: &[u8]) -> Vec<u8> {
+    stream.write_all(req).await.unwrap();
+    let mut resp = vec![];
+    let mut buf = vec![0; 4096];
+    let header_len = loop {
+        if let Some(i) = resp.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "{}", String::from_utf8_lossy(&resp));
+        resp.extend_from_slice(&buf[..n]);
+    };
+    let header = String::from_utf8_lossy(&resp[..header_len]).to_ascii_lowercase();
+    let status = header
+        .get(9..12)
+        .and_then(|s| s.parse::<u16>().ok())
+        .unwrap_or_default();
+    if req.starts_with(b"HEAD ") || status < 200 || status == 204 || status == 304 {
+        return resp;
+    }
+    let content_length = header
+        .split("\r\n")
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .map(|len| len.trim().parse::<usize>().unwrap());
+    let chunked = header.contains("\r\ntransfer-encoding: chunked\r\n");
+    loop {
+        let body = &resp[header_len..];
+        match content_length {
+            Some(len) if body.len() >= len => return resp,
+            None if chunked && body.ends_with(b"0\r\n\r\n") => return resp,
+            _ => {}
+        }
+        let n = stream.read(&mut buf).await.unwrap();
+        if n == 0 {
+            assert!(
+                content_length.is_none() && !chunked,
+                "{}",
+                String::from_utf8_lossy(&resp)
+            );
+            return resp;
+        }
+        resp.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// [request_bytes()] as text
+pub async fn request(stream: &mut TcpStream, req: &[u8]) -> String {
+    String::from_utf8_lossy(&request_bytes(stream, req).await).into_owned()
+}
+
+/// the response to a GET of `path` from `example.com`
+pub async fn get(stream: &mut TcpStream, path: &str) -> String {
+    let req = format!("GET {path} HTTP/1.1\r\nHost: example.com\r\n\r\n");
+    request(stream, req.as_bytes()).await
+}
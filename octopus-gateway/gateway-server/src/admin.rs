@@ -0,0 +1,261 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the admin endpoint
+//!
+//! `POST /purge/<service>` purges the cached responses of a service, selected by one of the
+//! query parameters
+//! - `url=example.com/index.html`: the response of the url, with all its variants
+//! - `prefix=example.com/images/`: the responses whose url starts with the prefix
+//! - `tag=product-1`: the responses the upstream tagged with the `Surrogate-Key` header
+//!
+//! with `soft=true` they are marked stale instead of removed. the response is
+//! `{"purged": <the number of urls purged>}`
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use gateway_cache::{CacheKey, PurgeTarget, Purger};
+use gateway_core::apps::{HttpServerApp, ServerSession};
+use gateway_core::protocols::Stream;
+use gateway_core::server::ShutdownWatch;
+use gateway_error::{Error, ErrorType::*, Result};
+use gateway_httpd::util::percent_decode;
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use http::Method;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// the admin endpoint of the server
+#[derive(Default)]
+pub struct AdminApp {
+    /// the purgers of the caches, keyed by the service names
+    purgers: HashMap<String, Purger>,
+}
+
+impl AdminApp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// purge the cache of `service` with `purger`
+    pub fn add_purger(&mut self, service: &str, purger: Purger) {
+        self.purgers.insert(service.to_string(), purger);
+    }
+
+    async fn handle(&self, req: &RequestHeader) -> Result<String> {
+        let Some(service) = req.uri.path().strip_prefix("/purge/") else {
+            return Error::e_explain(HTTPStatus(404), "unknown endpoint");
+        };
+        // PURGE is what the caching proxies commonly take
+        if req.method != Method::POST && req.method.as_str() != "PURGE" {
+            return Error::e_explain(HTTPStatus(405), "only POST is allowed");
+        }
+        let Some(purger) = self.purgers.get(service) else {
+            return Error::e_explain(HTTPStatus(404), format!("service `{service}` has no cache"));
+        };
+        let mut target = None;
+        let mut soft = false;
+        for (name, value) in req
+            .uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+        {
+            let value = percent_decode(value)?;
+            let t = match name {
+                "url" => PurgeTarget::Key(parse_url(&value)?),
+                "prefix" => PurgeTarget::Prefix(value),
+                "tag" => PurgeTarget::Tag(value),
+                "soft" => {
+                    soft = value == "true" || value == "1";
+                    continue;
+                }
+                _ => return Error::e_explain(HTTPStatus(400), format!("unknown parameter `{name}`")),
+            };
+            if target.replace(t).is_some() {
+                return Error::e_explain(HTTPStatus(400), "only one of `url`, `prefix` and `tag` is allowed");
+            }
+        }
+        let Some(target) = target else {
+            return Error::e_explain(HTTPStatus(400), "one of `url`, `prefix` and `tag` is required");
+        };
+        let purged = if soft {
+            purger.soft_purge(&target).await?
+        } else {
+            purger.purge(&target).await?
+        };
+        info!("{service}: purged {purged} urls of {target:?}, soft: {soft}");
+        Ok(format!("{{\"purged\": {purged}}}\n"))
+    }
+}
+
+#[async_trait]
+impl HttpServerApp for AdminApp {
+    async fn process_new_http(
+        self: &Arc<Self>,
+        mut session: ServerSession,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        // the body is not used
+        loop {
+            match session.read_body_bytes().await {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("failed to read the admin request: {e}");
+                    return None;
+                }
+            }
+        }
+        let (status, body) = match self.handle(session.req_header()).await {
+            Ok(body) => (200, body),
+            Err(e) => {
+                let status = match e.etype() {
+                    HTTPStatus(status) => *status,
+                    _ => 500,
+                };
+                let msg = e.context.as_ref().map(|c| c.as_str()).unwrap_or_default();
+                (status, format!("{{\"error\": {}}}\n", json_string(msg)))
+            }
+        };
+        let written = async {
+            let mut resp = ResponseHeader::build(status, Some(2))?;
+            resp.insert_header("Content-Type", "application/json")?;
+            resp.insert_header("Content-Length", body.len().to_string())?;
+            session.write_response_header(Box::new(resp)).await?;
+            session.write_body(Bytes::from(body)).await?;
+            session.finish_body().await
+        };
+        if let Err(e) = written.await {
+            warn!("failed to respond to the admin request: {e}");
+            return None;
+        }
+        session.reuse().await
+    }
+}
+
+/// `example.com/path?query` to the key of its GET request
+fn parse_url(url: &str) -> Result<CacheKey> {
+    let (host, path) = match url.find('/') {
+        Some(i) => url.split_at(i),
+        None => (url, "/"),
+    };
+    if host.is_empty() {
+        return Error::e_explain(HTTPStatus(400), "the url must start with the host");
+    }
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    Ok(CacheKey::new(Method::GET, host, path, query))
+}
+
+/// `msg` as a json string
+fn json_string(msg: &str) -> String {
+    let mut json = String::with_capacity(msg.len() + 2);
+    json.push('"');
+    for c in msg.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gateway_cache::{CacheIndex, CacheMeta, CacheObject, MemoryStorage, Storage};
+    use gateway_core::server::transfer_fd::Fds;
+    use gateway_core::services::listening::ListeningService;
+    use gateway_core::services::Service;
+    use gateway_proxy::testing::request;
+    use std::time::{Duration, SystemTime};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_purge() {
+        let storage = Arc::new(MemoryStorage::new(10000));
+        let index = Arc::new(CacheIndex::new());
+        let now = SystemTime::now();
+        for (path, tag) in [("/a", "x"), ("/b/1", "y"), ("/b/2", "y")] {
+            let key = CacheKey::new(Method::GET, "example.com", path, None);
+            let meta = CacheMeta::new(
+                ResponseHeader::build(200, None).unwrap(),
+                now,
+                now + Duration::from_secs(60),
+                vec![],
+            );
+            storage
+                .put(&key.hash(), CacheObject::new(meta, Bytes::new()))
+                .await
+                .unwrap();
+            index.insert(&key, &key.hash(), vec![tag.to_string()]);
+        }
+        let mut app = AdminApp::new();
+        app.add_purger("main", Purger::new(storage.clone(), index));
+        let mut service = ListeningService::new("admin".to_string(), app);
+        service.add_tcp("127.0.0.1:0");
+        service.bind(&mut Fds::new()).unwrap();
+        let addr = service.bound_addrs()[0];
+        let (_shutdown, rx) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move { service.start_service(rx).await });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let purge = |query: &str| format!("POST /purge/main?{query} HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        let resp = request(&mut stream, purge("url=example.com%2Fa&soft=true").as_bytes()).await;
+        assert!(
+            resp.starts_with("HTTP/1.1 200 OK\r\n") && resp.ends_with("{\"purged\": 1}\n"),
+            "{resp}"
+        );
+        let a = CacheKey::new(Method::GET, "example.com", "/a", None).hash();
+        assert!(!storage
+            .lookup(&a)
+            .await
+            .unwrap()
+            .unwrap()
+            .meta
+            .is_fresh(SystemTime::now()));
+        let resp = request(&mut stream, purge("tag=y").as_bytes()).await;
+        assert!(resp.ends_with("{\"purged\": 2}\n"), "{resp}");
+        let resp = request(&mut stream, b"PURGE /purge/main?prefix=example.com/ HTTP/1.1\r\n\r\n").await;
+        assert!(resp.ends_with("{\"purged\": 1}\n"), "{resp}");
+        assert!(storage.is_empty());
+
+        let resp = request(&mut stream, purge("url=a&tag=b").as_bytes()).await;
+        assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{resp}");
+        let resp = request(&mut stream, purge("url=%zz").as_bytes()).await;
+        assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{resp}");
+        assert!(resp.ends_with("{\"error\": \"invalid escape in `%zz`\"}\n"), "{resp}");
+        let resp = request(&mut stream, b"POST /purge/other?tag=y HTTP/1.1\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"), "{resp}");
+        let resp = request(&mut stream, b"GET /purge/main?tag=y HTTP/1.1\r\n\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{resp}");
+    }
+}
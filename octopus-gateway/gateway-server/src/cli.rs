@@ -0,0 +1,118 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the command line of the gateway server
+
+use crate::config::Config;
+use clap::{Parser, Subcommand, ValueEnum};
+use gateway_error::Result;
+use std::path::PathBuf;
+
+/// the octopus gateway server
+#[derive(Parser, Debug, Default)]
+#[command(name = "gateway-server", version)]
+pub struct Opt {
+    /// the configuration file, TOML if it ends with `.toml`, YAML otherwise
+    #[arg(short, long, env = "OCTOPUS_CONF")]
+    pub conf: Option<PathBuf>,
+    /// run in the background, overrides `daemon` of the configuration
+    #[arg(short, long)]
+    pub daemon: bool,
+    /// take over the listening sockets from the running instance, which should be sent
+    /// `SIGQUIT` once this one is started
+    #[arg(short, long)]
+    pub upgrade: bool,
+    /// test the configuration and exit
+    #[arg(short, long, alias = "test-config")]
+    pub test: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// print the effective configuration, i.e. the configuration file with the defaults and the
+    /// command line options applied
+    Config {
+        #[arg(short, long, value_enum, default_value_t = Format::Yaml)]
+        format: Format,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+}
+
+impl Opt {
+    /// load the configuration file, or the default one if not given, then apply the command
+    /// line options on top of it
+    pub fn load_config(&self) -> Result<Config> {
+        let mut conf = match self.conf.as_ref() {
+            Some(path) => Config::load_from_file(path)?,
+            None => Config::default(),
+        };
+        if self.daemon {
+            conf.daemon = true;
+        }
+        Ok(conf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let opt = Opt::try_parse_from(["gateway-server", "-c", "octopus.yaml", "-d", "--upgrade"]).unwrap();
+        assert_eq!(opt.conf, Some(PathBuf::from("octopus.yaml")));
+        assert!(opt.daemon && opt.upgrade && !opt.test);
+        assert_eq!(opt.command, None);
+
+        let opt = Opt::try_parse_from(["gateway-server", "--test-config"]).unwrap();
+        assert!(opt.test);
+
+        let opt = Opt::try_parse_from(["gateway-server", "config", "--format", "toml"]).unwrap();
+        assert_eq!(opt.command, Some(Command::Config { format: Format::Toml }));
+
+        assert!(Opt::try_parse_from(["gateway-server", "--nope"]).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.toml");
+        std::fs::write(&path, "threads = 3\ndaemon = false\n").unwrap();
+        let opt = Opt {
+            conf: Some(path),
+            daemon: true,
+            ..Default::default()
+        };
+        let conf = opt.load_config().unwrap();
+        assert_eq!(conf.threads, 3);
+        assert!(conf.daemon);
+
+        assert_eq!(Opt::default().load_config().unwrap(), Config::default());
+    }
+}
@@ -0,0 +1,1740 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the configuration file of the gateway server
+//!
+//! the file is YAML, or TOML if its extension is `.toml`. every field has a default, so an empty
+//! file is valid but serves nothing. a minimal YAML config:
+//! ```yaml
+//! threads: 4
+//! upstreams:
+//!   backend:
+//!     backends: ["10.0.0.1:80", {addr: "10.0.0.2:80", weight: 2}]
+//!     health_check: {type: http, host: example.com, path: /health}
+//! services:
+//!   - name: main
+//!     listeners: ["0.0.0.0:8080"]
+//!     upstream: backend
+//! ```
+
+use gateway_cache::{CacheLock, DiskStorage, MemoryStorage, Storage};
+use gateway_core::lb::circuit_breaker::CircuitBreakerConfig;
+use gateway_core::lb::health_check::HttpHealthCheck;
+use gateway_core::lb::outlier::OutlierConfig;
+use gateway_core::listeners::{ServerAddress, TcpKeepalive, TcpSocketOptions};
+use gateway_core::protocols::tls::cert_store::CertStore;
+use gateway_core::protocols::tls::load_certs;
+use gateway_core::protocols::tls::server::TlsSettings;
+use gateway_core::ratelimit::{
+    AimdConfig, ConcurrencyConfig, EstimatedWindow, RateLimiter, SlidingWindow, TokenBucket,
+};
+use gateway_core::server::configuration::ServerConf;
+use gateway_error::{Error, ErrorType::*, OrErr, Result};
+use gateway_proxy::rate_limit::{LimitKey, RateLimit};
+use gateway_proxy::rewrite::{HeaderRewrite, Rewrite};
+use gateway_proxy::router::{PathMatcher, RouteMatcher, ValueMatcher};
+use gateway_proxy::static_response::StaticResponse;
+use http::header::HeaderName;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// the whole configuration of the gateway server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// the number of worker threads of each service, unless the service sets its own
+    pub threads: usize,
+    /// run in the background
+    pub daemon: bool,
+    /// where the pid of the server is written
+    pub pid_file: PathBuf,
+    /// the user to switch to once the listening sockets are bound
+    pub user: Option<String>,
+    /// the group to switch to, the primary group of `user` if not set
+    pub group: Option<String>,
+    /// the unix domain socket the listening sockets are handed over on upgrade
+    pub upgrade_sock: String,
+    /// how long the services may take to drain on graceful shutdown
+    pub grace_period_seconds: Option<u64>,
+    /// how long to wait for the remaining tasks once the grace period is over
+    pub graceful_shutdown_timeout_seconds: Option<u64>,
+    pub log: LogConf,
+    /// the upstream groups, keyed by their names
+    pub upstreams: BTreeMap<String, UpstreamConf>,
+    pub services: Vec<ServiceConf>,
+    /// the admin endpoint, not served if not set
+    pub admin: Option<AdminConf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let server = ServerConf::default();
+        Config {
+            threads: server.threads,
+            daemon: server.daemon,
+            pid_file: server.pid_file,
+            user: server.user,
+            group: server.group,
+            upgrade_sock: server.upgrade_sock,
+            grace_period_seconds: server.grace_period_seconds,
+            graceful_shutdown_timeout_seconds: server.graceful_shutdown_timeout_seconds,
+            log: LogConf::default(),
+            upstreams: BTreeMap::new(),
+            services: vec![],
+            admin: None,
+        }
+    }
+}
+
+/// the settings of the logs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConf {
+    /// one of `error`, `warn`, `info`, `debug` and `trace`
+    pub level: String,
+    /// the file the logs are appended to, stderr if not set
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogConf {
+    fn default() -> Self {
+        LogConf {
+            level: "info".to_string(),
+            file: None,
+        }
+    }
+}
+
+/// the admin endpoint, which purges the caches of the services
+///
+/// it is not authenticated, so it should only listen on a private address or with
+/// `client_auth`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConf {
+    /// the addresses to listen to
+    pub listeners: Vec<ListenerConf>,
+}
+
+/// a proxy service
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConf {
+    pub name: String,
+    /// the addresses to listen to
+    pub listeners: Vec<ListenerConf>,
+    /// the name of the upstream group the requests matching no route are proxied to, they are
+    /// answered with 404 if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    /// the routes, tried from the highest priority down
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<RouteConf>,
+    /// the number of worker threads, the server default is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>,
+    /// cache the responses, not cached if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheConf>,
+    /// compress the responses, not compressed if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionConf>,
+    /// limit the rate of the requests, checked before the limit of their route
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitConf>,
+}
+
+/// a route of a service, matching the requests which meet all its conditions
+///
+/// e.g.
+/// ```yaml
+/// routes:
+///   - hosts: [api.example.com, "*.api.example.com"]
+///     regex: /users/(?<id>\d+)
+///     methods: [GET, HEAD]
+///     headers: {x-canary: {present: true}}
+///     query: {lang: {regex: "en|fr"}, debug: "1"}
+///     priority: 10
+///     upstream: canary
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConf {
+    /// the routes of a higher priority are tried first, then the ones listed first
+    pub priority: i32,
+    /// any of the hosts, a leading `*.` matches the subdomains
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    /// the exact path, only one of `path`, `prefix` and `regex` is allowed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// the path prefix, matched at a segment boundary unless it ends with `/`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// a regex matching the whole path, its captures are the params of the route
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    /// any of the methods
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, ValueConf>,
+    /// the query parameters, percent decoded
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, ValueConf>,
+    /// the upstream group the requests are proxied to, the one of the service if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    /// cache the responses if the service caches them
+    pub cache: bool,
+    /// the compression of the responses, the one of the service if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionConf>,
+    /// rewrite the requests to the upstream and the responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<RewriteConf>,
+    /// redirect the requests instead of proxying them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<RedirectConf>,
+    /// answer the requests with a fixed response instead of proxying them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub respond: Option<RespondConf>,
+    /// limit the rate of the requests of the route
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitConf>,
+}
+
+impl Default for RouteConf {
+    fn default() -> Self {
+        RouteConf {
+            priority: 0,
+            hosts: vec![],
+            path: None,
+            prefix: None,
+            regex: None,
+            methods: vec![],
+            headers: BTreeMap::new(),
+            query: BTreeMap::new(),
+            upstream: None,
+            cache: true,
+            compression: None,
+            rewrite: None,
+            redirect: None,
+            respond: None,
+            rate_limit: None,
+        }
+    }
+}
+
+impl RouteConf {
+    /// build the matcher of the route
+    pub fn matcher(&self) -> Result<RouteMatcher> {
+        let mut matcher = RouteMatcher::new();
+        for host in self.hosts.iter() {
+            matcher.add_host(host)?;
+        }
+        match (self.path.as_ref(), self.prefix.as_ref(), self.regex.as_ref()) {
+            (None, None, None) => {}
+            (Some(path), None, None) => matcher.set_path(PathMatcher::Exact(path.clone())),
+            (None, Some(prefix), None) => matcher.set_path(PathMatcher::Prefix(prefix.clone())),
+            (None, None, Some(regex)) => matcher.set_path(PathMatcher::regex(regex)?),
+            _ => return Error::e_explain(ConfigError, "only one of `path`, `prefix` and `regex` is allowed"),
+        }
+        for method in self.methods.iter() {
+            matcher.add_method(method)?;
+        }
+        for (name, value) in self.headers.iter() {
+            matcher.add_header(name, value.matcher()?)?;
+        }
+        for (name, value) in self.query.iter() {
+            matcher.add_query(name, value.matcher()?);
+        }
+        Ok(matcher)
+    }
+
+    /// build the response of the route, if it never reaches an upstream
+    pub fn response(&self) -> Result<Option<StaticResponse>> {
+        match (self.redirect.as_ref(), self.respond.as_ref()) {
+            (None, None) => Ok(None),
+            (Some(redirect), None) => StaticResponse::redirect(redirect.status, &redirect.location).map(Some),
+            (None, Some(respond)) => respond.response().map(Some),
+            _ => Error::e_explain(ConfigError, "only one of `redirect` and `respond` is allowed"),
+        }
+    }
+}
+
+/// how the requests of a route and their responses are rewritten, the values may refer to the
+/// captures of the route as `{name}`
+///
+/// e.g.
+/// ```yaml
+/// rewrite:
+///   strip_prefix: /legacy
+///   host: "{tenant}.internal"
+///   request_headers: {set: {x-user-id: "{id}"}, remove: [cookie]}
+///   response_headers: {append: {cache-control: no-transform}}
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RewriteConf {
+    /// the new path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// a regex substituted in the path, the replacement may refer to its groups as `$1`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_regex: Option<PathRegexConf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add_prefix: Option<String>,
+    /// the `Host` sent to the upstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub request_headers: HeadersConf,
+    pub response_headers: HeadersConf,
+}
+
+impl RewriteConf {
+    /// build the rewrite actions
+    pub fn rewrite(&self) -> Result<Rewrite> {
+        let mut rewrite = Rewrite::new();
+        if let Some(path) = self.path.as_ref() {
+            rewrite.set_path(path);
+        }
+        if let Some(regex) = self.path_regex.as_ref() {
+            rewrite.set_path_regex(&regex.pattern, &regex.replacement)?;
+        }
+        if let Some(prefix) = self.strip_prefix.as_ref() {
+            rewrite.set_strip_prefix(prefix);
+        }
+        if let Some(prefix) = self.add_prefix.as_ref() {
+            rewrite.set_add_prefix(prefix);
+        }
+        if let Some(host) = self.host.as_ref() {
+            rewrite.set_host(host);
+        }
+        self.request_headers.apply(rewrite.request_headers_mut())?;
+        self.response_headers.apply(rewrite.response_headers_mut())?;
+        Ok(rewrite)
+    }
+}
+
+/// a redirect, the location may refer to the captures of the route and the values of the request,
+/// see [gateway_proxy::static_response]
+///
+/// e.g. `redirect: {location: "https://{hostname}{uri}", status: 308}` redirects to https
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConf {
+    pub location: String,
+    /// one of the 3xx
+    #[serde(default = "RedirectConf::default_status")]
+    pub status: u16,
+}
+
+impl RedirectConf {
+    fn default_status() -> u16 {
+        301
+    }
+}
+
+/// a fixed response, e.g. a maintenance page or a health endpoint
+///
+/// e.g.
+/// ```yaml
+/// respond:
+///   status: 503
+///   headers: {content-type: text/html, retry-after: "600"}
+///   body_file: /etc/octopus/maintenance.html
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RespondConf {
+    pub status: u16,
+    /// the header values may refer to the captures of the route and the values of the request
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// the file the body is read from when the server starts, only one of `body` and `body_file`
+    /// is allowed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_file: Option<PathBuf>,
+}
+
+impl Default for RespondConf {
+    fn default() -> Self {
+        RespondConf {
+            status: 200,
+            headers: BTreeMap::new(),
+            body: None,
+            body_file: None,
+        }
+    }
+}
+
+impl RespondConf {
+    /// build the response, reading `body_file`
+    pub fn response(&self) -> Result<StaticResponse> {
+        let mut resp = StaticResponse::new(self.status)?;
+        for (name, value) in self.headers.iter() {
+            resp.add_header(name, value)?;
+        }
+        match (self.body.as_ref(), self.body_file.as_ref()) {
+            (None, None) => {}
+            (Some(body), None) => resp.set_body(body.clone()),
+            (None, Some(path)) => {
+                let body = std::fs::read(path).or_err_with(FileReadError, || format!("failed to read {path:?}"))?;
+                resp.set_body(body);
+            }
+            _ => return Error::e_explain(ConfigError, "only one of `body` and `body_file` is allowed"),
+        }
+        Ok(resp)
+    }
+}
+
+/// a rate limit, the requests over it are answered with 429
+///
+/// e.g. `rate_limit: {limit: 100, period_ms: 60000, key: "header:x-api-key"}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConf {
+    pub algorithm: RateLimitAlgorithm,
+    /// the number of requests per `period_ms`
+    pub limit: u64,
+    pub period_ms: u64,
+    /// the max burst of the token bucket, `limit` if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u64>,
+    /// what the requests are counted by, `client_ip`, `all` for all the requests together, or
+    /// `header:<name>` for the value of a header, e.g. an api key, or else the client ip
+    pub key: String,
+}
+
+impl Default for RateLimitConf {
+    fn default() -> Self {
+        RateLimitConf {
+            algorithm: RateLimitAlgorithm::default(),
+            limit: 100,
+            period_ms: 1000,
+            burst: None,
+            key: "client_ip".to_string(),
+        }
+    }
+}
+
+/// the counters of the sketches of the `estimated` algorithm, 4 rows of 16384 in 2 sketches take
+/// 512KiB
+const SKETCH_WIDTH: usize = 16384;
+const SKETCH_DEPTH: usize = 4;
+
+impl RateLimitConf {
+    /// build the rate limit
+    pub fn rate_limit(&self) -> Result<RateLimit> {
+        if self.limit == 0 || self.period_ms == 0 || self.burst == Some(0) {
+            return Error::e_explain(ConfigError, "the limit, the period and the burst must be positive");
+        }
+        let key = match self.key.as_str() {
+            "client_ip" => LimitKey::ClientIp,
+            "all" => LimitKey::All,
+            key => match key.strip_prefix("header:").map(|h| HeaderName::from_bytes(h.as_bytes())) {
+                Some(Ok(name)) => LimitKey::Header(name),
+                _ => return Error::e_explain(ConfigError, format!("invalid key `{key}`")),
+            },
+        };
+        let period = Duration::from_millis(self.period_ms);
+        let limiter: Box<dyn RateLimiter> = match self.algorithm {
+            RateLimitAlgorithm::TokenBucket => {
+                Box::new(TokenBucket::new(self.limit, period, self.burst.unwrap_or(self.limit)))
+            }
+            RateLimitAlgorithm::SlidingWindow => Box::new(SlidingWindow::new(self.limit, period)),
+            RateLimitAlgorithm::Estimated => {
+                Box::new(EstimatedWindow::new(self.limit, period, SKETCH_WIDTH, SKETCH_DEPTH))
+            }
+        };
+        Ok(RateLimit::new(limiter, key))
+    }
+}
+
+/// how the requests are counted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    /// bursts of up to `burst` requests, refilled at `limit` per `period_ms`
+    #[default]
+    TokenBucket,
+    /// at most `limit` requests over any `period_ms`, approximately
+    SlidingWindow,
+    /// a sliding window in a fixed amount of memory for many keys, which may limit a little early
+    Estimated,
+}
+
+/// a regex substitution in the path
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathRegexConf {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+/// the headers to remove, then to set, then to append
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersConf {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub append: BTreeMap<String, String>,
+}
+
+impl HeadersConf {
+    fn apply(&self, headers: &mut HeaderRewrite) -> Result<()> {
+        for name in self.remove.iter() {
+            headers.remove(name)?;
+        }
+        for (name, value) in self.set.iter() {
+            headers.set(name, value)?;
+        }
+        for (name, value) in self.append.iter() {
+            headers.append(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// how the value of a header or a query parameter is matched, an exact value, `{regex: ...}`
+/// matching the whole value, or `{present: bool}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ValueConf {
+    Exact(String),
+    Regex { regex: String },
+    Present { present: bool },
+}
+
+impl ValueConf {
+    fn matcher(&self) -> Result<ValueMatcher> {
+        match self {
+            ValueConf::Exact(value) => Ok(ValueMatcher::Exact(value.clone())),
+            ValueConf::Regex { regex } => ValueMatcher::regex(regex),
+            ValueConf::Present { present } => Ok(ValueMatcher::Present(*present)),
+        }
+    }
+}
+
+/// the response cache of a service
+///
+/// only the responses which tell how long they are fresh, e.g. with `Cache-Control: max-age`, are
+/// cached
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConf {
+    /// the dir the responses are stored under, in memory if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// the max total size of the cached responses in bytes
+    pub max_size: usize,
+    /// the responses with a larger body are not cached
+    pub max_object_size: usize,
+    /// how long the requests of a response being fetched wait for it before fetching it on their
+    /// own, 0 to always fetch on their own
+    pub lock_timeout_ms: u64,
+}
+
+impl Default for CacheConf {
+    fn default() -> Self {
+        CacheConf {
+            path: None,
+            max_size: 128 << 20,
+            max_object_size: 1 << 20,
+            lock_timeout_ms: 5000,
+        }
+    }
+}
+
+impl CacheConf {
+    /// create the storage of the cache
+    pub fn storage(&self) -> Result<Arc<dyn Storage>> {
+        Ok(match self.path.as_ref() {
+            Some(path) => Arc::new(DiskStorage::new(path, self.max_size)?),
+            None => Arc::new(MemoryStorage::new(self.max_size)),
+        })
+    }
+
+    /// create the lock of the cache, if the requests wait for the response being fetched
+    pub fn lock(&self) -> Option<Arc<CacheLock>> {
+        (self.lock_timeout_ms > 0).then(|| Arc::new(CacheLock::new(Duration::from_millis(self.lock_timeout_ms))))
+    }
+}
+
+/// the response compression of a service
+///
+/// the responses of a text-like type are compressed with gzip, brotli or zstd, whichever the
+/// downstream accepts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConf {
+    /// the compression level, capped at the max level of each encoding, 0 not to compress
+    pub level: u32,
+    /// decompress the responses compressed with an encoding the downstream does not accept
+    pub decompress: bool,
+    /// the responses with a smaller `Content-Length` are not compressed
+    pub min_size: usize,
+}
+
+impl Default for CompressionConf {
+    fn default() -> Self {
+        CompressionConf {
+            level: 6,
+            decompress: false,
+            min_size: 1024,
+        }
+    }
+}
+
+/// a listening address of a service
+///
+/// either a plain `ip:port`, or a TCP address or unix domain socket with its options, e.g.
+/// ```yaml
+/// listeners:
+///   - 0.0.0.0:80
+///   - {address: "[::]:80", ipv6_only: true, reuseport: true, nodelay: true}
+///   - {path: /run/octopus.sock, mode: 0o660}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ListenerConf {
+    Addr(String),
+    Tcp(TcpListenerConf),
+    Uds(UdsListenerConf),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpListenerConf {
+    /// `ip:port` or `host:port`
+    pub address: String,
+    /// accept only IPv6 on an IPv6 address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_only: Option<bool>,
+    /// set `SO_REUSEPORT`
+    #[serde(default)]
+    pub reuseport: bool,
+    /// the size of the accept queue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backlog: Option<i32>,
+    /// set `TCP_NODELAY` on the accepted connections
+    #[serde(default)]
+    pub nodelay: bool,
+    /// the max number of pending TCP fast open requests, off if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_fastopen: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive: Option<KeepaliveConf>,
+    /// terminate TLS on the listener
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ListenerTlsConf>,
+}
+
+/// the TLS settings of a listener
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerTlsConf {
+    /// the PEM file of the default certificate chain, leaf first
+    pub cert: String,
+    /// the PEM file of the private key
+    pub key: String,
+    /// more certs, each served to the SNIs it is valid for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sni_certs: Vec<CertConf>,
+    /// check the cert files for changes this often, never if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reload_interval_ms: Option<u64>,
+    /// offer http/2 over ALPN
+    #[serde(default)]
+    pub h2: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handshake_timeout_ms: Option<u64>,
+    /// authenticate the clients with their certs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_auth: Option<ClientAuthConf>,
+}
+
+/// the client cert authentication of a listener
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthConf {
+    /// the PEM file of the CAs issuing the client certs
+    pub ca: String,
+    /// let the clients without a cert in, their requests carry no client identity
+    #[serde(default)]
+    pub optional: bool,
+}
+
+/// the PEM files of a cert chain and its key
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertConf {
+    pub cert: String,
+    pub key: String,
+}
+
+impl ListenerTlsConf {
+    /// load all the certs of the listener
+    pub fn cert_store(&self) -> Result<CertStore> {
+        let store = CertStore::with_default_files(&self.cert, &self.key)?;
+        for cert in self.sni_certs.iter() {
+            store.add_files(&cert.cert, &cert.key)?;
+        }
+        Ok(store)
+    }
+
+    /// the [TlsSettings] of the listener, serving the certs in `store`
+    pub fn settings(&self, store: Arc<CertStore>) -> Result<TlsSettings> {
+        let mut settings = TlsSettings::with_cert_store(store);
+        if let Some(auth) = self.client_auth.as_ref() {
+            settings.set_client_auth(load_certs(&auth.ca)?, !auth.optional);
+        }
+        if self.h2 {
+            settings.enable_h2();
+        }
+        if let Some(ms) = self.handshake_timeout_ms {
+            settings.set_handshake_timeout(Duration::from_millis(ms));
+        }
+        Ok(settings)
+    }
+}
+
+/// TCP keepalive of the accepted connections
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeepaliveConf {
+    pub idle_seconds: u64,
+    pub interval_seconds: u64,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdsListenerConf {
+    /// the path of the socket file
+    pub path: String,
+    /// the permissions of the socket file, e.g. `0o660`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+impl ListenerConf {
+    /// the address to listen to
+    pub fn server_address(&self) -> ServerAddress {
+        match self {
+            ListenerConf::Addr(addr) => ServerAddress::Tcp(addr.clone(), None),
+            ListenerConf::Tcp(tcp) => ServerAddress::Tcp(
+                tcp.address.clone(),
+                Some(TcpSocketOptions {
+                    ipv6_only: tcp.ipv6_only,
+                    reuseport: tcp.reuseport,
+                    backlog: tcp.backlog,
+                    nodelay: tcp.nodelay,
+                    tcp_fastopen: tcp.tcp_fastopen,
+                    keepalive: tcp.keepalive.as_ref().map(|ka| TcpKeepalive {
+                        idle: Duration::from_secs(ka.idle_seconds),
+                        interval: Duration::from_secs(ka.interval_seconds),
+                        count: ka.count,
+                    }),
+                }),
+            ),
+            ListenerConf::Uds(uds) => ServerAddress::Uds(uds.path.clone(), uds.mode),
+        }
+    }
+
+    /// the TLS settings, `None` if the listener is plaintext
+    pub fn tls(&self) -> Option<&ListenerTlsConf> {
+        match self {
+            ListenerConf::Tcp(tcp) => tcp.tls.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
+        match self {
+            ListenerConf::Addr(addr) | ListenerConf::Tcp(TcpListenerConf { address: addr, .. }) => {
+                if !is_host_port(addr) {
+                    return Err(format!("invalid address `{addr}`"));
+                }
+            }
+            ListenerConf::Uds(uds) => {
+                if !uds.path.starts_with('/') {
+                    return Err(format!("the socket path `{}` must be absolute", uds.path));
+                }
+                if uds.mode.is_some_and(|m| m > 0o777) {
+                    return Err(format!("invalid mode {:o}", uds.mode.unwrap_or_default()));
+                }
+            }
+        }
+        if let ListenerConf::Tcp(tcp) = self {
+            if tcp.backlog.is_some_and(|b| b <= 0) {
+                return Err("backlog must be positive".into());
+            }
+            if tcp.keepalive.as_ref().is_some_and(|ka| ka.idle_seconds == 0 || ka.interval_seconds == 0) {
+                return Err("the keepalive idle and interval must be positive".into());
+            }
+            if let Some(tls) = &tcp.tls {
+                let mut certs = std::iter::once((&tls.cert, &tls.key))
+                    .chain(tls.sni_certs.iter().map(|c| (&c.cert, &c.key)));
+                if certs.any(|(cert, key)| cert.is_empty() || key.is_empty()) {
+                    return Err("both the TLS cert and key are required".into());
+                }
+                if tls.handshake_timeout_ms == Some(0) {
+                    return Err("the TLS handshake timeout must be positive".into());
+                }
+                if tls.reload_interval_ms == Some(0) {
+                    return Err("the TLS reload interval must be positive".into());
+                }
+                if tls.client_auth.as_ref().is_some_and(|auth| auth.ca.is_empty()) {
+                    return Err("the CA of the client certs is required".into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// how the backend of a request is selected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    #[default]
+    RoundRobin,
+    Random,
+    /// consistent on the client address
+    Hash,
+}
+
+/// a backend in the static list of an upstream group
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BackendConf {
+    /// `ip:port` or `host:port` with weight 1
+    Addr(String),
+    Weighted { addr: String, weight: usize },
+}
+
+impl BackendConf {
+    pub fn addr(&self) -> &str {
+        match self {
+            BackendConf::Addr(addr) => addr,
+            BackendConf::Weighted { addr, .. } => addr,
+        }
+    }
+
+    pub fn weight(&self) -> usize {
+        match self {
+            BackendConf::Addr(_) => 1,
+            BackendConf::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+/// a group of backends the requests are load balanced to
+///
+/// the backends come from exactly one of `backends`, `file` and `dns`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConf {
+    /// a static list of backends
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backends: Vec<BackendConf>,
+    /// a JSON or YAML file listing the backends, re-read when it changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// `host:port` names resolved with the nameserver in `/etc/resolv.conf`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns: Vec<String>,
+    /// how often the `file` and `dns` backends are refreshed
+    pub discovery_interval_ms: Option<u64>,
+    pub selection: Selection,
+    /// the server name of the backends, also the `Host` of the http health check by default
+    pub sni: String,
+    pub connection_timeout_ms: Option<u64>,
+    pub read_timeout_ms: Option<u64>,
+    pub write_timeout_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheckConf>,
+    /// eject the backends failing the proxied requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetectionConf>,
+    /// connect to the backends over TLS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<UpstreamTlsConf>,
+    /// limit the requests in flight to the group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<ConcurrencyConf>,
+    /// reject the requests to the group with 503 while it is failing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreakerConf>,
+}
+
+/// the limit of the requests in flight to an upstream group, the excess waits in a bounded
+/// queue and is then rejected with 503
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencyConf {
+    /// the initial limit if `adaptive`
+    pub max_in_flight: usize,
+    /// 0 to reject the excess right away
+    pub max_queue: usize,
+    pub queue_timeout_ms: u64,
+    /// adapt the limit to the latency of the group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<AdaptiveConf>,
+}
+
+impl Default for ConcurrencyConf {
+    fn default() -> Self {
+        ConcurrencyConf {
+            max_in_flight: 100,
+            max_queue: 100,
+            queue_timeout_ms: 1000,
+            adaptive: None,
+        }
+    }
+}
+
+impl ConcurrencyConf {
+    /// the settings of the limiter
+    pub fn config(&self) -> Result<ConcurrencyConfig> {
+        if self.max_in_flight == 0 || self.queue_timeout_ms == 0 {
+            return Error::e_explain(ConfigError, "`max_in_flight` and `queue_timeout_ms` must be positive");
+        }
+        let adaptive = self.adaptive.as_ref().map(|a| a.config()).transpose()?;
+        Ok(ConcurrencyConfig {
+            max_in_flight: self.max_in_flight,
+            max_queue: self.max_queue,
+            queue_timeout: Duration::from_millis(self.queue_timeout_ms),
+            adaptive,
+        })
+    }
+}
+
+/// the AIMD adaptation of the limit: it grows by one for every `limit` responses faster than
+/// `latency_threshold_ms`, and is multiplied by `backoff` on a slower or failed one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveConf {
+    pub min_limit: usize,
+    pub max_limit: usize,
+    pub latency_threshold_ms: u64,
+    pub backoff: f64,
+}
+
+impl Default for AdaptiveConf {
+    fn default() -> Self {
+        let aimd = AimdConfig::default();
+        AdaptiveConf {
+            min_limit: aimd.min_limit,
+            max_limit: aimd.max_limit,
+            latency_threshold_ms: aimd.latency_threshold.as_millis() as u64,
+            backoff: aimd.backoff,
+        }
+    }
+}
+
+impl AdaptiveConf {
+    fn config(&self) -> Result<AimdConfig> {
+        if self.min_limit == 0 || self.min_limit > self.max_limit {
+            return Error::e_explain(ConfigError, "`min_limit` must be positive and at most `max_limit`");
+        }
+        if self.latency_threshold_ms == 0 {
+            return Error::e_explain(ConfigError, "`latency_threshold_ms` must be positive");
+        }
+        if !(self.backoff > 0.0 && self.backoff < 1.0) {
+            return Error::e_explain(ConfigError, "`backoff` must be between 0 and 1");
+        }
+        Ok(AimdConfig {
+            min_limit: self.min_limit,
+            max_limit: self.max_limit,
+            latency_threshold: Duration::from_millis(self.latency_threshold_ms),
+            backoff: self.backoff,
+        })
+    }
+}
+
+/// the passive health check of an upstream group: the backends failing the proxied requests are
+/// ejected for a while, longer every time in a row
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutlierDetectionConf {
+    /// eject after this many consecutive refused or timed out connections, 0 to disable
+    pub consecutive_connect_failures: usize,
+    /// eject after this many consecutive 5xx responses, 0 to disable
+    pub consecutive_5xx: usize,
+    /// eject when the ratio of the failed requests over `interval_ms` reaches this, 0 to disable
+    pub failure_rate: f64,
+    /// the failure rate is only evaluated with at least this many requests in the interval
+    pub failure_rate_min_requests: usize,
+    pub interval_ms: u64,
+    pub base_ejection_ms: u64,
+    pub max_ejection_ms: u64,
+    /// at most this percentage of the backends is ejected at the same time, at least one unless
+    /// it is 0
+    pub max_ejection_percent: usize,
+    /// after the ejection, the traffic of the backend ramps up over this period
+    pub ramp_up_ms: u64,
+}
+
+impl Default for OutlierDetectionConf {
+    fn default() -> Self {
+        let config = OutlierConfig::default();
+        OutlierDetectionConf {
+            consecutive_connect_failures: config.consecutive_connect_failures,
+            consecutive_5xx: config.consecutive_5xx,
+            failure_rate: config.failure_rate,
+            failure_rate_min_requests: config.failure_rate_min_requests,
+            interval_ms: config.interval.as_millis() as u64,
+            base_ejection_ms: config.base_ejection_time.as_millis() as u64,
+            max_ejection_ms: config.max_ejection_time.as_millis() as u64,
+            max_ejection_percent: config.max_ejection_percent,
+            ramp_up_ms: config.ramp_up_time.as_millis() as u64,
+        }
+    }
+}
+
+impl OutlierDetectionConf {
+    /// the settings of the outlier detection
+    pub fn config(&self) -> Result<OutlierConfig> {
+        if !(0.0..=1.0).contains(&self.failure_rate) {
+            return Error::e_explain(ConfigError, "`failure_rate` must be between 0 and 1");
+        }
+        if self.interval_ms == 0 || self.base_ejection_ms == 0 {
+            return Error::e_explain(ConfigError, "`interval_ms` and `base_ejection_ms` must be positive");
+        }
+        if self.max_ejection_ms < self.base_ejection_ms {
+            return Error::e_explain(ConfigError, "`max_ejection_ms` must be at least `base_ejection_ms`");
+        }
+        if self.max_ejection_percent > 100 {
+            return Error::e_explain(ConfigError, "`max_ejection_percent` must be at most 100");
+        }
+        Ok(OutlierConfig {
+            consecutive_connect_failures: self.consecutive_connect_failures,
+            consecutive_5xx: self.consecutive_5xx,
+            failure_rate: self.failure_rate,
+            failure_rate_min_requests: self.failure_rate_min_requests,
+            interval: Duration::from_millis(self.interval_ms),
+            base_ejection_time: Duration::from_millis(self.base_ejection_ms),
+            max_ejection_time: Duration::from_millis(self.max_ejection_ms),
+            max_ejection_percent: self.max_ejection_percent,
+            ramp_up_time: Duration::from_millis(self.ramp_up_ms),
+        })
+    }
+}
+
+/// the circuit breaker of an upstream group
+///
+/// the circuit opens when the ratio of the failed or of the slow requests over the last
+/// `window_ms` reaches its threshold, then after `open_ms` lets `half_open_calls` trial requests
+/// through to decide whether to close again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConf {
+    /// 0 to disable
+    pub failure_rate: f64,
+    /// 0 to disable
+    pub slow_call_rate: f64,
+    pub slow_call_ms: u64,
+    /// the rates are only evaluated with at least this many requests in the window
+    pub min_calls: usize,
+    pub window_ms: u64,
+    pub open_ms: u64,
+    pub half_open_calls: usize,
+}
+
+impl Default for CircuitBreakerConf {
+    fn default() -> Self {
+        let config = CircuitBreakerConfig::default();
+        CircuitBreakerConf {
+            failure_rate: config.failure_rate,
+            slow_call_rate: config.slow_call_rate,
+            slow_call_ms: config.slow_call_duration.as_millis() as u64,
+            min_calls: config.min_calls,
+            window_ms: config.window.as_millis() as u64,
+            open_ms: config.open_duration.as_millis() as u64,
+            half_open_calls: config.half_open_calls,
+        }
+    }
+}
+
+impl CircuitBreakerConf {
+    /// the settings of the circuit breaker
+    pub fn config(&self) -> Result<CircuitBreakerConfig> {
+        for rate in [self.failure_rate, self.slow_call_rate] {
+            if !(0.0..=1.0).contains(&rate) {
+                return Error::e_explain(ConfigError, "the rates must be between 0 and 1");
+            }
+        }
+        if self.slow_call_ms == 0 || self.window_ms == 0 || self.open_ms == 0 || self.half_open_calls == 0 {
+            return Error::e_explain(
+                ConfigError,
+                "`slow_call_ms`, `window_ms`, `open_ms` and `half_open_calls` must be positive",
+            );
+        }
+        Ok(CircuitBreakerConfig {
+            failure_rate: self.failure_rate,
+            slow_call_rate: self.slow_call_rate,
+            slow_call_duration: Duration::from_millis(self.slow_call_ms),
+            min_calls: self.min_calls,
+            window: Duration::from_millis(self.window_ms),
+            open_duration: Duration::from_millis(self.open_ms),
+            half_open_calls: self.half_open_calls,
+            ..Default::default()
+        })
+    }
+}
+
+/// how the backends of an upstream group are connected over TLS
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTlsConf {
+    /// the PEM file of the CAs to verify the backends with, the webpki roots if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca: Option<String>,
+    /// verify the certs of the backends, only turn it off in test environments
+    pub verify_cert: bool,
+    /// verify that the certs of the backends are valid for the `sni` of the group
+    pub verify_hostname: bool,
+    /// the cert to authenticate to the backends with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<CertConf>,
+}
+
+impl Default for UpstreamTlsConf {
+    fn default() -> Self {
+        UpstreamTlsConf {
+            ca: None,
+            verify_cert: true,
+            verify_hostname: true,
+            client_cert: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckType {
+    /// the backend is healthy if it accepts the connection
+    #[default]
+    Tcp,
+    /// the backend is healthy if it answers a request with the expected status
+    Http,
+}
+
+/// the active health check of an upstream group
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConf {
+    #[serde(rename = "type")]
+    pub check_type: HealthCheckType,
+    /// the `Host` of the http check, the `sni` of the group if not set
+    pub host: Option<String>,
+    /// the path of the http check
+    pub path: String,
+    /// the expected status of the http check, any 2xx if empty
+    pub expected_status: Vec<u16>,
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+    /// how many consecutive successes turn an unhealthy backend healthy
+    pub consecutive_success: usize,
+    /// how many consecutive failures turn a healthy backend unhealthy
+    pub consecutive_failure: usize,
+}
+
+impl Default for HealthCheckConf {
+    fn default() -> Self {
+        HealthCheckConf {
+            check_type: HealthCheckType::Tcp,
+            host: None,
+            path: "/".to_string(),
+            expected_status: vec![],
+            interval_ms: 5000,
+            timeout_ms: 1000,
+            consecutive_success: 1,
+            consecutive_failure: 1,
+        }
+    }
+}
+
+impl Config {
+    /// load and validate the config file
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).or_err_with(FileReadError, || {
+            format!("failed to read the config file {}", path.display())
+        })?;
+        let origin = path.display().to_string();
+        let conf = if path.extension().is_some_and(|e| e == "toml") {
+            Self::parse_toml(&content, &origin)?
+        } else {
+            Self::parse_yaml(&content, &origin)?
+        };
+        conf.validate()?;
+        Ok(conf)
+    }
+
+    /// parse the YAML config, without validation
+    pub fn from_yaml(content: &str) -> Result<Self> {
+        Self::parse_yaml(content, "<yaml>")
+    }
+
+    /// parse the TOML config, without validation
+    pub fn from_toml(content: &str) -> Result<Self> {
+        Self::parse_toml(content, "<toml>")
+    }
+
+    pub fn to_yaml(&self) -> String {
+        serde_yaml::to_string(self).expect("the config is always serializable")
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("the config is always serializable")
+    }
+
+    fn parse_yaml(content: &str, origin: &str) -> Result<Self> {
+        // an empty document is not a map
+        if content.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_yaml::from_str(content).explain_err(ConfigError, |e| format!("{origin}: {e}"))
+    }
+
+    fn parse_toml(content: &str, origin: &str) -> Result<Self> {
+        toml::from_str(content).explain_err(ConfigError, |e| format!("{origin}: {e}"))
+    }
+
+    /// check the values which are well-formed but not valid, e.g. a service proxying to an
+    /// upstream group which does not exist
+    ///
+    /// all the problems are reported at once, each prefixed with the path of the field
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        let mut error = |field: String, msg: String| errors.push(format!("{field}: {msg}"));
+
+        if self.threads == 0 {
+            error("threads".into(), "must be at least 1".into());
+        }
+        if self.user.as_deref() == Some("") {
+            error("user".into(), "must not be empty".into());
+        }
+        if self.group.as_deref() == Some("") {
+            error("group".into(), "must not be empty".into());
+        }
+        if !["error", "warn", "info", "debug", "trace", "off"].contains(&self.log.level.as_str()) {
+            error("log.level".into(), format!("unknown level `{}`", self.log.level));
+        }
+
+        let mut names = HashSet::new();
+        let mut addresses = HashSet::new();
+        for (i, service) in self.services.iter().enumerate() {
+            let field = |name: &str| format!("services[{i}].{name}");
+            if service.name.is_empty() {
+                error(field("name"), "must not be empty".into());
+            } else if !names.insert(service.name.as_str()) {
+                error(field("name"), format!("duplicate service `{}`", service.name));
+            }
+            if service.listeners.is_empty() {
+                error(field("listeners"), "at least one listener is required".into());
+            }
+            for (j, listener) in service.listeners.iter().enumerate() {
+                let addr = listener.server_address().to_string();
+                if let Err(msg) = listener.validate() {
+                    error(field(&format!("listeners[{j}]")), msg);
+                } else if !addresses.insert(addr.clone()) {
+                    error(field(&format!("listeners[{j}]")), format!("`{addr}` is listened twice"));
+                }
+            }
+            let unknown = |upstream: &&String| !self.upstreams.contains_key(*upstream);
+            if let Some(upstream) = service.upstream.as_ref().filter(unknown) {
+                error(field("upstream"), format!("unknown upstream group `{upstream}`"));
+            }
+            if service.upstream.is_none() && service.routes.is_empty() {
+                error(field("upstream"), "required without `routes`".into());
+            }
+            for (j, route) in service.routes.iter().enumerate() {
+                let field = |name: &str| field(&format!("routes[{j}]{name}"));
+                let rewrite = route.rewrite.as_ref().map(|r| r.rewrite());
+                let rate_limit = route.rate_limit.as_ref().map(|r| r.rate_limit().map(|_| ()));
+                let response = (route.matcher().and(rewrite.transpose()).and(rate_limit.transpose()))
+                    .and(route.response());
+                if let Err(e) = response.as_ref() {
+                    error(field(""), e.context.as_ref().map_or_else(|| e.to_string(), |c| c.to_string()));
+                }
+                let proxied = response.is_ok_and(|r| r.is_none());
+                if let Some(upstream) = route.upstream.as_ref().filter(unknown) {
+                    error(field(".upstream"), format!("unknown upstream group `{upstream}`"));
+                } else if proxied && route.upstream.is_none() && service.upstream.is_none() {
+                    error(field(".upstream"), "required without the `upstream` of the service".into());
+                }
+            }
+            if service.threads == Some(0) {
+                error(field("threads"), "must be at least 1".into());
+            }
+            if let Some(Err(e)) = service.rate_limit.as_ref().map(|r| r.rate_limit()) {
+                error(field("rate_limit"), e.context.as_ref().map_or_else(|| e.to_string(), |c| c.to_string()));
+            }
+            if let Some(cache) = service.cache.as_ref() {
+                if cache.path.as_ref().is_some_and(|p| p.as_os_str().is_empty()) {
+                    error(field("cache.path"), "must not be empty".into());
+                }
+                if cache.max_object_size == 0 || cache.max_object_size > cache.max_size {
+                    error(field("cache.max_object_size"), "must be positive and at most `max_size`".into());
+                }
+            }
+        }
+
+        if let Some(admin) = self.admin.as_ref() {
+            if admin.listeners.is_empty() {
+                error("admin.listeners".into(), "at least one listener is required".into());
+            }
+            for (j, listener) in admin.listeners.iter().enumerate() {
+                let addr = listener.server_address().to_string();
+                if let Err(msg) = listener.validate() {
+                    error(format!("admin.listeners[{j}]"), msg);
+                } else if !addresses.insert(addr.clone()) {
+                    error(format!("admin.listeners[{j}]"), format!("`{addr}` is listened twice"));
+                }
+            }
+        }
+
+        for (name, upstream) in self.upstreams.iter() {
+            let field = |f: &str| format!("upstreams.{name}.{f}");
+            let sources = [!upstream.backends.is_empty(), upstream.file.is_some(), !upstream.dns.is_empty()];
+            match sources.iter().filter(|s| **s).count() {
+                0 => error(format!("upstreams.{name}"), "one of `backends`, `file` and `dns` is required".into()),
+                1 => {}
+                _ => error(format!("upstreams.{name}"), "only one of `backends`, `file` and `dns` is allowed".into()),
+            }
+            for (i, backend) in upstream.backends.iter().enumerate() {
+                if !is_host_port(backend.addr()) {
+                    error(field(&format!("backends[{i}]")), format!("invalid address `{}`", backend.addr()));
+                }
+                if backend.weight() == 0 {
+                    error(field(&format!("backends[{i}].weight")), "must be at least 1".into());
+                }
+            }
+            for (i, addr) in upstream.dns.iter().enumerate() {
+                if !is_host_port(addr) {
+                    error(field(&format!("dns[{i}]")), format!("invalid address `{addr}`"));
+                }
+            }
+            for (f, value) in [
+                ("discovery_interval_ms", upstream.discovery_interval_ms),
+                ("connection_timeout_ms", upstream.connection_timeout_ms),
+                ("read_timeout_ms", upstream.read_timeout_ms),
+                ("write_timeout_ms", upstream.write_timeout_ms),
+            ] {
+                if value == Some(0) {
+                    error(field(f), "must be positive".into());
+                }
+            }
+            if let Some(hc) = upstream.health_check.as_ref() {
+                if hc.interval_ms == 0 {
+                    error(field("health_check.interval_ms"), "must be positive".into());
+                }
+                if hc.timeout_ms == 0 {
+                    error(field("health_check.timeout_ms"), "must be positive".into());
+                }
+                if !hc.path.starts_with('/') {
+                    error(field("health_check.path"), "must start with `/`".into());
+                }
+                for status in hc.expected_status.iter() {
+                    if !(100..600).contains(status) {
+                        error(field("health_check.expected_status"), format!("invalid status {status}"));
+                    }
+                }
+                if hc.consecutive_success == 0 || hc.consecutive_failure == 0 {
+                    error(field("health_check"), "the consecutive counts must be at least 1".into());
+                }
+                if hc.check_type == HealthCheckType::Http {
+                    let (f, host) = match hc.host.as_ref() {
+                        Some(host) => ("health_check.host", host),
+                        None => ("sni", &upstream.sni),
+                    };
+                    if let Err(e) = HttpHealthCheck::new(host) {
+                        error(field(f), e.context.as_ref().map_or_else(|| e.to_string(), |c| c.to_string()));
+                    }
+                }
+            }
+            if let Some(tls) = upstream.tls.as_ref() {
+                if tls.ca.as_deref() == Some("") {
+                    error(field("tls.ca"), "must not be empty".into());
+                }
+                if tls.client_cert.as_ref().is_some_and(|c| c.cert.is_empty() || c.key.is_empty()) {
+                    error(field("tls.client_cert"), "both the cert and key are required".into());
+                }
+                if tls.verify_hostname && upstream.sni.is_empty() {
+                    error(field("sni"), "required to verify the hostname of the backends".into());
+                }
+            }
+            if let Some(Err(e)) = upstream.outlier_detection.as_ref().map(|o| o.config()) {
+                let msg = e.context.as_ref().map_or_else(|| e.to_string(), |c| c.to_string());
+                error(field("outlier_detection"), msg);
+            }
+            if let Some(Err(e)) = upstream.concurrency.as_ref().map(|c| c.config()) {
+                error(field("concurrency"), e.context.as_ref().map_or_else(|| e.to_string(), |c| c.to_string()));
+            }
+            if let Some(Err(e)) = upstream.circuit_breaker.as_ref().map(|c| c.config()) {
+                let msg = e.context.as_ref().map_or_else(|| e.to_string(), |c| c.to_string());
+                error(field("circuit_breaker"), msg);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Error::e_explain(ConfigError, errors.join("\n"))
+        }
+    }
+
+    /// the settings of the [gateway_core::server::Server]
+    pub fn server_conf(&self) -> ServerConf {
+        ServerConf {
+            threads: self.threads,
+            daemon: self.daemon,
+            pid_file: self.pid_file.clone(),
+            // stdout and stderr of the daemon end up with the logs
+            error_log: self.log.file.clone(),
+            user: self.user.clone(),
+            group: self.group.clone(),
+            upgrade_sock: self.upgrade_sock.clone(),
+            grace_period_seconds: self.grace_period_seconds,
+            graceful_shutdown_timeout_seconds: self.graceful_shutdown_timeout_seconds,
+        }
+    }
+}
+
+/// whether `addr` looks like `ip:port` or `host:port`
+fn is_host_port(addr: &str) -> bool {
+    if addr.parse::<std::net::SocketAddr>().is_ok() {
+        return true;
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty() && !host.contains(['[', ']', ':', '/']) && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = r#"
+threads: 2
+log:
+  level: debug
+upstreams:
+  backend:
+    backends: ["127.0.0.1:8000", {addr: "localhost:8001", weight: 3}]
+    selection: hash
+    read_timeout_ms: 1000
+    health_check:
+      type: http
+      path: /health
+    concurrency: {max_in_flight: 50, adaptive: {latency_threshold_ms: 200}}
+    circuit_breaker: {failure_rate: 0.25, open_ms: 5000}
+    outlier_detection: {consecutive_5xx: 3, base_ejection_ms: 10000}
+services:
+  - name: main
+    listeners:
+      - 0.0.0.0:8080
+      - {address: "[::]:8080", ipv6_only: true, nodelay: true, keepalive: {idle_seconds: 60, interval_seconds: 5, count: 3}}
+      - {path: /run/octopus.sock, mode: 0o660}
+      - address: "0.0.0.0:8443"
+        tls:
+          cert: /etc/octopus/cert.pem
+          key: /etc/octopus/key.pem
+          sni_certs: [{cert: /etc/octopus/a.pem, key: /etc/octopus/a.key}]
+          reload_interval_ms: 60000
+          h2: true
+          client_auth: {ca: /etc/octopus/clients.pem, optional: true}
+    upstream: backend
+    cache:
+      max_size: 1048576
+    compression: {decompress: true}
+    rate_limit: {limit: 10, key: "header:x-api-key"}
+    routes:
+      - hosts: ["*.example.com"]
+        regex: /users/(?<id>\d+)
+        methods: [GET]
+        headers: {x-canary: {present: true}, x-version: {regex: "2|3"}}
+        query: {lang: en}
+        priority: 10
+        cache: false
+        rewrite:
+          path: /v2/users/{id}
+          host: backend.internal
+          request_headers: {set: {x-user-id: "{id}"}, remove: [cookie]}
+      - prefix: /static
+      - path: /health
+        respond: {headers: {content-type: text/plain}, body: "ok\n"}
+      - hosts: [old.example.com]
+        redirect: {location: "https://example.com{uri}"}
+admin:
+  listeners: [127.0.0.1:9000]
+"#;
+
+    #[test]
+    fn test_yaml() {
+        let conf = Config::from_yaml(YAML).unwrap();
+        conf.validate().unwrap();
+        assert_eq!(conf.threads, 2);
+        assert_eq!(conf.log.level, "debug");
+        let upstream = &conf.upstreams["backend"];
+        assert_eq!(upstream.selection, Selection::Hash);
+        assert_eq!(upstream.backends[1].weight(), 3);
+        let hc = upstream.health_check.as_ref().unwrap();
+        assert_eq!(hc.check_type, HealthCheckType::Http);
+        let concurrency = upstream.concurrency.as_ref().unwrap().config().unwrap();
+        assert_eq!(concurrency.max_in_flight, 50);
+        assert_eq!(concurrency.adaptive.unwrap().latency_threshold, Duration::from_millis(200));
+        // defaults
+        assert_eq!(hc.interval_ms, 5000);
+        assert_eq!((concurrency.max_queue, concurrency.queue_timeout), (100, Duration::from_secs(1)));
+        let outlier = upstream.outlier_detection.as_ref().unwrap().config().unwrap();
+        assert_eq!(outlier.consecutive_5xx, 3);
+        assert_eq!(outlier.base_ejection_time, Duration::from_secs(10));
+        assert_eq!(outlier.max_ejection_time, Duration::from_secs(300));
+        let breaker = upstream.circuit_breaker.as_ref().unwrap().config().unwrap();
+        assert_eq!(breaker.failure_rate, 0.25);
+        assert_eq!(breaker.open_duration, Duration::from_secs(5));
+        assert_eq!((breaker.min_calls, breaker.window), (20, Duration::from_secs(10)));
+        assert_eq!(conf.grace_period_seconds, Some(60));
+        assert_eq!(conf.services[0].threads, None);
+        let cache = conf.services[0].cache.as_ref().unwrap();
+        assert_eq!((cache.max_size, cache.max_object_size), (1 << 20, 1 << 20));
+        assert!(cache.path.is_none());
+        assert!(cache.lock().is_some_and(|lock| lock.timeout() == Duration::from_secs(5)));
+        let routes = &conf.services[0].routes;
+        assert_eq!((routes[0].priority, routes[0].cache, routes[1].cache), (10, false, true));
+        assert_eq!(routes[0].headers["x-canary"], ValueConf::Present { present: true });
+        let mut req = gateway_httpd::RequestHeader::build("GET", b"/users/42?lang=en", None).unwrap();
+        req.insert_header("Host", "api.example.com").unwrap();
+        req.insert_header("X-Canary", "1").unwrap();
+        req.insert_header("X-Version", "2").unwrap();
+        let params = routes[0].matcher().unwrap().matches(&req).unwrap();
+        assert_eq!(params, vec![("id".into(), "42".into())]);
+        let rewrite = routes[0].rewrite.as_ref().unwrap().rewrite().unwrap();
+        rewrite.rewrite_request(&mut req, &params).unwrap();
+        assert_eq!(req.uri, "/v2/users/42?lang=en");
+        assert_eq!(req.headers["x-user-id"], "42");
+        assert!(routes[1].response().unwrap().is_none());
+        let health = routes[2].response().unwrap().unwrap();
+        let resp = health.response_header(&req, &[]).unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.headers["content-length"], "3");
+        let redirect = routes[3].response().unwrap().unwrap();
+        let resp = redirect.response_header(&req, &[]).unwrap();
+        assert_eq!(resp.status, 301);
+        assert_eq!(resp.headers["location"], "https://example.com/v2/users/42?lang=en");
+        let rate_limit = conf.services[0].rate_limit.as_ref().unwrap();
+        assert_eq!(rate_limit.algorithm, RateLimitAlgorithm::TokenBucket);
+        assert_eq!((rate_limit.limit, rate_limit.period_ms, rate_limit.burst), (10, 1000, None));
+        assert!(rate_limit.rate_limit().is_ok());
+        let compression = conf.services[0].compression.as_ref().unwrap();
+        assert_eq!((compression.level, compression.decompress, compression.min_size), (6, true, 1024));
+
+        let listeners: Vec<_> = conf.services[0].listeners.iter().map(|l| l.server_address()).collect();
+        assert_eq!(listeners[0], ServerAddress::Tcp("0.0.0.0:8080".into(), None));
+        match &listeners[1] {
+            ServerAddress::Tcp(addr, Some(options)) => {
+                assert_eq!(addr, "[::]:8080");
+                assert_eq!(options.ipv6_only, Some(true));
+                assert!(options.nodelay && !options.reuseport);
+                assert_eq!(options.keepalive.as_ref().unwrap().count, 3);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert_eq!(listeners[2], ServerAddress::Uds("/run/octopus.sock".into(), Some(0o660)));
+        let tls = conf.services[0].listeners[3].tls().unwrap();
+        assert_eq!(tls.cert, "/etc/octopus/cert.pem");
+        assert_eq!(tls.sni_certs[0].key, "/etc/octopus/a.key");
+        assert!(tls.h2);
+        assert!(tls.client_auth.as_ref().unwrap().optional);
+        assert!(conf.services[0].listeners[0].tls().is_none());
+        assert_eq!(tls.cert_store().unwrap_err().etype(), &FileReadError);
+    }
+
+    #[test]
+    fn test_toml() {
+        let conf = Config::from_toml(
+            r#"
+threads = 2
+[upstreams.backend]
+backends = ["127.0.0.1:8000"]
+[[services]]
+name = "main"
+listeners = ["0.0.0.0:8080"]
+upstream = "backend"
+"#,
+        )
+        .unwrap();
+        conf.validate().unwrap();
+        assert_eq!(conf.services[0].name, "main");
+    }
+
+    #[test]
+    fn test_serialize() {
+        let conf = Config::from_yaml(YAML).unwrap();
+        assert_eq!(Config::from_yaml(&conf.to_yaml()).unwrap(), conf);
+        assert_eq!(Config::from_toml(&conf.to_toml()).unwrap(), conf);
+    }
+
+    #[test]
+    fn test_empty() {
+        let conf = Config::from_yaml("").unwrap();
+        conf.validate().unwrap();
+        assert_eq!(conf, Config::default());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let e = Config::from_yaml("threads: 1\nservices:\n  - name: main\n    listener: []\n").unwrap_err();
+        assert_eq!(e.etype(), &ConfigError);
+        let msg = e.to_string();
+        assert!(msg.contains("services[0]"), "{msg}");
+        assert!(msg.contains("unknown field `listener`"), "{msg}");
+        assert!(msg.contains("line 4"), "{msg}");
+
+        let e = Config::from_toml("threads = \"two\"\n").unwrap_err();
+        assert!(e.to_string().contains("line 1"), "{e}");
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut conf = Config::from_yaml(YAML).unwrap();
+        conf.threads = 0;
+        conf.services[0].upstream = Some("nowhere".into());
+        conf.services[0].listeners.push(ListenerConf::Addr("0.0.0.0".into()));
+        conf.services[0].listeners.push(ListenerConf::Addr("0.0.0.0:8080".into()));
+        conf.services[0].listeners.push(ListenerConf::Uds(UdsListenerConf {
+            path: "octopus.sock".into(),
+            mode: None,
+        }));
+        conf.services[0].listeners.push(ListenerConf::Tcp(TcpListenerConf {
+            address: "0.0.0.0:9443".into(),
+            tls: Some(ListenerTlsConf {
+                cert: "cert.pem".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }));
+        conf.services[0].cache.as_mut().unwrap().max_object_size = 2 << 20;
+        conf.admin.as_mut().unwrap().listeners.push(ListenerConf::Addr("0.0.0.0:8080".into()));
+        conf.services[0].routes[0].regex = Some("(".into());
+        conf.services[0].routes[1].upstream = Some("nowhere".into());
+        let rewrite = RewriteConf {
+            path_regex: Some(PathRegexConf {
+                pattern: "[".into(),
+                replacement: "".into(),
+            }),
+            ..Default::default()
+        };
+        conf.services[0].routes.push(RouteConf {
+            rewrite: Some(rewrite),
+            ..Default::default()
+        });
+        conf.services[0].routes[2].redirect = Some(RedirectConf {
+            location: "/".into(),
+            status: 200,
+        });
+        conf.services[0].routes[3].redirect.as_mut().unwrap().status = 200;
+        conf.services[0].routes[1].rate_limit = Some(RateLimitConf {
+            period_ms: 0,
+            ..Default::default()
+        });
+        conf.services[0].rate_limit.as_mut().unwrap().key = "header:".into();
+        conf.upstreams.get_mut("backend").unwrap().file = Some("backends.yaml".into());
+        conf.upstreams.get_mut("backend").unwrap().tls = Some(UpstreamTlsConf::default());
+        conf.upstreams.get_mut("backend").unwrap().concurrency.as_mut().unwrap().adaptive = Some(AdaptiveConf {
+            backoff: 1.5,
+            ..Default::default()
+        });
+        conf.upstreams.get_mut("backend").unwrap().circuit_breaker.as_mut().unwrap().half_open_calls = 0;
+        conf.upstreams.get_mut("backend").unwrap().outlier_detection.as_mut().unwrap().max_ejection_ms = 1;
+        conf.upstreams.get_mut("backend").unwrap().health_check.as_mut().unwrap().host = Some("a\nb".into());
+        let msg = conf.validate().unwrap_err().to_string();
+        assert!(msg.contains("threads: must be at least 1"), "{msg}");
+        assert!(msg.contains("services[0].upstream: unknown upstream group `nowhere`"), "{msg}");
+        assert!(msg.contains("services[0].listeners[4]: invalid address `0.0.0.0`"), "{msg}");
+        assert!(msg.contains("services[0].listeners[5]: `tcp://0.0.0.0:8080` is listened twice"), "{msg}");
+        assert!(msg.contains("services[0].listeners[6]: the socket path"), "{msg}");
+        assert!(msg.contains("services[0].listeners[7]: both the TLS cert and key are required"), "{msg}");
+        assert!(msg.contains("services[0].cache.max_object_size: must be positive"), "{msg}");
+        assert!(msg.contains("services[0].rate_limit: invalid key `header:`"), "{msg}");
+        assert!(msg.contains("services[0].routes[1]: the limit, the period and the burst must be"), "{msg}");
+        assert!(msg.contains("services[0].routes[0]: invalid path regex `(`"), "{msg}");
+        assert!(msg.contains("services[0].routes[1].upstream: unknown upstream group `nowhere`"), "{msg}");
+        assert!(msg.contains("services[0].routes[2]: only one of `redirect` and `respond`"), "{msg}");
+        assert!(msg.contains("services[0].routes[3]: 200 is not a redirect status"), "{msg}");
+        assert!(msg.contains("services[0].routes[4]: invalid regex `[`"), "{msg}");
+        assert!(msg.contains("admin.listeners[1]: `tcp://0.0.0.0:8080` is listened twice"), "{msg}");
+        assert!(msg.contains("upstreams.backend: only one of"), "{msg}");
+        assert!(msg.contains("upstreams.backend.sni: required to verify the hostname"), "{msg}");
+        assert!(msg.contains("upstreams.backend.concurrency: `backoff` must be between 0 and 1"), "{msg}");
+        assert!(msg.contains("upstreams.backend.circuit_breaker: `slow_call_ms`, `window_ms`"), "{msg}");
+        assert!(msg.contains("upstreams.backend.outlier_detection: `max_ejection_ms` must be at least"), "{msg}");
+        assert!(msg.contains("upstreams.backend.health_check.host: invalid host `a\nb`"), "{msg}");
+    }
+
+    #[test]
+    fn test_load_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.yaml");
+        std::fs::write(&path, YAML).unwrap();
+        let conf = Config::load_from_file(&path).unwrap();
+        assert_eq!(conf.server_conf().threads, 2);
+
+        std::fs::write(&path, "threads: 0\n").unwrap();
+        let e = Config::load_from_file(&path).unwrap_err();
+        assert_eq!(e.etype(), &ConfigError);
+
+        assert_eq!(
+            Config::load_from_file(dir.path().join("missing.yaml")).unwrap_err().etype(),
+            &FileReadError
+        );
+    }
+
+    #[test]
+    fn test_is_host_port() {
+        assert!(is_host_port("127.0.0.1:80"));
+        assert!(is_host_port("[::1]:80"));
+        assert!(is_host_port("example.com:443"));
+        assert!(!is_host_port("example.com"));
+        assert!(!is_host_port(":80"));
+        assert!(!is_host_port("example.com:http"));
+    }
+}
@@ -0,0 +1,146 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the gateway server, which runs the proxy services described by a configuration file
+
+pub mod admin;
+pub mod cli;
+pub mod config;
+pub mod proxy;
+pub mod upstream;
+
+use admin::AdminApp;
+use config::{Config, ListenerConf, LogConf};
+use gateway_cache::{CacheIndex, Purger};
+use gateway_core::listeners::ServerAddress;
+use gateway_core::protocols::tls::cert_store::CertReloader;
+use gateway_core::services::background::background_service;
+use gateway_core::services::listening::ListeningService;
+use gateway_core::server::Server;
+use gateway_error::{ErrorType::*, OrErr, Result};
+use gateway_proxy::http_proxy_service;
+use proxy::{Route, ServiceProxy};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use upstream::UpstreamGroup;
+
+/// create the server with all the services described by the config
+pub fn build_server(conf: &Config) -> Result<Server> {
+    let mut server = Server::new(conf.server_conf());
+
+    // the first discovery runs before serving, so the services start with their backends
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .or_err(InternalError, "failed to create the runtime")?;
+    let mut groups = HashMap::new();
+    for (name, upstream) in conf.upstreams.iter() {
+        let group = Arc::new(UpstreamGroup::new(name, upstream)?);
+        runtime.block_on(group.update())?;
+        if let Some(service) = group.background_service() {
+            server.add_services(vec![service]);
+        }
+        groups.insert(name.as_str(), group);
+    }
+
+    let mut admin_app = AdminApp::new();
+    for service in conf.services.iter() {
+        // the config is validated, the upstreams exist
+        let group = |name: &Option<String>| name.as_ref().map(|n| groups[n.as_str()].clone());
+        let mut service_proxy = ServiceProxy::new(group(&service.upstream));
+        for route in service.routes.iter() {
+            let target = Route {
+                upstream: group(&route.upstream),
+                no_cache: !route.cache,
+                compression: route.compression.clone(),
+                rewrite: route.rewrite.as_ref().map(|r| r.rewrite()).transpose()?,
+                response: route.response()?,
+                rate_limit: route.rate_limit.as_ref().map(|r| r.rate_limit()).transpose()?,
+            };
+            service_proxy.add_route(route.matcher()?, route.priority, target);
+        }
+        if let Some(cache) = service.cache.as_ref() {
+            let storage = cache.storage()?;
+            let index = Arc::new(CacheIndex::new());
+            storage.set_eviction_listener(index.clone());
+            service_proxy.set_cache(storage.clone(), cache.max_object_size);
+            service_proxy.set_cache_index(index.clone());
+            if let Some(lock) = cache.lock() {
+                service_proxy.set_cache_lock(lock);
+            }
+            admin_app.add_purger(&service.name, Purger::new(storage, index));
+        }
+        if let Some(compression) = service.compression.as_ref() {
+            service_proxy.set_compression(compression.clone());
+        }
+        if let Some(rate_limit) = service.rate_limit.as_ref() {
+            service_proxy.set_rate_limit(rate_limit.rate_limit()?);
+        }
+        let mut proxy = http_proxy_service(&service.name, service_proxy);
+        add_listeners(&mut server, &mut proxy, &service.listeners)?;
+        proxy.threads = service.threads;
+        server.add_service(proxy);
+    }
+
+    if let Some(admin) = conf.admin.as_ref() {
+        let mut admin_service = ListeningService::new("admin".to_string(), admin_app);
+        add_listeners(&mut server, &mut admin_service, &admin.listeners)?;
+        server.add_service(admin_service);
+    }
+    Ok(server)
+}
+
+/// add the listeners to the service, the certs of the TLS ones are reloaded in the background if
+/// configured
+fn add_listeners<A>(server: &mut Server, service: &mut ListeningService<A>, listeners: &[ListenerConf]) -> Result<()> {
+    for listener in listeners.iter() {
+        match (listener.server_address(), listener.tls()) {
+            (ServerAddress::Tcp(addr, options), Some(tls)) => {
+                let store = Arc::new(tls.cert_store()?);
+                if let Some(ms) = tls.reload_interval_ms {
+                    let reloader = CertReloader::new(store.clone(), Duration::from_millis(ms));
+                    server.add_service(background_service(&format!("cert reload {addr}"), reloader));
+                }
+                service.add_tls_with_settings(&addr, options, tls.settings(store)?)?
+            }
+            (addr, _) => service.add_address(addr),
+        }
+    }
+    Ok(())
+}
+
+/// log to stderr, or the log file if set
+pub fn init_logger(conf: &LogConf) -> Result<()> {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&conf.level);
+    if let Some(path) = conf.file.as_ref() {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .or_err_with(FileOpenError, || format!("failed to open the log file {}", path.display()))?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.init();
+    Ok(())
+}
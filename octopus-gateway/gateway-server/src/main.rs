@@ -1,3 +1,67 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use clap::Parser;
+use gateway_server::cli::{Command, Format, Opt};
+use gateway_server::{build_server, init_logger};
+use log::error;
+
 fn main() {
-    println!("Hello, world!");
+    let opt = Opt::parse();
+    let conf = match opt.load_config() {
+        Ok(conf) => conf,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    match opt.command {
+        Some(Command::Config { format: Format::Yaml }) => {
+            print!("{}", conf.to_yaml());
+            return;
+        }
+        Some(Command::Config { format: Format::Toml }) => {
+            print!("{}", conf.to_toml());
+            return;
+        }
+        None => {}
+    }
+    if opt.test {
+        println!("the configuration is ok");
+        return;
+    }
+
+    if let Err(e) = init_logger(&conf.log) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    let mut server = match build_server(&conf) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("failed to create the server: {e}");
+            std::process::exit(1);
+        }
+    };
+    server.upgrade = opt.upgrade;
+    server.run_forever()
 }
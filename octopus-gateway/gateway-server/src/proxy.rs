@@ -0,0 +1,509 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! the proxy logic of the services
+
+use crate::config::CompressionConf;
+use crate::upstream::UpstreamGroup;
+use async_trait::async_trait;
+use gateway_cache::{CacheIndex, CacheLock, Storage};
+use gateway_core::lb::circuit_breaker::Call;
+use gateway_core::lb::outlier::Outcome;
+use gateway_core::lb::Backend;
+use gateway_core::ratelimit::Permit;
+use gateway_core::upstreams::peer::HttpPeer;
+use gateway_httpd::{RequestHeader, ResponseHeader};
+use gateway_error::{Error, ErrorType::*, Result};
+use gateway_proxy::{ProxyHttp, RateLimit, Rewrite, RouteMatcher, Router, Session, StaticResponse};
+use log::info;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// proxy the requests of a service to the upstream group of the route they match, or else to the
+/// one of the service
+pub struct ServiceProxy {
+    upstream: Option<Arc<UpstreamGroup>>,
+    router: Router<Arc<Route>>,
+    cache: Option<(Arc<dyn Storage>, usize)>,
+    cache_lock: Option<Arc<CacheLock>>,
+    cache_index: Option<Arc<CacheIndex>>,
+    compression: Option<CompressionConf>,
+    rate_limit: Option<RateLimit>,
+}
+
+/// where and how the requests of a route are proxied
+#[derive(Default)]
+pub struct Route {
+    /// the upstream group of the service if not set
+    pub upstream: Option<Arc<UpstreamGroup>>,
+    /// skip the cache of the service
+    pub no_cache: bool,
+    /// the compression of the service if not set
+    pub compression: Option<CompressionConf>,
+    /// rewrite the requests to the upstream and the responses
+    pub rewrite: Option<Rewrite>,
+    /// answer the requests without any upstream
+    pub response: Option<StaticResponse>,
+    /// limit the rate of the requests of the route, after the limit of the service
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// the state of a request
+#[derive(Default)]
+pub struct ServiceCtx {
+    /// the route the request matches
+    route: Option<Arc<Route>>,
+    /// the captures of the route
+    params: Vec<(String, String)>,
+    /// the request let through the circuit breaker of the upstream group
+    call: Option<Call>,
+    /// the slot of the request among the ones in flight to the upstream group
+    permit: Option<Permit>,
+    /// when the request was sent to the upstream group
+    upstream_start: Option<Instant>,
+    /// the backend of the last attempt, until its outcome is reported
+    backend: Option<Backend>,
+    /// the status the upstream responded with
+    upstream_status: Option<u16>,
+    /// how long the upstream took to respond, `None` if it did not or with a 5xx
+    upstream_latency: Option<Duration>,
+}
+
+impl ServiceCtx {
+    /// the captured value of `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+impl ServiceProxy {
+    /// the requests matching no route are answered with 404 without an `upstream`
+    pub fn new(upstream: Option<Arc<UpstreamGroup>>) -> Self {
+        ServiceProxy {
+            upstream,
+            router: Router::new(),
+            cache: None,
+            cache_lock: None,
+            cache_index: None,
+            compression: None,
+            rate_limit: None,
+        }
+    }
+
+    /// route the requests `matcher` matches, see [Router::add()]
+    pub fn add_route(&mut self, matcher: RouteMatcher, priority: i32, route: Route) {
+        self.router.add(matcher, priority, Arc::new(route));
+    }
+
+    /// cache the responses in `storage`, except those with a body larger than `max_object_size`
+    pub fn set_cache(&mut self, storage: Arc<dyn Storage>, max_object_size: usize) {
+        self.cache = Some((storage, max_object_size));
+    }
+
+    /// record the cached responses in `index` to purge them
+    pub fn set_cache_index(&mut self, index: Arc<CacheIndex>) {
+        self.cache_index = Some(index);
+    }
+
+    /// let only one request fetch a missing response, the others wait on `lock` for it
+    pub fn set_cache_lock(&mut self, lock: Arc<CacheLock>) {
+        self.cache_lock = Some(lock);
+    }
+
+    /// compress the responses, unless the route has its own compression
+    pub fn set_compression(&mut self, compression: CompressionConf) {
+        self.compression = Some(compression);
+    }
+
+    /// limit the rate of all the requests, before the limits of their routes
+    pub fn set_rate_limit(&mut self, rate_limit: RateLimit) {
+        self.rate_limit = Some(rate_limit);
+    }
+
+    /// the upstream group of the request
+    fn upstream<'a>(&'a self, ctx: &'a ServiceCtx) -> Option<&'a Arc<UpstreamGroup>> {
+        let route = ctx.route.as_ref().and_then(|r| r.upstream.as_ref());
+        route.or(self.upstream.as_ref())
+    }
+}
+
+#[async_trait]
+impl ProxyHttp for ServiceProxy {
+    type CTX = ServiceCtx;
+
+    fn new_ctx(&self) -> Self::CTX {
+        ServiceCtx::default()
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut ServiceCtx) -> Result<bool> {
+        if let Some(matched) = self.router.route(session.req_header()) {
+            ctx.route = Some(matched.target.clone());
+            ctx.params = matched.params;
+        }
+        let route = ctx.route.as_ref().and_then(|r| r.rate_limit.as_ref());
+        for rate_limit in self.rate_limit.iter().chain(route) {
+            if rate_limit.limit_request(session).await? {
+                return Ok(true);
+            }
+        }
+        if let Some(response) = ctx.route.as_ref().and_then(|r| r.response.as_ref()) {
+            response.respond(session, &ctx.params).await?;
+            return Ok(true);
+        }
+        if self.upstream(ctx).is_none() {
+            session.respond_error(404).await?;
+            return Ok(true);
+        }
+        let route = ctx.route.as_ref().and_then(|r| r.compression.as_ref());
+        if let Some(compression) = route.or(self.compression.as_ref()) {
+            session.compression.set_level(compression.level);
+            session.compression.set_decompress(compression.decompress);
+            session.compression.set_min_size(compression.min_size);
+        }
+        Ok(false)
+    }
+
+    fn request_cache_filter(&self, session: &mut Session, ctx: &mut ServiceCtx) -> Result<()> {
+        if ctx.route.as_ref().is_some_and(|r| r.no_cache) {
+            return Ok(());
+        }
+        if let Some((storage, max_object_size)) = self.cache.as_ref() {
+            session.cache.enable(storage.clone(), *max_object_size);
+            if let Some(lock) = self.cache_lock.as_ref() {
+                session.cache.set_lock(lock.clone());
+            }
+            if let Some(index) = self.cache_index.as_ref() {
+                session.cache.set_index(index.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn upstream_peer(&self, session: &mut Session, ctx: &mut ServiceCtx) -> Result<Box<HttpPeer>> {
+        let Some(upstream) = self.upstream(ctx).cloned() else {
+            return Error::e_explain(HTTPStatus(404), "no upstream group");
+        };
+        // only once, not for every retry
+        if ctx.upstream_start.is_none() {
+            // fail fast, without waiting in the queue
+            let call = upstream.allow()?;
+            match upstream.acquire().await {
+                Ok(permit) => ctx.permit = permit,
+                Err(e) => {
+                    // the load shed is not a failure of the upstream
+                    if let Some(call) = call {
+                        upstream.cancel(call);
+                    }
+                    return Err(e);
+                }
+            }
+            ctx.call = call;
+            ctx.upstream_start = Some(Instant::now());
+        }
+        // the hash selection keeps a client on the same backend
+        let key = session.client_addr().map(|a| a.ip().to_string()).unwrap_or_default();
+        let (backend, peer) = upstream.peer(key.as_bytes())?;
+        ctx.backend = Some(backend);
+        Ok(peer)
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut ServiceCtx,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        // the request may be retried with another backend
+        let backend = ctx.backend.take();
+        if let (Some(backend), Some(outcome)) = (backend, Outcome::from_error(&e)) {
+            if let Some(upstream) = self.upstream(ctx) {
+                upstream.report_outcome(&backend, outcome);
+            }
+        }
+        e
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut ServiceCtx,
+    ) -> Result<()> {
+        if let Some(rewrite) = ctx.route.as_ref().and_then(|r| r.rewrite.as_ref()) {
+            rewrite.rewrite_request(upstream_request, &ctx.params)?;
+        }
+        Ok(())
+    }
+
+    // not in `response_filter`, which also gets the responses served from the cache
+    async fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut ServiceCtx,
+    ) -> Result<()> {
+        ctx.upstream_status = Some(upstream_response.status.as_u16());
+        if upstream_response.status.as_u16() < 500 {
+            ctx.upstream_latency = ctx.upstream_start.map(|t| t.elapsed());
+        }
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut ServiceCtx,
+    ) -> Result<()> {
+        if let Some(rewrite) = ctx.route.as_ref().and_then(|r| r.rewrite.as_ref()) {
+            rewrite.rewrite_response(upstream_response, &ctx.params)?;
+        }
+        Ok(())
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut ServiceCtx) {
+        // an error while proxying the body is a failure as well
+        let latency = ctx.upstream_latency.filter(|_| e.is_none());
+        if let Some(permit) = ctx.permit.take() {
+            permit.release(latency);
+        }
+        if let Some(call) = ctx.call.take() {
+            if let Some(upstream) = self.upstream(ctx) {
+                upstream.report(call, latency);
+            }
+        }
+        if let Some(backend) = ctx.backend.take() {
+            let outcome = match e {
+                Some(e) => Outcome::from_error(e),
+                None => ctx.upstream_status.map(Outcome::from_status),
+            };
+            if let (Some(outcome), Some(upstream)) = (outcome, self.upstream(ctx)) {
+                upstream.report_outcome(&backend, outcome);
+            }
+        }
+        let req = session.req_header();
+        let status = session.response_written().map_or(0, |r| r.status.as_u16());
+        info!(
+            "{} \"{} {}\" {status} {} cache:{}",
+            session.client_addr().map(|a| a.to_string()).unwrap_or_default(),
+            req.method,
+            req.uri,
+            self.upstream(ctx).map_or("-", |u| u.name()),
+            session.cache.phase().as_str()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, RateLimitConf};
+    use gateway_core::lb::circuit_breaker::CircuitState;
+    use gateway_proxy::router::PathMatcher;
+    use gateway_proxy::testing::{get, mock_upstream, start_proxy, MockResponse};
+    use gateway_cache::MemoryStorage;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// a group of `backends` with the other settings of `extra`, a YAML map
+    async fn group(backends: &[SocketAddr], extra: &str) -> Arc<UpstreamGroup> {
+        let backends: Vec<String> = backends.iter().map(|b| format!("\"{b}\"")).collect();
+        let extra = if extra.is_empty() { String::new() } else { format!(", {extra}") };
+        let yaml = format!("upstreams:\n  backend: {{backends: [{}]{extra}}}\n", backends.join(", "));
+        let conf = Config::from_yaml(&yaml).unwrap();
+        let (name, upstream) = conf.upstreams.iter().next().unwrap();
+        let group = UpstreamGroup::new(name, upstream).unwrap();
+        group.update().await.unwrap();
+        Arc::new(group)
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let upstream = group(&[mock_upstream(|_| MockResponse::new(200)).await], "").await;
+        let mut proxy = ServiceProxy::new(Some(upstream));
+        let conf = RateLimitConf {
+            limit: 1,
+            period_ms: 60000,
+            key: "all".into(),
+            ..Default::default()
+        };
+        proxy.set_rate_limit(conf.rate_limit().unwrap());
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+        // counted across the connections
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{resp}");
+        assert!(resp.contains("\r\nRetry-After: 60\r\n"), "{resp}");
+    }
+
+    #[tokio::test]
+    async fn test_route_normalized_path() {
+        let backend = mock_upstream(|req| MockResponse::new(200).body(req.uri.path().to_string())).await;
+        let mut proxy = ServiceProxy::new(None);
+        let mut public = RouteMatcher::new();
+        public.set_path(PathMatcher::Prefix("/public".into()));
+        let route = Route {
+            upstream: Some(group(&[backend], "").await),
+            ..Default::default()
+        };
+        proxy.add_route(public, 0, route);
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // not let through the route of `/public`
+        for path in ["/public/../admin", "/public/%2e%2e/admin"] {
+            let resp = get(&mut stream, path).await;
+            assert!(resp.starts_with("HTTP/1.1 404"), "{resp}");
+        }
+        // forwarded as matched
+        let resp = get(&mut stream, "//public/./x/../y").await;
+        assert!(resp.starts_with("HTTP/1.1 200") && resp.ends_with("\r\n\r\n/public/y"), "{resp}");
+    }
+
+    #[tokio::test]
+    async fn test_circuit_overloaded() {
+        let conf = "concurrency: {max_in_flight: 1, max_queue: 0}, \
+                    circuit_breaker: {min_calls: 1, open_ms: 100, half_open_calls: 1}";
+        let upstream = group(&[mock_upstream(|_| MockResponse::new(500)).await], conf).await;
+        let (addr, _shutdown) = start_proxy(ServiceProxy::new(Some(upstream.clone())), None);
+
+        // the load shed is not a failure
+        let permit = upstream.acquire().await.unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 503"), "{resp}");
+        assert_eq!(upstream.circuit_state(), Some(CircuitState::Closed));
+        drop(permit);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 500"), "{resp}");
+        assert_eq!(upstream.circuit_state(), Some(CircuitState::Open));
+
+        // the trial call of the half-open circuit finds the group overloaded, and gives its slot back
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let permit = upstream.acquire().await.unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 503"), "{resp}");
+        assert_eq!(upstream.circuit_state(), Some(CircuitState::HalfOpen));
+        drop(permit);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 500"), "{resp}");
+        assert_eq!(upstream.circuit_state(), Some(CircuitState::Open));
+    }
+
+    /// an upstream whose first response is cached and served when it is stale on errors, after
+    /// which it fails
+    async fn failing_upstream() -> SocketAddr {
+        let fetches = AtomicUsize::new(0);
+        mock_upstream(move |_| match fetches.fetch_add(1, Ordering::Relaxed) {
+            0 => MockResponse::new(200)
+                .header("Cache-Control", "max-age=0, stale-if-error=60")
+                .header("ETag", "\"v1\"")
+                .body("v1"),
+            _ => MockResponse::new(500),
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_circuit_stale_if_error() {
+        let upstream = group(&[failing_upstream().await], "circuit_breaker: {min_calls: 2}").await;
+        let mut proxy = ServiceProxy::new(Some(upstream.clone()));
+        proxy.set_cache(Arc::new(MemoryStorage::new(1 << 20)), 1024);
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 200") && resp.ends_with("\r\n\r\nv1"), "{resp}");
+        // served the stale response, but the upstream failed
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 200") && resp.contains("Age: "), "{resp}");
+        assert_eq!(upstream.circuit_state(), Some(CircuitState::Open));
+    }
+
+    #[tokio::test]
+    async fn test_outlier_detection() {
+        let failing = mock_upstream(|_| MockResponse::new(500)).await;
+        let healthy = mock_upstream(|_| MockResponse::new(200)).await;
+        let upstream = group(&[failing, healthy], "outlier_detection: {consecutive_5xx: 2}").await;
+        let (addr, _shutdown) = start_proxy(ServiceProxy::new(Some(upstream)), None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut failures = 0;
+        for _ in 0..4 {
+            if get(&mut stream, "/").await.starts_with("HTTP/1.1 500") {
+                failures += 1;
+            }
+        }
+        // the round robin alternates until the second 500 ejects the failing backend
+        assert_eq!(failures, 2);
+        for _ in 0..6 {
+            let resp = get(&mut stream, "/").await;
+            assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outlier_stale_if_error() {
+        let upstream = group(&[failing_upstream().await], "outlier_detection: {consecutive_5xx: 1}").await;
+        let mut proxy = ServiceProxy::new(Some(upstream.clone()));
+        proxy.set_cache(Arc::new(MemoryStorage::new(1 << 20)), 1024);
+        let (addr, _shutdown) = start_proxy(proxy, None);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        get(&mut stream, "/").await;
+        assert!(upstream.select(b"").is_some());
+        // served the stale response, but the only backend failed and is ejected
+        let resp = get(&mut stream, "/").await;
+        assert!(resp.starts_with("HTTP/1.1 200") && resp.contains("Age: "), "{resp}");
+        assert!(upstream.select(b"").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_outlier_connect_failure() {
+        let refused = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let healthy = mock_upstream(|_| MockResponse::new(200)).await;
+        let upstream = group(&[refused, healthy], "outlier_detection: {consecutive_connect_failures: 1}").await;
+        let (addr, _shutdown) = start_proxy(ServiceProxy::new(Some(upstream.clone())), None);
+
+        // the refused connection is retried with the other backend
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..2 {
+            let resp = get(&mut stream, "/").await;
+            assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+        }
+        for _ in 0..4 {
+            assert_eq!(upstream.select(b"").unwrap().addr, healthy);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//                                      MIT License
+//
+// Copyright (c) [2024] [ryandonglin]
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! the upstream groups built from the configuration
+
+use crate::config::{BackendConf, HealthCheckConf, HealthCheckType, Selection, UpstreamConf, UpstreamTlsConf};
+use gateway_core::lb::circuit_breaker::{Call, CircuitBreaker, CircuitState};
+use gateway_core::lb::discovery::{self, Dns, DnsQuery, DnsResolver, ServiceDiscovery};
+use gateway_core::lb::health_check::{HealthCheck, HttpHealthCheck, TcpHealthCheck};
+use gateway_core::lb::outlier::Outcome;
+use gateway_core::lb::selection::{BackendIter, BackendSelection, FnvHash, Random, RoundRobin};
+use gateway_core::lb::{Backend, Backends, LoadBalancer};
+use gateway_core::protocols::tls::{load_cert_key, load_certs};
+use gateway_core::ratelimit::{ConcurrencyLimiter, Permit};
+use gateway_core::services::background::GenBackgroundService;
+use gateway_core::services::Service;
+use gateway_core::upstreams::peer::{HttpPeer, PeerOptions};
+use gateway_error::{Error, ErrorType::*, OkOrErr, OrErr, Result};
+use gateway_httpd::RequestHeader;
+use std::collections::BTreeSet;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// the max number of backends tried to find a ready one
+const MAX_ITERATIONS: usize = 256;
+/// how often the `file` and `dns` backends are refreshed by default
+const DEFAULT_DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
+
+enum Balancer {
+    RoundRobin(Arc<LoadBalancer<RoundRobin>>),
+    Random(Arc<LoadBalancer<Random>>),
+    Hash(Arc<LoadBalancer<FnvHash>>),
+}
+
+/// a load balanced group of backends
+pub struct UpstreamGroup {
+    name: String,
+    balancer: Balancer,
+    sni: String,
+    tls: bool,
+    options: PeerOptions,
+    concurrency: Option<Arc<ConcurrencyLimiter>>,
+    circuit_breaker: Option<CircuitBreaker>,
+}
+
+impl UpstreamGroup {
+    /// build the group, the backends are empty until [Self::update()] is called
+    pub fn new(name: &str, conf: &UpstreamConf) -> Result<Self> {
+        let balancer = match conf.selection {
+            Selection::RoundRobin => Balancer::RoundRobin(Arc::new(build_lb(conf)?)),
+            Selection::Random => Balancer::Random(Arc::new(build_lb(conf)?)),
+            Selection::Hash => Balancer::Hash(Arc::new(build_lb(conf)?)),
+        };
+        let mut options = PeerOptions {
+            connection_timeout: conf.connection_timeout_ms.map(Duration::from_millis),
+            read_timeout: conf.read_timeout_ms.map(Duration::from_millis),
+            write_timeout: conf.write_timeout_ms.map(Duration::from_millis),
+            ..Default::default()
+        };
+        if let Some(tls) = conf.tls.as_ref() {
+            tls_options(tls, &mut options)?;
+        }
+        let concurrency = match conf.concurrency.as_ref() {
+            Some(c) => Some(Arc::new(ConcurrencyLimiter::new(c.config()?))),
+            None => None,
+        };
+        let circuit_breaker = match conf.circuit_breaker.as_ref() {
+            Some(c) => Some(CircuitBreaker::new(name, c.config()?)),
+            None => None,
+        };
+        Ok(UpstreamGroup {
+            name: name.to_string(),
+            balancer,
+            sni: conf.sni.clone(),
+            tls: conf.tls.is_some(),
+            options,
+            concurrency,
+            circuit_breaker,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// fetch the backends from the service discovery
+    pub async fn update(&self) -> Result<()> {
+        match &self.balancer {
+            Balancer::RoundRobin(lb) => lb.update().await,
+            Balancer::Random(lb) => lb.update().await,
+            Balancer::Hash(lb) => lb.update().await,
+        }
+    }
+
+    /// the service which refreshes the backends and checks their health, `None` if there is
+    /// nothing to do in the background
+    pub fn background_service(&self) -> Option<Box<dyn Service>> {
+        fn service<S>(name: String, lb: &Arc<LoadBalancer<S>>) -> Option<Box<dyn Service>>
+        where
+            S: BackendSelection + Send + Sync + 'static,
+            S::Iter: BackendIter,
+        {
+            if lb.update_frequency.is_none() && lb.health_check_frequency.is_none() {
+                return None;
+            }
+            Some(Box::new(GenBackgroundService::new(name, lb.clone())))
+        }
+
+        let name = format!("lb {}", self.name);
+        match &self.balancer {
+            Balancer::RoundRobin(lb) => service(name, lb),
+            Balancer::Random(lb) => service(name, lb),
+            Balancer::Hash(lb) => service(name, lb),
+        }
+    }
+
+    /// select a ready backend, `key` is only used by the hash selection
+    pub fn select(&self, key: &[u8]) -> Option<Backend> {
+        match &self.balancer {
+            Balancer::RoundRobin(lb) => lb.select(key, MAX_ITERATIONS),
+            Balancer::Random(lb) => lb.select(key, MAX_ITERATIONS),
+            Balancer::Hash(lb) => lb.select(key, MAX_ITERATIONS),
+        }
+    }
+
+    /// wait for a request in flight to the group to finish if there are too many of them,
+    /// `HTTPStatus(503)` if the group is overloaded, `None` without any limit
+    pub async fn acquire(&self) -> Result<Option<Permit>> {
+        let Some(limiter) = self.concurrency.as_ref() else {
+            return Ok(None);
+        };
+        match limiter.acquire().await {
+            Ok(permit) => Ok(Some(permit)),
+            Err(e) => Err(Error::because(HTTPStatus(503), format!("upstream {} is overloaded", self.name), e)),
+        }
+    }
+
+    /// let a request through the circuit breaker of the group, `CircuitOpen` if the group is
+    /// failing, `None` without any circuit breaker
+    pub fn allow(&self) -> Result<Option<Call>> {
+        let Some(breaker) = self.circuit_breaker.as_ref() else {
+            return Ok(None);
+        };
+        match breaker.allow() {
+            Ok(call) => Ok(Some(call)),
+            Err(e) => Err(Error::because(CircuitOpen, format!("upstream {} is failing", self.name), e)),
+        }
+    }
+
+    /// the state of the circuit breaker of the group, `None` without any circuit breaker
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.circuit_breaker.as_ref().map(|b| b.state())
+    }
+
+    /// report the outcome of a request let through by [Self::allow()], `latency` is how long
+    /// the group took to respond, `None` if it failed
+    pub fn report(&self, call: Call, latency: Option<Duration>) {
+        if let Some(breaker) = self.circuit_breaker.as_ref() {
+            breaker.report(call, latency);
+        }
+    }
+
+    /// give up on a request let through by [Self::allow()] which was never sent
+    pub fn cancel(&self, call: Call) {
+        if let Some(breaker) = self.circuit_breaker.as_ref() {
+            breaker.cancel(call);
+        }
+    }
+
+    /// report the outcome of a request to `backend` to the outlier detection, if any
+    pub fn report_outcome(&self, backend: &Backend, outcome: Outcome) {
+        match &self.balancer {
+            Balancer::RoundRobin(lb) => lb.report(backend, outcome),
+            Balancer::Random(lb) => lb.report(backend, outcome),
+            Balancer::Hash(lb) => lb.report(backend, outcome),
+        }
+    }
+
+    /// the peer to send a request to and its backend, 503 if no backend is ready
+    pub fn peer(&self, key: &[u8]) -> Result<(Backend, Box<HttpPeer>)> {
+        let backend = self
+            .select(key)
+            .or_err_with(HTTPStatus(503), || format!("no available backend in upstream {}", self.name))?;
+        let mut peer = HttpPeer::from_backend(&backend, self.sni.clone());
+        peer.tls = self.tls;
+        // the CA and the client cert are shared, so are the TLS sessions of the backends
+        peer.options = self.options.clone();
+        Ok((backend, Box::new(peer)))
+    }
+}
+
+/// load the CA bundle and the client cert of the backends
+fn tls_options(conf: &UpstreamTlsConf, options: &mut PeerOptions) -> Result<()> {
+    options.verify_cert = conf.verify_cert;
+    options.verify_hostname = conf.verify_hostname;
+    if let Some(ca) = conf.ca.as_ref() {
+        options.ca = Some(Arc::new(load_certs(ca)?));
+    }
+    if let Some(cert) = conf.client_cert.as_ref() {
+        options.client_cert = Some(Arc::new(load_cert_key(&cert.cert, &cert.key)?));
+    }
+    Ok(())
+}
+
+fn build_lb<S>(conf: &UpstreamConf) -> Result<LoadBalancer<S>>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    let discovery: Box<dyn ServiceDiscovery + Send + Sync> = if let Some(file) = conf.file.as_ref() {
+        discovery::File::new(file)
+    } else if !conf.dns.is_empty() {
+        let queries = conf
+            .dns
+            .iter()
+            .map(|addr| {
+                let (name, port) = addr
+                    .rsplit_once(':')
+                    .and_then(|(name, port)| Some((name, port.parse().ok()?)))
+                    .or_err_with(ConfigError, || format!("invalid dns name {addr}"))?;
+                Ok(DnsQuery::Host {
+                    name: name.to_string(),
+                    port,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Dns::new(DnsResolver::from_resolv_conf()?, queries)
+    } else {
+        discovery::Static::new(resolve_backends(&conf.backends)?)
+    };
+    let dynamic = conf.file.is_some() || !conf.dns.is_empty();
+
+    let mut lb = LoadBalancer::from_backends(Backends::new(discovery));
+    if dynamic {
+        lb.update_frequency = Some(
+            conf.discovery_interval_ms
+                .map_or(DEFAULT_DISCOVERY_INTERVAL, Duration::from_millis),
+        );
+    }
+    if let Some(hc) = conf.health_check.as_ref() {
+        lb.set_health_check(health_check(conf, hc)?);
+        lb.health_check_frequency = Some(Duration::from_millis(hc.interval_ms));
+    }
+    if let Some(outlier) = conf.outlier_detection.as_ref() {
+        lb.set_outlier_detection(outlier.config()?);
+    }
+    Ok(lb)
+}
+
+/// resolve the static backends, `host:port` is resolved once here
+fn resolve_backends(backends: &[BackendConf]) -> Result<BTreeSet<Backend>> {
+    let mut resolved = BTreeSet::new();
+    for backend in backends {
+        let addrs = backend
+            .addr()
+            .to_socket_addrs()
+            .or_err_with(DnsError, || format!("failed to resolve {}", backend.addr()))?;
+        for addr in addrs {
+            resolved.insert(Backend {
+                addr,
+                weight: backend.weight(),
+            });
+        }
+    }
+    if resolved.is_empty() {
+        return Error::e_explain(DnsError, "no backend address resolved");
+    }
+    Ok(resolved)
+}
+
+fn health_check(upstream: &UpstreamConf, conf: &HealthCheckConf) -> Result<Box<dyn HealthCheck + Send + Sync>> {
+    let timeout = Duration::from_millis(conf.timeout_ms);
+    Ok(match conf.check_type {
+        HealthCheckType::Tcp => {
+            let mut check = TcpHealthCheck::new();
+            check.consecutive_success = conf.consecutive_success;
+            check.consecutive_failure = conf.consecutive_failure;
+            check.connect_timeout = timeout;
+            check
+        }
+        HealthCheckType::Http => {
+            let host = conf.host.as_deref().unwrap_or(&upstream.sni);
+            let mut check = HttpHealthCheck::new(host)?;
+            let mut req = RequestHeader::build("GET", conf.path.as_bytes(), None)?;
+            req.append_header("Host", host)?;
+            check.req = req;
+            check.expected_status = conf.expected_status.clone();
+            check.consecutive_success = conf.consecutive_success;
+            check.consecutive_failure = conf.consecutive_failure;
+            check.connect_timeout = timeout;
+            check.read_timeout = timeout;
+            Box::new(check)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn group(yaml: &str) -> UpstreamGroup {
+        let conf = Config::from_yaml(yaml).unwrap();
+        let (name, upstream) = conf.upstreams.iter().next().unwrap();
+        UpstreamGroup::new(name, upstream).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_static_group() {
+        let group = group(
+            r#"
+upstreams:
+  backend:
+    backends: ["127.0.0.1:8000", "127.0.0.1:8001"]
+    sni: example.com
+    read_timeout_ms: 1500
+"#,
+        );
+        // nothing to run in the background
+        assert!(group.background_service().is_none());
+        group.update().await.unwrap();
+
+        let (_, first) = group.peer(b"").unwrap();
+        let (_, second) = group.peer(b"").unwrap();
+        assert_ne!(first.address, second.address);
+        assert_eq!(first.sni, "example.com");
+        assert_eq!(first.options.read_timeout, Some(Duration::from_millis(1500)));
+    }
+
+    #[tokio::test]
+    async fn test_no_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("backends.yaml");
+        std::fs::write(&file, "[]").unwrap();
+        let group = group(&format!(
+            "upstreams:\n  backend:\n    file: {}\n    health_check: {{type: http, host: example.com}}\n",
+            file.display()
+        ));
+        assert!(group.background_service().is_some());
+        group.update().await.unwrap();
+        assert_eq!(group.peer(b"").unwrap_err().etype(), &HTTPStatus(503));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let group = group(
+            r#"
+upstreams:
+  backend:
+    backends: ["127.0.0.1:8000"]
+    circuit_breaker: {min_calls: 2}
+"#,
+        );
+        let call = group.allow().unwrap().unwrap();
+        group.report(call, Some(Duration::from_millis(10)));
+        let call = group.allow().unwrap().unwrap();
+        group.report(call, None);
+        let e = group.allow().unwrap_err();
+        assert_eq!(e.etype(), &CircuitOpen);
+        assert_eq!(e.context.as_ref().unwrap().to_string(), "upstream backend is failing");
+    }
+}